@@ -0,0 +1,86 @@
+use crate::{DiskAlloc, DiskAllocBuilder, Stats};
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    fmt,
+};
+
+/// Arena on disk: allocate many values, then throw
+/// all of them away at once with [`DiskArena::reset`].
+///
+/// Meant for batch jobs building huge temporary
+/// structures per iteration.
+/// Destructors of the values are never run.
+///
+/// ```rust
+/// use diskallocator::DiskArena;
+///
+/// let mut arena = DiskArena::new().unwrap();
+/// for batch in 0..3_u64 {
+///     let a = arena.alloc([batch; 1024]);
+///     let b = arena.alloc(batch + 1);
+///     assert_eq!(a[1023] + 1, *b);
+///     arena.reset();
+/// }
+/// ```
+pub struct DiskArena {
+    alloc: DiskAlloc,
+}
+
+impl DiskArena {
+    /// Arena on a new temporary file, see [`DiskAlloc::new`].
+    pub fn new() -> Result<Self, std::io::Error> {
+        Self::with_builder(&DiskAllocBuilder::new())
+    }
+
+    /// Arena on a new temporary file
+    /// configured by `builder`.
+    pub fn with_builder(builder: &DiskAllocBuilder) -> Result<Self, std::io::Error> {
+        // The allocator is never shared, so reset can free everything
+        Ok(Self {
+            alloc: builder.build()?,
+        })
+    }
+
+    /// Move `value` into the arena.
+    ///
+    /// # Panics
+    /// Panics if the file can not grow,
+    /// see [`DiskArena::try_alloc`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T>(&self, value: T) -> &mut T {
+        self.try_alloc(value)
+            .expect("failed to allocate in the disk arena")
+    }
+
+    /// Like [`DiskArena::alloc`], but fails if the file can not grow.
+    #[allow(clippy::mut_from_ref)]
+    pub fn try_alloc<T>(&self, value: T) -> Result<&mut T, AllocError> {
+        let ptr = self.alloc.allocate(Layout::new::<T>())?.cast::<T>();
+        // Every allocation is only handed out once, until reset
+        unsafe {
+            ptr.as_ptr().write(value);
+            Ok(&mut *ptr.as_ptr())
+        }
+    }
+
+    /// Throw away all values, truncating the file.
+    ///
+    /// Takes constant time, no matter how many values there are.
+    pub fn reset(&mut self) {
+        // No references into the arena exist, as they borrow self
+        unsafe { self.alloc.reset() }.expect("failed to truncate the disk arena");
+    }
+
+    /// Current usage statistics, see [`DiskAlloc::stats`].
+    pub fn stats(&self) -> Stats {
+        self.alloc.stats()
+    }
+}
+
+impl fmt::Debug for DiskArena {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskArena")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
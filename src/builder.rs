@@ -0,0 +1,783 @@
+use crate::{
+    diskalloc::{AtomDiskAlloc, Options, Quota, Trace},
+    DiskAlloc, DiskAllocError, DiskBudget,
+};
+use std::{
+    alloc::Layout,
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tempfile::TempPath;
+
+/// When to shrink the backing file, see [`DiskAllocBuilder::shrink_policy`].
+///
+/// Shrinking saves disk space, not shrinking saves syscalls
+/// for workloads alternating between growing and shrinking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShrinkPolicy {
+    /// Only shrink on [`DiskAlloc::trim`].
+    Never,
+    /// Shrink as soon as the end of the file is freed.
+    Immediate,
+    /// Shrink once more than `slack` bytes at the end are free.
+    Hysteresis {
+        /// Free bytes to keep in the file at most.
+        slack: u64,
+    },
+}
+
+/// What happens to the backing file when the allocator is dropped,
+/// see [`DiskAllocBuilder::on_drop`].
+///
+/// By default, temporary files are deleted, and files of
+/// [`DiskAlloc::on_file`] and [`DiskAlloc::create`] are kept
+/// at their length, which may include free space at the end
+/// (see [`DiskAllocBuilder::growth_chunk`]).
+/// Read-only and copy-on-write allocators leave the file alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropBehavior {
+    /// Leave the file as it is, also temporary files with
+    /// a name (see [`DiskAllocBuilder::prefix`]).
+    /// Unnamed temporary files vanish anyway.
+    Keep,
+    /// Like [`DropBehavior::Keep`], but cut the file to
+    /// the used size first.
+    TruncateToUsed,
+    /// Delete the file, also one passed to [`DiskAlloc::on_file`].
+    /// Finding the name of an open file needs Linux or Windows,
+    /// elsewhere only temporary files and files opened by path
+    /// (see [`DiskAllocBuilder::create`]) are deleted.
+    Delete,
+}
+
+/// How to find space for allocations, see [`DiskAllocBuilder::strategy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Allocate at the end of the file, reusing freed blocks first fit.
+    /// Wastes nothing for alignment of growing vectors.
+    #[default]
+    Bump,
+    /// Round every allocation up to a power of two, aligned to its
+    /// size, and merge freed blocks with their neighbour of the same
+    /// size. Finds free space in constant time and fragments less
+    /// with many allocations of mixed sizes coming and going, e.g.
+    /// the tables of hash maps, for up to half of each block unused.
+    Buddy,
+}
+
+/// Configures and creates a [`DiskAlloc`].
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use diskallocator::DiskAllocBuilder;
+///
+/// let alloc = DiskAllocBuilder::new()
+///     .dir(std::env::temp_dir())
+///     .reservation(16 * 1024 * 1024 * 1024)
+///     .growth_chunk(64 * 1024 * 1024)
+///     .build()
+///     .unwrap();
+/// let mut v: Vec<u64, _> = Vec::new_in(alloc);
+/// v.push(1);
+/// ```
+#[derive(Clone, Debug)]
+pub struct DiskAllocBuilder {
+    dirs: Vec<PathBuf>,
+    min_free_space: u64,
+    stripe_dirs: Vec<PathBuf>,
+    prefix: Option<String>,
+    chain_segments: bool,
+    in_memory: bool,
+    checksums: bool,
+    background_flush: Option<(Duration, u64)>,
+    background_extend: Option<Duration>,
+    max_file_size: Option<u64>,
+    record_trace: bool,
+    mode: u32,
+    owner: (Option<u32>, Option<u32>),
+    options: Options,
+}
+
+impl Default for DiskAllocBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskAllocBuilder {
+    /// Builder with the same settings as [`DiskAlloc::new`].
+    pub fn new() -> Self {
+        Self {
+            dirs: crate::sys::default_dirs(),
+            min_free_space: 0,
+            stripe_dirs: Vec::new(),
+            prefix: None,
+            chain_segments: true,
+            in_memory: false,
+            checksums: false,
+            background_flush: None,
+            background_extend: None,
+            max_file_size: None,
+            record_trace: false,
+            mode: 0o600,
+            owner: (None, None),
+            options: Options::default(),
+        }
+    }
+
+    /// Directory to create the temporary file in.
+    /// Defaults to `/var/tmp/`, falling back to the system temporary
+    /// directory (`$TMPDIR`, or `/tmp`), see [`Self::dirs`].
+    /// On Windows, only the system temporary directory is used.
+    pub fn dir<P: AsRef<Path>>(self, dir: P) -> Self {
+        self.dirs([dir])
+    }
+
+    /// Directories to try in order, e.g. `/var/tmp`, then a scratch disk.
+    /// The temporary file is created in the first one which is writable
+    /// and has at least [`Self::min_free_space`] left.
+    /// See [`DiskAlloc::dir`] for the one chosen.
+    ///
+    /// Chained segments pick again, so they can move on to the next
+    /// directory once the first one filled up.
+    pub fn dirs<I, P>(mut self, dirs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.dirs = dirs
+            .into_iter()
+            .map(|dir| dir.as_ref().to_path_buf())
+            .collect();
+        self
+    }
+
+    /// Skip directories (see [`Self::dirs`]) with less than `bytes`
+    /// of free space left for the current user. Defaults to 0.
+    pub fn min_free_space(mut self, bytes: u64) -> Self {
+        self.min_free_space = bytes;
+        self
+    }
+
+    /// Create one temporary file in each of `dirs`, e.g. on
+    /// different disks, and let them take turns for new allocations.
+    ///
+    /// Whole allocations are striped, not the pages within one,
+    /// so this spreads the disk load of many vectors filled at once.
+    /// Chained segments are spread over the same directories.
+    /// Replaces [`Self::dir`], and allocations always need
+    /// a lock, see [`Self::growth_chunk`].
+    pub fn stripe_dirs<I, P>(mut self, dirs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.stripe_dirs = dirs
+            .into_iter()
+            .map(|dir| dir.as_ref().to_path_buf())
+            .collect();
+        self
+    }
+
+    /// Label the allocator, e.g. with the job it is used for,
+    /// to tell it apart in the [`crate::registry`].
+    /// Also see [`DiskAlloc::name`].
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.options.name = Some(name.into().into());
+        self
+    }
+
+    /// Give the temporary file a visible name starting with `prefix`.
+    ///
+    /// Without a prefix, the file is unlinked right away
+    /// and can not be seen in the directory.
+    /// With a prefix, the file stays visible (e.g. for `du`)
+    /// until the allocator is dropped.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Mode bits of the files created, temporary or persistent
+    /// (see [`Self::create`]). Set after creating the file,
+    /// so the umask does not apply. Defaults to `0o600`,
+    /// only the owner can read the spilled data.
+    ///
+    /// Has no effect on Windows, where files get the
+    /// permissions of their directory.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Change the owning user and group of the files created,
+    /// `None` keeps the one of the process.
+    /// Changing the user needs privileges, building fails otherwise.
+    ///
+    /// Has no effect on Windows.
+    pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.owner = (uid, gid);
+        self
+    }
+
+    /// Bytes of address space to reserve, see
+    /// [`DiskAlloc::with_reservation`].
+    ///
+    /// Defaults to 512GiB (1GiB on 32-bit targets), or the biggest
+    /// reservation (down to 64MiB) which can be mapped, if the
+    /// address space is limited (e.g. `ulimit -v`).
+    /// [`DiskAlloc::remaining_reservation`] tells how much it got.
+    /// Building fails if `bytes` can not be reserved, or do not
+    /// fit into the address space of the target at all.
+    pub fn reservation(mut self, bytes: u64) -> Self {
+        self.options.reservation = bytes;
+        self.options.auto_reservation = false;
+        self
+    }
+
+    /// Grow and shrink the file in multiples of `bytes`,
+    /// saving resize syscalls for the cost of some disk space.
+    ///
+    /// Defaults to 1, resizing the file to exactly the used size.
+    /// With bigger chunks, the file does not shrink again
+    /// until [`DiskAlloc::trim`] is called, see [`Self::shrink_policy`].
+    /// Allocations fitting into the rest of the last chunk
+    /// do not lock the allocator, so threads sharing it
+    /// need the lock less often.
+    ///
+    /// # Panics
+    /// Panics if `bytes` is zero.
+    pub fn growth_chunk(mut self, bytes: u64) -> Self {
+        assert!(bytes > 0, "growth chunk must not be zero");
+        self.options.growth_chunk = bytes;
+        self
+    }
+
+    /// Write all data back to disk (`msync` and `fsync`)
+    /// when the allocator is dropped. Defaults to `false`.
+    ///
+    /// Otherwise the OS writes it back some time later, which
+    /// is lost on a power failure. Persistent vectors are
+    /// configured with [`crate::DiskVec::create_with`].
+    pub fn flush_on_drop(mut self, flush: bool) -> Self {
+        self.options.flush_on_drop = flush;
+        self
+    }
+
+    /// Give the disk space of freed blocks in the middle of the file
+    /// back to the file system (`fallocate` with `FALLOC_FL_PUNCH_HOLE`).
+    /// Only has an effect on Linux. Defaults to `true`.
+    pub fn punch_holes(mut self, punch: bool) -> Self {
+        self.options.punch_holes = punch;
+        self
+    }
+
+    /// Let the OS reclaim the memory of freed space without writing
+    /// it back (`madvise` with `MADV_FREE`): freed blocks in the middle
+    /// of the file, and freed space at its end while the file is not
+    /// truncated (see [`Self::shrink_policy`]).
+    ///
+    /// Linux only supports `MADV_FREE` for anonymous memory, so there
+    /// the pages are removed (`MADV_REMOVE`), which also gives their
+    /// disk space back. Defaults to `false`, as growing into the space
+    /// again has to fault the pages in anew.
+    pub fn lazy_free(mut self, free: bool) -> Self {
+        self.options.lazy_free = free;
+        self
+    }
+
+    /// Map the file with huge pages, cutting TLB misses
+    /// when iterating over big vectors.
+    ///
+    /// Linux only. Files on a `hugetlbfs` mount (see [`Self::dir`])
+    /// are mapped with `MAP_HUGETLB`, growing in 2MiB steps.
+    /// For all other files, transparent huge pages are requested
+    /// with `MADV_HUGEPAGE`. If neither is available,
+    /// normal pages are used.
+    ///
+    /// `false` rules out transparent huge pages (`MADV_NOHUGEPAGE`),
+    /// which can slow down sparse random access by faulting in
+    /// 2MiB at a time. By default, the system setting applies.
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.options.map.huge_pages = Some(huge_pages);
+        self
+    }
+
+    /// Fault in the data of an existing file when mapping it
+    /// (`MAP_POPULATE` on Linux, reading every page elsewhere),
+    /// so reopened vectors do not pay for page faults in later,
+    /// maybe latency sensitive, reads. Opening reads the whole
+    /// file then. Defaults to `false`.
+    pub fn populate(mut self, populate: bool) -> Self {
+        self.options.map.populate = populate;
+        self
+    }
+
+    /// Overwrite the file with zeros (and wait for it to reach the
+    /// disk) when the allocator is dropped, and freed parts before
+    /// they are cut off or punched out of it. Defaults to `false`.
+    ///
+    /// For sensitive data, which then does not end up in disk blocks
+    /// the file system hands out to other files later. Makes freeing
+    /// and dropping take as long as writing the data once more.
+    /// Files of [`Self::create`] are never erased on drop.
+    /// Copies kept by the file system or the disk (journals,
+    /// snapshots, wear leveling of SSDs) are out of reach.
+    pub fn erase_on_drop(mut self, erase: bool) -> Self {
+        self.options.erase = erase;
+        self
+    }
+
+    /// Whether child processes created with `fork()` get the mapping.
+    /// Defaults to `true`.
+    ///
+    /// Then parent and child share the file and its mapping:
+    /// each sees what the other writes, and both allocating
+    /// (or one dropping the allocator) corrupts the other's data.
+    /// With `false`, the mapping is left out of the child
+    /// (`MADV_DONTFORK`), so it gets a SIGSEGV on any access,
+    /// and dropping the allocator in the child leaves the file alone.
+    /// The child can create allocators of its own.
+    ///
+    /// Linux only, building fails with `false` on other Unix systems.
+    /// Copy-on-write allocators (see [`Self::open_private`]) are
+    /// copied into the child either way, Windows has no `fork()`.
+    pub fn inherit_on_fork(mut self, inherit: bool) -> Self {
+        self.options.map.dont_fork = !inherit;
+        self
+    }
+
+    /// Allocate disk space when the file grows (`fallocate`),
+    /// instead of growing a sparse file.
+    ///
+    /// A full disk then makes the allocation fail with an
+    /// `AllocError`, instead of a SIGBUS crash on the first write.
+    /// Falls back to sparse growth on file systems without `fallocate`.
+    /// Linux and FreeBSD (`posix_fallocate`) only, defaults to `true` there.
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.options.map.preallocate = preallocate;
+        self
+    }
+
+    /// When the reservation is used up, continue on another
+    /// temporary file with its own reservation instead of
+    /// failing the allocation. Defaults to `true`.
+    ///
+    /// Allocations that do not fit into the reservation
+    /// get a file with a big enough reservation.
+    /// Allocators on a given file never chain.
+    pub fn chain_segments(mut self, chain: bool) -> Self {
+        self.chain_segments = chain;
+        self
+    }
+
+    /// When the reservation is used up by a single allocation,
+    /// move the mapping to a bigger reservation (at least twice
+    /// the size) instead of failing or chaining segments, and
+    /// return the moved block from `grow`. Defaults to `false`.
+    ///
+    /// Pointers into the allocator other than the grown block,
+    /// like those from [`DiskAlloc::data`], become invalid.
+    /// Fails as before with more than one allocation, for files
+    /// opened with [`Self::open`] and on Windows.
+    pub fn grow_reservation(mut self, grow: bool) -> Self {
+        self.options.grow_reservation = grow;
+        self
+    }
+
+    /// Start allocations of at least `bytes` at a page boundary
+    /// and round them up to whole pages. Off by default.
+    ///
+    /// Costs up to a page of disk space per allocation, but no
+    /// other allocation shares their pages, so the disk space
+    /// of freed ones is given back (see [`Self::punch_holes`]),
+    /// and [`DiskAlloc::evict`] or `mprotect` work per allocation.
+    pub fn page_align_above(mut self, bytes: usize) -> Self {
+        self.options.page_align_above = Some(bytes);
+        self
+    }
+
+    /// Grow the file to `bytes` right away (and allocate
+    /// the disk space, see [`Self::preallocate`]),
+    /// so a workload of known size does not grow it step by step.
+    ///
+    /// The file does not shrink below `bytes` again,
+    /// not even with [`DiskAlloc::trim`].
+    /// Chained segments (see [`Self::chain_segments`]) start empty,
+    /// striped files (see [`Self::stripe_dirs`]) all get the capacity.
+    pub fn capacity(mut self, bytes: u64) -> Self {
+        self.options.capacity = bytes;
+        self
+    }
+
+    /// Keep the data in memory (`memfd_create`) instead of a file
+    /// on disk, e.g. for test suites without a big temporary
+    /// directory. Everything else works the same.
+    /// Directory and prefix are ignored. Defaults to `false`.
+    ///
+    /// Where there is no `memfd_create` (other systems than Linux and
+    /// FreeBSD), anonymous memory is mapped instead, backed by the page
+    /// file on Windows. There is no file then, so [`DiskAlloc::file`]
+    /// and [`DiskAlloc::into_file`] fail, and the reservation can not
+    /// be moved (see [`Self::grow_reservation`]).
+    /// Under Miri, which can not map memory, the reservation
+    /// (at most 64MiB) is allocated from the heap.
+    pub fn in_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+
+    /// Keep a CRC32 checksum of every 4KiB of data of persistent
+    /// allocators (see [`Self::create`]), in a file next to it
+    /// with `.crc` appended to the name. Defaults to `false`.
+    ///
+    /// The checksums are written by [`DiskAlloc::flush`] and on drop,
+    /// which read all the data. Opening the file checks them,
+    /// failing with [`DiskAllocError::ChecksumMismatch`] if the disk
+    /// silently corrupted the data, or changes after the last flush
+    /// were lost in a crash. Files without checksums are not checked.
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// Start a thread writing data back to disk every `interval`,
+    /// once at least `dirty_bytes` were added at the end of the file
+    /// since the last time. The written pages are dropped from
+    /// the page cache, see [`DiskAlloc::evict`]. Off by default.
+    ///
+    /// Long running appends otherwise fill the memory with
+    /// dirty pages, and stall when the OS writes them all at once.
+    /// Only data appended to the file is flushed, changes before
+    /// it are written back by the OS as usual.
+    /// The thread stops when the allocator is dropped.
+    pub fn background_flush(mut self, interval: Duration, dirty_bytes: u64) -> Self {
+        self.background_flush = Some((interval, dirty_bytes));
+        self
+    }
+
+    /// Start a thread keeping the file `distance` bytes longer than
+    /// the used part (`fallocate` with [`Self::preallocate`]),
+    /// checking every `interval`. Off by default.
+    ///
+    /// Allocations at the end of the file then rarely have to
+    /// wait for the file to grow, which updates file system
+    /// metadata. Unless set, the [`Self::shrink_policy`] only
+    /// shrinks the file once more than `distance` is left over.
+    /// The thread stops when the allocator is dropped.
+    pub fn background_extend(mut self, interval: Duration, distance: u64) -> Self {
+        self.background_extend = Some(interval);
+        self.options.extend_ahead = distance;
+        self
+    }
+
+    /// How to find space for allocations.
+    /// Defaults to [`Strategy::Bump`].
+    ///
+    /// Buddy allocations always need a lock, see [`Self::growth_chunk`].
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.options.buddy = strategy == Strategy::Buddy;
+        self
+    }
+
+    /// What to do with the file when the allocator is dropped,
+    /// see [`DropBehavior`].
+    pub fn on_drop(mut self, behavior: DropBehavior) -> Self {
+        self.options.on_drop = Some(behavior);
+        self
+    }
+
+    /// When to shrink the file after memory at its end was freed.
+    ///
+    /// Defaults to [`ShrinkPolicy::Immediate`], or
+    /// [`ShrinkPolicy::Never`] with a [`Self::growth_chunk`].
+    pub fn shrink_policy(mut self, policy: ShrinkPolicy) -> Self {
+        self.options.shrink_policy = Some(policy);
+        self
+    }
+
+    /// Never reuse or give back freed memory of a persistent
+    /// allocator, so other processes can follow the file while it
+    /// is written, see [`DiskAlloc::refresh`]. Defaults to `false`.
+    ///
+    /// The used size only grows: the file never shrinks below it,
+    /// whatever the [`Self::shrink_policy`], and freed or shrunk
+    /// blocks stay where they are, untouched.
+    /// [`crate::DiskVec::compact`] fails, and [`Strategy::Buddy`]
+    /// is rejected when the file is created or opened.
+    /// The mode is recorded in the header when the file is opened
+    /// for writing. Temporary files are not affected.
+    pub fn append_only(mut self, append_only: bool) -> Self {
+        self.options.append_only = append_only;
+        self
+    }
+
+    /// Fail allocations with an `AllocError` instead of growing
+    /// the file beyond `bytes`, e.g. to keep a runaway vector
+    /// from filling the file system. Unlimited by default.
+    ///
+    /// Chained segments (see [`Self::chain_segments`])
+    /// count towards the same limit.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Record every allocation, resize and free with a timestamp,
+    /// and every new file length, to write them out with
+    /// [`DiskAlloc::write_trace`]. Defaults to `false`.
+    ///
+    /// The events are kept in memory until the allocator is
+    /// dropped, about 48 bytes each.
+    pub fn record_trace(mut self, record: bool) -> Self {
+        self.record_trace = record;
+        self
+    }
+
+    /// Count the files of the allocator against `budget`, shared
+    /// with all other allocators using it, see [`DiskBudget`].
+    /// Applies along with [`Self::max_file_size`].
+    pub fn budget(mut self, budget: &DiskBudget) -> Self {
+        self.options.budget = Some(budget.clone());
+        self
+    }
+
+    /// Call `hook` with the new length of a file
+    /// whenever it grew, e.g. to report the disk usage.
+    ///
+    /// Runs while the allocator is locked, so it must not
+    /// use the allocator. Chained and striped segments
+    /// report the length of their own file.
+    pub fn on_grow(mut self, hook: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        self.options.hooks.on_grow = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with the layout of every allocation,
+    /// including grown and shrunk ones.
+    pub fn on_alloc(mut self, hook: impl Fn(Layout) + Send + Sync + 'static) -> Self {
+        self.options.hooks.on_alloc = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with the reason of every failed allocation,
+    /// see [`DiskAlloc::take_error`]. Like [`Self::on_grow`],
+    /// it must not use the allocator.
+    ///
+    /// A panic in the hook makes every later use of the allocator
+    /// panic as well, to stop the process use [`std::process::abort`].
+    pub fn on_error(mut self, hook: impl Fn(&DiskAllocError) + Send + Sync + 'static) -> Self {
+        self.options.hooks.on_error = Some(Arc::new(hook));
+        self
+    }
+
+    /// Create the temporary file and the memory map.
+    pub fn build(&self) -> Result<DiskAlloc, DiskAllocError> {
+        // All segments share the quota
+        let builder = Self {
+            options: self.allocator_options(),
+            ..self.clone()
+        };
+        let stripes = (0..self.stripe_dirs.len().max(1))
+            .map(|index| builder.new_segment(0, index))
+            .collect::<Result<_, _>>()?;
+        let alloc = DiskAlloc::from_stripes(stripes, self.chain_segments.then_some(builder));
+        self.start_threads(alloc)
+    }
+
+    fn start_threads(&self, mut alloc: DiskAlloc) -> Result<DiskAlloc, DiskAllocError> {
+        if let Some((interval, dirty_bytes)) = self.background_flush {
+            alloc = alloc.with_flusher(interval, dirty_bytes)?;
+        }
+        if let Some(interval) = self.background_extend {
+            alloc = alloc.with_extender(interval, self.options.extend_ahead)?;
+        }
+        Ok(alloc)
+    }
+
+    // Options for a new allocator, with its own quota and trace.
+    fn allocator_options(&self) -> Options {
+        Options {
+            quota: self.max_file_size.map(|max| Arc::new(Quota::new(max))),
+            trace: self.record_trace.then(|| Arc::new(Trace::new())),
+            ..self.options.clone()
+        }
+    }
+
+    // Apply the mode and owner to a file just created.
+    fn set_permissions(&self, file: &File) -> Result<(), std::io::Error> {
+        let (uid, gid) = self.owner;
+        crate::sys::set_permissions(file, self.mode, uid, gid)
+    }
+
+    // Temporary file with a reservation of at least `min_reservation`,
+    // in the stripe directory for the segment at `index`.
+    pub(crate) fn new_segment(
+        &self,
+        min_reservation: u64,
+        index: usize,
+    ) -> Result<AtomDiskAlloc, std::io::Error> {
+        let mut options = self.options.clone();
+        if min_reservation > options.reservation {
+            options.reservation = min_reservation;
+            options.auto_reservation = false;
+        }
+        if index >= self.stripe_dirs.len().max(1) {
+            options.capacity = 0;
+        }
+        let dirs = match self.stripe_dirs.len() {
+            0 => &self.dirs[..],
+            stripes => std::slice::from_ref(&self.stripe_dirs[index % stripes]),
+        };
+        if self.in_memory {
+            return AtomDiskAlloc::in_memory(options);
+        }
+        let mut last_error = std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no directory for the temporary file",
+        );
+        for dir in dirs {
+            match self.tempfile_in(dir) {
+                Ok((file, path)) => {
+                    let mut alloc = AtomDiskAlloc::on_file(file, path, options)?;
+                    alloc.set_dir(dir);
+                    return Ok(alloc);
+                }
+                Err(err) => last_error = err,
+            }
+        }
+        Err(last_error)
+    }
+
+    // Temporary file in `dir`, if it has enough space left.
+    fn tempfile_in(&self, dir: &Path) -> Result<(File, Option<TempPath>), std::io::Error> {
+        let (file, path) = match &self.prefix {
+            None => (crate::sys::tempfile_in(dir)?, None),
+            Some(prefix) => {
+                let (file, path) = tempfile::Builder::new()
+                    .prefix(prefix)
+                    .tempfile_in(dir)?
+                    .into_parts();
+                (file, Some(path))
+            }
+        };
+        if self.min_free_space > 0 && crate::sys::free_space(&file)? < self.min_free_space {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                format!(
+                    "less than {} bytes free in {}",
+                    self.min_free_space,
+                    dir.display()
+                ),
+            ));
+        }
+        self.set_permissions(&file)?;
+        Ok((file, path))
+    }
+
+    /// Use custom file (must be read/write) instead of
+    /// a temporary one, see [`DiskAlloc::on_file`].
+    /// Directory, prefix, mode and owner are ignored.
+    pub fn build_on_file(&self, file: File) -> Result<DiskAlloc, DiskAllocError> {
+        self.build_on_file_at(file, 0)
+    }
+
+    /// Like [`Self::build_on_file`], but starting `offset`
+    /// bytes into the file, see [`DiskAlloc::on_file_at`].
+    pub fn build_on_file_at(&self, file: File, offset: u64) -> Result<DiskAlloc, DiskAllocError> {
+        let mut options = self.allocator_options();
+        options.map.offset = offset;
+        let alloc = AtomDiskAlloc::on_file(file, None, options)?;
+        self.start_threads(DiskAlloc::from_segments(alloc, None))
+    }
+
+    /// Create a persistent allocator at `path`,
+    /// see [`DiskAlloc::create`]. The file gets
+    /// the mode and owner set on the builder.
+    pub fn create<P: AsRef<Path>>(&self, path: P) -> Result<DiskAlloc, DiskAllocError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())?;
+        self.set_permissions(&file)?;
+        let mut alloc = AtomDiskAlloc::create(file, self.allocator_options())?;
+        alloc.set_path(path.as_ref())?;
+        if self.checksums {
+            alloc.use_checksums(path.as_ref())?;
+        }
+        self.start_threads(DiskAlloc::from_segments(alloc, None))
+    }
+
+    /// Reopen a persistent allocator at `path`,
+    /// see [`DiskAlloc::open`].
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<DiskAlloc, DiskAllocError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref())?;
+        let mut alloc = AtomDiskAlloc::open(file, self.allocator_options())?;
+        alloc.set_path(path.as_ref())?;
+        if self.checksums {
+            alloc.use_checksums(path.as_ref())?;
+        }
+        self.start_threads(DiskAlloc::from_segments(alloc, None))
+    }
+
+    /// Open a persistent allocator at `path` copy-on-write,
+    /// see [`DiskAlloc::open_private`].
+    pub fn open_private<P: AsRef<Path>>(&self, path: P) -> Result<DiskAlloc, DiskAllocError> {
+        let file = File::open(path.as_ref())?;
+        let mut options = self.allocator_options();
+        options.map.private = true;
+        let mut alloc = AtomDiskAlloc::open(file, options)?;
+        alloc.set_path(path.as_ref())?;
+        if self.checksums {
+            alloc.use_checksums(path.as_ref())?;
+        }
+        Ok(DiskAlloc::from_segments(alloc, None))
+    }
+
+    /// Open a persistent allocator at `path` read-only,
+    /// see [`DiskAlloc::open_readonly`].
+    pub fn open_readonly<P: AsRef<Path>>(&self, path: P) -> Result<DiskAlloc, DiskAllocError> {
+        let file = File::open(path.as_ref())?;
+        let mut options = self.allocator_options();
+        options.map.read_only = true;
+        let mut alloc = AtomDiskAlloc::open(file, options)?;
+        alloc.set_path(path.as_ref())?;
+        if self.checksums {
+            alloc.use_checksums(path.as_ref())?;
+        }
+        Ok(DiskAlloc::from_segments(alloc, None))
+    }
+
+    /// Create an append-only persistent allocator (see
+    /// [`Self::append_only`]) in the POSIX shared memory object
+    /// `name`, see [`DiskAlloc::create_shared_memory`].
+    /// It gets the mode and owner set on the builder,
+    /// checksums are not kept.
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    pub fn create_shared_memory(&self, name: &str) -> Result<DiskAlloc, DiskAllocError> {
+        let file = crate::sys::shm_open(name, true)?;
+        self.set_permissions(&file)?;
+        let mut options = self.allocator_options();
+        options.append_only = true;
+        let alloc = AtomDiskAlloc::create(file, options)?;
+        self.start_threads(DiskAlloc::from_segments(alloc, None))
+    }
+
+    /// Open the shared memory object `name` read-only,
+    /// see [`DiskAlloc::open_shared_memory`].
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    pub fn open_shared_memory(&self, name: &str) -> Result<DiskAlloc, DiskAllocError> {
+        let file = crate::sys::shm_open(name, false)?;
+        let mut options = self.allocator_options();
+        options.map.read_only = true;
+        let alloc = AtomDiskAlloc::open(file, options)?;
+        Ok(DiskAlloc::from_segments(alloc, None))
+    }
+}
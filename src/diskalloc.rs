@@ -1,269 +1,2945 @@
+mod buddy;
+mod budget;
+mod bump;
+#[cfg(feature = "debug-canaries")]
+mod canaries;
+mod checksums;
+pub(crate) mod dirty;
+mod extender;
+mod flusher;
+mod guards;
+mod poison;
+mod pressure;
+mod registry;
+mod segments;
+mod trace;
+#[cfg(any(debug_assertions, feature = "track-allocations"))]
+mod tracking;
+
+use crate::{
+    freelist::FreeList,
+    header::{
+        Header, Region, VecMeta, FLAG_APPEND_ONLY, FLAG_VEC, HEADER_LEN, JOURNAL_VERSION,
+        VEC_SLOTS, VERSION,
+    },
+    stats::{SizeCounters, Stats},
+    sys::{self, Mapping},
+    DiskAllocBuilder, DiskAllocError, DropBehavior, ShrinkPolicy,
+};
+pub use budget::DiskBudget;
+use bump::Bump;
+use checksums::Checksums;
+use dirty::DirtyPages;
+pub use pressure::MemoryPressure;
+pub use registry::{registry, AllocatorInfo};
+use segments::Segments;
 use std::{
-    alloc::{Allocator, Layout},
-    cell::RefCell,
+    alloc::{AllocError, Allocator, Layout},
+    collections::HashMap,
+    fmt,
     fs::File,
-    os::fd::AsRawFd,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     ptr::NonNull,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tempfile::TempPath;
+pub(crate) use trace::Trace;
+
+// Default size of the address space reserved per allocator.
+// 32-bit targets have 4GiB of address space at most
+pub(crate) const STORAGE: u64 = if usize::BITS < 64 {
+    1024 * 1024 * 1024
+} else {
+    512 * 1024 * 1024 * 1024
 };
+// Smallest reservation tried when the default one does not fit
+const MIN_AUTO_RESERVATION: u64 = 64 * 1024 * 1024;
+
+// Patterns for memory which is not initialized or not in use
+// anymore, with the `debug-fill` feature.
+const DEBUG_FILL: bool = cfg!(feature = "debug-fill");
+const ALLOCATED_FILL: u8 = 0xAA;
+const FREED_FILL: u8 = 0xDD;
+
+// Policies of a single allocator, filled by the builder.
+#[derive(Clone, Debug)]
+pub(crate) struct Options {
+    pub reservation: u64,
+    // Halve the reservation until it can be mapped, if not set explicitly
+    pub auto_reservation: bool,
+    pub growth_chunk: u64,
+    pub flush_on_drop: bool,
+    pub punch_holes: bool,
+    // Free the memory of dead pages, see DiskAllocBuilder::lazy_free
+    pub lazy_free: bool,
+    // See DiskAllocBuilder::background_extend
+    pub extend_ahead: u64,
+    pub map: sys::MapOptions,
+    pub guard_pages: bool,
+    // Allocate power of two blocks, see Strategy::Buddy
+    pub buddy: bool,
+    // Limit of the file size, shared by all segments
+    pub quota: Option<Arc<Quota>>,
+    // Limit of the file sizes of many allocators
+    pub budget: Option<DiskBudget>,
+    // See DiskAllocBuilder::record_trace, shared by all segments
+    pub trace: Option<Arc<Trace>>,
+    // See DiskAllocBuilder::name
+    pub name: Option<Arc<str>>,
+    // Derived from the growth chunk if not set
+    pub shrink_policy: Option<ShrinkPolicy>,
+    // Allocations this big take whole pages
+    pub page_align_above: Option<usize>,
+    // The file never gets shorter than this
+    pub capacity: u64,
+    // Move the mapping instead of failing, if nothing else points into it
+    pub grow_reservation: bool,
+    // Overwrite data with zeros before giving its disk space back
+    pub erase: bool,
+    // Never reuse freed space, see DiskAllocBuilder::append_only
+    pub append_only: bool,
+    // Defaults depend on whether the file is temporary
+    pub on_drop: Option<DropBehavior>,
+    pub hooks: Hooks,
+}
+
+type Hook<T> = Arc<dyn Fn(T) + Send + Sync>;
+type ErrorHook = Arc<dyn Fn(&DiskAllocError) + Send + Sync>;
+
+// Callbacks set on the builder, shared by all segments.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub on_grow: Option<Hook<u64>>,
+    pub on_alloc: Option<Hook<Layout>>,
+    pub on_error: Option<ErrorHook>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_grow", &self.on_grow.is_some())
+            .field("on_alloc", &self.on_alloc.is_some())
+            .field("on_error", &self.on_error.is_some())
+            .finish()
+    }
+}
+
+// Bytes of files an allocator may still use.
+#[derive(Debug)]
+pub(crate) struct Quota {
+    max: u64,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(max: u64) -> Self {
+        Self {
+            max,
+            used: 0.into(),
+        }
+    }
+
+    fn reserve(&self, bytes: u64) -> Result<(), std::io::Error> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&sum| sum <= self.max)
+            })
+            .map(|_| ())
+            .map_err(|_| DiskAllocError::QuotaExceeded.into())
+    }
+
+    // Count an existing file, even beyond the limit.
+    fn add(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    fn remaining(&self) -> u64 {
+        self.max.saturating_sub(self.used.load(Ordering::SeqCst))
+    }
+
+    fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            reservation: STORAGE,
+            auto_reservation: true,
+            growth_chunk: 1,
+            flush_on_drop: false,
+            punch_holes: true,
+            lazy_free: false,
+            extend_ahead: 0,
+            map: sys::MapOptions {
+                preallocate: cfg!(any(target_os = "linux", target_os = "freebsd")),
+                ..Default::default()
+            },
+            guard_pages: cfg!(feature = "debug-guards"),
+            buddy: false,
+            quota: None,
+            budget: None,
+            trace: None,
+            name: None,
+            shrink_policy: None,
+            on_drop: None,
+            page_align_above: None,
+            capacity: 0,
+            grow_reservation: false,
+            erase: false,
+            append_only: false,
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+// Keep file and pointer to memorymap.
+// Memory map can only be created once without changing
+// addresses. So create once with multiple gigabytes
+// of data and increase file size before allocating more.
+pub(crate) struct AtomDiskAlloc {
+    // Missing for anonymous memory, see `in_memory`
+    file: Option<File>,
+    // Keeps a named backing file alive, deletes it on drop.
+    path: Option<TempPath>,
+    // Path the file was opened with, for persistent allocators
+    opened_at: Option<PathBuf>,
+    // Directory a temporary file was created in
+    dir: Option<PathBuf>,
+    // Used size and file length, shared with lock-free allocations
+    bump: Arc<Bump>,
+    // Freed blocks before the end of the file.
+    // Only locked under the lock of DiskAlloc, so never contended.
+    free: Mutex<FreeList>,
+    // Free blocks of the buddy strategy
+    buddy: Mutex<buddy::BuddyLists>,
+    // See Stats, the other counters are read from `bump`
+    peak_file_size: AtomicU64,
+    resize_syscalls: AtomicU64,
+    // End of the data written back by the background flusher
+    flushed: AtomicU64,
+    // Used size at the last flush, data appended beyond it
+    // is written back by incremental flushes
+    synced: AtomicU64,
+    // Ranges marked as written since the last flush
+    dirty: Mutex<DirtyPages>,
+    // Real layout of guarded allocations by offset
+    guards: Mutex<HashMap<u64, Layout>>,
+    // Why the last allocation failed
+    error: Mutex<Option<DiskAllocError>>,
+    mmap: Mapping,
+    // Keep a header in the first page of the file
+    persistent: bool,
+    // Sidecar file with checksums of the data, if kept up to date
+    checksums: Option<Checksums>,
+    // Process which created the mapping, see DiskAllocBuilder::inherit_on_fork
+    pid: u32,
+    options: Options,
+}
+
+// SAFETY: the mapping is owned exclusively by this struct
+// and unmapped on drop, so moving it to another thread is fine.
+unsafe impl Send for AtomDiskAlloc {}
+
+// SAFETY: everything changed through a shared reference is atomic
+// or behind a Mutex, so sharing never races. The used size only
+// moves by compare-and-swap, which lock-free allocations rely on
+// already. Allocating through several references at once still
+// goes through the Mutex in DiskAlloc, to keep the free lists
+// consistent with the used size.
+unsafe impl Sync for AtomDiskAlloc {}
+
+fn calc_byte_skip_for_alignment(first_free_addr: usize, alignment: usize) -> usize {
+    (alignment - first_free_addr % alignment) % alignment
+}
+
+fn is_aligned(ptr: NonNull<u8>, alignment: usize) -> bool {
+    ptr.as_ptr().addr().is_multiple_of(alignment)
+}
+
+// `layout` with room for the canary after the block.
+fn canary_layout(layout: Layout) -> Layout {
+    #[cfg(feature = "debug-canaries")]
+    let layout = Layout::from_size_align(layout.size() + canaries::CANARY_LEN, layout.align())
+        .unwrap_or(layout);
+    layout
+}
+
+fn round_up_to_chunk(size: u64, chunk: u64) -> u64 {
+    size.div_ceil(chunk) * chunk
+}
+
+// Buddy blocks are merged and handed out again when freed.
+fn check_append_only(options: &Options) -> Result<(), std::io::Error> {
+    if options.append_only && options.buddy {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "append-only allocators can not use the buddy strategy",
+        ));
+    }
+    Ok(())
+}
+
+/// How the memory of an allocator is going to be accessed,
+/// see [`DiskAlloc::advise`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccessPattern {
+    /// No special treatment, the OS reads ahead a little.
+    #[default]
+    Normal,
+    /// Scanned from start to end, the OS reads ahead aggressively
+    /// and may drop pages soon after they were accessed.
+    Sequential,
+    /// Accessed at random places, the OS does not read ahead.
+    Random,
+}
+
+/// Manages the allocation of ideally one vector.  
+/// Sits on top of a file, and resizes it as needed
+/// by the vector.
+///
+/// Usage with vector:
+/// ```rust
+/// #![feature(allocator_api)]
+/// let alloc = diskallocator::DiskAlloc::new().unwrap();
+/// let data: Vec<u64, diskallocator::DiskAlloc> = Vec::new_in(alloc);
+/// ```
+///
+/// See [`DiskAllocBuilder`] for more configuration options.
+///
+/// In debug builds, every live allocation is recorded, and freeing,
+/// growing or shrinking memory which is not live (e.g. freeing it
+/// twice) or with a layout which does not fit it panics.
+#[derive(Clone)]
+pub struct DiskAlloc {
+    alloc: Arc<Mutex<Segments>>,
+    // Lock-free allocation in the first segment, if possible
+    bump: Option<Arc<Bump>>,
+    // See DiskAllocBuilder::page_align_above
+    page_align_above: Option<usize>,
+    hooks: Hooks,
+    trace: Option<Arc<Trace>>,
+    name: Option<Arc<str>>,
+    // See Stats::size_histogram
+    sizes: Arc<SizeCounters>,
+    #[cfg(any(debug_assertions, feature = "track-allocations"))]
+    tracker: Arc<tracking::Tracker>,
+    #[cfg(feature = "debug-canaries")]
+    canaries: Arc<canaries::Canaries>,
+    _flusher: Option<Arc<flusher::Flusher>>,
+    _extender: Option<Arc<extender::Extender>>,
+}
+
+impl fmt::Debug for DiskAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("DiskAlloc");
+        if let Some(name) = &self.name {
+            debug.field("name", name);
+        }
+        // Might be printed while it is locked, e.g. from a hook
+        let Ok(segments) = self.alloc.try_lock() else {
+            return debug.finish_non_exhaustive();
+        };
+        let stats = segments.stats();
+        let reservation: u64 = segments.iter().map(AtomDiskAlloc::reservation).sum();
+        debug
+            .field("backing_path", &segments.primary().backing_path())
+            .field("segments", &segments.len())
+            .field("reservation", &reservation)
+            .field("file_size", &stats.file_size)
+            .field("used_size", &stats.used_size)
+            .field("live_allocations", &stats.live_allocations)
+            .finish()
+    }
+}
+
+impl Drop for AtomDiskAlloc {
+    fn drop(&mut self) {
+        if self.options.map.dont_fork && std::process::id() != self.pid {
+            // A forked child without the mapping, the file is the parent's
+            if let Some(path) = self.path.take() {
+                let _ = path.keep();
+            }
+            return;
+        }
+        let writable = !self.options.map.read_only && !self.options.map.private;
+        let on_drop = self.options.on_drop.filter(|_| writable);
+        // The address range is unmapped, and might be used by others next
+        self.unpoison_all();
+        if self.options.erase && !self.persistent {
+            // Nobody to report to, the data stays on disk
+            let _ = self.erase(0, self.bump.file_len());
+        } else if self.options.flush_on_drop {
+            // Nobody to report to, the OS will write back eventually
+            let _ = self.flush();
+        } else if let Some(checksums) = &self.checksums {
+            let _ = checksums.update(self.persistent_data());
+        }
+        let file_len = match on_drop {
+            Some(DropBehavior::TruncateToUsed) if !self.options.map.shared_file => self.get_size(),
+            _ => self.bump.file_len(),
+        };
+        self.mmap.close(self.file.as_ref(), file_len);
+        self.release_quota(self.bump.file_len(), 0);
+        match on_drop {
+            Some(DropBehavior::Keep) => {
+                if let Some(path) = self.path.take() {
+                    let _ = path.keep();
+                }
+            }
+            Some(DropBehavior::TruncateToUsed) => {
+                // Done by closing on Windows, where mapped files can not shrink
+                if let Some(file) = self.file.as_ref().filter(|_| !self.options.map.shared_file) {
+                    let _ = file.set_len(self.options.map.offset + file_len);
+                }
+                if let Some(path) = self.path.take() {
+                    let _ = path.keep();
+                }
+            }
+            Some(DropBehavior::Delete) if self.path.is_none() => {
+                // Deleted after the file is closed, which Windows insists on
+                self.path = self
+                    .file()
+                    .and_then(sys::file_path)
+                    .and_then(TempPath::try_from_path)
+                    .ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl AtomDiskAlloc {
+    #[cfg(test)]
+    pub fn new(reservation: u64) -> Result<Self, std::io::Error> {
+        let file = tempfile::tempfile_in(crate::sys::default_dir())?;
+        Self::on_file(
+            file,
+            None,
+            Options {
+                reservation,
+                auto_reservation: false,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn on_file(
+        file: File,
+        path: Option<TempPath>,
+        options: Options,
+    ) -> Result<Self, std::io::Error> {
+        Self::on_backing(Some(file), path, options)
+    }
+
+    // On a file only living in memory, or on anonymous memory
+    // where there is none (see sys::memory_file).
+    pub fn in_memory(mut options: Options) -> Result<Self, std::io::Error> {
+        match sys::memory_file()? {
+            Some(file) => Self::on_file(file, None, options),
+            None => {
+                // Changes are only in memory, like those of a private mapping
+                options.map.private = true;
+                Self::on_backing(None, None, options)
+            }
+        }
+    }
+
+    fn on_backing(
+        file: Option<File>,
+        path: Option<TempPath>,
+        mut options: Options,
+    ) -> Result<Self, std::io::Error> {
+        let mmap = Self::map(file.as_ref(), &mut options)?;
+        let file_len = mmap.file_len(file.as_ref())?;
+        if let Some(quota) = &options.quota {
+            quota.add(file_len);
+        }
+        if let Some(budget) = &options.budget {
+            budget.add(file_len);
+        }
+        let granularity = mmap.resize_granularity();
+        options.growth_chunk = options.growth_chunk.next_multiple_of(granularity);
+        options.capacity = options.capacity.next_multiple_of(granularity);
+        let alloc = Self {
+            file,
+            path,
+            opened_at: None,
+            dir: None,
+            bump: Arc::new(Bump::new(mmap.ptr(), file_len)),
+            mmap,
+            free: Default::default(),
+            buddy: Default::default(),
+            flushed: 0.into(),
+            synced: 0.into(),
+            dirty: Default::default(),
+            peak_file_size: file_len.into(),
+            resize_syscalls: 0.into(),
+            guards: Default::default(),
+            error: Default::default(),
+            persistent: false,
+            checksums: None,
+            pid: std::process::id(),
+            options,
+        };
+        if alloc.options.capacity > file_len && !alloc.options.map.read_only {
+            alloc.set_file_len(alloc.options.capacity, granularity)?;
+        }
+        Ok(alloc)
+    }
+
+    // Address space can be limited (`ulimit -v`, sanitizers), so
+    // the default reservation shrinks to the biggest one possible.
+    fn map(file: Option<&File>, options: &mut Options) -> Result<Mapping, DiskAllocError> {
+        let min = MIN_AUTO_RESERVATION.max(options.capacity);
+        loop {
+            match Mapping::new(file, options.reservation, &options.map) {
+                Err(io)
+                    if options.auto_reservation
+                        && io.kind() == std::io::ErrorKind::OutOfMemory
+                        && options.reservation / 2 >= min =>
+                {
+                    options.reservation /= 2;
+                }
+                result => return result.map_err(|io| DiskAllocError::MapFailed { io }),
+            }
+        }
+    }
+
+    // Remember where the file is, and delete it on drop if asked to.
+    pub fn set_path(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        let writable = !self.options.map.read_only && !self.options.map.private;
+        if writable && self.options.on_drop == Some(DropBehavior::Delete) {
+            self.path = Some(TempPath::try_from_path(path)?);
+        }
+        self.opened_at = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    // See DiskAllocBuilder::dirs.
+    pub fn set_dir(&mut self, dir: &Path) {
+        self.dir = Some(dir.to_path_buf());
+    }
+
+    fn backing_path(&self) -> Option<PathBuf> {
+        match (&self.path, &self.opened_at) {
+            (Some(temp_path), _) => Some(temp_path.to_path_buf()),
+            (None, Some(path)) => Some(path.clone()),
+            // Passed to on_file, or unnamed
+            (None, None) => self.file().and_then(sys::file_path).ok(),
+        }
+    }
+
+    // Start a new persistent allocation on an empty file.
+    // Guard pages would end up in the file, so they are not used.
+    pub fn create(file: File, mut options: Options) -> Result<Self, std::io::Error> {
+        options.guard_pages = false;
+        check_append_only(&options)?;
+        let mut alloc = Self::on_file(file, None, options)?;
+        alloc.persistent = true;
+        alloc.resize(HEADER_LEN)?;
+        let mut header = Header::new(HEADER_LEN);
+        if alloc.options.append_only {
+            header.flags |= FLAG_APPEND_ONLY;
+        }
+        unsafe { alloc.header().write(header) };
+        Ok(alloc)
+    }
+
+    // Restore a persistent allocation from its header.
+    pub fn open(file: File, mut options: Options) -> Result<Self, std::io::Error> {
+        options.guard_pages = false;
+        // Restored allocations are not counted, they might still be in use
+        options.grow_reservation = false;
+        check_append_only(&options)?;
+        let mut alloc = Self::on_file(file, None, options)?;
+        let file_len = alloc.bump.file_len();
+        if file_len < HEADER_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file too small for a diskallocator header",
+            ));
+        }
+        let mut header = unsafe { alloc.header().read() };
+        // Never write to a file of another format
+        header.validate_format()?;
+        // Crashed before the file length reached the disk
+        let recovered = header.recover(file_len);
+        let old_version = header.version < VERSION;
+        if (recovered || old_version) && !alloc.options.map.read_only {
+            header.upgrade();
+            unsafe { alloc.header().write(header) };
+        }
+        // Readers following the file check the mode of the last writer
+        let append_only = header.flags & FLAG_APPEND_ONLY != 0;
+        if append_only != alloc.options.append_only && !alloc.options.map.read_only {
+            header.flags ^= FLAG_APPEND_ONLY;
+            unsafe { std::ptr::addr_of_mut!((*alloc.header()).flags).write(header.flags) };
+        }
+        header.validate(file_len)?;
+        alloc.persistent = true;
+        alloc.bump.set_size(header.used);
+        // Everything there is on disk already
+        alloc.synced.store(header.used, Ordering::SeqCst);
+        Ok(alloc)
+    }
+
+    // Only valid for persistent allocators.
+    fn header(&self) -> *mut Header {
+        self.mmap.ptr().cast::<Header>()
+    }
+
+    // Everything after the header.
+    fn persistent_data(&self) -> &[u8] {
+        let len = self.get_size() - HEADER_LEN;
+        unsafe {
+            std::slice::from_raw_parts(self.mmap.ptr().add(HEADER_LEN as usize), len as usize)
+        }
+    }
+
+    // Check the data of the file at `path` against the checksums next
+    // to it, and keep them up to date unless the mapping is read-only
+    // or private. A missing sidecar is created, or ignored if read-only.
+    pub fn use_checksums(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        let sidecar_path = checksums::sidecar_path(path);
+        let writable = !self.options.map.read_only && !self.options.map.private;
+        let sidecar = if writable {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(sidecar_path)?
+        } else {
+            match File::open(sidecar_path) {
+                Ok(sidecar) => sidecar,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        };
+        let checksums = Checksums::new(sidecar);
+        if let Some(offset) = self.unpoisoned(|| checksums.verify(self.persistent_data()))? {
+            return Err(DiskAllocError::ChecksumMismatch {
+                offset: HEADER_LEN + offset,
+            }
+            .into());
+        }
+        if writable {
+            self.checksums = Some(checksums);
+        }
+        Ok(())
+    }
+
+    // Follow the used size written by another process.
+    fn refresh(&self) -> Result<(), std::io::Error> {
+        if !self.persistent || !self.options.map.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only read-only persistent allocators can be refreshed",
+            ));
+        }
+        let header = Header {
+            used: unsafe { Header::load_used(self.header()) },
+            ..unsafe { Header::read_shared(self.header()) }
+        };
+        // After the used size, the file grows before it is stored
+        let file_len = self.mmap.file_len(self.file.as_ref())?;
+        // Otherwise the writer may shrink the file under the mapping
+        if header.flags & FLAG_APPEND_ONLY == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the file is not written append-only, see DiskAllocBuilder::append_only",
+            ));
+        }
+        header.validate(file_len.min(self.reservation()))?;
+        self.mmap
+            .follow_file_len(self.file.as_ref(), file_len.min(self.reservation()))?;
+        self.bump.set_size(header.used);
+        self.bump.grow_file_len(file_len);
+        Ok(())
+    }
+
+    fn data_start(&self) -> u64 {
+        if self.persistent {
+            HEADER_LEN
+        } else {
+            0
+        }
+    }
+
+    // Set the used size of an allocator nobody else has access to.
+    fn resize(&self, size: u64) -> Result<(), std::io::Error> {
+        self.bump.set_size(size);
+        self.fit_file(size)
+    }
+
+    // Move the end of the used part from `current` to a bigger `size`.
+    // Returns false if it is not at `current` (anymore).
+    fn try_extend(&self, current: u64, size: u64) -> Result<bool, std::io::Error> {
+        if !self.bump.compare_size(current, size) {
+            return Ok(false);
+        }
+        if let Err(err) = self.fit_file(size) {
+            // Nobody can allocate beyond the old file length in the meantime
+            self.bump.compare_size(size, current);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    // Like try_extend, but for a smaller `size`.
+    // If the file can not be truncated, it just stays longer,
+    // the error is kept for DiskAlloc::take_error.
+    fn try_truncate(&self, current: u64, size: u64) -> bool {
+        if !self.bump.compare_size(current, size) {
+            return false;
+        }
+        if let Err(err) = self.fit_file(size) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(size, error = %err, "truncating the file failed");
+            *self.error.lock().unwrap() = Some(err.into());
+        }
+        true
+    }
+
+    // Resize the file to hold `size` bytes.
+    fn fit_file(&self, size: u64) -> Result<(), std::io::Error> {
+        if self.options.map.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "allocator is read-only",
+            ));
+        }
+        let chunk = self.options.growth_chunk;
+        let file_len = round_up_to_chunk(size, chunk).max(self.options.capacity);
+        let old_len = self.bump.file_len();
+        let resize = match self.shrink_policy() {
+            _ if file_len >= old_len => file_len > old_len,
+            ShrinkPolicy::Never => false,
+            ShrinkPolicy::Immediate => true,
+            ShrinkPolicy::Hysteresis { slack } => old_len - file_len > slack,
+        };
+        let resized = if resize {
+            self.set_file_len(file_len, chunk)
+        } else {
+            if file_len < old_len {
+                self.free_tail(size, old_len);
+            }
+            Ok(())
+        };
+        // A file which could not shrink still holds less data
+        if self.persistent && (resized.is_ok() || file_len < old_len) {
+            // Persistent allocators never allocate lock-free
+            unsafe { Header::store_used(self.header(), size) };
+        }
+        resized
+    }
+
+    fn shrink_policy(&self) -> ShrinkPolicy {
+        // Chunked files keep their size until trimmed
+        let chunked = self.options.growth_chunk > self.mmap.resize_granularity();
+        match self.options.shrink_policy {
+            _ if self.append_only() => ShrinkPolicy::Never,
+            Some(policy) => policy,
+            None if chunked => ShrinkPolicy::Never,
+            // Not undoing the extension with every allocation
+            None if self.options.extend_ahead > 0 => ShrinkPolicy::Hysteresis {
+                slack: self.options.extend_ahead,
+            },
+            None => ShrinkPolicy::Immediate,
+        }
+    }
+
+    // Whether freed space is kept from reuse, see DiskAllocBuilder::append_only.
+    fn append_only(&self) -> bool {
+        self.persistent && self.options.append_only
+    }
+
+    // Resize the file to `file_len`, a multiple of `chunk`.
+    fn set_file_len(&self, mut file_len: u64, chunk: u64) -> Result<(), std::io::Error> {
+        let old_len = self.bump.file_len();
+        if file_len < old_len {
+            file_len = self.bump.shrink_file_len(file_len, chunk);
+        }
+        if file_len != old_len {
+            if file_len > old_len {
+                self.reserve_quota(file_len - old_len)?;
+            }
+            if file_len < old_len {
+                // Private mappings zero the part cut off
+                let cut = unsafe { self.mmap.ptr().add(file_len as usize) };
+                unsafe { poison::unpoison(cut, (old_len - file_len) as usize) };
+                self.erase(file_len, old_len)?;
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(old_len, file_len, "resizing the file");
+            if let Err(io) = self
+                .mmap
+                .set_file_len(self.file.as_ref(), old_len, file_len)
+            {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(old_len, file_len, error = %io, "resizing the file failed");
+                self.release_quota(file_len, old_len);
+                return Err(DiskAllocError::ResizeFailed { io }.into());
+            }
+            if let Some(trace) = &self.options.trace {
+                trace.record(trace::Kind::Resize, 0, old_len, file_len);
+            }
+            if file_len > old_len {
+                self.bump.grow_file_len(file_len);
+                if let Some(on_grow) = &self.options.hooks.on_grow {
+                    on_grow(file_len);
+                }
+            } else {
+                self.release_quota(old_len, file_len);
+            }
+            self.resize_syscalls.fetch_add(1, Ordering::SeqCst);
+            self.peak_file_size.fetch_max(file_len, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    // Zero the file from `start` to `end` and wait for it to reach
+    // the disk, see DiskAllocBuilder::erase_on_drop.
+    fn erase(&self, start: u64, end: u64) -> Result<(), std::io::Error> {
+        let map = &self.options.map;
+        if !self.options.erase || map.read_only || map.private || end <= start {
+            return Ok(());
+        }
+        unsafe {
+            let ptr = self.mmap.ptr().add(start as usize);
+            poison::unpoison(ptr, (end - start) as usize);
+            std::ptr::write_bytes(
+                self.mmap.ptr().add(start as usize),
+                0,
+                (end - start) as usize,
+            )
+        };
+        // Pages not written back yet would be dropped with the file
+        self.mmap.flush(start, end - start)?;
+        self.sync_data()
+    }
+
+    // Shrink the file to the used size, ignoring the growth chunk.
+    fn trim(&self) -> Result<(), std::io::Error> {
+        if self.options.map.read_only {
+            return Ok(());
+        }
+        let granularity = self.mmap.resize_granularity();
+        let file_len = round_up_to_chunk(self.get_size(), granularity).max(self.options.capacity);
+        self.set_file_len(file_len, granularity)
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            file_size: self.bump.file_len(),
+            used_size: self.get_size(),
+            live_allocations: self.bump.live_allocations(),
+            wasted_alignment_bytes: self.bump.wasted_alignment_bytes(),
+            peak_file_size: self.peak_file_size.load(Ordering::SeqCst),
+            resize_syscalls: self.resize_syscalls.load(Ordering::SeqCst),
+            ..Default::default()
+        }
+    }
+
+    fn flush(&self) -> Result<(), std::io::Error> {
+        let used = self.get_size();
+        // Marks from now on are not covered
+        self.dirty.lock().unwrap().clear();
+        self.mmap.flush(0, used)?;
+        self.synced.store(used, Ordering::SeqCst);
+        self.commit(used)
+    }
+
+    // Like flush, but only write back the marked ranges
+    // and what was appended since the last flush.
+    fn flush_incremental(&self) -> Result<(), std::io::Error> {
+        let used = self.get_size();
+        let mut dirty = self.dirty.lock().unwrap().take();
+        let synced = self.synced.load(Ordering::SeqCst).min(used);
+        dirty.mark(synced, used - synced);
+        let written = dirty
+            .iter()
+            .map(|(start, end)| (start, end.min(used)))
+            .filter(|(start, end)| start < end)
+            .try_for_each(|(start, end)| self.mmap.flush(start, end - start));
+        if let Err(err) = written {
+            self.dirty.lock().unwrap().merge(dirty);
+            return Err(err);
+        }
+        self.synced.store(used, Ordering::SeqCst);
+        self.commit(used)
+    }
+
+    // Sync the file once the data up to `used` is written back,
+    // and record that in the header and the checksums.
+    fn commit(&self, used: u64) -> Result<(), std::io::Error> {
+        self.sync_all()?;
+        if self.persistent && !self.options.map.read_only && !self.options.map.private {
+            // Only now the data up to `used` is known to be on disk
+            unsafe { Header::commit(self.header(), used) };
+            self.mmap.flush(0, HEADER_LEN)?;
+            self.sync_data()?;
+        }
+        if let Some(checksums) = &self.checksums {
+            self.unpoisoned(|| checksums.update(self.persistent_data()))?;
+            checksums.sync()?;
+        }
+        Ok(())
+    }
+
+    fn mark_dirty(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.check_range(offset, len)?;
+        self.dirty.lock().unwrap().mark(offset, len);
+        Ok(())
+    }
+
+    fn flush_range(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.check_range(offset, len)?;
+        self.mmap.flush(offset, len)?;
+        self.sync_all()
+    }
+
+    fn flush_async(&self) -> Result<(), std::io::Error> {
+        self.mmap.flush_async(0, self.get_size())
+    }
+
+    // Write back and evict the whole pages used since the last
+    // call, once they are at least `min_bytes`.
+    fn flush_appended(&self, min_bytes: u64) -> Result<(), std::io::Error> {
+        if self.options.map.read_only || self.options.map.private {
+            return Ok(());
+        }
+        let size = self.get_size();
+        let flushed = self.flushed.load(Ordering::SeqCst).min(size);
+        self.flushed.store(flushed, Ordering::SeqCst);
+        if size - flushed < min_bytes.max(1) {
+            return Ok(());
+        }
+        // The last page is probably still being written
+        let page = sys::page_size();
+        let (start, end) = (flushed / page * page, size / page * page);
+        if end > start {
+            self.mmap.flush(start, end - start)?;
+            self.mmap.advise(
+                self.file.as_ref(),
+                start,
+                end - start,
+                sys::Advice::DontNeed,
+            )?;
+            self.flushed.store(end, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    // Grow the file to `distance` beyond the used size once
+    // half of that is used up, see DiskAllocBuilder::background_extend.
+    fn extend_ahead(&self, distance: u64) -> Result<(), std::io::Error> {
+        let map = &self.options.map;
+        if map.read_only || map.private || map.shared_file {
+            return Ok(());
+        }
+        let chunk = self.options.growth_chunk;
+        let file_len = round_up_to_chunk(self.get_size() + distance, chunk).min(self.reservation());
+        if file_len < self.bump.file_len() + distance / 2 {
+            return Ok(());
+        }
+        self.set_file_len(file_len, chunk)
+    }
+
+    // Move the only live block to the start and shrink the file
+    // behind it. Returns the new block and the bytes given back.
+    unsafe fn compact(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(NonNull<[u8]>, u64), std::io::Error> {
+        if self.options.map.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "allocator is read-only",
+            ));
+        }
+        if self.options.guard_pages {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "compaction of guarded allocations",
+            ));
+        }
+        if self.append_only() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "compaction of an append-only allocator",
+            ));
+        }
+        // Buddy blocks keep the alignment to their size
+        let block = if self.options.buddy {
+            buddy::block_layout(layout)
+        } else {
+            layout
+        };
+        let start = self.data_start();
+        let start = start + calc_byte_skip_for_alignment(start as usize, block.align()) as u64;
+        let offset = self.offset_of(ptr);
+        let start = if offset > start {
+            poison::unpoison(self.mmap.ptr().add(start as usize), layout.size());
+            // Overlaps if the block is bigger than the dead space
+            std::ptr::copy(
+                ptr.as_ptr(),
+                self.mmap.ptr().add(start as usize),
+                layout.size(),
+            );
+            start
+        } else {
+            offset
+        };
+        let old_len = self.bump.file_len();
+        *self.free.lock().unwrap() = FreeList::default();
+        *self.buddy.lock().unwrap() = Default::default();
+        let size = start + block.size() as u64;
+        self.bump.set_size(size);
+        if self.persistent {
+            Header::store_used(self.header(), size);
+        }
+        // The block moved already, a file which can not shrink just stays longer
+        let _ = self.trim();
+        let reclaimed = old_len.saturating_sub(self.bump.file_len());
+        Ok((self.fat_ptr_at(start, layout.size()), reclaimed))
+    }
+
+    // Forget all allocations at once, nobody may use them anymore.
+    unsafe fn reset(&self) -> Result<(), std::io::Error> {
+        if self.append_only() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "reset of an append-only allocator",
+            ));
+        }
+        let page = sys::page_size();
+        for (offset, block) in self.guards.lock().unwrap().drain() {
+            self.mmap
+                .protect(offset + block.size() as u64 - page, page, true)?;
+        }
+        *self.free.lock().unwrap() = FreeList::default();
+        *self.buddy.lock().unwrap() = Default::default();
+        self.bump.clear_allocations();
+        let start = self.data_start();
+        self.bump.set_size(start);
+        self.fit_file(start)
+    }
+
+    // Count `bytes` the file grows by against the quota and the budget.
+    fn reserve_quota(&self, bytes: u64) -> Result<(), std::io::Error> {
+        if let Some(quota) = &self.options.quota {
+            quota.reserve(bytes)?;
+        }
+        if let Some(budget) = &self.options.budget {
+            if let Err(err) = budget.reserve(bytes) {
+                if let Some(quota) = &self.options.quota {
+                    quota.release(bytes);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // Give back the quota of a file shrinking from `from` to `to`.
+    fn release_quota(&self, from: u64, to: u64) {
+        if from <= to {
+            return;
+        }
+        if let Some(quota) = &self.options.quota {
+            quota.release(from - to);
+        }
+        if let Some(budget) = &self.options.budget {
+            budget.release(from - to);
+        }
+    }
+
+    // Copy the used part to a new file at `path`.
+    fn snapshot(&self, path: &Path) -> Result<(), std::io::Error> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // Renamed when complete, so `path` is never half written
+        let mut copy = tempfile::NamedTempFile::new_in(dir)?;
+        self.copy_used(copy.as_file_mut())?;
+        copy.as_file().sync_all()?;
+        copy.persist(path)?;
+        Ok(())
+    }
+
+    // Write the used part of the file to the empty file `dest`,
+    // returning its length.
+    fn copy_used(&self, dest: &mut File) -> Result<u64, std::io::Error> {
+        let used = self.get_size();
+        if self.options.map.private {
+            // Changes are only in memory
+            let data = unsafe { std::slice::from_raw_parts(self.mmap.ptr(), used as usize) };
+            self.unpoisoned(|| dest.write_all(data))?;
+            return Ok(used);
+        }
+        self.mmap.flush(0, used)?;
+        // Sharing the blocks of the file would be instant,
+        // the rest of it gets cut off again
+        let mut file = self.file()?;
+        if sys::clone_range(file, self.options.map.offset, dest).is_ok() {
+            dest.set_len(used)?;
+            return Ok(used);
+        }
+        file.seek(SeekFrom::Start(self.options.map.offset))?;
+        // Uses copy_file_range on Linux
+        std::io::copy(&mut file.take(used), dest)?;
+        Ok(used)
+    }
+
+    fn export_to(&self, path: &Path) -> Result<u64, std::io::Error> {
+        let mut copy = File::create(path)?;
+        let len = self.copy_used(&mut copy)?;
+        copy.sync_all()?;
+        Ok(len)
+    }
+
+    fn link_into(&self, path: &Path) -> Result<(), std::io::Error> {
+        match &self.path {
+            Some(temp_path) => std::fs::hard_link(temp_path, path),
+            None => sys::link_file(self.file()?, path),
+        }
+    }
+
+    fn get_size(&self) -> u64 {
+        self.bump.size()
+    }
+
+    fn file(&self) -> Result<&File, std::io::Error> {
+        sys::backing(self.file.as_ref())
+    }
+
+    // Anonymous memory has nothing to sync.
+    fn sync_all(&self) -> Result<(), std::io::Error> {
+        self.file.as_ref().map_or(Ok(()), File::sync_all)
+    }
+
+    fn sync_data(&self) -> Result<(), std::io::Error> {
+        self.file.as_ref().map_or(Ok(()), File::sync_data)
+    }
+
+    // Flush and unmap, leaving the file at the used size.
+    fn into_file(mut self) -> Result<(File, Option<TempPath>), std::io::Error> {
+        let writable = !self.options.map.read_only && !self.options.map.private;
+        let resizable = writable && !self.options.map.shared_file;
+        if writable {
+            self.flush()?;
+        }
+        let (size, offset) = (self.get_size(), self.options.map.offset);
+        let file = self.file()?.try_clone()?;
+        let path = self.path.take();
+        // The file is handed out, not given up
+        self.options.on_drop = None;
+        drop(self);
+        if resizable {
+            file.set_len(offset + size)?;
+        }
+        Ok((file, path))
+    }
+
+    // Give up this allocator for `n` on slices of its file
+    // and reservation, see DiskAlloc::partition.
+    fn partition(mut self, n: usize) -> Result<Vec<Self>, std::io::Error> {
+        if self.persistent || self.options.map.read_only || self.options.map.private {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only writable allocators without a header can be partitioned",
+            ));
+        }
+        if self.bump.live_allocations() > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "allocator is in use",
+            ));
+        }
+        // Fits the offsets of huge pages and of Windows views
+        let align = 2 * 1024 * 1024;
+        let slice = self.reservation() / n as u64 / align * align;
+        if slice == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "reservation too small for that many partitions",
+            ));
+        }
+        let mut options = self.options.clone();
+        options.reservation = slice;
+        options.auto_reservation = false;
+        options.capacity = 0;
+        // Neighbours are in the way
+        options.grow_reservation = false;
+        options.map.shared_file = true;
+        let file = self.file()?.try_clone()?;
+        let mut path = self.path.take();
+        // The file is handed on, not given up
+        self.options.on_drop = None;
+        drop(self);
+        file.set_len(options.map.offset + slice * n as u64)?;
+        (0..n as u64)
+            .map(|index| {
+                let mut options = options.clone();
+                options.map.offset += index * slice;
+                // The first partition keeps a named file
+                Self::on_file(file.try_clone()?, path.take(), options)
+            })
+            .collect()
+    }
+
+    // Bytes the file got extended by are zero already,
+    // so only the part which was in the file before
+    // (left over from an earlier allocation) needs zeroing.
+    unsafe fn zero_below_file_len(&self, ptr: NonNull<u8>, len: usize, old_file_len: u64) {
+        let offset = self.offset_of(ptr);
+        if offset >= old_file_len {
+            return;
+        }
+        let stale = (old_file_len - offset).min(len as u64) as usize;
+        std::ptr::write_bytes(ptr.as_ptr(), 0, stale);
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > self.get_size())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "range exceeds the used part of the file",
+            ));
+        }
+        Ok(())
+    }
+
+    fn advise(&self, offset: u64, len: u64, advice: sys::Advice) -> Result<(), std::io::Error> {
+        self.check_range(offset, len)?;
+        self.mmap.advise(self.file.as_ref(), offset, len, advice)
+    }
+
+    pub fn set_access_pattern(&self, pattern: AccessPattern) -> Result<(), std::io::Error> {
+        self.mmap.set_access_pattern(self.file.as_ref(), pattern)
+    }
+
+    fn evict(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.check_range(offset, len)?;
+        // Dirty pages can not be dropped, write them first
+        self.mmap.flush(offset, len)?;
+        self.mmap
+            .advise(self.file.as_ref(), offset, len, sys::Advice::DontNeed)
+    }
+
+    // Evict all data, see MemoryPressure.
+    fn shed(&self) -> Result<(), std::io::Error> {
+        let size = self.get_size();
+        // Dropping private pages would lose the changes
+        if self.options.map.private || size == 0 {
+            return Ok(());
+        }
+        self.evict(0, size)
+    }
+
+    // Return all whole pages of a hole to the file system.
+    fn release_hole(&self, (start, len): (u64, u64)) {
+        let options = &self.options;
+        if !options.punch_holes && !options.lazy_free {
+            return;
+        }
+        let page = sys::page_size();
+        let first_page = start.next_multiple_of(page);
+        let end_page = (start + len) / page * page;
+        if end_page > first_page {
+            if self.erase(first_page, end_page).is_err() {
+                // The data stays in the file then, and is erased on drop
+                return;
+            }
+            let len = end_page - first_page;
+            unsafe { poison::poison(self.mmap.ptr().add(first_page as usize), len as usize) };
+            // Not supported by every file system, the space just stays in use
+            if options.punch_holes {
+                let _ = self.mmap.punch_hole(self.file.as_ref(), first_page, len);
+            }
+            if options.lazy_free {
+                let _ = self
+                    .mmap
+                    .advise(self.file.as_ref(), first_page, len, sys::Advice::Free);
+            }
+        }
+    }
+
+    // Free the pages after the used `size` of a file which
+    // is not truncated, see DiskAllocBuilder::lazy_free.
+    fn free_tail(&self, size: u64, file_len: u64) {
+        let page = sys::page_size();
+        let start = size.next_multiple_of(page);
+        if !self.options.lazy_free || start >= file_len {
+            return;
+        }
+        // Lock-free allocations can not use the pages in the meantime
+        let start = self.bump.shrink_file_len(start, 1).next_multiple_of(page);
+        let end = file_len / page * page;
+        if end > start && self.erase(start, end).is_ok() {
+            // Only a hint, the memory is reclaimed later then
+            let _ = self
+                .mmap
+                .advise(self.file.as_ref(), start, end - start, sys::Advice::Free);
+        }
+        self.bump.grow_file_len(file_len);
+    }
+
+    fn contains(&self, ptr: NonNull<u8>) -> bool {
+        self.contains_addr(ptr.as_ptr().addr())
+    }
+
+    fn contains_addr(&self, addr: usize) -> bool {
+        let start = self.mmap.ptr().addr();
+        addr >= start && addr - start < self.reservation() as usize
+    }
+
+    unsafe fn offset_of(&self, ptr: NonNull<u8>) -> u64 {
+        ptr.as_ptr().offset_from_unsigned(self.mmap.ptr()) as u64
+    }
+
+    // The reservation fits into the address space (see
+    // sys::check_map_len), so offsets below it fit into usize.
+    unsafe fn fat_ptr_at(&self, offset: u64, len: usize) -> NonNull<[u8]> {
+        let end = offset.checked_add(len as u64);
+        assert!(
+            end.is_some_and(|end| end <= self.reservation()),
+            "block at offset {offset} with {len} bytes beyond the reservation"
+        );
+        let start_ptr = self.mmap.ptr().add(offset as usize);
+        // Handed out, or about to be
+        poison::unpoison(start_ptr, len);
+        NonNull::new(std::ptr::slice_from_raw_parts_mut(start_ptr, len)).unwrap()
+    }
+
+    // Bytes of address space reserved for the file.
+    fn reservation(&self) -> u64 {
+        self.mmap.len()
+    }
+
+    // Move the mapping to a reservation of at least `size` bytes,
+    // if exactly `live` allocations exist, the one to be grown.
+    // See DiskAllocBuilder::grow_reservation.
+    fn move_mapping(&self, size: u64, live: u64) -> bool {
+        if !self.options.grow_reservation {
+            return false;
+        }
+        self.bump.paused(|| {
+            if self.bump.live_allocations() != live {
+                return false;
+            }
+            // Freed blocks are accessible at the new address
+            self.unpoison_all();
+            // At least double, so a growing vector rarely moves
+            let len = size.max(self.reservation().saturating_mul(2));
+            let moved = self
+                .mmap
+                .grow_reservation(self.file.as_ref(), len)
+                .or_else(|_| self.mmap.grow_reservation(self.file.as_ref(), size));
+            if moved.is_ok() {
+                self.bump.rebase(self.mmap.ptr());
+            }
+            moved.is_ok()
+        })
+    }
+
+    // Remember why an allocation failed, see DiskAlloc::take_error.
+    fn fail(&self, err: impl Into<DiskAllocError>) -> AllocError {
+        let err = err.into();
+        #[cfg(feature = "tracing")]
+        tracing::warn!(error = %err, "allocation failed");
+        *self.error.lock().unwrap() = Some(err);
+        AllocError
+    }
+
+    fn take_error(&self) -> Option<DiskAllocError> {
+        self.error.lock().unwrap().take()
+    }
+
+    // Run `f`, which reads the used part including the holes of
+    // the free list, poisoning them again afterwards.
+    fn unpoisoned<T>(&self, f: impl FnOnce() -> T) -> T {
+        if !poison::ENABLED {
+            return f();
+        }
+        let free = self.free.lock().unwrap();
+        unsafe { poison::unpoison(self.mmap.ptr(), self.get_size() as usize) };
+        let result = f();
+        for (start, len) in free.intervals() {
+            unsafe { poison::poison(self.mmap.ptr().add(start as usize), len as usize) };
+        }
+        result
+    }
+
+    // Everything ever poisoned is below the peak file size.
+    fn unpoison_all(&self) {
+        let peak = self
+            .peak_file_size
+            .load(Ordering::SeqCst)
+            .min(self.reservation());
+        unsafe { poison::unpoison(self.mmap.ptr(), peak as usize) };
+    }
+
+    // Move a block to a place fitting the new alignment.
+    unsafe fn realign(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+        let new_ptr = self.allocate(new_layout)?;
+        std::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size().min(new_layout.size()),
+        );
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+
+    // Allocate the blocks one after another at the end,
+    // growing the file only once, see DiskAlloc::allocate_many.
+    fn allocate_many(&self, layouts: &[Layout]) -> Result<Vec<NonNull<[u8]>>, AllocError> {
+        let start = self.mmap.ptr().addr();
+        if layouts
+            .iter()
+            .any(|layout| !start.is_multiple_of(layout.align()))
+        {
+            return Err(self.fail(DiskAllocError::UnsupportedLayout));
+        }
+        let (size, offsets) = loop {
+            let size = self.get_size();
+            let mut end = size;
+            let offsets: Vec<u64> = layouts
+                .iter()
+                .map(|layout| {
+                    let offset = end.next_multiple_of(layout.align() as u64);
+                    end = offset + layout.size() as u64;
+                    offset
+                })
+                .collect();
+            if end > self.reservation() && !self.move_mapping(end, 0) {
+                return Err(self.fail(DiskAllocError::ReservationExhausted));
+            }
+            // Lock-free allocations might have moved the end
+            if self.try_extend(size, end).map_err(|err| self.fail(err))? {
+                break (size, offsets);
+            }
+        };
+        let mut block_end = size;
+        let ptrs = layouts.iter().zip(offsets).map(|(layout, offset)| {
+            // Padding is kept like for single allocations
+            let skip = offset - block_end;
+            unsafe { poison::poison(self.mmap.ptr().add(block_end as usize), skip as usize) };
+            if !self.append_only() {
+                let padding = self.free.lock().unwrap().insert(block_end, skip);
+                self.release_hole(padding);
+            }
+            self.bump.count_allocation(skip);
+            block_end = offset + layout.size() as u64;
+            unsafe { self.fat_ptr_at(offset, layout.size()) }
+        });
+        Ok(ptrs.collect())
+    }
+}
+
+unsafe impl Allocator for AtomDiskAlloc {
+    fn allocate(
+        &self,
+        layout: std::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+        // Offsets are aligned, the start of the mapping only to pages
+        if !self.mmap.ptr().addr().is_multiple_of(layout.align()) {
+            return Err(self.fail(DiskAllocError::UnsupportedLayout));
+        }
+        let reused = self
+            .free
+            .lock()
+            .unwrap()
+            .take(layout.size() as u64, layout.align() as u64);
+        if let Some(interval_start) = reused {
+            self.bump.count_allocation(0);
+            return Ok(unsafe { self.fat_ptr_at(interval_start, layout.size()) });
+        }
+
+        let (interval_start, skip) = loop {
+            let size = self.get_size();
+            let skip = calc_byte_skip_for_alignment(size as usize, layout.align()) as u64;
+            let interval_end = size + skip + layout.size() as u64;
+            if interval_end > self.reservation() && !self.move_mapping(interval_end, 0) {
+                return Err(self.fail(DiskAllocError::ReservationExhausted));
+            }
+            // Lock-free allocations might have moved the end
+            if self
+                .try_extend(size, interval_end)
+                .map_err(|err| self.fail(err))?
+            {
+                break (size + skip, skip);
+            }
+        };
+        // Freed along with the block after it, or reused before
+        unsafe {
+            poison::poison(
+                self.mmap.ptr().add((interval_start - skip) as usize),
+                skip as usize,
+            )
+        };
+        if !self.append_only() {
+            let padding = self
+                .free
+                .lock()
+                .unwrap()
+                .insert(interval_start - skip, skip);
+            self.release_hole(padding);
+        }
+        self.bump.count_allocation(skip);
+        Ok(unsafe { self.fat_ptr_at(interval_start, layout.size()) })
+    }
+
+    fn by_ref(&self) -> &Self
+    where
+        Self: Sized,
+    {
+        self
+    }
+
+    fn allocate_zeroed(
+        &self,
+        layout: std::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+        let old_file_len = self.bump.file_len();
+        let ptr = self.allocate(layout)?;
+        unsafe { self.zero_below_file_len(ptr.cast::<u8>(), layout.size(), old_file_len) };
+        Ok(ptr)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: std::alloc::Layout,
+        new_layout: std::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+        if !is_aligned(ptr, new_layout.align()) {
+            return self.realign(ptr, old_layout, new_layout);
+        }
+        let offset = self.offset_of(ptr);
+        let block_end = offset + old_layout.size() as u64;
+        let new_end = offset + new_layout.size() as u64;
+        let ptr = if new_end > self.reservation() && self.move_mapping(new_end, 1) {
+            // The only allocation, at the same offset of the new mapping
+            self.fat_ptr_at(offset, 0).cast::<u8>()
+        } else {
+            ptr
+        };
+        if new_end <= self.reservation()
+            && self
+                .try_extend(block_end, new_end)
+                .map_err(|err| self.fail(err))?
+        {
+            // Block at the end of the file
+            return Ok(self.fat_ptr_at(offset, new_layout.size()));
+        }
+        if self
+            .free
+            .lock()
+            .unwrap()
+            .take_at(block_end, new_end - block_end)
+        {
+            // Followed by a big enough hole
+            return Ok(self.fat_ptr_at(offset, new_layout.size()));
+        }
+        let new_ptr = self.allocate(new_layout)?;
+        std::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size(),
+        );
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: std::alloc::Layout,
+        new_layout: std::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+        if !is_aligned(ptr, new_layout.align()) {
+            return self.realign(ptr, old_layout, new_layout);
+        }
+        let offset = self.offset_of(ptr);
+        let block_end = offset + old_layout.size() as u64;
+        let new_end = offset + new_layout.size() as u64;
+        let cut = ptr.as_ptr().add(new_layout.size());
+        // Readers of append-only files may still look at the rest
+        if DEBUG_FILL && !self.append_only() {
+            std::ptr::write_bytes(cut, FREED_FILL, old_layout.size() - new_layout.size());
+        }
+        // Before others can get it
+        poison::poison(cut, old_layout.size() - new_layout.size());
+        if self.append_only() {
+            return Ok(self.fat_ptr_at(offset, new_layout.size()));
+        }
+        if !self.try_truncate(block_end, new_end) {
+            // Not at the end of the file, keep the rest for later allocations
+            let hole = self
+                .free
+                .lock()
+                .unwrap()
+                .insert(new_end, block_end - new_end);
+            self.release_hole(hole);
+        }
+        Ok(self.fat_ptr_at(offset, new_layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: std::alloc::Layout) {
+        self.bump.count_deallocation();
+        // Readers of append-only files may still look at it
+        if DEBUG_FILL && !self.append_only() {
+            std::ptr::write_bytes(ptr.as_ptr(), FREED_FILL, layout.size());
+        }
+        // Before others can get it
+        poison::poison(ptr.as_ptr(), layout.size());
+        if self.append_only() {
+            return;
+        }
+        let offset = self.offset_of(ptr);
+        let block_end = offset + layout.size() as u64;
+        let mut free = self.free.lock().unwrap();
+        // Holes right before the freed block can be truncated as well
+        let mut new_end = offset;
+        while let Some(start) = free.pop_tail(new_end) {
+            new_end = start;
+        }
+        if !self.try_truncate(block_end, new_end) {
+            // Not at the end of the file, keep the hole for later allocations
+            let hole = free.insert(new_end, block_end - new_end);
+            self.release_hole(hole);
+        }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: std::alloc::Layout,
+        new_layout: std::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+        let old_file_len = self.bump.file_len();
+        let new_ptr = self.grow(ptr, old_layout, new_layout)?;
+        let grown_part = new_ptr.cast::<u8>().add(old_layout.size());
+        self.zero_below_file_len(
+            grown_part,
+            new_layout.size() - old_layout.size(),
+            old_file_len,
+        );
+        Ok(new_ptr)
+    }
+}
+
+impl DiskAlloc {
+    /// Create a new temporary file in `/var/tmp/`
+    /// (the system temporary directory on Windows)
+    /// and wait for potential "memory" allocation.
+    ///
+    /// Might fail, if file can not be created
+    /// or memory map fails.  
+    /// A [`DiskAllocError::MapFailed`] error indicates, that
+    /// no big enough address space could be found
+    /// for the memory map (512GiB, or 1GiB on 32-bit targets,
+    /// or less down to 64MiB if the address space is limited).
+    pub fn new() -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().build()
+    }
+
+    /// Like [`DiskAlloc::new`], but reserve `bytes` of
+    /// address space instead of the default 512GiB
+    /// (1GiB on 32-bit targets).
+    ///
+    /// Use a smaller value on machines with limited
+    /// virtual address space, or a bigger one for vectors
+    /// larger than 512GiB. Allocations beyond the reservation
+    /// continue on another temporary file with its own
+    /// reservation, see [`DiskAllocBuilder::chain_segments`].
+    pub fn with_reservation(bytes: u64) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().reservation(bytes).build()
+    }
+
+    /// Like [`DiskAlloc::new`], but keep the data in memory,
+    /// see [`DiskAllocBuilder::in_memory`].
+    pub fn new_in_memory() -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().in_memory(true).build()
+    }
+
+    /// Like [`DiskAlloc::new`], but grow the file to `bytes`
+    /// right away, see [`DiskAllocBuilder::capacity`].
+    pub fn with_capacity(bytes: u64) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().capacity(bytes).build()
+    }
+
+    /// Start configuring a new allocator,
+    /// see [`DiskAllocBuilder`].
+    pub fn builder() -> DiskAllocBuilder {
+        DiskAllocBuilder::new()
+    }
+
+    /// Use custom file (must be read/write)
+    /// to allocate "memory".
+    ///
+    /// Can be useful for debugging.
+    ///
+    /// Do not use same file twice or you will get
+    /// memory access, bus or other unrecoverable hardware errors.
+    ///
+    /// The data is not written to disk when the allocator is dropped,
+    /// only eventually by the OS. Use [`DiskAllocBuilder::flush_on_drop`]
+    /// and [`DiskAllocBuilder::build_on_file`] to wait for it.
+    pub fn on_file(file: File) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().build_on_file(file)
+    }
+
+    /// Like [`DiskAlloc::on_file`], but map and grow the file
+    /// starting `offset` bytes in, e.g. after a header of the
+    /// application's own. Everything before it is left alone,
+    /// a shorter file is extended with zeros.
+    ///
+    /// The offset has to be a multiple of the page size
+    /// (of 64KiB on Windows), building fails otherwise.
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::io::{Read, Seek, Write};
+    /// use diskallocator::DiskAlloc;
+    ///
+    /// let mut file = tempfile::tempfile().unwrap();
+    /// file.write_all(b"SUPERBLOCK").unwrap();
+    /// let alloc = DiskAlloc::on_file_at(file.try_clone().unwrap(), 64 * 1024).unwrap();
+    /// let mut v: Vec<u8, _> = Vec::new_in(alloc);
+    /// v.extend_from_slice(b"data");
+    ///
+    /// let mut header = [0; 10];
+    /// file.rewind().unwrap();
+    /// file.read_exact(&mut header).unwrap();
+    /// assert_eq!(&header, b"SUPERBLOCK");
+    /// assert!(file.metadata().unwrap().len() > 64 * 1024);
+    /// ```
+    pub fn on_file_at(file: File, offset: u64) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().build_on_file_at(file, offset)
+    }
+
+    /// Combination of [`DiskAlloc::on_file`] and
+    /// [`DiskAlloc::with_reservation`].
+    pub fn on_file_with_reservation(file: File, bytes: u64) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new()
+            .reservation(bytes)
+            .build_on_file(file)
+    }
+
+    /// Create (or truncate) the file at `path` and use it
+    /// as persistent storage.
+    ///
+    /// The first 4KiB of the file are used for a header
+    /// recording how many bytes are in use,
+    /// so the data can be restored with [`DiskAlloc::open`].
+    /// Its layout is described in the README under "File format".
+    /// The file is kept when the allocator is dropped.
+    ///
+    /// Make sure the container is not dropped (which would
+    /// free its memory), e.g. by using [`std::mem::ManuallyDrop`]
+    /// or [`Vec::into_raw_parts_with_alloc`].
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().create(path)
+    }
+
+    /// Open a file created by [`DiskAlloc::create`],
+    /// restoring the used size from its header.
+    ///
+    /// Use [`DiskAlloc::data`] to find the stored data again.
+    /// Space freed before the file was closed is not reused.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().open(path)
+    }
+
+    /// Like [`DiskAlloc::open`], but map the file copy-on-write
+    /// (`MAP_PRIVATE`), e.g. to change a template dataset
+    /// in memory without touching the file.
+    ///
+    /// Changes and growth beyond the end of the file are kept in
+    /// anonymous memory, which is lost when the allocator is dropped.
+    /// [`DiskAlloc::evict`] fails, as it would lose changes.
+    /// Not supported on Windows.
+    pub fn open_private<P: AsRef<Path>>(path: P) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().open_private(path)
+    }
+
+    /// Like [`DiskAlloc::open`], but map the file read-only
+    /// (`PROT_READ`), e.g. to share a prebuilt dataset
+    /// between processes.
+    ///
+    /// All allocations fail, use [`DiskAlloc::as_slice`]
+    /// to access the stored data.
+    pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().open_readonly(path)
+    }
+
+    /// Like [`DiskAlloc::create`], but in the POSIX shared memory
+    /// object `name` (`shm_open`, without the leading slash) instead
+    /// of a file on disk. Linux and FreeBSD only.
+    ///
+    /// The allocator is append-only, see
+    /// [`DiskAllocBuilder::append_only`], so other processes can
+    /// follow it with [`DiskAlloc::open_shared_memory`] and
+    /// [`DiskAlloc::refresh`] while it is written.
+    /// The object is kept until [`DiskAlloc::remove_shared_memory`].
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    pub fn create_shared_memory(name: &str) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().create_shared_memory(name)
+    }
+
+    /// Open the shared memory object `name` of
+    /// [`DiskAlloc::create_shared_memory`] read-only,
+    /// like [`DiskAlloc::open_readonly`].
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    pub fn open_shared_memory(name: &str) -> Result<Self, DiskAllocError> {
+        DiskAllocBuilder::new().open_shared_memory(name)
+    }
+
+    /// Remove the shared memory object `name` (`shm_unlink`).
+    /// Allocators still using it keep their memory.
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    pub fn remove_shared_memory(name: &str) -> Result<(), std::io::Error> {
+        crate::sys::shm_unlink(name)
+    }
+
+    /// The used part of the file as a slice of `T`,
+    /// like [`DiskAlloc::data`].
+    ///
+    /// # Safety
+    /// The data must consist of valid values of type `T`,
+    /// as stored by a [`crate::DiskVec<T>`].
+    /// It must not be changed while the slice is in use,
+    /// which is guaranteed for [`DiskAlloc::open_readonly`].
+    pub unsafe fn as_slice<T>(&self) -> &[T] {
+        let data = self.data();
+        let len = data
+            .len()
+            .checked_div(std::mem::size_of::<T>())
+            .unwrap_or(0);
+        std::slice::from_raw_parts(data.cast::<T>().as_ptr(), len)
+    }
+
+    /// The used part of the file, excluding the header
+    /// of persistent allocators.
+    ///
+    /// The first allocation of a persistent allocator starts
+    /// at the beginning of this region (if its alignment is
+    /// at most 4096), so after [`DiskAlloc::open`] a vector
+    /// can be rebuilt with [`Vec::from_raw_parts_in`].
+    pub fn data(&self) -> NonNull<[u8]> {
+        let segments = self.alloc.lock().unwrap();
+        let alloc = segments.primary();
+        let start = alloc.data_start();
+        let len = (alloc.get_size() - start) as usize;
+        unsafe { alloc.fat_ptr_at(start, len) }
+    }
+
+    // What a DiskVec stored in the header, if this is a persistent allocator.
+    pub(crate) fn vec_meta(&self) -> Option<VecMeta> {
+        let segments = self.alloc.lock().unwrap();
+        let alloc = segments.primary();
+        if !alloc.persistent {
+            return None;
+        }
+        let header = unsafe { Header::read_shared(alloc.header()) };
+        (header.flags & FLAG_VEC != 0).then_some(header.vec)
+    }
+
+    pub(crate) fn set_vec_meta(&self, meta: VecMeta) {
+        let segments = self.alloc.lock().unwrap();
+        let alloc = segments.primary();
+        if alloc.persistent && !alloc.options.map.read_only {
+            // Readers following the file see the elements first
+            std::sync::atomic::fence(Ordering::Release);
+            // The used size may be written at the same time
+            unsafe {
+                let header = alloc.header();
+                std::ptr::addr_of_mut!((*header).vec).write(meta);
+                std::ptr::addr_of_mut!((*header).flags).write((*header).flags | FLAG_VEC);
+                std::ptr::addr_of_mut!((*header).version).write(VERSION);
+            }
+        }
+    }
+
+    // Entry of the slot table of a persistent allocator.
+    pub(crate) fn vec_slot(&self, slot: usize) -> Result<Region, std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        let alloc = segments.primary();
+        if !alloc.persistent {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "only persistent allocators have vector slots",
+            ));
+        }
+        if slot >= VEC_SLOTS {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("vector slot {slot} out of range, there are {VEC_SLOTS}"),
+            ));
+        }
+        let header = unsafe { Header::read_shared(alloc.header()) };
+        if header.version < JOURNAL_VERSION {
+            return Ok(Region::default());
+        }
+        Ok(header.slots[slot])
+    }
+
+    pub(crate) fn set_vec_slot(&self, slot: usize, region: Region) {
+        let segments = self.alloc.lock().unwrap();
+        let alloc = segments.primary();
+        if alloc.persistent && !alloc.options.map.read_only && slot < VEC_SLOTS {
+            std::sync::atomic::fence(Ordering::Release);
+            unsafe { std::ptr::addr_of_mut!((*alloc.header()).slots[slot]).write(region) };
+        }
+    }
+
+    /// Read the used size again from the header of a file another
+    /// process is still writing to, so [`DiskAlloc::data`] covers
+    /// the new allocations. Only for allocators opened with
+    /// [`DiskAlloc::open_readonly`] or [`DiskAlloc::open_shared_memory`].
+    /// Use [`crate::DiskVec::refresh`] to follow a vector.
+    ///
+    /// Fails with [`std::io::ErrorKind::Unsupported`] unless the
+    /// writer is append-only (see [`DiskAllocBuilder::append_only`]),
+    /// as reading a part it gave back and cut off from the file
+    /// would crash with SIGBUS, or see other data.
+    /// The used size grows when memory is allocated, before
+    /// the writer fills it, so the data itself has to tell
+    /// how much of it is valid. Only one process may write.
+    pub fn refresh(&self) -> Result<(), std::io::Error> {
+        self.alloc.lock().unwrap().primary().refresh()
+    }
+
+    /// Ask the OS to read `len` bytes starting at
+    /// `offset` (from the start of the file) into memory,
+    /// without waiting for it (`madvise` with `MADV_WILLNEED`).
+    ///
+    /// Useful to hide disk latency when scanning
+    /// big vectors sequentially.
+    /// Fails if the range is not in use.
+    pub fn prefetch(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.alloc
+            .lock()
+            .unwrap()
+            .primary()
+            .advise(offset, len, sys::Advice::WillNeed)
+    }
+
+    /// Tell the OS that `len` bytes starting at `offset` are not
+    /// going to be used for a while (`madvise` with `MADV_COLD`),
+    /// so they are dropped from memory before other data
+    /// when memory gets scarce.
+    ///
+    /// Unlike [`DiskAlloc::evict`], the data stays in memory
+    /// until then, and nothing is written. Only a hint: skipped
+    /// before Linux 5.4 and on other unix systems, on Windows
+    /// the pages leave the working set.
+    /// Fails if the range is not in use.
+    pub fn mark_cold(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.alloc
+            .lock()
+            .unwrap()
+            .primary()
+            .advise(offset, len, sys::Advice::Cold)
+    }
+
+    /// Tell the OS how the whole allocator is going to be accessed
+    /// (`madvise` and `posix_fadvise`), also for memory
+    /// allocated later.
+    ///
+    /// [`AccessPattern::Sequential`] gets more readahead when
+    /// scanning huge vectors, [`AccessPattern::Random`] keeps
+    /// the page cache from filling with data read ahead in vain.
+    /// Only a hint, ignored on Windows.
+    pub fn advise(&self, pattern: AccessPattern) -> Result<(), std::io::Error> {
+        self.alloc.lock().unwrap().set_access_pattern(pattern)
+    }
+
+    /// Why the last failed allocation failed, if one
+    /// failed since the last call.
+    ///
+    /// Freeing and shrinking memory never fail, if the file can
+    /// not be shrunk (or a guard page not be removed), the
+    /// error is kept here as well.
+    ///
+    /// The [`Allocator`] functions can only return an
+    /// [`AllocError`], so the reason is kept here,
+    /// e.g. to tell a full disk
+    /// ([`DiskAllocError::ResizeFailed`]) from a used up
+    /// reservation ([`DiskAllocError::ReservationExhausted`]).
+    pub fn take_error(&self) -> Option<DiskAllocError> {
+        self.alloc.lock().unwrap().take_error()
+    }
+
+    /// Current usage statistics, e.g. for monitoring.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            size_histogram: self.sizes.histogram(),
+            ..self.alloc.lock().unwrap().stats()
+        }
+    }
+
+    /// Bytes of reserved address space not used yet,
+    /// summed over all segments.
+    ///
+    /// Allocations which do not fit fail, unless another
+    /// segment can be added, see [`DiskAllocBuilder::chain_segments`].
+    /// Freed space before the end of the used part is not counted.
+    pub fn remaining_reservation(&self) -> u64 {
+        self.alloc
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|segment| segment.reservation() - segment.get_size())
+            .sum()
+    }
+
+    /// Bytes of disk space the files take (`st_blocks * 512`),
+    /// unlike [`Stats::file_size`], which is their length.
+    ///
+    /// Less than the length where holes were punched (see
+    /// [`DiskAllocBuilder::punch_holes`]) or nothing was written
+    /// yet, more if the file system allocates ahead.
+    pub fn disk_usage(&self) -> Result<u64, std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        segments
+            .iter()
+            // Anonymous memory takes no disk space
+            .filter_map(|segment| segment.file.as_ref())
+            .map(sys::disk_usage)
+            .sum()
+    }
+
+    /// Bytes the files may still grow by: the free space of the
+    /// file system (`fstatvfs`), or the rest of
+    /// [`DiskAllocBuilder::max_file_size`] or of the
+    /// [`DiskAllocBuilder::budget`] if that is less.
+    ///
+    /// For striped allocators, the smallest free space of
+    /// all directories. Worth checking before ingesting
+    /// more data, instead of failing halfway through.
+    pub fn headroom_on_disk(&self) -> Result<u64, std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        let mut headroom = u64::MAX;
+        for file in segments.iter().filter_map(|segment| segment.file.as_ref()) {
+            headroom = headroom.min(sys::free_space(file)?);
+        }
+        if let Some(quota) = &segments.primary().options.quota {
+            headroom = headroom.min(quota.remaining());
+        }
+        if let Some(budget) = &segments.primary().options.budget {
+            headroom = headroom.min(budget.remaining());
+        }
+        Ok(headroom)
+    }
+
+    /// A new handle to the backing file of the first segment,
+    /// e.g. to `fstat` or copy it.
+    ///
+    /// It refers to the same open file, do not resize it
+    /// or write to it while the allocator is in use.
+    pub fn file(&self) -> Result<File, std::io::Error> {
+        self.alloc.lock().unwrap().primary().file()?.try_clone()
+    }
+
+    /// Where the backing file of the first segment is, if known:
+    /// the path of [`DiskAlloc::create`] or [`DiskAlloc::open`],
+    /// that of a named temporary file (see [`DiskAllocBuilder::prefix`]),
+    /// or, on Linux and Windows, the current name of a file
+    /// passed to [`DiskAlloc::on_file`]. `None` if the file has
+    /// no name, like unnamed temporary files on unix.
+    pub fn backing_path(&self) -> Option<PathBuf> {
+        self.alloc.lock().unwrap().primary().backing_path()
+    }
+
+    /// The label set with [`DiskAllocBuilder::name`].
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Directory the temporary file of the first segment
+    /// was created in, the first usable one of
+    /// [`DiskAllocBuilder::dirs`]. `None` for other files
+    /// and in-memory allocators.
+    pub fn dir(&self) -> Option<PathBuf> {
+        self.alloc.lock().unwrap().primary().dir.clone()
+    }
+
+    /// Number of files (each with its own memory map) in use.
+    ///
+    /// Allocators on temporary files add another file
+    /// when the reservation of all previous ones is used up,
+    /// see [`DiskAllocBuilder::chain_segments`].
+    /// Striped allocators start with one file per directory,
+    /// see [`DiskAllocBuilder::stripe_dirs`].
+    pub fn segment_count(&self) -> usize {
+        self.alloc.lock().unwrap().len()
+    }
+
+    /// Write all data back to disk and wait for it
+    /// (`msync` with `MS_SYNC`, then `fsync`).
+    ///
+    /// Without flushing, the OS writes the data back
+    /// whenever it wants to, which is fine unless the
+    /// machine crashes.
+    ///
+    /// Persistent allocators then record the used size in
+    /// the header, so [`DiskAlloc::open`] can roll back to it
+    /// after a crash left the header pointing past the end of the file.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.alloc
+            .lock()
+            .unwrap()
+            .iter()
+            .try_for_each(AtomDiskAlloc::flush)
+    }
+
+    /// Like [`DiskAlloc::flush`], but only write back the ranges
+    /// marked with [`DiskAlloc::mark_dirty`] and the data appended
+    /// since the last flush, instead of going through all pages.
+    ///
+    /// The OS only writes dirty pages to disk anyway, but `msync`
+    /// still has to check every page mapped, which takes long for
+    /// big files of which only a few pages changed. Changes not
+    /// marked (and not appended) are written whenever the OS wants,
+    /// like without flushing. The mapping is not write-protected
+    /// to find them, that would cost a page fault for every first
+    /// write.
+    pub fn flush_incremental(&self) -> Result<(), std::io::Error> {
+        self.alloc
+            .lock()
+            .unwrap()
+            .iter()
+            .try_for_each(AtomDiskAlloc::flush_incremental)
+    }
 
-const STORAGE: u64 = 512 * 1024 * 1024 * 1024;
+    /// Record that `len` bytes starting at `offset` (from the start
+    /// of the file) were changed, so the next
+    /// [`DiskAlloc::flush_incremental`] writes them back.
+    /// Fails if the range is not in use.
+    pub fn mark_dirty(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.alloc.lock().unwrap().primary().mark_dirty(offset, len)
+    }
 
-// Keep file and pointer to memorymap.
-// Memory map can only be created once without changing
-// addresses. So create once with multiple gigabytes
-// of data and increase file size before allocating more.
-struct AtomDiskAlloc {
-    file: File,
-    size: RefCell<u64>,
-    mmap: *mut u8,
-}
+    /// Like [`DiskAlloc::flush`], but only for `len` bytes
+    /// starting at `offset` (from the start of the file).
+    /// Fails if the range is not in use.
+    pub fn flush_range(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.alloc
+            .lock()
+            .unwrap()
+            .primary()
+            .flush_range(offset, len)
+    }
 
-fn calc_byte_skip_for_alignment(first_free_addr: usize, alignment: usize) -> usize {
-    (alignment - first_free_addr % alignment) % alignment
-}
+    /// Start writing all data back to disk,
+    /// without waiting for it (`msync` with `MS_ASYNC`).
+    pub fn flush_async(&self) -> Result<(), std::io::Error> {
+        self.alloc
+            .lock()
+            .unwrap()
+            .iter()
+            .try_for_each(AtomDiskAlloc::flush_async)
+    }
 
-/// Manages the allocation of ideally one vector.  
-/// Sits on top of a file, and resizes it as needed
-/// by the vector.
-///
-/// Usage with vector:
-/// ```rust
-/// #![feature(allocator_api)]
-/// let alloc = diskallocator::DiskAlloc::new().unwrap();
-/// let data: Vec<u64, diskallocator::DiskAlloc> = Vec::new_in(alloc);
-/// ```
-#[derive(Clone)]
-pub struct DiskAlloc {
-    alloc: Arc<Mutex<AtomDiskAlloc>>,
-}
+    /// Shrink the file to the used size.
+    ///
+    /// Files growing in chunks (see [`DiskAllocBuilder::growth_chunk`])
+    /// only shrink when trimmed by default, so the space of freed
+    /// memory stays in the file for later allocations,
+    /// see [`DiskAllocBuilder::shrink_policy`].
+    pub fn trim(&self) -> Result<(), std::io::Error> {
+        self.alloc
+            .lock()
+            .unwrap()
+            .iter()
+            .try_for_each(AtomDiskAlloc::trim)
+    }
 
-impl Drop for AtomDiskAlloc {
-    fn drop(&mut self) {
-        unsafe {
-            libc::munmap(self.mmap.cast::<libc::c_void>(), STORAGE as libc::size_t);
+    /// Write the used part of the file to a new file at `path`,
+    /// replacing it atomically (write to a temporary file, then rename).
+    ///
+    /// A snapshot of a persistent allocator (see [`DiskAlloc::create`])
+    /// can be opened with [`DiskAlloc::open`].
+    /// Writes to the memory are not stopped, so data changed
+    /// while the snapshot is taken might be half old, half new.
+    /// Allocations wait until the snapshot is done.
+    /// Fails for allocators which chained more than one file.
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        if segments.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "snapshot of an allocator with more than one segment",
+            ));
         }
+        segments.primary().snapshot(path.as_ref())
     }
-}
 
-impl AtomDiskAlloc {
-    pub fn new() -> Result<Self, std::io::Error> {
-        let file = tempfile::tempfile_in("/var/tmp/")?;
-        Self::on_file(file)
-    }
-
-    pub fn on_file(file: File) -> Result<Self, std::io::Error> {
-        #[cfg(target_os = "linux")]
-        let addr = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                STORAGE as libc::size_t,
-                libc::PROT_WRITE | libc::PROT_READ,
-                libc::MAP_SHARED_VALIDATE,
-                file.as_raw_fd(),
-                0,
-            )
+    /// Copy the used part of the file to `path`, creating or
+    /// truncating it, and return its length. The unused rest of
+    /// the reservation is not copied, e.g. to archive a finished
+    /// data set.
+    ///
+    /// The file system is asked to share the blocks (a reflink on
+    /// Btrfs and XFS) where it can, otherwise the data is copied in
+    /// the kernel (`copy_file_range`) on Linux. Unlike
+    /// [`DiskAlloc::snapshot`], `path` is written in place, so
+    /// it is half written if this fails, but no temporary file is
+    /// created next to it. The same caveats about concurrent writes
+    /// apply, and it fails for allocators which chained more than one file.
+    ///
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let dir = tempfile::tempdir()?;
+    /// let alloc = diskallocator::DiskAlloc::create(dir.path().join("data"))?;
+    /// let len = alloc.export_to(dir.path().join("archive"))?;
+    /// assert_eq!(std::fs::metadata(dir.path().join("archive"))?.len(), len);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn export_to<P: AsRef<Path>>(&self, path: P) -> Result<u64, std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        if segments.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "export of an allocator with more than one segment",
+            ));
+        }
+        segments.primary().export_to(path.as_ref())
+    }
+
+    /// Move the only live allocation to the start of the file
+    /// and shrink the file behind it, giving back the dead space
+    /// of freed allocations. Returns the moved block and the
+    /// number of bytes the file got shorter.
+    ///
+    /// [`DiskVec::compact`](crate::DiskVec::compact) does this for a vector.
+    /// Fails (without moving anything) for allocators which chained
+    /// more than one file, are read-only or use guard pages.
+    /// If the file can not be shrunk, it just stays longer.
+    ///
+    /// # Safety
+    /// `ptr` and `layout` must describe the only live allocation
+    /// of this allocator, and no other thread may allocate meanwhile.
+    /// `ptr` is invalid afterwards, use the returned block instead.
+    pub unsafe fn compact(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(NonNull<[u8]>, u64), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        if segments.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "compaction of an allocator with more than one segment",
+            ));
+        }
+        self.check_live(ptr, layout, "compact");
+        self.check_canary(ptr, "compact");
+        let canary = self.has_canary(ptr);
+        let block_layout = if canary {
+            canary_layout(layout)
+        } else {
+            layout
         };
-        #[cfg(target_os = "macos")]
-        let addr = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                STORAGE as libc::size_t,
-                libc::PROT_WRITE | libc::PROT_READ,
-                libc::MAP_SHARED,
-                file.as_raw_fd(),
-                0,
-            )
+        let (block, reclaimed) = segments.primary().compact(ptr, block_layout)?;
+        self.untrack(ptr, layout);
+        self.forget_canary(ptr);
+        // It was moved along with the data
+        let block = if canary {
+            self.set_canary(block, layout)
+        } else {
+            block
         };
-        if addr == libc::MAP_FAILED {
-            return Err(std::io::Error::last_os_error());
+        self.track(block, layout);
+        Ok((block, reclaimed))
+    }
+
+    /// Give the backing file another name at `path`, which is kept
+    /// when the allocator is dropped. E.g. to save the data
+    /// after a computation turned out to be worth keeping.
+    ///
+    /// The file stays in use, later changes end up in it as well.
+    /// It might be longer than the used size (see
+    /// [`DiskAllocBuilder::growth_chunk`]), and is only complete once
+    /// [`DiskAlloc::flush`]ed. `path` must be on the same file system.
+    /// Unnamed temporary files (see [`DiskAllocBuilder::prefix`]) are
+    /// created with `O_TMPFILE` and can only be linked on Linux.
+    /// Fails for allocators which chained more than one file.
+    pub fn link_into<P: AsRef<Path>>(&self, path: P) -> Result<(), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        if segments.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "link of an allocator with more than one segment",
+            ));
         }
-        Ok(Self {
-            file,
-            mmap: addr.cast::<u8>(),
-            size: 0.into(),
+        segments.primary().link_into(path.as_ref())
+    }
+
+    /// Write all data back to disk, unmap it and return the file,
+    /// cut to the used size. E.g. to archive the raw bytes
+    /// of a finished vector.
+    ///
+    /// Fails if the allocator is still in use by a clone
+    /// (like one owned by a container) or chained more than one file.
+    /// Take the container apart first, e.g. with
+    /// [`Vec::into_raw_parts_with_alloc`], and do not
+    /// use its pointer afterwards.
+    pub fn into_file(self) -> Result<File, std::io::Error> {
+        Ok(self.into_primary()?.into_file()?.0)
+    }
+
+    /// Like [`DiskAlloc::into_file`], but also keep the temporary
+    /// file by moving it to `path`. Only works for files with a
+    /// name, see [`DiskAllocBuilder::prefix`].
+    pub fn keep<P: AsRef<Path>>(self, path: P) -> Result<File, std::io::Error> {
+        let primary = self.into_primary()?;
+        if primary.path.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "temporary file without a name",
+            ));
+        }
+        let (file, temp_path) = primary.into_file()?;
+        temp_path.unwrap().persist(path).map_err(|err| err.error)?;
+        Ok(file)
+    }
+
+    /// Split an allocator which is not in use into `n`
+    /// independent ones, each with its own contiguous slice
+    /// of the reservation and of the same file.
+    ///
+    /// Every partition allocates at the end of its slice and gives
+    /// memory there back like an allocator of its own, so several
+    /// vectors can grow in one file. As the file is shared, it keeps
+    /// the length of all slices together, sparse on most file systems.
+    /// Space a partition gives back is zeroed (punched out on Linux)
+    /// instead of cut off. A partition can not grow beyond its
+    /// slice of the reservation, nor chain more files.
+    ///
+    /// Fails if the allocator is persistent, read-only,
+    /// copy-on-write, has live allocations, is still in use by
+    /// a clone, chained more than one file or its reservation
+    /// is too small for slices of at least 2MiB.
+    ///
+    /// # Panics
+    /// Panics if `n` is zero.
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use diskallocator::DiskAlloc;
+    ///
+    /// let alloc = DiskAlloc::with_reservation(1 << 30).unwrap();
+    /// let mut partitions = alloc.partition(2).unwrap();
+    /// let mut b: Vec<u64, _> = Vec::new_in(partitions.pop().unwrap());
+    /// let mut a: Vec<u64, _> = Vec::new_in(partitions.pop().unwrap());
+    /// for i in 0..1000 {
+    ///     // Both grow in place
+    ///     a.push(i);
+    ///     b.push(i);
+    /// }
+    /// ```
+    pub fn partition(self, n: usize) -> Result<Vec<DiskAlloc>, std::io::Error> {
+        assert!(n > 0, "at least one partition is needed");
+        let partitions = self.into_primary()?.partition(n)?;
+        Ok(partitions
+            .into_iter()
+            .map(|partition| DiskAlloc::from_segments(partition, None))
+            .collect())
+    }
+
+    // The only segment, if nobody else uses the allocator.
+    fn into_primary(self) -> Result<AtomDiskAlloc, std::io::Error> {
+        let Self { alloc, bump, .. } = self;
+        drop(bump);
+        let segments = Arc::try_unwrap(alloc)
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::ResourceBusy,
+                    "allocator is still in use",
+                )
+            })?
+            .into_inner()
+            .unwrap();
+        // Only now nobody can free the recorded allocations anymore
+        #[cfg(any(debug_assertions, feature = "track-allocations"))]
+        self.tracker.clear();
+        #[cfg(feature = "debug-canaries")]
+        self.canaries.clear();
+        segments.into_primary().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "allocator with more than one segment",
+            )
         })
     }
 
-    fn resize(&self, size: u64) -> Result<(), std::io::Error> {
-        *self.size.borrow_mut() = size;
-        self.file.set_len(size)?;
-        Ok(())
+    /// Write `len` bytes starting at `offset` back to disk
+    /// and drop them from memory (`msync`, then `madvise` with
+    /// `MADV_DONTNEED` and `posix_fadvise` with `POSIX_FADV_DONTNEED`).
+    ///
+    /// The data stays valid and is read from disk again on access.
+    /// Useful to keep a big vector from filling the page cache
+    /// when parts of it are not needed for a while.
+    /// Fails if the range is not in use.
+    pub fn evict(&self, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        self.alloc.lock().unwrap().primary().evict(offset, len)
     }
 
-    fn get_size(&self) -> u64 {
-        *self.size.borrow()
+    // Pointer based versions of the range functions above,
+    // for memory in any segment.
+    pub(crate) fn prefetch_ptr(&self, ptr: NonNull<u8>, len: u64) -> Result<(), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        let segment = segments.find(ptr);
+        let offset = unsafe { segment.offset_of(ptr) };
+        segment.advise(offset, len, sys::Advice::WillNeed)
     }
 
-    unsafe fn layout_is_end_of_file(&self, ptr: NonNull<u8>, layout: &Layout) -> bool {
-        let file_end = self.mmap.offset(self.get_size() as isize);
-        let interval_end = ptr.as_ptr().add(layout.size());
-        file_end == interval_end
+    pub(crate) fn mark_cold_ptr(&self, ptr: NonNull<u8>, len: u64) -> Result<(), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        let segment = segments.find(ptr);
+        let offset = unsafe { segment.offset_of(ptr) };
+        segment.advise(offset, len, sys::Advice::Cold)
     }
-}
 
-unsafe impl Allocator for AtomDiskAlloc {
-    fn allocate(
-        &self,
-        layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        let interval_start = self.get_size()
-            + calc_byte_skip_for_alignment(self.get_size() as usize, layout.align()) as u64;
-        let interval_end = interval_start + layout.size() as u64;
-        self.resize(interval_end)
-            .map_err(|_| std::alloc::AllocError)?;
-        let start_ptr: *mut u8 = unsafe { self.mmap.offset(interval_start as isize) };
-        let fat_ptr = unsafe { std::slice::from_raw_parts_mut(start_ptr, layout.size()) };
-        Ok(NonNull::new(fat_ptr).unwrap())
+    pub(crate) fn mark_dirty_ptr(&self, ptr: NonNull<u8>, len: u64) -> Result<(), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        let segment = segments.find(ptr);
+        let offset = unsafe { segment.offset_of(ptr) };
+        segment.mark_dirty(offset, len)
     }
 
-    fn by_ref(&self) -> &Self
-    where
-        Self: Sized,
-    {
-        todo!()
+    pub(crate) fn evict_ptr(&self, ptr: NonNull<u8>, len: u64) -> Result<(), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        let segment = segments.find(ptr);
+        let offset = unsafe { segment.offset_of(ptr) };
+        segment.evict(offset, len)
     }
 
-    fn allocate_zeroed(
-        &self,
-        _layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        todo!()
+    // Addresses of the first segment's reservation.
+    #[cfg(feature = "global-alloc")]
+    pub(crate) fn address_range(&self) -> std::ops::Range<usize> {
+        let segments = self.alloc.lock().unwrap();
+        let start = segments.primary().mmap.ptr().addr();
+        start..start + segments.primary().reservation() as usize
     }
 
-    unsafe fn grow(
-        &self,
-        ptr: NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        // TODO how to handle different alignments?
-        assert_eq!(old_layout.align(), new_layout.align());
-        let growth = new_layout.size() - old_layout.size();
+    // Free everything at once, all memory handed out becomes invalid.
+    pub(crate) unsafe fn reset(&self) -> Result<(), std::io::Error> {
+        #[cfg(any(debug_assertions, feature = "track-allocations"))]
+        self.tracker.clear();
+        #[cfg(feature = "debug-canaries")]
+        self.canaries.clear();
+        self.alloc.lock().unwrap().reset()
+    }
 
-        if !self.layout_is_end_of_file(ptr, &old_layout) {
-            // Can only grow at the end
-            return self.allocate(new_layout);
+    /// Address and layout of every allocation not freed yet,
+    /// sorted by address. Available with the
+    /// `track-allocations` feature.
+    ///
+    /// With the feature, dropping the last clone of an allocator
+    /// on temporary files with allocations left prints a
+    /// warning, or panics in debug builds.
+    #[cfg(feature = "track-allocations")]
+    pub fn live_allocations(&self) -> Vec<(NonNull<u8>, Layout)> {
+        let segments = self.alloc.lock().unwrap();
+        self.tracker
+            .live()
+            .into_iter()
+            .filter_map(|(addr, layout)| {
+                // With the provenance of the mapping it points into
+                let segment = segments.iter().find(|s| s.contains_addr(addr))?;
+                Some((NonNull::new(segment.mmap.ptr().with_addr(addr))?, layout))
+            })
+            .collect()
+    }
+
+    /// Check the canaries after all live allocations, and fail
+    /// with [`std::io::ErrorKind::InvalidData`] if one of them was
+    /// overwritten. Available with the `debug-canaries` feature.
+    ///
+    /// With the feature, random bytes are put after every allocation,
+    /// and checked whenever it is grown, shrunk or freed, panicking
+    /// if a write past its end damaged them.
+    #[cfg(feature = "debug-canaries")]
+    pub fn check_integrity(&self) -> Result<(), std::io::Error> {
+        let segments = self.alloc.lock().unwrap();
+        let ptr_at = |addr| {
+            let segment = segments.iter().find(|s| s.contains_addr(addr))?;
+            NonNull::new(segment.mmap.ptr().with_addr(addr))
+        };
+        match self.canaries.find_damaged(ptr_at) {
+            Some((addr, size)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("bytes after the {size} bytes allocated at {addr:#x} were overwritten"),
+            )),
+            None => Ok(()),
         }
-        self.resize(self.get_size() + growth as u64).unwrap();
+    }
 
-        let fat_ptr = std::slice::from_raw_parts_mut(ptr.as_ptr(), new_layout.size());
-        Ok(NonNull::new(fat_ptr).unwrap())
+    // The caller may only use the part before the canary.
+    #[cfg_attr(not(feature = "debug-canaries"), allow(unused_variables))]
+    fn set_canary(&self, ptr: NonNull<[u8]>, layout: Layout) -> NonNull<[u8]> {
+        #[cfg(feature = "debug-canaries")]
+        let ptr = unsafe { self.canaries.set(ptr, layout.size()) };
+        ptr
     }
 
-    unsafe fn shrink(
-        &self,
-        ptr: NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        let fat_ptr = std::slice::from_raw_parts_mut(ptr.as_ptr(), new_layout.size());
-        let success_result = Ok(NonNull::new(fat_ptr).unwrap());
-        if !self.layout_is_end_of_file(ptr, &old_layout) {
-            return success_result;
+    #[cfg_attr(not(feature = "debug-canaries"), allow(unused_variables))]
+    fn has_canary(&self, ptr: NonNull<u8>) -> bool {
+        #[cfg(feature = "debug-canaries")]
+        return self.canaries.contains(ptr);
+        #[cfg(not(feature = "debug-canaries"))]
+        false
+    }
+
+    #[cfg_attr(not(feature = "debug-canaries"), allow(unused_variables))]
+    fn check_canary(&self, ptr: NonNull<u8>, operation: &str) {
+        #[cfg(feature = "debug-canaries")]
+        unsafe {
+            self.canaries.check(ptr, operation)
+        };
+    }
+
+    #[cfg_attr(not(feature = "debug-canaries"), allow(unused_variables))]
+    fn forget_canary(&self, ptr: NonNull<u8>) {
+        #[cfg(feature = "debug-canaries")]
+        self.canaries.forget(ptr);
+    }
+
+    // Live allocations are always tracked in debug builds,
+    // to catch double frees and mismatched layouts.
+    #[cfg_attr(
+        not(any(debug_assertions, feature = "track-allocations")),
+        allow(unused_variables)
+    )]
+    fn track(&self, ptr: NonNull<[u8]>, layout: Layout) {
+        #[cfg(any(debug_assertions, feature = "track-allocations"))]
+        self.tracker.insert(ptr, layout);
+    }
+
+    #[cfg_attr(
+        not(any(debug_assertions, feature = "track-allocations")),
+        allow(unused_variables)
+    )]
+    fn untrack(&self, ptr: NonNull<u8>, layout: Layout) {
+        #[cfg(any(debug_assertions, feature = "track-allocations"))]
+        self.tracker.remove(ptr, layout);
+    }
+
+    // Panic if `ptr` is not live or `layout` does not fit it,
+    // before `operation` corrupts the free space.
+    #[cfg_attr(
+        not(any(debug_assertions, feature = "track-allocations")),
+        allow(unused_variables)
+    )]
+    fn check_live(&self, ptr: NonNull<u8>, layout: Layout, operation: &str) {
+        #[cfg(any(debug_assertions, feature = "track-allocations"))]
+        self.tracker.check(ptr, layout, operation);
+    }
+
+    fn allocated(&self, layout: Layout) {
+        self.sizes.record(layout.size());
+        if let Some(on_alloc) = &self.hooks.on_alloc {
+            on_alloc(layout);
         }
-        let shrinkage = old_layout.size() - new_layout.size();
-        self.resize(self.get_size() - shrinkage as u64).unwrap();
-        success_result
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: std::alloc::Layout) {
-        if !self.layout_is_end_of_file(ptr, &layout) {
-            // Vectors always deallocate at the end
-            return;
+    // See DiskAllocBuilder::record_trace.
+    fn record(&self, kind: trace::Kind, ptr: NonNull<u8>, old_size: usize, size: usize) {
+        if let Some(trace) = &self.trace {
+            trace.record(kind, ptr.as_ptr().addr(), old_size as u64, size as u64);
         }
-        self.resize(self.get_size() - layout.size() as u64).unwrap();
     }
 
-    unsafe fn grow_zeroed(
-        &self,
-        _ptr: NonNull<u8>,
-        _old_layout: std::alloc::Layout,
-        _new_layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        todo!()
+    /// Write the events recorded with [`DiskAllocBuilder::record_trace`]
+    /// to `out` as JSON in the Chrome trace event format, to open it
+    /// in `chrome://tracing` or [Perfetto](https://ui.perfetto.dev).
+    ///
+    /// Every allocation, grow, shrink and free is an instant event
+    /// with its address and sizes, on the thread it happened on.
+    /// The counters `live bytes` and `file size` show how the
+    /// requested memory and the file grew and shrunk over time.
+    /// Fails with [`std::io::ErrorKind::Unsupported`] if
+    /// nothing was recorded.
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use diskallocator::DiskAllocBuilder;
+    ///
+    /// let alloc = DiskAllocBuilder::new().record_trace(true).build().unwrap();
+    /// let mut v: Vec<u64, _> = Vec::new_in(alloc.clone());
+    /// for i in 0..100 {
+    ///     v.push(i);
+    /// }
+    /// drop(v);
+    /// let mut json = Vec::new();
+    /// alloc.write_trace(&mut json).unwrap();
+    /// assert!(String::from_utf8(json).unwrap().contains("\"name\":\"grow\""));
+    /// ```
+    pub fn write_trace<W: Write>(&self, out: W) -> Result<(), std::io::Error> {
+        match &self.trace {
+            Some(trace) => trace.write_json(out),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "no trace recorded, see DiskAllocBuilder::record_trace",
+            )),
+        }
+    }
+
+    // Whether no clone of this allocator exists.
+    pub(crate) fn is_unique(&self) -> bool {
+        Arc::strong_count(&self.alloc) == 1
+    }
+
+    // Whether `ptr` points into any segment.
+    pub(crate) fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.alloc
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|segment| segment.contains(ptr))
+    }
+
+    pub(crate) fn from_segments(first: AtomDiskAlloc, builder: Option<DiskAllocBuilder>) -> Self {
+        Self::from_stripes(vec![first], builder)
+    }
+
+    pub(crate) fn from_stripes(
+        stripes: Vec<AtomDiskAlloc>,
+        builder: Option<DiskAllocBuilder>,
+    ) -> Self {
+        // Guarded, buddy, persistent and striped allocations need the lock
+        let first = &stripes[0];
+        let lock_free = !first.persistent
+            && !first.options.guard_pages
+            && !first.options.buddy
+            && stripes.len() == 1;
+        let alloc = Self {
+            bump: lock_free.then(|| first.bump.clone()),
+            page_align_above: first.options.page_align_above,
+            hooks: first.options.hooks.clone(),
+            trace: first.options.trace.clone(),
+            name: first.options.name.clone(),
+            sizes: Default::default(),
+            #[cfg(any(debug_assertions, feature = "track-allocations"))]
+            tracker: Arc::new(tracking::Tracker::new(!first.persistent)),
+            #[cfg(feature = "debug-canaries")]
+            canaries: Arc::new(canaries::Canaries::new()),
+            _flusher: None,
+            _extender: None,
+            alloc: Arc::new(Mutex::new(Segments::new(stripes, builder))),
+        };
+        registry::register(&alloc);
+        alloc
+    }
+
+    // Start a background flusher, see DiskAllocBuilder::background_flush.
+    pub(crate) fn with_flusher(
+        mut self,
+        interval: Duration,
+        dirty_bytes: u64,
+    ) -> Result<Self, std::io::Error> {
+        let segments = Arc::downgrade(&self.alloc);
+        let flusher = flusher::Flusher::spawn(segments, interval, dirty_bytes)?;
+        self._flusher = Some(Arc::new(flusher));
+        Ok(self)
+    }
+
+    // Keep the files extended, see DiskAllocBuilder::background_extend.
+    pub(crate) fn with_extender(
+        mut self,
+        interval: Duration,
+        distance: u64,
+    ) -> Result<Self, std::io::Error> {
+        let segments = Arc::downgrade(&self.alloc);
+        let extender = extender::Extender::spawn(segments, interval, distance)?;
+        self._extender = Some(Arc::new(extender));
+        Ok(self)
+    }
+
+    // The layout actually allocated for `layout`.
+    fn page_layout(&self, layout: Layout) -> Layout {
+        self.round_to_pages(canary_layout(layout))
+    }
+
+    // The layout allocated for the block at `ptr`,
+    // restored blocks have no canary.
+    fn block_layout(&self, ptr: NonNull<u8>, layout: Layout) -> Layout {
+        if self.has_canary(ptr) {
+            self.page_layout(layout)
+        } else {
+            self.round_to_pages(layout)
+        }
+    }
+
+    fn round_to_pages(&self, layout: Layout) -> Layout {
+        match self.page_align_above {
+            Some(threshold) if layout.size() >= threshold => {
+                let page = sys::page_size() as usize;
+                let size = layout.size().next_multiple_of(page);
+                Layout::from_size_align(size, layout.align().max(page)).unwrap_or(layout)
+            }
+            _ => layout,
+        }
     }
 }
 
 impl DiskAlloc {
-    /// Create a new temporary file in `/var/tmp/`
-    /// and wait for potential "memory" allocation.
+    /// Allocate a block for each of `layouts` at once, growing the file
+    /// a single time instead of once per block. Either all or none
+    /// of the blocks are allocated.
     ///
-    /// Might fail, if file can not be created
-    /// or memory map fails.  
-    /// An OutOfMemory error indicates, that
-    /// no big enough address space could be found
-    /// for the memory map (512GiB).
-    pub fn new() -> Result<Self, std::io::Error> {
-        Ok(Self {
-            alloc: Arc::new(Mutex::new(AtomDiskAlloc::new()?)),
-        })
-    }
-
-    /// Use custom file (must be read/write)
-    /// to allocate "memory".
+    /// Each block is freed on its own with [`Allocator::deallocate`],
+    /// and its layout. Guarded, buddy and striped allocators
+    /// allocate the blocks one by one.
     ///
-    /// Can be useful for debugging.
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use diskallocator::DiskAlloc;
+    /// use std::alloc::{Allocator, Layout};
     ///
-    /// Do not use same file twice or you will get
-    /// memory access, bus or other unrecoverable hardware errors.
-    pub fn on_file(file: File) -> Result<Self, std::io::Error> {
-        Ok(Self {
-            alloc: Arc::new(Mutex::new(AtomDiskAlloc::on_file(file)?)),
-        })
+    /// let alloc = DiskAlloc::new().unwrap();
+    /// let layouts = [Layout::new::<[u64; 512]>(), Layout::new::<[u8; 100]>()];
+    /// let blocks = alloc.allocate_many(&layouts).unwrap();
+    /// assert_eq!(blocks[1].len(), 100);
+    /// for (block, layout) in blocks.into_iter().zip(layouts) {
+    ///     unsafe { alloc.deallocate(block.cast(), layout) };
+    /// }
+    /// ```
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(count = layouts.len()), err)
+    )]
+    pub fn allocate_many(&self, layouts: &[Layout]) -> Result<Vec<NonNull<[u8]>>, AllocError> {
+        let page_layouts: Vec<Layout> = layouts
+            .iter()
+            .map(|&layout| self.page_layout(layout))
+            .collect();
+        let ptrs = self.alloc.lock().unwrap().allocate_many(&page_layouts)?;
+        let mut blocks = Vec::with_capacity(ptrs.len());
+        for (ptr, &layout) in ptrs.into_iter().zip(layouts) {
+            if DEBUG_FILL {
+                unsafe {
+                    std::ptr::write_bytes(ptr.cast::<u8>().as_ptr(), ALLOCATED_FILL, ptr.len())
+                };
+            }
+            let ptr = self.set_canary(ptr, layout);
+            self.track(ptr, layout);
+            self.allocated(layout);
+            self.record(trace::Kind::Allocate, ptr.cast(), 0, layout.size());
+            blocks.push(ptr);
+        }
+        Ok(blocks)
     }
-}
 
-unsafe impl Allocator for DiskAlloc {
-    fn allocate(
-        &self,
-        layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        self.alloc.lock().unwrap().allocate(layout)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            name = "allocate",
+            skip_all,
+            fields(size = layout.size(), align = layout.align(), zeroed),
+            err
+        )
+    )]
+    fn allocate_any(&self, layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+        let page_layout = self.page_layout(layout);
+        let bump = self.bump.as_ref();
+        let ptr = match bump.and_then(|bump| bump.try_allocate(page_layout)) {
+            Some(ptr) => {
+                if zeroed {
+                    // Might be left over from an earlier allocation
+                    unsafe { std::ptr::write_bytes(ptr.cast::<u8>().as_ptr(), 0, ptr.len()) };
+                }
+                ptr
+            }
+            None if zeroed => self.alloc.lock().unwrap().allocate_zeroed(page_layout)?,
+            None => self.alloc.lock().unwrap().allocate(page_layout)?,
+        };
+        if DEBUG_FILL && !zeroed {
+            unsafe { std::ptr::write_bytes(ptr.cast::<u8>().as_ptr(), ALLOCATED_FILL, ptr.len()) };
+        }
+        let ptr = self.set_canary(ptr, layout);
+        self.track(ptr, layout);
+        self.allocated(layout);
+        self.record(trace::Kind::Allocate, ptr.cast(), 0, layout.size());
+        Ok(ptr)
     }
 
-    unsafe fn grow(
+    unsafe fn reallocate(
         &self,
         ptr: NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        self.alloc.lock().unwrap().grow(ptr, old_layout, new_layout)
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let operation = if zeroed {
+            "grow_zeroed"
+        } else {
+            "grow or shrink"
+        };
+        self.check_live(ptr, old_layout, operation);
+        self.check_canary(ptr, operation);
+        // Another thread may get the old block as soon as it is freed
+        #[cfg(any(debug_assertions, feature = "track-allocations"))]
+        let tracked = self.tracker.remove(ptr, old_layout);
+        let result = self.reallocate_tracked(ptr, old_layout, new_layout, zeroed);
+        #[cfg(any(debug_assertions, feature = "track-allocations"))]
+        if result.is_err() {
+            self.tracker.restore(ptr, tracked);
+        }
+        result
     }
 
-    unsafe fn grow_zeroed(
+    unsafe fn reallocate_tracked(
         &self,
         ptr: NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        self.alloc
-            .lock()
-            .unwrap()
-            .grow_zeroed(ptr, old_layout, new_layout)
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // Rounded up pages might have been written to
+        let old_size = old_layout.size();
+        let (old_layout, page_layout) = (
+            self.block_layout(ptr, old_layout),
+            self.page_layout(new_layout),
+        );
+        let grow = page_layout.size() >= old_layout.size();
+        let bump = self.bump.as_ref().filter(|_| grow);
+        // Lock-free growth zeroes nothing, the segments zero the grown part
+        let (new_ptr, zero_until) =
+            match bump.and_then(|bump| bump.try_grow(ptr, old_layout, page_layout)) {
+                Some(new_ptr) => (new_ptr, page_layout.size()),
+                None => {
+                    let mut segments = self.alloc.lock().unwrap();
+                    let new_ptr = segments.reallocate(ptr, old_layout, page_layout, zeroed)?;
+                    (new_ptr, old_layout.size())
+                }
+            };
+        if zeroed && zero_until > old_size {
+            let start = new_ptr.cast::<u8>().add(old_size);
+            std::ptr::write_bytes(start.as_ptr(), 0, zero_until - old_size);
+        } else if DEBUG_FILL && !zeroed && new_ptr.len() > old_size {
+            let start = new_ptr.cast::<u8>().add(old_size);
+            std::ptr::write_bytes(start.as_ptr(), ALLOCATED_FILL, new_ptr.len() - old_size);
+        }
+        self.forget_canary(ptr);
+        let new_ptr = self.set_canary(new_ptr, new_layout);
+        self.track(new_ptr, new_layout);
+        self.allocated(new_layout);
+        let kind = if new_layout.size() >= old_size {
+            trace::Kind::Grow
+        } else {
+            trace::Kind::Shrink
+        };
+        self.record(kind, new_ptr.cast(), old_size, new_layout.size());
+        Ok(new_ptr)
     }
+}
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: std::alloc::Layout) {
+unsafe impl Allocator for DiskAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_any(layout, false)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_any(layout, true)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(size = layout.size()))
+    )]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.check_live(ptr, layout, "deallocate");
+        self.check_canary(ptr, "deallocate");
+        self.untrack(ptr, layout);
+        self.record(trace::Kind::Deallocate, ptr, layout.size(), 0);
+        let layout = self.block_layout(ptr, layout);
+        self.forget_canary(ptr);
         self.alloc.lock().unwrap().deallocate(ptr, layout)
     }
 
-    unsafe fn shrink(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(old_size = old_layout.size(), new_size = new_layout.size()),
+            err
+        )
+    )]
+    unsafe fn grow(
         &self,
         ptr: NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        self.alloc
-            .lock()
-            .unwrap()
-            .shrink(ptr, old_layout, new_layout)
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout, false)
     }
 
-    fn allocate_zeroed(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(old_size = old_layout.size(), new_size = new_layout.size()),
+            err
+        )
+    )]
+    unsafe fn grow_zeroed(
         &self,
-        layout: std::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
-        self.alloc.lock().unwrap().allocate_zeroed(layout)
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout, true)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(old_size = old_layout.size(), new_size = new_layout.size()),
+            err
+        )
+    )]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout, false)
     }
 
     fn by_ref(&self) -> &Self
@@ -278,18 +2954,96 @@ unsafe impl Allocator for DiskAlloc {
 mod test {
     use super::*;
 
+    #[test]
+    #[should_panic(expected = "beyond the reservation")]
+    fn no_pointer_beyond_reservation() {
+        let allocator = AtomDiskAlloc::new(1024 * 1024).unwrap();
+        let reservation = allocator.reservation();
+        unsafe { allocator.fat_ptr_at(reservation - 8, 16) };
+    }
+
+    #[test]
+    fn shared_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AtomDiskAlloc>();
+        let allocator = AtomDiskAlloc::new(STORAGE).unwrap();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                allocator.allocate(layout).unwrap();
+            });
+        });
+        assert_eq!(allocator.stats().resize_syscalls, 1);
+    }
+
+    // Where there is no memory file, e.g. under Miri
+    #[test]
+    fn anonymous_memory() {
+        let mut options = Options {
+            reservation: 16 * 1024 * 1024,
+            auto_reservation: false,
+            ..Default::default()
+        };
+        options.map.private = true;
+        let allocator = AtomDiskAlloc::on_backing(None, None, options).unwrap();
+        let layout = Layout::from_size_align(100_000, 8).unwrap();
+        let block = allocator.allocate(layout).unwrap().cast::<u8>();
+        unsafe {
+            std::ptr::write_bytes(block.as_ptr(), 1, layout.size());
+            allocator.deallocate(block, layout);
+        }
+        assert_eq!(allocator.stats().file_size, 0);
+        // Given back memory reads as zeros again
+        let block = allocator.allocate(layout).unwrap().cast::<u8>();
+        let data = unsafe { std::slice::from_raw_parts(block.as_ptr(), layout.size()) };
+        assert!(data.iter().all(|&byte| byte == 0));
+        allocator.flush().unwrap();
+        assert!(allocator.file().is_err());
+        unsafe { allocator.deallocate(block, layout) };
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mapping_kept_from_children() {
+        let mapped_in_child = |inherit: bool| {
+            let file = tempfile::tempfile_in(crate::sys::default_dir()).unwrap();
+            let mut options = Options {
+                reservation: 1 << 30,
+                ..Default::default()
+            };
+            options.map.dont_fork = !inherit;
+            let allocator = AtomDiskAlloc::on_file(file, None, options).unwrap();
+            let ptr = allocator.allocate(Layout::new::<u64>()).unwrap();
+            let addr = ptr.cast::<libc::c_void>().as_ptr();
+            unsafe {
+                match libc::fork() {
+                    // Fails with ENOMEM for unmapped memory
+                    0 => libc::_exit(libc::madvise(addr, 4096, libc::MADV_NORMAL).abs()),
+                    child => {
+                        let mut status = 0;
+                        libc::waitpid(child, &mut status, 0);
+                        libc::WEXITSTATUS(status) == 0
+                    }
+                }
+            }
+        };
+        assert!(mapped_in_child(true));
+        assert!(!mapped_in_child(false));
+    }
+
     #[test]
     fn alloc_grow_shrink() {
-        let allocator = AtomDiskAlloc::new().unwrap();
-        assert_eq!(*allocator.size.borrow(), 0);
+        let allocator = AtomDiskAlloc::new(STORAGE).unwrap();
+        assert_eq!(allocator.get_size(), 0);
         let _alloc1 = allocator
             .allocate(Layout::from_size_align(64, 8).unwrap())
             .unwrap();
-        assert_eq!(*allocator.size.borrow(), 64);
+        assert_eq!(allocator.get_size(), 64);
         let _alloc2 = allocator
+            .by_ref()
             .allocate(Layout::from_size_align(64_000, 16).unwrap())
             .unwrap();
-        assert_eq!(*allocator.size.borrow(), 64_064);
+        assert_eq!(allocator.get_size(), 64_064);
         let _alloc2a = unsafe {
             allocator
                 .shrink(
@@ -299,7 +3053,7 @@ mod test {
                 )
                 .unwrap()
         };
-        assert_eq!(*allocator.size.borrow(), 128);
+        assert_eq!(allocator.get_size(), 128);
         let _alloc2b = unsafe {
             allocator.grow(
                 NonNull::new(_alloc2a.as_ptr().cast::<u8>()).unwrap(),
@@ -307,6 +3061,67 @@ mod test {
                 Layout::from_size_align(128_000, 16).unwrap(),
             )
         };
-        assert_eq!(*allocator.size.borrow(), 128_064);
+        assert_eq!(allocator.get_size(), 128_064);
+    }
+
+    #[test]
+    fn realign_on_grow_and_shrink() {
+        let allocator = AtomDiskAlloc::new(STORAGE).unwrap();
+        let _padding = allocator
+            .allocate(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        let old_layout = Layout::from_size_align(64, 8).unwrap();
+        for shrinking in [false, true] {
+            let block = allocator.allocate(old_layout).unwrap().cast::<u8>();
+            unsafe { std::ptr::write_bytes(block.as_ptr(), 0xab, 64) };
+            let size = if shrinking { 32 } else { 128 };
+            let new_layout = Layout::from_size_align(size, 4096).unwrap();
+            let moved = unsafe {
+                if shrinking {
+                    allocator.shrink(block, old_layout, new_layout)
+                } else {
+                    allocator.grow(block, old_layout, new_layout)
+                }
+            }
+            .unwrap()
+            .cast::<u8>();
+            assert_eq!(moved.as_ptr().addr() % 4096, 0);
+            let bytes = unsafe { std::slice::from_raw_parts(moved.as_ptr(), 32) };
+            assert!(bytes.iter().all(|b| *b == 0xab));
+        }
+    }
+
+    #[test]
+    fn zeroed_after_reuse() {
+        // Chunked growth keeps the stale bytes in the file
+        let allocator = AtomDiskAlloc::on_file(
+            tempfile::tempfile().unwrap(),
+            None,
+            Options {
+                growth_chunk: 1024 * 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let first = allocator.allocate(layout).unwrap();
+        unsafe {
+            std::ptr::write_bytes(first.as_ptr().cast::<u8>(), 0xff, 4096);
+            allocator.deallocate(first.cast::<u8>(), layout);
+        }
+        let second = allocator.allocate_zeroed(layout).unwrap();
+        let bytes = unsafe { second.as_ref() };
+        assert!(bytes.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn alloc_beyond_reservation() {
+        let allocator = AtomDiskAlloc::new(4096).unwrap();
+        assert!(allocator
+            .allocate(Layout::from_size_align(4096, 8).unwrap())
+            .is_ok());
+        assert!(allocator
+            .allocate(Layout::from_size_align(1, 1).unwrap())
+            .is_err());
     }
 }
@@ -0,0 +1,297 @@
+// Buddy allocation, see `Strategy::Buddy`.
+//
+// Blocks are a power of two big, at least MIN_BLOCK bytes, and start
+// at an offset which is a multiple of their size. The buddy of
+// a block is the other half of the block twice its size.
+// A freed block is merged with its buddy as long as that is free,
+// so free space is found without searching, at the cost of rounding
+// every allocation up to the next power of two.
+//
+// Blocks which are not free are cut from the end of the used part,
+// the space skipped to align them becomes free blocks.
+// Free blocks at the end are given back by truncating the file.
+
+use super::{AtomDiskAlloc, DiskAllocError};
+use std::{
+    alloc::{AllocError, Layout},
+    collections::BTreeSet,
+    ptr::NonNull,
+};
+
+const MIN_ORDER: u32 = 4;
+const MIN_BLOCK: u64 = 1 << MIN_ORDER;
+
+// Offsets of free blocks, by order (log2 of the size).
+#[derive(Debug, Default)]
+pub(crate) struct BuddyLists {
+    free: Vec<BTreeSet<u64>>,
+}
+
+impl BuddyLists {
+    fn insert(&mut self, offset: u64, order: u32) {
+        let order = order as usize;
+        if self.free.len() <= order {
+            self.free.resize_with(order + 1, BTreeSet::new);
+        }
+        self.free[order].insert(offset);
+    }
+
+    fn remove(&mut self, offset: u64, order: u32) -> bool {
+        self.free
+            .get_mut(order as usize)
+            .is_some_and(|blocks| blocks.remove(&offset))
+    }
+
+    // Smallest free block of at least `order`.
+    fn pop(&mut self, order: u32) -> Option<(u64, u32)> {
+        let (found, blocks) = self
+            .free
+            .iter_mut()
+            .enumerate()
+            .skip(order as usize)
+            .find(|(_, blocks)| !blocks.is_empty())?;
+        Some((blocks.pop_first().unwrap(), found as u32))
+    }
+
+    // Remove the free block ending at `end`, returning its start.
+    fn pop_ending_at(&mut self, end: u64) -> Option<u64> {
+        let order = (0..self.free.len() as u32).find(|&order| {
+            end.checked_sub(1 << order)
+                .is_some_and(|start| self.free[order as usize].contains(&start))
+        })?;
+        let start = end - (1 << order);
+        self.remove(start, order);
+        Some(start)
+    }
+}
+
+// Order of the block holding `layout`.
+fn order_of(layout: Layout) -> u32 {
+    let size = (layout.size().max(layout.align()) as u64).max(MIN_BLOCK);
+    size.next_power_of_two().trailing_zeros()
+}
+
+// Layout of the whole block holding `layout`.
+pub(crate) fn block_layout(layout: Layout) -> Layout {
+    let size = 1 << order_of(layout);
+    Layout::from_size_align(size, size).unwrap_or(layout)
+}
+
+impl AtomDiskAlloc {
+    pub fn allocate_buddy(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        // Offsets are aligned, the start of the mapping only to pages
+        if !self.mmap.ptr().addr().is_multiple_of(layout.align()) {
+            return Err(self.fail(DiskAllocError::UnsupportedLayout));
+        }
+        let order = order_of(layout);
+        let reused = self.buddy.lock().unwrap().pop(order);
+        let offset = match reused {
+            Some((offset, found)) => {
+                // Give back the upper halves
+                for half in (order..found).rev() {
+                    self.buddy
+                        .lock()
+                        .unwrap()
+                        .insert(offset + (1 << half), half);
+                }
+                offset
+            }
+            None => self.extend_buddy(order)?,
+        };
+        self.bump.count_allocation(0);
+        Ok(unsafe { self.fat_ptr_at(offset, layout.size()) })
+    }
+
+    pub fn allocate_zeroed_buddy(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let old_file_len = self.bump.file_len();
+        let ptr = self.allocate_buddy(layout)?;
+        unsafe { self.zero_below_file_len(ptr.cast::<u8>(), layout.size(), old_file_len) };
+        Ok(ptr)
+    }
+
+    // Cut a new block from the end.
+    fn extend_buddy(&self, order: u32) -> Result<u64, AllocError> {
+        let size = 1 << order;
+        let end = self.get_size();
+        let start = end.next_multiple_of(size);
+        let new_end = start + size;
+        if new_end > self.reservation() && !self.move_mapping(new_end, 0) {
+            return Err(self.fail(DiskAllocError::ReservationExhausted));
+        }
+        // Buddy allocators never allocate lock-free, the end stays
+        if !self
+            .try_extend(end, new_end)
+            .map_err(|err| self.fail(err))?
+        {
+            return Err(AllocError);
+        }
+        self.free_range(end, start);
+        Ok(start)
+    }
+
+    // Split the space skipped for alignment into free blocks.
+    fn free_range(&self, mut start: u64, end: u64) {
+        let mut buddy = self.buddy.lock().unwrap();
+        while start < end {
+            let aligned = start.trailing_zeros().min(63);
+            let fitting = (end - start).ilog2();
+            let order = aligned.min(fitting);
+            // Less than the smallest block, never handed out
+            if order >= MIN_ORDER {
+                buddy.insert(start, order);
+            }
+            start += 1 << order;
+        }
+    }
+
+    pub unsafe fn deallocate_buddy(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.bump.count_deallocation();
+        if super::DEBUG_FILL {
+            std::ptr::write_bytes(ptr.as_ptr(), super::FREED_FILL, layout.size());
+        }
+        self.release_block(self.offset_of(ptr), order_of(layout));
+    }
+
+    // Free a block, merging it with its buddies,
+    // or cut it off if it is at the end.
+    fn release_block(&self, mut offset: u64, mut order: u32) {
+        // Before others can get it
+        unsafe { super::poison::poison(self.mmap.ptr().add(offset as usize), 1 << order) };
+        let mut buddy = self.buddy.lock().unwrap();
+        while order < 63 && buddy.remove(offset ^ (1 << order), order) {
+            offset &= !(1 << order);
+            order += 1;
+        }
+        let block_end = offset + (1 << order);
+        if block_end == self.get_size() {
+            let mut new_end = offset;
+            while let Some(start) = buddy.pop_ending_at(new_end) {
+                new_end = start;
+            }
+            if self.try_truncate(block_end, new_end) {
+                return;
+            }
+            // Free blocks before it were taken out
+            drop(buddy);
+            self.free_range(new_end, offset);
+            buddy = self.buddy.lock().unwrap();
+        }
+        buddy.insert(offset, order);
+        self.release_hole((offset, 1 << order));
+    }
+
+    pub unsafe fn reallocate_buddy(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let offset = self.offset_of(ptr);
+        let (old_order, new_order) = (order_of(old_layout), order_of(new_layout));
+        let aligned = super::is_aligned(ptr, new_layout.align());
+        let old_file_len = self.bump.file_len();
+        let old_end = offset + (1 << old_order);
+        let new_end = offset + (1 << new_order);
+        let in_place = if !aligned {
+            false
+        } else if new_order <= old_order {
+            for half in new_order..old_order {
+                self.release_block(offset + (1 << half), half);
+            }
+            true
+        } else {
+            // The last block grows along with the file
+            offset.is_multiple_of(1 << new_order)
+                && new_end <= self.reservation()
+                && self
+                    .try_extend(old_end, new_end)
+                    .map_err(|err| self.fail(err))?
+        };
+        let new_ptr = if in_place {
+            self.fat_ptr_at(offset, new_layout.size())
+        } else {
+            let new_ptr = self.allocate_buddy(new_layout)?;
+            std::ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size().min(new_layout.size()),
+            );
+            self.deallocate_buddy(ptr, old_layout);
+            new_ptr
+        };
+        if zeroed && new_layout.size() > old_layout.size() {
+            let grown_part = new_ptr.cast::<u8>().add(old_layout.size());
+            self.zero_below_file_len(
+                grown_part,
+                new_layout.size() - old_layout.size(),
+                old_file_len,
+            );
+        }
+        Ok(new_ptr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diskalloc::Options;
+
+    fn buddy_alloc() -> AtomDiskAlloc {
+        let file = tempfile::tempfile_in(crate::sys::default_dir()).unwrap();
+        let options = Options {
+            buddy: true,
+            reservation: 1 << 30,
+            ..Default::default()
+        };
+        AtomDiskAlloc::on_file(file, None, options).unwrap()
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let alloc = buddy_alloc();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let a = alloc.allocate_buddy(layout).unwrap().cast::<u8>();
+        let big = Layout::from_size_align(1000, 8).unwrap();
+        let b = alloc.allocate_buddy(big).unwrap().cast::<u8>();
+        // 128 bytes, then 1024 aligned to 1024
+        assert_eq!(unsafe { alloc.offset_of(b) }, 1024);
+        assert_eq!(alloc.get_size(), 2048);
+        // Fits into the space skipped before `b`
+        let c = alloc.allocate_buddy(layout).unwrap().cast::<u8>();
+        assert_eq!(unsafe { alloc.offset_of(c) }, 128);
+        unsafe {
+            alloc.deallocate_buddy(a, layout);
+            alloc.deallocate_buddy(c, layout);
+        }
+        // Merged back into one block of 1024
+        let d = alloc.allocate_buddy(big).unwrap().cast::<u8>();
+        assert_eq!(unsafe { alloc.offset_of(d) }, 0);
+        unsafe {
+            alloc.deallocate_buddy(b, big);
+            assert_eq!(alloc.get_size(), 1024);
+            alloc.deallocate_buddy(d, big);
+        }
+        assert_eq!(alloc.get_size(), 0);
+        assert!(alloc
+            .buddy
+            .lock()
+            .unwrap()
+            .free
+            .iter()
+            .all(BTreeSet::is_empty));
+    }
+
+    #[test]
+    fn grow_in_place_at_the_end() {
+        let alloc = buddy_alloc();
+        let small = Layout::from_size_align(4096, 8).unwrap();
+        let ptr = alloc.allocate_buddy(small).unwrap().cast::<u8>();
+        let big = Layout::from_size_align(8192, 8).unwrap();
+        let grown = unsafe { alloc.reallocate_buddy(ptr, small, big, true) }.unwrap();
+        assert_eq!(grown.cast::<u8>(), ptr);
+        let half = unsafe { alloc.reallocate_buddy(ptr, big, small, false) }.unwrap();
+        assert_eq!(half.cast::<u8>(), ptr);
+        assert_eq!(alloc.get_size(), 4096);
+    }
+}
@@ -0,0 +1,99 @@
+// A limit of the combined file sizes of many allocators,
+// counted like the quota of DiskAllocBuilder::max_file_size.
+
+use super::Quota;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+type ExceededHook = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Bytes of disk space shared by all allocators built with
+/// [`crate::DiskAllocBuilder::budget`]. Their files together
+/// never grow beyond it, e.g. for a service creating an allocator
+/// per request.
+///
+/// Growing a file beyond the budget fails the allocation with
+/// [`crate::DiskAllocError::QuotaExceeded`]. Shrinking and dropping
+/// allocators gives the space back. Clones share the same budget.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use diskallocator::{DiskAllocBuilder, DiskBudget};
+///
+/// let budget = DiskBudget::new(1 << 20);
+/// let builder = DiskAllocBuilder::new().budget(&budget);
+/// let mut a: Vec<u8, _> = Vec::new_in(builder.build().unwrap());
+/// let mut b: Vec<u8, _> = Vec::new_in(builder.build().unwrap());
+/// a.try_reserve_exact(800 * 1024).unwrap();
+/// assert!(b.try_reserve_exact(800 * 1024).is_err());
+/// drop(a);
+/// b.try_reserve_exact(800 * 1024).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct DiskBudget {
+    quota: Arc<Quota>,
+    on_exceeded: Arc<Mutex<Option<ExceededHook>>>,
+}
+
+impl fmt::Debug for DiskBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskBudget")
+            .field("max", &self.quota.max)
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+impl DiskBudget {
+    /// Allow `bytes` of files in total.
+    pub fn new(bytes: u64) -> Self {
+        Self {
+            quota: Arc::new(Quota::new(bytes)),
+            on_exceeded: Default::default(),
+        }
+    }
+
+    /// Call `hook` with the missing bytes whenever a file would
+    /// grow beyond the budget, before failing the allocation.
+    /// If it made enough space, e.g. by dropping allocators
+    /// of finished jobs, the file grows after all.
+    ///
+    /// Runs while the growing allocator is locked, so it must not
+    /// use that one.
+    pub fn on_exceeded(self, hook: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        *self.on_exceeded.lock().unwrap() = Some(Arc::new(hook));
+        self
+    }
+
+    /// Bytes of files of all allocators using the budget.
+    pub fn used(&self) -> u64 {
+        self.quota.used()
+    }
+
+    /// Bytes the files may still grow by together.
+    pub fn remaining(&self) -> u64 {
+        self.quota.remaining()
+    }
+
+    pub(crate) fn reserve(&self, bytes: u64) -> Result<(), std::io::Error> {
+        if self.quota.reserve(bytes).is_ok() {
+            return Ok(());
+        }
+        // Not holding the lock, the hook may drop allocators
+        let hook = self.on_exceeded.lock().unwrap().clone();
+        if let Some(hook) = hook {
+            hook(bytes - self.remaining().min(bytes));
+        }
+        self.quota.reserve(bytes)
+    }
+
+    pub(crate) fn add(&self, bytes: u64) {
+        self.quota.add(bytes);
+    }
+
+    pub(crate) fn release(&self, bytes: u64) {
+        self.quota.release(bytes);
+    }
+}
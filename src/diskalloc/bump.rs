@@ -0,0 +1,222 @@
+// Lock-free bump allocation.
+//
+// The used size of an allocator (the bump pointer) is atomic,
+// so allocations and in place growth at the end of the file,
+// which fit into the space the file has already
+// (see `growth_chunk`), do not need to lock the allocator.
+// Everything else still goes through the Mutex in DiskAlloc,
+// but moves the bump pointer with compare-and-swap as well,
+// as lock-free allocations may happen at any time.
+// So do allocations which need padding for their alignment,
+// the padding is kept in the free list for later allocations.
+//
+// The file length only grows after the file did, and only
+// shrinks before the file does, waiting for lock-free
+// allocations which may still use the old length.
+// Moving the mapping waits for them the same way.
+// All accesses are SeqCst, so the lengths and the
+// counter of running allocations are seen in order.
+
+use std::{
+    alloc::Layout,
+    ptr::NonNull,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering::SeqCst},
+};
+
+pub(crate) struct Bump {
+    // Start of the mapping
+    base: AtomicPtr<u8>,
+    size: AtomicU64,
+    file_len: AtomicU64,
+    // Lock-free allocations currently running
+    running: AtomicUsize,
+    live_allocations: AtomicU64,
+    wasted_alignment_bytes: AtomicU64,
+}
+
+impl Bump {
+    pub fn new(base: *mut u8, file_len: u64) -> Self {
+        Self {
+            base: AtomicPtr::new(base),
+            size: 0.into(),
+            file_len: file_len.into(),
+            running: 0.into(),
+            live_allocations: 0.into(),
+            wasted_alignment_bytes: 0.into(),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size.load(SeqCst)
+    }
+
+    // Only for allocators nobody else has access to.
+    pub fn set_size(&self, size: u64) {
+        self.size.store(size, SeqCst);
+    }
+
+    // Move the bump pointer, if nobody else did.
+    pub fn compare_size(&self, current: u64, new: u64) -> bool {
+        self.size
+            .compare_exchange(current, new, SeqCst, SeqCst)
+            .is_ok()
+    }
+
+    pub fn file_len(&self) -> u64 {
+        self.file_len.load(SeqCst)
+    }
+
+    // Call after the file got longer.
+    pub fn grow_file_len(&self, file_len: u64) {
+        self.file_len.store(file_len, SeqCst);
+    }
+
+    // Call before the file gets shorter, `wanted` must fit the used size.
+    // Returns the length the file may be truncated to, which is
+    // longer if lock-free allocations used the space in the meantime.
+    pub fn shrink_file_len(&self, wanted: u64, chunk: u64) -> u64 {
+        self.file_len.store(wanted, SeqCst);
+        while self.running.load(SeqCst) != 0 {
+            std::hint::spin_loop();
+        }
+        // Later allocations see the new length
+        let needed = super::round_up_to_chunk(self.size(), chunk);
+        if needed > wanted {
+            self.file_len.store(needed, SeqCst);
+            return needed;
+        }
+        wanted
+    }
+
+    pub fn live_allocations(&self) -> u64 {
+        self.live_allocations.load(SeqCst)
+    }
+
+    pub fn wasted_alignment_bytes(&self) -> u64 {
+        self.wasted_alignment_bytes.load(SeqCst)
+    }
+
+    pub fn count_allocation(&self, wasted_alignment_bytes: u64) {
+        self.live_allocations.fetch_add(1, SeqCst);
+        self.wasted_alignment_bytes
+            .fetch_add(wasted_alignment_bytes, SeqCst);
+    }
+
+    pub fn count_deallocation(&self) {
+        // Allocations restored by open are not counted
+        let _ = self
+            .live_allocations
+            .fetch_update(SeqCst, SeqCst, |count| count.checked_sub(1));
+    }
+
+    pub fn clear_allocations(&self) {
+        self.live_allocations.store(0, SeqCst);
+    }
+
+    // Allocate at the end without growing the file.
+    // The memory might be left over from an earlier allocation.
+    pub fn try_allocate(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        self.while_running(|| loop {
+            if !self.base().addr().is_multiple_of(layout.align()) {
+                return None;
+            }
+            let size = self.size();
+            if !size.is_multiple_of(layout.align() as u64) {
+                return None;
+            }
+            let end = size.checked_add(layout.size() as u64)?;
+            if end > self.file_len() {
+                return None;
+            }
+            if self.compare_size(size, end) {
+                self.count_allocation(0);
+                return Some(self.fat_ptr_at(size, layout.size()));
+            }
+        })
+    }
+
+    // Grow the last block in place without growing the file.
+    pub fn try_grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let addr = ptr.as_ptr().addr();
+        if !addr.is_multiple_of(new_layout.align()) {
+            return None;
+        }
+        self.while_running(|| {
+            let start = addr.checked_sub(self.base().addr())? as u64;
+            let old_end = start + old_layout.size() as u64;
+            let new_end = start.checked_add(new_layout.size() as u64)?;
+            let grown = new_end <= self.file_len() && self.compare_size(old_end, new_end);
+            grown.then(|| self.fat_ptr_at(start, new_layout.size()))
+        })
+    }
+
+    // Run `f` while no lock-free allocation runs,
+    // e.g. to move the mapping.
+    pub fn paused<T>(&self, f: impl FnOnce() -> T) -> T {
+        let file_len = self.file_len.swap(0, SeqCst);
+        while self.running.load(SeqCst) != 0 {
+            std::hint::spin_loop();
+        }
+        let result = f();
+        self.file_len.store(file_len, SeqCst);
+        result
+    }
+
+    // Only while paused.
+    pub fn rebase(&self, base: *mut u8) {
+        self.base.store(base, SeqCst);
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.base.load(SeqCst)
+    }
+
+    fn while_running<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.running.fetch_add(1, SeqCst);
+        let result = f();
+        self.running.fetch_sub(1, SeqCst);
+        result
+    }
+
+    fn fat_ptr_at(&self, offset: u64, len: usize) -> NonNull<[u8]> {
+        // Below the file length, inside the mapping
+        let start = self.base().wrapping_add(offset as usize);
+        unsafe { super::poison::unpoison(start, len) };
+        NonNull::new(std::ptr::slice_from_raw_parts_mut(start, len)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bump_within_file_len() {
+        let mut memory = vec![0u8; 4096];
+        let bump = Bump::new(memory.as_mut_ptr(), 4096);
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        let first = bump.try_allocate(layout).unwrap();
+        let grown = bump
+            .try_grow(
+                first.cast::<u8>(),
+                layout,
+                Layout::from_size_align(2000, 8).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(grown.cast::<u8>(), first.cast::<u8>());
+        assert_eq!(bump.size(), 2000);
+        let second = Layout::from_size_align(1500, 8).unwrap();
+        assert!(bump.try_allocate(second).is_some());
+        assert!(bump.try_allocate(second).is_none());
+        assert_eq!(bump.size(), 3500);
+        // Padding needs the free list
+        assert!(bump.try_allocate(Layout::new::<u64>()).is_none());
+        assert_eq!(bump.live_allocations(), 2);
+        assert_eq!(bump.shrink_file_len(1024, 1), 3500);
+    }
+}
@@ -0,0 +1,90 @@
+// Canary bytes after every allocation (feature `debug-canaries`).
+// A write past the end of a block damages them before it reaches
+// the next block, which is noticed when the block is resized or
+// freed, or on DiskAlloc::check_integrity.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    ptr::NonNull,
+    sync::Mutex,
+};
+
+pub(crate) const CANARY_LEN: usize = std::mem::size_of::<u64>();
+
+pub(crate) struct Canaries {
+    // Random per allocator, so stale or copied data does not look intact
+    value: u64,
+    // Requested size of every live block by address
+    live: Mutex<HashMap<usize, usize>>,
+}
+
+impl Canaries {
+    pub fn new() -> Self {
+        Self {
+            value: RandomState::new().hash_one(std::process::id()) | 1,
+            live: Default::default(),
+        }
+    }
+
+    // Write the canary after the `size` bytes of a new block,
+    // and return the part the caller may use.
+    pub unsafe fn set(&self, ptr: NonNull<[u8]>, size: usize) -> NonNull<[u8]> {
+        let start = ptr.cast::<u8>();
+        start.add(size).cast::<u64>().write_unaligned(self.value);
+        self.live
+            .lock()
+            .unwrap()
+            .insert(start.as_ptr().addr(), size);
+        NonNull::slice_from_raw_parts(start, size)
+    }
+
+    // Panic if the canary after the live block at `ptr` was
+    // overwritten, before `operation` spreads the damage.
+    pub unsafe fn check(&self, ptr: NonNull<u8>, operation: &str) {
+        let Some(&size) = self.live.lock().unwrap().get(&ptr.as_ptr().addr()) else {
+            // Restored on open, or not allocated by us at all
+            return;
+        };
+        assert!(
+            self.intact(ptr, size),
+            "DiskAlloc: {operation} of {ptr:p}, the {CANARY_LEN} bytes after its \
+             {size} bytes were overwritten (buffer overflow?)"
+        );
+    }
+
+    pub fn contains(&self, ptr: NonNull<u8>) -> bool {
+        self.live.lock().unwrap().contains_key(&ptr.as_ptr().addr())
+    }
+
+    pub fn forget(&self, ptr: NonNull<u8>) {
+        self.live.lock().unwrap().remove(&ptr.as_ptr().addr());
+    }
+
+    // Allocations given up on purpose.
+    pub fn clear(&self) {
+        self.live.lock().unwrap().clear();
+    }
+
+    // Address and size of the first live block with a damaged canary.
+    // `ptr_at` turns addresses into pointers into the mapping.
+    pub fn find_damaged(
+        &self,
+        ptr_at: impl Fn(usize) -> Option<NonNull<u8>>,
+    ) -> Option<(usize, usize)> {
+        let live = self.live.lock().unwrap();
+        let mut damaged: Vec<(usize, usize)> = live
+            .iter()
+            .map(|(&addr, &size)| (addr, size))
+            .filter(|&(addr, size)| {
+                ptr_at(addr).is_some_and(|ptr| !unsafe { self.intact(ptr, size) })
+            })
+            .collect();
+        damaged.sort_unstable();
+        damaged.first().copied()
+    }
+
+    unsafe fn intact(&self, ptr: NonNull<u8>, size: usize) -> bool {
+        ptr.add(size).cast::<u64>().read_unaligned() == self.value
+    }
+}
@@ -0,0 +1,133 @@
+// CRC32 checksums of the data of a persistent allocator,
+// kept in a sidecar file next to it.
+//
+// The sidecar holds the number of data bytes covered (after the
+// header), followed by one checksum per 4KiB block, all native endian.
+// It is rewritten completely on every flush and on drop, so changes
+// since then (e.g. before a crash) are reported as corruption.
+// Blocks freed and cut off from the file are not checked.
+
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const BLOCK: usize = 4096;
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// `path` with `.crc` appended.
+pub(crate) fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".crc");
+    name.into()
+}
+
+pub(crate) struct Checksums {
+    file: File,
+}
+
+impl Checksums {
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    pub fn update(&self, data: &[u8]) -> Result<(), Error> {
+        let mut bytes = Vec::with_capacity(8 + data.len().div_ceil(BLOCK) * 4);
+        bytes.extend((data.len() as u64).to_ne_bytes());
+        for block in data.chunks(BLOCK) {
+            bytes.extend(crc32(block).to_ne_bytes());
+        }
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&bytes)?;
+        file.set_len(bytes.len() as u64)
+    }
+
+    pub fn sync(&self) -> Result<(), Error> {
+        self.file.sync_data()
+    }
+
+    // Offset of the first block of `data` not matching its checksum.
+    // An empty sidecar covers nothing.
+    pub fn verify(&self, data: &[u8]) -> Result<Option<u64>, Error> {
+        let mut bytes = Vec::new();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        let damaged = || Error::new(ErrorKind::InvalidData, "checksum file damaged");
+        let (covered, sums) = bytes.split_first_chunk::<8>().ok_or_else(damaged)?;
+        let covered = u64::from_ne_bytes(*covered) as usize;
+        if sums.len() != covered.div_ceil(BLOCK) * 4 {
+            return Err(damaged());
+        }
+        let sums = sums
+            .chunks_exact(4)
+            .map(|sum| u32::from_ne_bytes(sum.try_into().unwrap()));
+        let blocks = data[..covered.min(data.len())].chunks(BLOCK);
+        for (index, (block, sum)) in blocks.zip(sums).enumerate() {
+            let start = index * BLOCK;
+            // Partly cut off since, the checksum is of the whole block
+            if block.len() < BLOCK && start + block.len() < covered {
+                break;
+            }
+            if crc32(block) != sum {
+                return Ok(Some(start as u64));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn verify_blocks() {
+        let checksums = Checksums::new(tempfile::tempfile().unwrap());
+        let mut data = vec![7u8; 3 * BLOCK + 100];
+        assert_eq!(checksums.verify(&data).unwrap(), None);
+        checksums.update(&data).unwrap();
+        assert_eq!(checksums.verify(&data).unwrap(), None);
+        // Shorter or longer data is checked as far as covered
+        assert_eq!(checksums.verify(&data[..BLOCK + 5]).unwrap(), None);
+        data.extend([1; 5000]);
+        assert_eq!(checksums.verify(&data).unwrap(), None);
+        data[2 * BLOCK + 17] = 8;
+        assert_eq!(checksums.verify(&data).unwrap(), Some(2 * BLOCK as u64));
+    }
+}
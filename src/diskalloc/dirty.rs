@@ -0,0 +1,91 @@
+// Pages written since the last flush, as told by the application
+// (see DiskAlloc::mark_dirty), so an incremental flush only has
+// to write back those. Overlapping and adjacent ranges are merged.
+
+use crate::sys;
+use std::collections::BTreeMap;
+
+#[derive(Default, Debug)]
+pub(crate) struct DirtyPages {
+    // End by start offset, both multiples of pages
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl DirtyPages {
+    pub fn mark(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let (mut start, len) = sys::page_range(offset, len);
+        let mut end = start + len;
+        // Ranges are disjoint, so all touching ones are at the back
+        while let Some((&prev_start, &prev_end)) = self.ranges.range(..=end).next_back() {
+            if prev_end < start {
+                break;
+            }
+            self.ranges.remove(&prev_start);
+            start = start.min(prev_start);
+            end = end.max(prev_end);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    // Put back ranges taken out, e.g. after a failed flush.
+    pub fn merge(&mut self, other: DirtyPages) {
+        for (start, end) in other.iter() {
+            self.mark(start, end - start);
+        }
+    }
+
+    pub fn take(&mut self) -> DirtyPages {
+        std::mem::take(self)
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    // Whether any byte from `start` to `end` is marked.
+    #[cfg(all(feature = "object-store", target_os = "linux"))]
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.ranges
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, &range_end)| range_end > start)
+    }
+
+    // Start and end of each range.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(&start, &end)| (start, end))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merges_ranges() {
+        let page = sys::page_size();
+        let mut dirty = DirtyPages::default();
+        dirty.mark(0, 1);
+        dirty.mark(10 * page + 5, page);
+        dirty.mark(3 * page, 0);
+        assert_eq!(
+            dirty.iter().collect::<Vec<_>>(),
+            [(0, page), (10 * page, 12 * page)]
+        );
+        // Touching the first, overlapping the second
+        dirty.mark(page, 10 * page);
+        assert_eq!(dirty.iter().collect::<Vec<_>>(), [(0, 12 * page)]);
+
+        let taken = dirty.take();
+        assert_eq!(dirty.iter().count(), 0);
+        dirty.mark(20 * page, 1);
+        dirty.merge(taken);
+        assert_eq!(
+            dirty.iter().collect::<Vec<_>>(),
+            [(0, 12 * page), (20 * page, 21 * page)]
+        );
+    }
+}
@@ -0,0 +1,42 @@
+// Background thread growing the files ahead of the used size,
+// so allocations at the end rarely wait for the file system.
+
+use super::Segments;
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex, Weak,
+    },
+    time::Duration,
+};
+
+pub(crate) struct Extender {
+    // Dropped with the last handle to the allocator, stopping the thread
+    _stop: mpsc::Sender<()>,
+}
+
+impl Extender {
+    pub fn spawn(
+        segments: Weak<Mutex<Segments>>,
+        interval: Duration,
+        distance: u64,
+    ) -> Result<Self, std::io::Error> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        std::thread::Builder::new()
+            .name("diskalloc-extend".into())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let Some(segments) = segments.upgrade() else {
+                        break;
+                    };
+                    // Allocations grow the file themselves then
+                    let _ = segments
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .try_for_each(|segment| segment.extend_ahead(distance));
+                }
+            })?;
+        Ok(Self { _stop: stop })
+    }
+}
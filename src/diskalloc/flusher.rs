@@ -0,0 +1,43 @@
+// Background thread writing appended data back to disk
+// and dropping it from the page cache, so dirty pages
+// do not pile up until the OS writes them all at once.
+
+use super::Segments;
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex, Weak,
+    },
+    time::Duration,
+};
+
+pub(crate) struct Flusher {
+    // Dropped with the last handle to the allocator, stopping the thread
+    _stop: mpsc::Sender<()>,
+}
+
+impl Flusher {
+    pub fn spawn(
+        segments: Weak<Mutex<Segments>>,
+        interval: Duration,
+        dirty_bytes: u64,
+    ) -> Result<Self, std::io::Error> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        std::thread::Builder::new()
+            .name("diskalloc-flush".into())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let Some(segments) = segments.upgrade() else {
+                        break;
+                    };
+                    // Errors show up again with the next explicit flush
+                    let _ = segments
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .try_for_each(|segment| segment.flush_appended(dirty_bytes));
+                }
+            })?;
+        Ok(Self { _stop: stop })
+    }
+}
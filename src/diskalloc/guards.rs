@@ -0,0 +1,80 @@
+// Guard pages for debugging (feature `debug-guards`).
+//
+// Every allocation starts at a page boundary and is followed
+// by an inaccessible page, so writing past its end faults
+// right away instead of corrupting the next allocation.
+// Overruns into the unused rest of the last page are not caught.
+//
+// Allocations never grow or shrink in place,
+// they are always moved to a new guarded block.
+
+use super::AtomDiskAlloc;
+use crate::{sys, DiskAllocError};
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    ptr::NonNull,
+};
+
+impl AtomDiskAlloc {
+    pub fn allocate_guarded(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let page = sys::page_size();
+        let len = (layout.size() as u64).next_multiple_of(page);
+        let block =
+            Layout::from_size_align((len + page) as usize, layout.align().max(page as usize))
+                .map_err(|_| self.fail(DiskAllocError::UnsupportedLayout))?;
+        let ptr = self.allocate(block)?;
+        let offset = unsafe { self.offset_of(ptr.cast::<u8>()) };
+        if let Err(io) = self.mmap.protect(offset + len, page, false) {
+            unsafe { self.deallocate(ptr.cast::<u8>(), block) };
+            return Err(self.fail(DiskAllocError::MapFailed { io }));
+        }
+        self.guards.lock().unwrap().insert(offset, block);
+        Ok(unsafe { self.fat_ptr_at(offset, layout.size()) })
+    }
+
+    pub fn allocate_zeroed_guarded(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate_guarded(layout)?;
+        unsafe { std::ptr::write_bytes(ptr.cast::<u8>().as_ptr(), 0, layout.size()) };
+        Ok(ptr)
+    }
+
+    pub unsafe fn deallocate_guarded(&self, ptr: NonNull<u8>) {
+        let offset = self.offset_of(ptr);
+        let block = self
+            .guards
+            .lock()
+            .unwrap()
+            .remove(&offset)
+            .expect("deallocating memory not allocated by this allocator");
+        let page = sys::page_size();
+        let guard = offset + block.size() as u64 - page;
+        // Memory is given back to the allocator, so it must be unprotected
+        if let Err(io) = self.mmap.protect(guard, page, true) {
+            // Keep the block allocated, it can not be used again
+            *self.error.lock().unwrap() = Some(DiskAllocError::MapFailed { io });
+            return;
+        }
+        self.deallocate(ptr, block);
+    }
+
+    pub unsafe fn reallocate_guarded(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = if zeroed {
+            self.allocate_zeroed_guarded(new_layout)?
+        } else {
+            self.allocate_guarded(new_layout)?
+        };
+        std::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size().min(new_layout.size()),
+        );
+        self.deallocate_guarded(ptr);
+        Ok(new_ptr)
+    }
+}
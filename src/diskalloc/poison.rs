@@ -0,0 +1,81 @@
+// Marking memory of the mapping outside of live allocations as
+// inaccessible for sanitizers (feature `sanitizers`): freed blocks,
+// cut off tails and holes in the free list. Builds with
+// `-Zsanitizer=address` use the manual poisoning of AddressSanitizer,
+// others Valgrind client requests, which do nothing when not run
+// under Valgrind. Without the feature, everything here is a no-op.
+//
+// Poisoned memory is unpoisoned again whenever it is handed out,
+// or the allocator itself has to touch it.
+
+pub(crate) const ENABLED: bool = cfg!(feature = "sanitizers");
+
+#[cfg(all(feature = "sanitizers", sanitize = "address"))]
+extern "C" {
+    fn __asan_poison_memory_region(addr: *const u8, size: usize);
+    fn __asan_unpoison_memory_region(addr: *const u8, size: usize);
+}
+
+// memcheck.h: VG_USERREQ__MAKE_MEM_NOACCESS and _DEFINED
+#[cfg(feature = "sanitizers")]
+const MAKE_MEM_NOACCESS: u64 = 0x4d43_0000;
+#[cfg(feature = "sanitizers")]
+const MAKE_MEM_DEFINED: u64 = 0x4d43_0002;
+
+#[cfg_attr(not(feature = "sanitizers"), allow(unused_variables))]
+pub(crate) unsafe fn poison(ptr: *const u8, len: usize) {
+    #[cfg(all(feature = "sanitizers", sanitize = "address"))]
+    __asan_poison_memory_region(ptr, len);
+    #[cfg(all(feature = "sanitizers", not(sanitize = "address")))]
+    client_request(MAKE_MEM_NOACCESS, ptr, len);
+}
+
+// Mapped memory counts as defined for Valgrind,
+// so that is what it is turned back into.
+#[cfg_attr(not(feature = "sanitizers"), allow(unused_variables))]
+pub(crate) unsafe fn unpoison(ptr: *const u8, len: usize) {
+    #[cfg(all(feature = "sanitizers", sanitize = "address"))]
+    __asan_unpoison_memory_region(ptr, len);
+    #[cfg(all(feature = "sanitizers", not(sanitize = "address")))]
+    client_request(MAKE_MEM_DEFINED, ptr, len);
+}
+
+// The magic instruction sequence of valgrind.h, a no-op on the
+// real CPU. Valgrind recognizes it and reads the request from the
+// argument block, leaving its result in place of the default.
+#[cfg(all(feature = "sanitizers", target_arch = "x86_64"))]
+unsafe fn client_request(request: u64, ptr: *const u8, len: usize) {
+    let args = [request, ptr.addr() as u64, len as u64, 0, 0, 0];
+    std::arch::asm!(
+        "rol rdi, 3",
+        "rol rdi, 13",
+        "rol rdi, 61",
+        "rol rdi, 51",
+        "xchg rbx, rbx",
+        in("rax") args.as_ptr(),
+        inout("rdx") 0_u64 => _,
+        options(nostack),
+    );
+}
+
+#[cfg(all(feature = "sanitizers", target_arch = "aarch64"))]
+unsafe fn client_request(request: u64, ptr: *const u8, len: usize) {
+    let args = [request, ptr.addr() as u64, len as u64, 0, 0, 0];
+    std::arch::asm!(
+        "ror x12, x12, #3",
+        "ror x12, x12, #13",
+        "ror x12, x12, #51",
+        "ror x12, x12, #61",
+        "orr x10, x10, x10",
+        in("x4") args.as_ptr(),
+        inout("x3") 0_u64 => _,
+        options(nostack),
+    );
+}
+
+// Valgrind does not run on the other architectures
+#[cfg(all(
+    feature = "sanitizers",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+unsafe fn client_request(_request: u64, _ptr: *const u8, _len: usize) {}
@@ -0,0 +1,185 @@
+// Shedding page cache of registered allocators under memory pressure,
+// see `MemoryPressure`. A watcher thread polls a PSI file
+// like the flusher polls the segments.
+
+use super::{DiskAlloc, Segments};
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+/// Drops the data of registered [`DiskAlloc`]s from memory when
+/// the host runs out of it, so disk-backed heaps give way to
+/// the rest of the system instead of adding to the pressure.
+///
+/// Relieving writes all data back (`msync`) and drops it from
+/// memory (`madvise` with `MADV_DONTNEED`), like
+/// [`DiskAlloc::evict`] on the whole file. It stays valid
+/// and is read from disk again on access.
+/// Copy-on-write allocators (see [`DiskAlloc::open_private`])
+/// are left alone, their changes only live in memory.
+///
+/// Pressure is either read from a Linux PSI file (see
+/// [`MemoryPressure::watch`]), or signalled by calling
+/// [`MemoryPressure::relieve`], e.g. from a handler of
+/// the application's own memory warnings.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use diskallocator::{DiskAlloc, MemoryPressure};
+///
+/// let pressure = MemoryPressure::new();
+/// let alloc = DiskAlloc::new().unwrap();
+/// pressure.register(&alloc);
+/// let mut v: Vec<u64, _> = Vec::new_in(alloc);
+/// v.extend(0..1000);
+/// pressure.relieve().unwrap();
+/// assert_eq!(v[999], 999);
+/// ```
+pub struct MemoryPressure {
+    allocators: Arc<Mutex<Vec<Weak<Mutex<Segments>>>>>,
+    // Dropped with the handle, stopping the watcher thread
+    _stop: Option<mpsc::Sender<()>>,
+}
+
+impl Default for MemoryPressure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryPressure {
+    /// Without a watcher, only relieving on
+    /// [`MemoryPressure::relieve`].
+    pub fn new() -> Self {
+        Self {
+            allocators: Default::default(),
+            _stop: None,
+        }
+    }
+
+    /// Check `/proc/pressure/memory` every `interval`, and relieve
+    /// whenever tasks were stalled waiting for memory at least
+    /// `threshold` percent of the last 10 seconds (`some avg10`).
+    ///
+    /// Linux 4.20 and newer only, fails if the file can not be read.
+    pub fn watch(threshold: f64, interval: Duration) -> Result<Self, std::io::Error> {
+        Self::watch_file("/proc/pressure/memory", threshold, interval)
+    }
+
+    /// Like [`MemoryPressure::watch`], but read the pressure from
+    /// another file in the same format, e.g. `memory.pressure`
+    /// of a cgroup, to react to the limit of a container.
+    pub fn watch_file<P: AsRef<Path>>(
+        path: P,
+        threshold: f64,
+        interval: Duration,
+    ) -> Result<Self, std::io::Error> {
+        let path = path.as_ref().to_path_buf();
+        // Fail early if the file is missing or malformed
+        read_pressure(&path)?;
+        let mut pressure = Self::new();
+        let allocators = Arc::downgrade(&pressure.allocators);
+        let (stop, stopped) = mpsc::channel::<()>();
+        std::thread::Builder::new()
+            .name("diskalloc-pressure".into())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let Some(allocators) = allocators.upgrade() else {
+                        break;
+                    };
+                    // Nobody to report to, the next poll tries again
+                    if read_pressure(&path).is_ok_and(|avg10| avg10 >= threshold) {
+                        let _ = relieve_all(&allocators);
+                    }
+                }
+            })?;
+        pressure._stop = Some(stop);
+        Ok(pressure)
+    }
+
+    /// Relieve `alloc` too. It is dropped from the list
+    /// once the last handle to it is gone.
+    pub fn register(&self, alloc: &DiskAlloc) {
+        let mut allocators = self.allocators.lock().unwrap();
+        allocators.retain(|segments| segments.strong_count() > 0);
+        allocators.push(Arc::downgrade(&alloc.alloc));
+    }
+
+    /// Write back and drop the data of all registered
+    /// allocators from memory now. Returns the first error,
+    /// after trying all of them.
+    pub fn relieve(&self) -> Result<(), std::io::Error> {
+        relieve_all(&self.allocators)
+    }
+}
+
+fn relieve_all(allocators: &Mutex<Vec<Weak<Mutex<Segments>>>>) -> Result<(), std::io::Error> {
+    // Not holding the list while writing back, registering stays quick
+    let allocators: Vec<_> = allocators
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    let mut result = Ok(());
+    for segments in allocators {
+        for segment in segments.lock().unwrap().iter() {
+            let shed = segment.shed();
+            if result.is_ok() {
+                result = shed;
+            }
+        }
+    }
+    result
+}
+
+// The `some avg10` percentage of a PSI file.
+fn read_pressure(path: &Path) -> Result<f64, std::io::Error> {
+    let content = std::fs::read_to_string(path)?;
+    parse_pressure(&content).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "no `some avg10=` line in the pressure file",
+        )
+    })
+}
+
+fn parse_pressure(content: &str) -> Option<f64> {
+    let some = content
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))?;
+    let avg10 = some
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?;
+    avg10.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_psi() {
+        let content = "some avg10=12.50 avg60=3.00 avg300=0.75 total=123456\n\
+                       full avg10=1.00 avg60=0.50 avg300=0.10 total=456\n";
+        assert_eq!(parse_pressure(content), Some(12.5));
+        assert_eq!(parse_pressure("full avg10=1.00\n"), None);
+        assert_eq!(parse_pressure("some avg10=many\n"), None);
+    }
+
+    #[test]
+    fn drops_dead_allocators() {
+        let pressure = MemoryPressure::new();
+        let alloc = DiskAlloc::new().unwrap();
+        pressure.register(&alloc);
+        drop(alloc);
+        pressure.relieve().unwrap();
+        pressure.register(&DiskAlloc::new().unwrap());
+        assert_eq!(pressure.allocators.lock().unwrap().len(), 1);
+    }
+}
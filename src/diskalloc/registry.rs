@@ -0,0 +1,83 @@
+// All live allocators of the process, see `registry`.
+// Entries are weak like those of MemoryPressure, so they
+// vanish with the last handle to their allocator.
+
+use super::{DiskAlloc, Segments};
+use crate::stats::{SizeCounters, Stats};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+struct Entry {
+    name: Option<Arc<str>>,
+    segments: Weak<Mutex<Segments>>,
+    sizes: Weak<SizeCounters>,
+}
+
+static REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+pub(crate) fn register(alloc: &DiskAlloc) {
+    let mut entries = REGISTRY.lock().unwrap();
+    entries.retain(|entry| entry.segments.strong_count() > 0);
+    entries.push(Entry {
+        name: alloc.name.clone(),
+        segments: Arc::downgrade(&alloc.alloc),
+        sizes: Arc::downgrade(&alloc.sizes),
+    });
+}
+
+/// A live allocator, see [`registry`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AllocatorInfo {
+    /// Set with [`crate::DiskAllocBuilder::name`].
+    pub name: Option<String>,
+    /// Path of the file of the first segment,
+    /// see [`DiskAlloc::backing_path`].
+    pub backing_path: Option<PathBuf>,
+    /// See [`DiskAlloc::stats`].
+    pub stats: Stats,
+}
+
+/// Every [`DiskAlloc`] of the process which still has a handle,
+/// oldest first, e.g. for an endpoint showing where the disk
+/// space goes. Label them with [`crate::DiskAllocBuilder::name`].
+///
+/// ```rust
+/// use diskallocator::DiskAllocBuilder;
+///
+/// let alloc = DiskAllocBuilder::new().name("ingest").build().unwrap();
+/// let ingest = diskallocator::registry()
+///     .into_iter()
+///     .find(|info| info.name.as_deref() == Some("ingest"))
+///     .unwrap();
+/// assert_eq!(ingest.stats.live_allocations, 0);
+/// drop(alloc);
+/// ```
+pub fn registry() -> Vec<AllocatorInfo> {
+    // Not holding the list while asking the allocators
+    let entries: Vec<_> = REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|entry| {
+            let segments = entry.segments.upgrade()?;
+            Some((entry.name.clone(), segments, entry.sizes.upgrade()?))
+        })
+        .collect();
+    entries
+        .into_iter()
+        .map(|(name, segments, sizes)| {
+            let segments = segments.lock().unwrap();
+            AllocatorInfo {
+                name: name.map(|name| name.to_string()),
+                backing_path: segments.primary().backing_path(),
+                stats: Stats {
+                    size_histogram: sizes.histogram(),
+                    ..segments.stats()
+                },
+            }
+        })
+        .collect()
+}
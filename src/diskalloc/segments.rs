@@ -0,0 +1,281 @@
+// A DiskAlloc consists of one or more segments, each with
+// its own file and mapping. Further segments are only added
+// to allocators on temporary files, once the reservation
+// of all previous segments is used up.
+//
+// Striped allocators start with one segment per directory,
+// taking turns for new allocations. These segments are
+// kept until the allocator is dropped.
+
+use super::AtomDiskAlloc;
+use crate::{AccessPattern, DiskAllocBuilder, DiskAllocError, Stats};
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    ptr::NonNull,
+};
+
+pub(crate) struct Segments {
+    list: Vec<AtomDiskAlloc>,
+    // Creates more segments, None if the allocator can not grow
+    builder: Option<DiskAllocBuilder>,
+    // The first `stripes` segments are used in turns
+    stripes: usize,
+    next_stripe: usize,
+    // Why the last allocation failed
+    error: Option<DiskAllocError>,
+    // Set on new segments as well
+    access_pattern: AccessPattern,
+}
+
+impl Segments {
+    pub fn new(stripes: Vec<AtomDiskAlloc>, builder: Option<DiskAllocBuilder>) -> Self {
+        assert!(!stripes.is_empty());
+        Self {
+            stripes: stripes.len(),
+            next_stripe: 0,
+            list: stripes,
+            builder,
+            error: None,
+            access_pattern: AccessPattern::Normal,
+        }
+    }
+
+    // The segment created first, the only one for
+    // allocators on a given file.
+    pub fn primary(&self) -> &AtomDiskAlloc {
+        &self.list[0]
+    }
+
+    pub fn into_primary(mut self) -> Option<AtomDiskAlloc> {
+        (self.list.len() == 1).then(|| self.list.remove(0))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AtomDiskAlloc> {
+        self.list.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn take_error(&mut self) -> Option<DiskAllocError> {
+        self.error.take()
+    }
+
+    pub fn set_access_pattern(&mut self, pattern: AccessPattern) -> Result<(), std::io::Error> {
+        self.access_pattern = pattern;
+        self.list
+            .iter()
+            .try_for_each(|segment| segment.set_access_pattern(pattern))
+    }
+
+    fn position(&self, ptr: NonNull<u8>) -> usize {
+        self.try_position(ptr)
+            .expect("pointer not allocated by this allocator")
+    }
+
+    fn try_position(&self, ptr: NonNull<u8>) -> Option<usize> {
+        self.list.iter().position(|segment| segment.contains(ptr))
+    }
+
+    pub fn find(&self, ptr: NonNull<u8>) -> &AtomDiskAlloc {
+        &self.list[self.position(ptr)]
+    }
+
+    fn allocate_in(
+        segment: &AtomDiskAlloc,
+        layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (segment.options.guard_pages, segment.options.buddy, zeroed) {
+            (true, _, false) => segment.allocate_guarded(layout),
+            (true, _, true) => segment.allocate_zeroed_guarded(layout),
+            (false, true, false) => segment.allocate_buddy(layout),
+            (false, true, true) => segment.allocate_zeroed_buddy(layout),
+            (false, false, false) => segment.allocate(layout),
+            (false, false, true) => segment.allocate_zeroed(layout),
+        }
+    }
+
+    fn allocate_any(&mut self, layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+        // Newest segments are most likely to have space left
+        let first_stripe = self.next_stripe;
+        self.next_stripe = (first_stripe + 1) % self.stripes;
+        let stripes = (0..self.stripes).map(|i| (first_stripe + i) % self.stripes);
+        for index in (self.stripes..self.list.len()).rev().chain(stripes) {
+            let segment = &self.list[index];
+            match Self::allocate_in(segment, layout, zeroed) {
+                Ok(ptr) => return Ok(ptr),
+                Err(_) => self.error = segment.take_error(),
+            }
+        }
+        let builder = self.builder.as_ref().ok_or(AllocError)?;
+        // Leave room for alignment and guard pages of huge allocations
+        let needed = (layout.size() + layout.align()) as u64 + 2 * crate::sys::page_size();
+        let segment = match builder.new_segment(needed, self.list.len()) {
+            Ok(segment) => segment,
+            Err(err) => {
+                self.error = Some(err.into());
+                return Err(AllocError);
+            }
+        };
+        let ptr = Self::allocate_in(&segment, layout, zeroed).inspect_err(|_| {
+            self.error = segment.take_error();
+        })?;
+        if self.access_pattern != AccessPattern::Normal {
+            // Only a hint, the allocation succeeded anyway
+            let _ = segment.set_access_pattern(self.access_pattern);
+        }
+        self.list.push(segment);
+        Ok(ptr)
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.allocate_any(layout, false);
+        self.report(result)
+    }
+
+    pub fn allocate_zeroed(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.allocate_any(layout, true);
+        self.report(result)
+    }
+
+    pub fn allocate_many(&mut self, layouts: &[Layout]) -> Result<Vec<NonNull<[u8]>>, AllocError> {
+        let segment = self.list.last().unwrap();
+        let options = &segment.options;
+        // Guarded, buddy and striped blocks are placed one by one
+        if !options.guard_pages && !options.buddy && self.stripes == 1 {
+            match segment.allocate_many(layouts) {
+                Ok(ptrs) => return Ok(ptrs),
+                Err(_) => self.error = segment.take_error(),
+            }
+        }
+        let mut ptrs = Vec::with_capacity(layouts.len());
+        for &layout in layouts {
+            match self.allocate(layout) {
+                Ok(ptr) => ptrs.push(ptr),
+                Err(err) => {
+                    for (ptr, &layout) in ptrs.into_iter().zip(layouts) {
+                        unsafe { self.deallocate(ptr.cast(), layout) };
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(ptrs)
+    }
+
+    // Tell the error hook why an allocation failed.
+    fn report<T>(&self, result: Result<T, AllocError>) -> Result<T, AllocError> {
+        if let (Err(_), Some(err)) = (&result, &self.error) {
+            self.call_hook(err);
+        }
+        result
+    }
+
+    // Keep an error of an operation which did not fail as a whole.
+    fn report_error(&mut self, err: Option<DiskAllocError>) {
+        if let Some(err) = err {
+            self.call_hook(&err);
+            self.error = Some(err);
+        }
+    }
+
+    fn call_hook(&self, err: &DiskAllocError) {
+        if let Some(on_error) = &self.primary().options.hooks.on_error {
+            on_error(err);
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let index = match self.try_position(ptr) {
+            Some(index) => index,
+            // Zero-size blocks own no bytes, their segment may be gone already
+            None if layout.size() == 0 => return,
+            None => panic!("pointer not allocated by this allocator"),
+        };
+        let segment = &self.list[index];
+        if segment.options.guard_pages {
+            segment.deallocate_guarded(ptr);
+        } else if segment.options.buddy {
+            segment.deallocate_buddy(ptr, layout);
+        } else {
+            segment.deallocate(ptr, layout);
+        }
+        let err = segment.take_error();
+        let empty = segment.get_size() == 0;
+        self.report_error(err);
+        if index >= self.stripes && empty {
+            // Give the address space back
+            self.list.remove(index);
+        }
+    }
+
+    // Forget all allocations, see AtomDiskAlloc::reset.
+    pub unsafe fn reset(&mut self) -> Result<(), std::io::Error> {
+        self.list.truncate(self.stripes);
+        self.list.iter().try_for_each(|segment| segment.reset())
+    }
+
+    pub unsafe fn reallocate(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.reallocate_any(ptr, old_layout, new_layout, zeroed);
+        self.report(result)
+    }
+
+    unsafe fn reallocate_any(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.size() == 0 && self.try_position(ptr).is_none() {
+            // Nothing to copy out of the segment given back
+            return self.allocate_any(new_layout, zeroed);
+        }
+        let segment = self.find(ptr);
+        let in_segment = match (segment.options.guard_pages, zeroed) {
+            (true, _) => segment.reallocate_guarded(ptr, old_layout, new_layout, zeroed),
+            _ if segment.options.buddy => {
+                segment.reallocate_buddy(ptr, old_layout, new_layout, zeroed)
+            }
+            (false, _) if new_layout.size() < old_layout.size() => {
+                segment.shrink(ptr, old_layout, new_layout)
+            }
+            (false, false) => segment.grow(ptr, old_layout, new_layout),
+            (false, true) => segment.grow_zeroed(ptr, old_layout, new_layout),
+        };
+        let err = segment.take_error();
+        if in_segment.is_ok() {
+            // Shrinking the file might have failed
+            self.report_error(err);
+            return in_segment;
+        }
+        self.error = err;
+        if self.builder.is_none() {
+            return in_segment;
+        }
+        // Move to another (possibly new) segment
+        let new_ptr = self.allocate_any(new_layout, zeroed)?;
+        std::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size().min(new_layout.size()),
+        );
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.list
+            .iter()
+            .map(AtomDiskAlloc::stats)
+            .fold(Stats::default(), |sum, stats| sum.combine(&stats))
+    }
+}
@@ -0,0 +1,146 @@
+// A timeline of allocations and file resizes, see
+// DiskAllocBuilder::record_trace. Written out in the Chrome trace
+// event format, for chrome://tracing or https://ui.perfetto.dev.
+
+use std::{
+    fmt,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Allocate,
+    Grow,
+    Shrink,
+    Deallocate,
+    // A file got a new length, the size of the event
+    Resize,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Allocate => "allocate",
+            Kind::Grow => "grow",
+            Kind::Shrink => "shrink",
+            Kind::Deallocate => "deallocate",
+            Kind::Resize => "resize",
+        }
+    }
+}
+
+struct Event {
+    at: Duration,
+    thread: u64,
+    kind: Kind,
+    addr: usize,
+    old_size: u64,
+    size: u64,
+}
+
+pub(crate) struct Trace {
+    start: Instant,
+    events: Mutex<Vec<Event>>,
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Trace")
+            .field("events", &self.events.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            events: Default::default(),
+        }
+    }
+
+    pub fn record(&self, kind: Kind, addr: usize, old_size: u64, size: u64) {
+        let event = Event {
+            at: self.start.elapsed(),
+            thread: std::thread::current().id().as_u64().get(),
+            kind,
+            addr,
+            old_size,
+            size,
+        };
+        self.events.lock().unwrap().push(event);
+    }
+
+    // An instant event for each allocation, and counters of the
+    // live bytes and the file length to plot them over time.
+    pub fn write_json(&self, mut out: impl Write) -> Result<(), std::io::Error> {
+        let pid = std::process::id();
+        let events = self.events.lock().unwrap();
+        let mut live: u64 = 0;
+        write!(out, "{{\"traceEvents\":[")?;
+        for (index, event) in events.iter().enumerate() {
+            if index > 0 {
+                write!(out, ",")?;
+            }
+            let ts = event.at.as_nanos() as f64 / 1000.0;
+            let Event {
+                thread,
+                kind,
+                addr,
+                old_size,
+                size,
+                ..
+            } = event;
+            if *kind == Kind::Resize {
+                write!(
+                    out,
+                    "{{\"name\":\"file size\",\"ph\":\"C\",\"ts\":{ts},\"pid\":{pid},\
+                     \"args\":{{\"bytes\":{size}}}}}"
+                )?;
+                continue;
+            }
+            live = (live + size).saturating_sub(*old_size);
+            write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"diskalloc\",\"ph\":\"i\",\"s\":\"t\",\
+                 \"ts\":{ts},\"pid\":{pid},\"tid\":{thread},\
+                 \"args\":{{\"addr\":\"{addr:#x}\",\"old_size\":{old_size},\"size\":{size}}}}},\
+                 {{\"name\":\"live bytes\",\"ph\":\"C\",\"ts\":{ts},\"pid\":{pid},\
+                 \"args\":{{\"bytes\":{live}}}}}",
+                kind.name()
+            )?;
+        }
+        writeln!(out, "],\"displayTimeUnit\":\"ms\"}}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chrome_trace_json() {
+        let trace = Trace::new();
+        trace.record(Kind::Resize, 0, 0, 4096);
+        trace.record(Kind::Allocate, 0x1000, 0, 100);
+        trace.record(Kind::Grow, 0x1000, 100, 200);
+        trace.record(Kind::Deallocate, 0x1000, 200, 0);
+        let mut json = Vec::new();
+        trace.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"traceEvents\":[{\"name\":\"file size\",\"ph\":\"C\""));
+        assert!(json.contains("\"name\":\"grow\""));
+        assert!(json.contains("\"addr\":\"0x1000\",\"old_size\":100,\"size\":200"));
+        let live: Vec<&str> = json
+            .match_indices("\"args\":{\"bytes\":")
+            .map(|(at, pattern)| {
+                let rest = &json[at + pattern.len()..];
+                &rest[..rest.find('}').unwrap()]
+            })
+            .collect();
+        assert_eq!(live, ["4096", "100", "200", "0"]);
+        assert!(json.ends_with("],\"displayTimeUnit\":\"ms\"}\n"));
+    }
+}
@@ -0,0 +1,116 @@
+// Records every live allocation of an allocator, in debug builds
+// and with the `track-allocations` feature. Catches double frees
+// and mismatched layouts, and with the feature, reports leaks
+// when the last handle to the allocator is dropped.
+
+use std::{alloc::Layout, collections::HashMap, ptr::NonNull, sync::Mutex};
+
+pub(crate) struct Tracker {
+    // Layout and length of the returned block by address
+    live: Mutex<HashMap<usize, (Layout, usize)>>,
+    // Whether every live allocation is known.
+    // Persistent allocators restore theirs, and keep them on purpose.
+    complete: bool,
+}
+
+impl Tracker {
+    pub fn new(complete: bool) -> Self {
+        Self {
+            live: Default::default(),
+            complete,
+        }
+    }
+
+    // Zero-size blocks do not take up space, they may share their
+    // address with the next allocation, and are not tracked.
+    pub fn insert(&self, ptr: NonNull<[u8]>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+        let addr = ptr.cast::<u8>().as_ptr().addr();
+        self.live.lock().unwrap().insert(addr, (layout, ptr.len()));
+    }
+
+    // Panic unless `layout` fits the live allocation at `ptr`,
+    // see "Memory fitting" in std::alloc::Allocator.
+    pub fn check(&self, ptr: NonNull<u8>, layout: Layout, operation: &str) {
+        if layout.size() == 0 {
+            return;
+        }
+        let live = self.live.lock().unwrap();
+        let Some(&(allocated, len)) = live.get(&ptr.as_ptr().addr()) else {
+            drop(live);
+            if self.complete {
+                panic!("DiskAlloc: {operation} of {ptr:p}, which is not live (double free?)");
+            }
+            return;
+        };
+        drop(live);
+        let fits = layout.align() == allocated.align()
+            && (allocated.size()..=len).contains(&layout.size());
+        assert!(
+            fits,
+            "DiskAlloc: {operation} of {ptr:p} with {} bytes aligned to {}, \
+             allocated with {} bytes aligned to {}",
+            layout.size(),
+            layout.align(),
+            allocated.size(),
+            allocated.align(),
+        );
+    }
+
+    pub fn remove(&self, ptr: NonNull<u8>, layout: Layout) -> Option<(Layout, usize)> {
+        if layout.size() == 0 {
+            return None;
+        }
+        self.live.lock().unwrap().remove(&ptr.as_ptr().addr())
+    }
+
+    // Track a removed allocation again, e.g. after it failed to move.
+    pub fn restore(&self, ptr: NonNull<u8>, removed: Option<(Layout, usize)>) {
+        if let Some(removed) = removed {
+            self.live
+                .lock()
+                .unwrap()
+                .insert(ptr.as_ptr().addr(), removed);
+        }
+    }
+
+    // Allocations given up on purpose.
+    pub fn clear(&self) {
+        self.live.lock().unwrap().clear();
+    }
+
+    // Addresses only, pointers are made from the mapping.
+    #[cfg(feature = "track-allocations")]
+    pub fn live(&self) -> Vec<(usize, Layout)> {
+        let mut live: Vec<_> = self
+            .live
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&addr, &(layout, _))| (addr, layout))
+            .collect();
+        live.sort_by_key(|&(addr, _)| addr);
+        live
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let live = self.live.get_mut().unwrap();
+        if !cfg!(feature = "track-allocations") || !self.complete || live.is_empty() {
+            return;
+        }
+        let bytes: usize = live.values().map(|(layout, _)| layout.size()).sum();
+        let message = format!(
+            "DiskAlloc dropped with {} live allocations ({} bytes)",
+            live.len(),
+            bytes
+        );
+        if cfg!(debug_assertions) && !std::thread::panicking() {
+            panic!("{message}");
+        }
+        eprintln!("warning: {message}");
+    }
+}
@@ -0,0 +1,92 @@
+use crate::DiskAlloc;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+};
+
+/// A single value living on disk, owning its own [`DiskAlloc`].
+///
+/// Meant for one huge value, like a big struct or array.
+/// Like [`crate::DiskVec`], no `#![feature(allocator_api)]`
+/// is needed to use it.
+///
+/// ```rust
+/// use diskallocator::DiskBox;
+///
+/// // Too big for the stack, so it is zeroed on disk
+/// let mut table = unsafe { DiskBox::<[u64; 1 << 24]>::new_zeroed() }.unwrap();
+/// table[12345] = 1;
+/// assert_eq!(table.iter().sum::<u64>(), 1);
+/// ```
+pub struct DiskBox<T> {
+    inner: Box<T, DiskAlloc>,
+}
+
+impl<T> DiskBox<T> {
+    /// Move `value` to a new temporary file,
+    /// see [`DiskAlloc::new`].
+    pub fn new(value: T) -> Result<Self, std::io::Error> {
+        Ok(Self::new_in(value, DiskAlloc::new()?))
+    }
+
+    /// Move `value` to disk using a custom allocator,
+    /// e.g. one created by [`crate::DiskAllocBuilder`].
+    pub fn new_in(value: T, alloc: DiskAlloc) -> Self {
+        Self {
+            inner: Box::new_in(value, alloc),
+        }
+    }
+
+    /// Like [`DiskBox::new`], but the value is pinned
+    /// and can not be moved out again.
+    pub fn pin(value: T) -> Result<Pin<Self>, std::io::Error> {
+        let boxed = Self::new(value)?;
+        // The value stays at its place in the file until dropped
+        Ok(unsafe { Pin::new_unchecked(boxed) })
+    }
+
+    /// Value with all bytes zero on a new temporary file,
+    /// without ever putting it on the stack.
+    ///
+    /// # Safety
+    /// All zero bytes must be a valid value of `T`,
+    /// see [`std::mem::MaybeUninit::zeroed`].
+    pub unsafe fn new_zeroed() -> Result<Self, std::io::Error> {
+        let alloc = DiskAlloc::new()?;
+        let inner = Box::try_new_zeroed_in(alloc).map_err(std::io::Error::other)?;
+        Ok(Self {
+            inner: inner.assume_init(),
+        })
+    }
+
+    /// The allocator used by this box.
+    pub fn allocator(&self) -> &DiskAlloc {
+        Box::allocator(&self.inner)
+    }
+
+    /// Move the value back into memory.
+    pub fn into_inner(self) -> T {
+        *self.inner
+    }
+}
+
+impl<T> Deref for DiskBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for DiskBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for DiskBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{DiskAlloc, DiskVec};
+use std::{fmt, io::Write, ops::Deref};
+
+/// Bytes written to disk through [`std::io::Write`],
+/// e.g. the output of a decoder or a download.
+///
+/// Unlike writing a file, the bytes can be used in place
+/// as a `&[u8]` afterwards, or as a [`DiskVec`] with
+/// [`DiskBuf::into_vec`]. Writes fail instead of panicking
+/// when the file can not grow.
+///
+/// ```rust
+/// use diskallocator::DiskBuf;
+/// use std::io::Write;
+///
+/// let mut buf = DiskBuf::new().unwrap();
+/// for i in 0..1000 {
+///     writeln!(buf, "line {i}").unwrap();
+/// }
+/// assert!(buf.starts_with(b"line 0\nline 1\n"));
+/// let v = buf.into_vec();
+/// assert_eq!(v.iter().filter(|&&b| b == b'\n').count(), 1000);
+/// ```
+pub struct DiskBuf {
+    vec: DiskVec<u8>,
+}
+
+impl DiskBuf {
+    /// Empty buffer on a new temporary file,
+    /// see [`DiskAlloc::new`].
+    pub fn new() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            vec: DiskVec::new()?,
+        })
+    }
+
+    /// Empty buffer using a custom allocator,
+    /// e.g. one created by [`crate::DiskAllocBuilder`].
+    pub fn new_in(alloc: DiskAlloc) -> Self {
+        Self {
+            vec: DiskVec::new_in(alloc),
+        }
+    }
+
+    /// The allocator of this buffer.
+    pub fn allocator(&self) -> &DiskAlloc {
+        self.vec.allocator()
+    }
+
+    /// The written bytes as a vector, without copying them.
+    pub fn into_vec(self) -> DiskVec<u8> {
+        self.vec
+    }
+}
+
+impl Write for DiskBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.vec
+            .try_reserve(buf.len())
+            .map_err(std::io::Error::other)?;
+        self.vec.as_mut_vec().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    // Nothing is buffered, the bytes are in the mapping right away
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Deref for DiskBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.vec
+    }
+}
+
+impl AsRef<[u8]> for DiskBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Append to the bytes of `vec`.
+impl From<DiskVec<u8>> for DiskBuf {
+    fn from(vec: DiskVec<u8>) -> Self {
+        Self { vec }
+    }
+}
+
+impl From<DiskBuf> for DiskVec<u8> {
+    fn from(buf: DiskBuf) -> Self {
+        buf.vec
+    }
+}
+
+impl fmt::Debug for DiskBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskBuf")
+            .field("len", &self.len())
+            .field("capacity", &self.vec.capacity())
+            .finish()
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{DiskAlloc, DiskBuf, DiskVec};
+use std::{
+    fmt,
+    io::{BufRead, Read, Seek, SeekFrom},
+    ptr::NonNull,
+};
+
+/// Reads the bytes of a [`DiskVec<u8>`] or any other memory of
+/// a [`DiskAlloc`] through [`Read`], [`BufRead`] and [`Seek`],
+/// like [`std::io::Cursor`] does for memory.
+///
+/// Meant for parsers taking a reader, e.g. of archives or
+/// serialized data: the bytes are read from the mapping,
+/// they are never copied to the heap first.
+///
+/// ```rust
+/// use diskallocator::{DiskCursor, DiskVec};
+/// use std::io::{BufRead, Seek, SeekFrom};
+///
+/// let mut v = DiskVec::new().unwrap();
+/// v.extend(b"first\nsecond\n".iter().copied());
+/// let mut cursor = DiskCursor::new(v);
+/// cursor.seek(SeekFrom::Start(6)).unwrap();
+/// let mut line = String::new();
+/// cursor.read_line(&mut line).unwrap();
+/// assert_eq!(line, "second\n");
+/// ```
+pub struct DiskCursor {
+    source: Source,
+    // The bytes, inside the mapping of the allocator of `source`
+    data: NonNull<[u8]>,
+    pos: u64,
+}
+
+enum Source {
+    Vec(DiskVec<u8>),
+    Raw(DiskAlloc),
+}
+
+// Only reading the bytes, which belong to the cursor
+unsafe impl Send for DiskCursor {}
+unsafe impl Sync for DiskCursor {}
+
+impl DiskCursor {
+    /// Read the elements of `vec`, from the start.
+    pub fn new(vec: DiskVec<u8>) -> Self {
+        let data = NonNull::from(&*vec);
+        Self {
+            source: Source::Vec(vec),
+            data,
+            pos: 0,
+        }
+    }
+
+    /// Read `data` of `alloc`, e.g. [`DiskAlloc::data`]
+    /// of a file opened with [`DiskAlloc::open`].
+    ///
+    /// # Safety
+    /// `data` must be initialized memory of `alloc`, which is not
+    /// written or freed while the cursor exists.
+    pub unsafe fn from_raw_parts(alloc: DiskAlloc, data: NonNull<[u8]>) -> Self {
+        Self {
+            source: Source::Raw(alloc),
+            data,
+            pos: 0,
+        }
+    }
+
+    /// The allocator holding the bytes.
+    pub fn allocator(&self) -> &DiskAlloc {
+        match &self.source {
+            Source::Vec(vec) => vec.allocator(),
+            Source::Raw(alloc) => alloc,
+        }
+    }
+
+    /// All bytes, independent of the position.
+    pub fn get_ref(&self) -> &[u8] {
+        unsafe { self.data.as_ref() }
+    }
+
+    /// Where the next read starts.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Move to `pos`, which may be past the end.
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+
+    /// The vector read, `None` for raw memory.
+    pub fn into_vec(self) -> Option<DiskVec<u8>> {
+        match self.source {
+            Source::Vec(vec) => Some(vec),
+            Source::Raw(_) => None,
+        }
+    }
+
+    fn remaining(&self) -> &[u8] {
+        let data = self.get_ref();
+        let start = usize::try_from(self.pos).map_or(data.len(), |pos| pos.min(data.len()));
+        &data[start..]
+    }
+}
+
+impl Read for DiskCursor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.remaining().read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.remaining().read_exact(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl BufRead for DiskCursor {
+    // The whole rest is readable at once
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        Ok(self.remaining())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl Seek for DiskCursor {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::End(offset) => (self.get_ref().len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.pos)
+    }
+}
+
+impl From<DiskVec<u8>> for DiskCursor {
+    fn from(vec: DiskVec<u8>) -> Self {
+        Self::new(vec)
+    }
+}
+
+/// Read the bytes written to `buf`.
+impl From<DiskBuf> for DiskCursor {
+    fn from(buf: DiskBuf) -> Self {
+        Self::new(buf.into_vec())
+    }
+}
+
+impl fmt::Debug for DiskCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskCursor")
+            .field("len", &self.get_ref().len())
+            .field("position", &self.pos)
+            .finish()
+    }
+}
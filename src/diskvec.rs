@@ -0,0 +1,750 @@
+use crate::{
+    header::{Region, VecMeta},
+    DiskAlloc, DiskAllocBuilder, DiskAllocError,
+};
+use std::{
+    alloc::Layout,
+    collections::TryReserveError,
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut, Range},
+    path::Path,
+    ptr::NonNull,
+};
+
+/// A vector living on disk, owning its own [`DiskAlloc`].
+///
+/// Works on stable syntax: no `#![feature(allocator_api)]`
+/// is needed to use it.
+///
+/// ```rust
+/// use diskallocator::DiskVec;
+///
+/// let mut v = DiskVec::new().unwrap();
+/// for i in 0..1000_u64 {
+///     v.push(i);
+/// }
+/// assert_eq!(v[999], 999);
+/// assert_eq!(v.iter().sum::<u64>(), 499_500);
+/// ```
+///
+/// Vectors created with [`DiskVec::create`] keep their data
+/// in the file when dropped, so it can be opened again.
+pub struct DiskVec<T> {
+    vec: ManuallyDrop<Vec<T, DiskAlloc>>,
+    persistent: bool,
+    // Entry of the slot table, None for the vector of the whole file
+    slot: Option<usize>,
+}
+
+impl<T> DiskVec<T> {
+    /// Empty vector on a new temporary file,
+    /// see [`DiskAlloc::new`].
+    pub fn new() -> Result<Self, DiskAllocError> {
+        Ok(Self::new_in(DiskAlloc::new()?))
+    }
+
+    /// Empty vector on a new temporary file,
+    /// with space for at least `capacity` elements.
+    pub fn with_capacity(capacity: usize) -> Result<Self, DiskAllocError> {
+        let mut vec = Self::new()?;
+        if let Err(err) = vec.try_reserve_exact(capacity) {
+            // Kept by the allocator, unless the capacity overflowed
+            return Err(vec
+                .allocator()
+                .take_error()
+                .unwrap_or_else(|| DiskAllocError::Io(std::io::Error::other(err))));
+        }
+        Ok(vec)
+    }
+
+    /// Empty vector using a custom allocator,
+    /// e.g. one created by [`crate::DiskAllocBuilder`].
+    ///
+    /// Use exactly one vector per allocator.
+    pub fn new_in(alloc: DiskAlloc) -> Self {
+        Self {
+            vec: ManuallyDrop::new(Vec::new_in(alloc)),
+            persistent: false,
+            slot: None,
+        }
+    }
+
+    /// Empty vector stored in the file at `path`,
+    /// which is created or truncated.
+    ///
+    /// When dropped, the elements are kept in the file and
+    /// can be restored with [`DiskVec::open`].
+    /// Their destructors are not run.
+    ///
+    /// The size, alignment and name of `T` are stored in the
+    /// header of the file, and the length when the vector is dropped.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, DiskAllocError> {
+        Self::create_with(path, &DiskAllocBuilder::new())
+    }
+
+    /// Like [`DiskVec::create`], but configure the allocator with
+    /// `builder`, e.g. to write the data to disk on drop with
+    /// [`DiskAllocBuilder::flush_on_drop`].
+    pub fn create_with<P: AsRef<Path>>(
+        path: P,
+        builder: &DiskAllocBuilder,
+    ) -> Result<Self, DiskAllocError> {
+        let mut vec = Self::new_in(builder.create(path)?);
+        vec.persistent = true;
+        vec.allocator().set_vec_meta(VecMeta::new::<T>(0));
+        Ok(vec)
+    }
+
+    /// Reopen a vector stored by [`DiskVec::create`],
+    /// with the length it had when it was dropped.
+    ///
+    /// Fails with [`DiskAllocError::Io`] of kind
+    /// [`std::io::ErrorKind::InvalidData`] if the file holds
+    /// no vector, or one of a type with another size,
+    /// alignment or name.
+    ///
+    /// # Safety
+    /// The elements must still be valid values of `T`, which
+    /// is not the case if they point to memory, like `&T` or `Box<T>`.
+    /// Type names change with the crate defining them, so
+    /// a different type of the same name is not detected.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<Self, DiskAllocError> {
+        Self::open_with(path, &DiskAllocBuilder::new())
+    }
+
+    /// Like [`DiskVec::open`], but configure the allocator
+    /// with `builder`.
+    ///
+    /// # Safety
+    /// See [`DiskVec::open`].
+    pub unsafe fn open_with<P: AsRef<Path>>(
+        path: P,
+        builder: &DiskAllocBuilder,
+    ) -> Result<Self, DiskAllocError> {
+        let alloc = builder.open(path)?;
+        let stored = Self::stored(&alloc, None).map_err(DiskAllocError::Io)?;
+        let (ptr, capacity) = Self::locate(&alloc, None, &stored)?;
+        Ok(Self::restore(alloc, stored.vec, ptr, capacity, None)?)
+    }
+
+    /// Empty vector in `slot` of a persistent allocator (see
+    /// [`DiskAlloc::create`]), so one file can hold several
+    /// vectors, e.g. keys and values. There are 16 slots,
+    /// independent of the vector of [`DiskVec::create`].
+    ///
+    /// Like that one, the elements are kept when the vector is dropped,
+    /// and can be restored with [`DiskVec::open_in`].
+    pub fn create_in(alloc: DiskAlloc, slot: usize) -> Result<Self, std::io::Error> {
+        alloc.vec_slot(slot)?;
+        alloc.set_vec_slot(
+            slot,
+            Region {
+                vec: VecMeta::new::<T>(0),
+                ..Default::default()
+            },
+        );
+        let mut vec = Self::new_in(alloc);
+        vec.persistent = true;
+        vec.slot = Some(slot);
+        Ok(vec)
+    }
+
+    /// Reopen a vector stored by [`DiskVec::create_in`]
+    /// in `slot` of `alloc`, e.g. from [`DiskAlloc::open`].
+    ///
+    /// Fails like [`DiskVec::open`] if the slot is empty or of
+    /// another type. Open every slot only once.
+    ///
+    /// # Safety
+    /// See [`DiskVec::open`].
+    pub unsafe fn open_in(alloc: DiskAlloc, slot: usize) -> Result<Self, std::io::Error> {
+        let stored = Self::stored(&alloc, Some(slot))?;
+        let (ptr, capacity) = Self::locate(&alloc, Some(slot), &stored)?;
+        Self::restore(alloc, stored.vec, ptr, capacity, Some(slot))
+    }
+
+    /// Follow a vector another process is still writing, like
+    /// [`DiskAlloc::refresh`]: afterwards, it holds the elements
+    /// the writer had at its last [`DiskVec::flush`].
+    /// Only for vectors opened read-only, e.g. with
+    /// [`DiskVec::open_in`] on [`DiskAlloc::open_shared_memory`].
+    ///
+    /// The writer must be append-only (see
+    /// [`DiskAllocBuilder::append_only`]), so it never frees,
+    /// overwrites or cuts off what the reader might still look at.
+    /// Elements it changes in place are seen at any time,
+    /// possibly half-written.
+    pub fn refresh(&mut self) -> Result<(), std::io::Error> {
+        let alloc = self.allocator().clone();
+        // Before the used size, which then covers all the vector points to
+        let stored = Self::stored(&alloc, self.slot)?;
+        alloc.refresh()?;
+        // The elements are as valid as when the vector was opened
+        let vec = unsafe {
+            let (ptr, capacity) = Self::locate(&alloc, self.slot, &stored)?;
+            Self::restore(alloc, stored.vec, ptr, capacity, self.slot)?
+        };
+        // Nothing is freed, the old vector points into the same file
+        *self = vec;
+        Ok(())
+    }
+
+    // What the header has on the vector in `slot`,
+    // or the one of the whole file.
+    fn stored(alloc: &DiskAlloc, slot: Option<usize>) -> Result<Region, std::io::Error> {
+        let Some(slot) = slot else {
+            return alloc
+                .vec_meta()
+                .map(|vec| Region {
+                    vec,
+                    ..Default::default()
+                })
+                .ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "file holds no DiskVec")
+                });
+        };
+        let region = alloc.vec_slot(slot)?;
+        if region.vec.elem_align == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("vector slot {slot} is empty"),
+            ));
+        }
+        Ok(region)
+    }
+
+    // Pointer to and capacity of a `stored` vector, null if nothing was
+    // allocated. The vector of the whole file takes all of the data.
+    unsafe fn locate(
+        alloc: &DiskAlloc,
+        slot: Option<usize>,
+        stored: &Region,
+    ) -> Result<(*mut T, usize), std::io::Error> {
+        let data = alloc.data();
+        let Some(slot) = slot else {
+            return Ok(match std::mem::size_of::<T>() {
+                size if size > 0 && !data.is_empty() => {
+                    (data.as_ptr().cast::<T>(), data.len() / size)
+                }
+                _ => (std::ptr::null_mut(), 0),
+            });
+        };
+        let bytes = stored.capacity.checked_mul(std::mem::size_of::<T>() as u64);
+        if bytes.is_none_or(|bytes| {
+            stored.offset.saturating_add(bytes) > data.len() as u64
+                || !stored
+                    .offset
+                    .is_multiple_of(std::mem::align_of::<T>() as u64)
+        }) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("vector slot {slot} points outside of the file"),
+            ));
+        }
+        let ptr = match stored.capacity {
+            0 => std::ptr::null_mut(),
+            _ => data
+                .as_ptr()
+                .cast::<u8>()
+                .add(stored.offset as usize)
+                .cast::<T>(),
+        };
+        Ok((ptr, stored.capacity as usize))
+    }
+
+    // Rebuild a persistent vector of `capacity` elements at `ptr`,
+    // null if nothing was allocated.
+    unsafe fn restore(
+        alloc: DiskAlloc,
+        meta: VecMeta,
+        ptr: *mut T,
+        capacity: usize,
+        slot: Option<usize>,
+    ) -> Result<Self, std::io::Error> {
+        if !meta.same_type(&VecMeta::new::<T>(0)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file holds a DiskVec of another element type",
+            ));
+        }
+        let capacity = match std::mem::size_of::<T>() {
+            0 => usize::MAX,
+            _ => capacity,
+        };
+        let len = usize::try_from(meta.len).unwrap_or(usize::MAX);
+        if len > capacity {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file holds fewer elements than its header records",
+            ));
+        }
+        let vec = if ptr.is_null() {
+            // Zero-sized elements take no space, any length fits
+            let mut vec = Vec::new_in(alloc);
+            vec.set_len(len);
+            vec
+        } else {
+            Vec::from_raw_parts_in(ptr, len, capacity, alloc)
+        };
+        Ok(Self {
+            vec: ManuallyDrop::new(vec),
+            persistent: true,
+            slot,
+        })
+    }
+
+    /// Write the elements and the length of a persistent vector
+    /// to disk, see [`DiskAlloc::flush`].
+    ///
+    /// After a crash, [`DiskVec::open`] restores the vector as of
+    /// the last flush if later changes did not fully reach the disk.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.store_meta();
+        self.allocator().flush()
+    }
+
+    /// Like [`DiskVec::flush`], but only write back the elements
+    /// marked with [`DiskVec::mark_dirty_range`] and those appended
+    /// since the last flush, see [`DiskAlloc::flush_incremental`].
+    pub fn flush_incremental(&self) -> Result<(), std::io::Error> {
+        self.store_meta();
+        self.allocator().flush_incremental()
+    }
+
+    /// Record that the elements in `range` were changed,
+    /// see [`DiskAlloc::mark_dirty`].
+    ///
+    /// # Panics
+    /// Panics if `range` is out of bounds.
+    pub fn mark_dirty_range(&self, range: Range<usize>) -> Result<(), std::io::Error> {
+        match self.byte_range(range) {
+            Some((ptr, len)) => self.allocator().mark_dirty_ptr(ptr, len),
+            None => Ok(()),
+        }
+    }
+
+    /// Flush a persistent vector and copy the used part of its
+    /// file to `path`, which can then be opened with [`DiskVec::open`].
+    /// Returns the length of the copy, see [`DiskAlloc::export_to`].
+    ///
+    /// Fails with [`std::io::ErrorKind::Unsupported`] if the vector
+    /// is not persistent, its file would have no header.
+    pub fn save_as<P: AsRef<Path>>(&self, path: P) -> Result<u64, std::io::Error> {
+        if !self.persistent {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only persistent vectors can be saved",
+            ));
+        }
+        self.flush()?;
+        self.allocator().export_to(path)
+    }
+
+    // Record length and place of a persistent vector in the header.
+    fn store_meta(&self) {
+        let meta = VecMeta::new::<T>(self.len());
+        let Some(slot) = self.slot else {
+            self.allocator().set_vec_meta(meta);
+            return;
+        };
+        let allocated = self.vec.capacity() > 0 && std::mem::size_of::<T>() > 0;
+        let region = if allocated {
+            let data = self.allocator().data();
+            Region {
+                offset: (self.vec.as_ptr().addr() - data.as_ptr().addr()) as u64,
+                capacity: self.vec.capacity() as u64,
+                vec: meta,
+            }
+        } else {
+            Region {
+                vec: meta,
+                ..Default::default()
+            }
+        };
+        self.allocator().set_vec_slot(slot, region);
+    }
+
+    /// Whether the elements are kept on drop.
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    /// The allocator of this vector.
+    pub fn allocator(&self) -> &DiskAlloc {
+        self.vec.allocator()
+    }
+
+    /// The underlying vector, for the complete `Vec` API.
+    pub fn as_vec(&self) -> &Vec<T, DiskAlloc> {
+        &self.vec
+    }
+
+    /// The underlying vector, for the complete `Vec` API.
+    pub fn as_mut_vec(&mut self) -> &mut Vec<T, DiskAlloc> {
+        &mut self.vec
+    }
+
+    /// Ask the OS to read the elements in `range` into memory
+    /// in the background, see [`DiskAlloc::prefetch`].
+    ///
+    /// # Panics
+    /// Panics if `range` is out of bounds.
+    pub fn prefetch_range(&self, range: Range<usize>) -> Result<(), std::io::Error> {
+        match self.byte_range(range) {
+            Some((ptr, len)) => self.allocator().prefetch_ptr(ptr, len),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the elements in `range` to disk and drop them
+    /// from memory, see [`DiskAlloc::evict`].
+    ///
+    /// # Panics
+    /// Panics if `range` is out of bounds.
+    pub fn evict_range(&self, range: Range<usize>) -> Result<(), std::io::Error> {
+        match self.byte_range(range) {
+            Some((ptr, len)) => self.allocator().evict_ptr(ptr, len),
+            None => Ok(()),
+        }
+    }
+
+    /// Iterate over slices of `chunk_len` elements (the last one may
+    /// be shorter), like [`slice::chunks`], keeping only about two
+    /// of them in memory.
+    ///
+    /// The next chunk is prefetched while the current one is used,
+    /// see [`DiskVec::prefetch_range`], and chunks already passed
+    /// are dropped from memory, see [`DiskVec::evict_range`].
+    /// Failures of both are ignored, they only cost speed or memory.
+    ///
+    /// ```rust
+    /// use diskallocator::DiskVec;
+    ///
+    /// let mut v = DiskVec::new().unwrap();
+    /// v.extend(0..1_000_000_u64);
+    /// let sum: u64 = v.chunks_streamed(64 * 1024).map(|c| c.iter().sum::<u64>()).sum();
+    /// assert_eq!(sum, 499_999_500_000);
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `chunk_len` is zero.
+    pub fn chunks_streamed(&self, chunk_len: usize) -> ChunksStreamed<'_, T> {
+        assert!(chunk_len > 0, "chunk length must be non-zero");
+        ChunksStreamed {
+            vec: self,
+            chunk_len,
+            next: 0,
+            evicted: 0,
+        }
+    }
+
+    /// Tell the OS that the elements in `range` are not going
+    /// to be used for a while, see [`DiskAlloc::mark_cold`].
+    ///
+    /// # Panics
+    /// Panics if `range` is out of bounds.
+    pub fn mark_cold_range(&self, range: Range<usize>) -> Result<(), std::io::Error> {
+        match self.byte_range(range) {
+            Some((ptr, len)) => self.allocator().mark_cold_ptr(ptr, len),
+            None => Ok(()),
+        }
+    }
+
+    // Start and length in bytes of some elements, if any.
+    fn byte_range(&self, range: Range<usize>) -> Option<(NonNull<u8>, u64)> {
+        let elements = &self[range];
+        if elements.is_empty() {
+            return None;
+        }
+        let ptr = NonNull::from(elements).cast::<u8>();
+        Some((ptr, std::mem::size_of_val(elements) as u64))
+    }
+
+    /// Append an element, growing the file if needed.
+    ///
+    /// # Panics
+    /// Panics if the file can not be grown,
+    /// use [`DiskVec::try_reserve`] to handle that.
+    pub fn push(&mut self, value: T) {
+        self.vec.push(value)
+    }
+
+    /// Remove the last element.
+    pub fn pop(&mut self) -> Option<T> {
+        self.vec.pop()
+    }
+
+    /// Number of elements.
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    /// Whether there are no elements.
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// Number of elements fitting without growing the file.
+    pub fn capacity(&self) -> usize {
+        self.vec.capacity()
+    }
+
+    /// Remove all elements, keeping the capacity.
+    pub fn clear(&mut self) {
+        self.vec.clear()
+    }
+
+    /// Shorten the vector to `len` elements.
+    pub fn truncate(&mut self, len: usize) {
+        self.vec.truncate(len)
+    }
+
+    /// Shrink the file to fit the elements.
+    pub fn shrink_to_fit(&mut self) {
+        self.vec.shrink_to_fit()
+    }
+
+    /// Move the elements to the start of the file and shrink
+    /// the file behind them, see [`DiskAlloc::compact`].
+    /// Returns the number of bytes the file got shorter.
+    ///
+    /// Fails if the allocator is shared with anything else.
+    pub fn compact(&mut self) -> Result<u64, std::io::Error> {
+        if !self.allocator().is_unique() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                "allocator is shared",
+            ));
+        }
+        let before = self.allocator().stats().file_size;
+        if self.vec.capacity() == 0 || std::mem::size_of::<T>() == 0 {
+            // Nothing allocated to move
+            self.allocator().trim()?;
+            return Ok(before - self.allocator().stats().file_size);
+        }
+        let vec = unsafe { ManuallyDrop::take(&mut self.vec) };
+        let (ptr, len, capacity, alloc) = vec.into_raw_parts_with_alloc();
+        let layout = Layout::array::<T>(capacity).unwrap();
+        let ptr = NonNull::new(ptr).unwrap();
+        let moved = unsafe { alloc.compact(ptr.cast(), layout) };
+        // Nothing moved on failure
+        let (ptr, reclaimed) = match moved {
+            Ok((block, reclaimed)) => (block.cast::<T>(), Ok(reclaimed)),
+            Err(err) => (ptr, Err(err)),
+        };
+        let vec = unsafe { Vec::from_raw_parts_in(ptr.as_ptr(), len, capacity, alloc) };
+        self.vec = ManuallyDrop::new(vec);
+        reclaimed
+    }
+
+    /// Reserve space for at least `additional` more elements.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.vec.try_reserve(additional)
+    }
+
+    /// Reserve space for exactly `additional` more elements.
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.vec.try_reserve_exact(additional)
+    }
+}
+
+impl<T> Drop for DiskVec<T> {
+    fn drop(&mut self) {
+        if self.persistent {
+            self.store_meta();
+        }
+        let vec = unsafe { ManuallyDrop::take(&mut self.vec) };
+        if self.persistent {
+            // Only release the allocator, the buffer stays in the file
+            let (_, _, _, alloc) = vec.into_raw_parts_with_alloc();
+            drop(alloc);
+        } else {
+            drop(vec);
+        }
+    }
+}
+
+impl<T> Deref for DiskVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.vec
+    }
+}
+
+impl<T> DerefMut for DiskVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.vec
+    }
+}
+
+impl<T> Extend<T> for DiskVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.vec.extend(iter)
+    }
+}
+
+/// Iterator over chunks of a [`DiskVec`] managing their memory,
+/// see [`DiskVec::chunks_streamed`].
+pub struct ChunksStreamed<'a, T> {
+    vec: &'a DiskVec<T>,
+    chunk_len: usize,
+    // Start of the next chunk
+    next: usize,
+    // Elements before this are dropped from memory
+    evicted: usize,
+}
+
+impl<'a, T> Iterator for ChunksStreamed<'a, T> {
+    type Item = &'a [T];
+
+    fn next(&mut self) -> Option<&'a [T]> {
+        let (vec, len) = (self.vec, self.vec.len());
+        let start = self.next.min(len);
+        // The caller is done with the previous chunk
+        let _ = vec.evict_range(self.evicted..start);
+        self.evicted = start;
+        if start == len {
+            return None;
+        }
+        let end = start.saturating_add(self.chunk_len).min(len);
+        let ahead = end.saturating_add(self.chunk_len).min(len);
+        // The first chunk is needed right away, later ones were already prefetched
+        let _ = vec.prefetch_range(if start == 0 { start } else { end }..ahead);
+        self.next = end;
+        Some(&vec[start..end])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self
+            .vec
+            .len()
+            .saturating_sub(self.next)
+            .div_ceil(self.chunk_len);
+        (left, Some(left))
+    }
+}
+
+impl<T> ExactSizeIterator for ChunksStreamed<'_, T> {}
+
+#[cfg(feature = "rayon")]
+impl<T: Send> DiskVec<T> {
+    /// Append the items of a Rayon parallel iterator, in order.
+    ///
+    /// The worker threads collect their chunks on temporary files
+    /// of a [`ShardedDiskAlloc`](crate::ShardedDiskAlloc), in the
+    /// directory of this vector's allocator if it has one, so they
+    /// do not wait for each other's allocations. The chunks are then
+    /// moved into this vector, which grows only once.
+    ///
+    /// Fails if the temporary files can not be created or this
+    /// vector can not grow, dropping the collected items.
+    ///
+    /// ```rust
+    /// use diskallocator::DiskVec;
+    /// use rayon::prelude::*;
+    ///
+    /// let mut v = DiskVec::new().unwrap();
+    /// v.par_extend_from_iter((0..100_000_u64).into_par_iter().map(|i| i * i))
+    ///     .unwrap();
+    /// assert_eq!(v[99_999], 99_999 * 99_999);
+    /// ```
+    pub fn par_extend_from_iter<I>(&mut self, iter: I) -> Result<(), std::io::Error>
+    where
+        I: rayon::iter::IntoParallelIterator<Item = T>,
+    {
+        use rayon::iter::ParallelIterator;
+        let builder = match self.allocator().dir() {
+            Some(dir) => DiskAllocBuilder::new().dir(dir),
+            None => DiskAllocBuilder::new(),
+        };
+        let shards = crate::ShardedDiskAlloc::with_builder(rayon::current_num_threads(), builder)?;
+        let chunks = iter
+            .into_par_iter()
+            .fold(
+                || Vec::new_in(shards.clone()),
+                |mut chunk, item| {
+                    chunk.push(item);
+                    chunk
+                },
+            )
+            .collect::<Vec<_>>();
+        let len = chunks.iter().map(Vec::len).sum();
+        self.try_reserve(len).map_err(std::io::Error::other)?;
+        for mut chunk in chunks {
+            self.vec.extend(chunk.drain(..));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T> IntoIterator for &'a DiskVec<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut DiskVec<T> {
+    type Item = &'a mut T;
+    type IntoIter = std::slice::IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for DiskVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Serialized as a sequence, element by element,
+/// without copying the vector to memory.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for DiskVec<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for element in self.iter() {
+            seq.serialize_element(element)?;
+        }
+        seq.end()
+    }
+}
+
+/// Deserialized from a sequence into a vector on a new temporary
+/// file (see [`DiskVec::new`]), element by element.
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for DiskVec<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(SeqVisitor(std::marker::PhantomData))
+    }
+}
+
+#[cfg(feature = "serde")]
+struct SeqVisitor<T>(std::marker::PhantomData<T>);
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::de::Visitor<'de> for SeqVisitor<T> {
+    type Value = DiskVec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a sequence")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<DiskVec<T>, A::Error> {
+        use serde::de::Error;
+        let mut vec = DiskVec::new().map_err(A::Error::custom)?;
+        while let Some(element) = seq.next_element()? {
+            // Failing to grow the file is an error, not a panic
+            vec.try_reserve(1).map_err(A::Error::custom)?;
+            vec.push(element);
+        }
+        Ok(vec)
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{DiskAlloc, DiskVec};
+use bytemuck::Pod;
+use std::{fmt, ops::Deref, path::Path, ptr::NonNull};
+
+/// The elements of a vector stored by [`DiskVec::create`],
+/// mapped read-only, see [`DiskVec::open_view`].
+///
+/// ```rust
+/// use diskallocator::DiskVec;
+///
+/// # fn main() -> Result<(), std::io::Error> {
+/// let dir = tempfile::tempdir()?;
+/// let path = dir.path().join("samples");
+/// let mut v = DiskVec::create(&path)?;
+/// v.extend([0.5_f32, 1.5, 2.5]);
+/// drop(v);
+///
+/// let view = DiskVec::<f32>::open_view(&path)?;
+/// assert_eq!(view.iter().sum::<f32>(), 4.5);
+/// # Ok(())
+/// # }
+/// ```
+pub struct DiskView<T> {
+    alloc: DiskAlloc,
+    // The elements, inside the mapping of `alloc`
+    data: NonNull<[T]>,
+}
+
+// Only handing out shared references to the elements
+unsafe impl<T: Sync> Send for DiskView<T> {}
+unsafe impl<T: Sync> Sync for DiskView<T> {}
+
+impl<T: Pod> DiskVec<T> {
+    /// Map the elements of a vector stored by [`DiskVec::create`]
+    /// at `path` read-only, without copying them.
+    ///
+    /// Unlike [`DiskVec::open`], this is safe: every bit pattern
+    /// is a valid value of a [`Pod`] type. Only the size and
+    /// alignment of the elements are checked, not their type name,
+    /// so e.g. `u32` values can be viewed as `f32`.
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidData`] if the file
+    /// holds no vector, or one with elements of another size or
+    /// alignment, and for zero-sized `T`.
+    pub fn open_view<P: AsRef<Path>>(path: P) -> Result<DiskView<T>, std::io::Error> {
+        let alloc = DiskAlloc::open_readonly(path)?;
+        let Some(meta) = alloc.vec_meta() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file holds no DiskVec",
+            ));
+        };
+        let (size, align) = (std::mem::size_of::<T>(), std::mem::align_of::<T>());
+        if (meta.elem_size, meta.elem_align) != (size as u64, align as u64) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "file holds a DiskVec of {} byte elements aligned to {}, not {size} aligned to {align}",
+                    meta.elem_size, meta.elem_align
+                ),
+            ));
+        }
+        let data = alloc.data();
+        let bytes = meta.len.checked_mul(size as u64);
+        if bytes.is_none_or(|bytes| bytes > data.len() as u64) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file holds fewer elements than its header records",
+            ));
+        }
+        let bytes = unsafe { &data.as_ref()[..bytes.unwrap() as usize] };
+        let elements: &[T] = bytemuck::try_cast_slice(bytes)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+        let data = NonNull::from(elements);
+        Ok(DiskView { alloc, data })
+    }
+}
+
+impl<T> DiskView<T> {
+    /// The read-only allocator holding the elements.
+    pub fn allocator(&self) -> &DiskAlloc {
+        &self.alloc
+    }
+}
+
+impl<T> Deref for DiskView<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // Mapped as long as the allocator lives
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for DiskView<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, io};
+
+/// Why creating an allocator or allocating memory failed.
+///
+/// Returned by the constructors of [`crate::DiskAlloc`],
+/// [`crate::DiskAllocBuilder`] and [`crate::DiskVec`]. Allocations can only fail with
+/// an [`std::alloc::AllocError`], the reason is kept until
+/// read with [`crate::DiskAlloc::take_error`].
+///
+/// Converts into a [`std::io::Error`] and back, so it works with `?`
+/// in functions returning I/O errors, like the methods of [`crate::DiskVec`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DiskAllocError {
+    /// No address space could be reserved for the memory map,
+    /// or changing its protection failed.
+    MapFailed {
+        /// The error of the OS.
+        io: io::Error,
+    },
+    /// The file could not be resized,
+    /// e.g. [`io::ErrorKind::StorageFull`] if the disk is full.
+    ResizeFailed {
+        /// The error of the OS.
+        io: io::Error,
+    },
+    /// The allocation does not fit into the reserved address space,
+    /// and no further segment could be added.
+    ReservationExhausted,
+    /// The limit set with [`crate::DiskAllocBuilder::max_file_size`]
+    /// or the [`crate::DiskBudget`] was reached.
+    QuotaExceeded,
+    /// The alignment is bigger than the memory map provides,
+    /// usually the page size.
+    UnsupportedLayout,
+    /// The data of a persistent file does not match its checksum,
+    /// see [`crate::DiskAllocBuilder::checksums`].
+    ChecksumMismatch {
+        /// Start of the damaged 4KiB block, from the start of the file.
+        offset: u64,
+    },
+    /// Any other I/O error, e.g. when opening the file.
+    Io(io::Error),
+}
+
+impl DiskAllocError {
+    /// The kind of the equivalent [`std::io::Error`].
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::MapFailed { io } | Self::ResizeFailed { io } | Self::Io(io) => io.kind(),
+            Self::ReservationExhausted => io::ErrorKind::OutOfMemory,
+            Self::QuotaExceeded => io::ErrorKind::QuotaExceeded,
+            Self::UnsupportedLayout => io::ErrorKind::InvalidInput,
+            Self::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for DiskAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MapFailed { io } => write!(f, "memory map failed: {io}"),
+            Self::ResizeFailed { io } => write!(f, "resizing the file failed: {io}"),
+            Self::ReservationExhausted => f.write_str("reserved address space used up"),
+            Self::QuotaExceeded => f.write_str("maximum file size or disk budget reached"),
+            Self::UnsupportedLayout => f.write_str("alignment not supported by the allocator"),
+            Self::ChecksumMismatch { offset } => {
+                write!(f, "data at offset {offset} does not match its checksum")
+            }
+            Self::Io(io) => io.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DiskAllocError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::MapFailed { io } | Self::ResizeFailed { io } => Some(io),
+            Self::Io(io) => io.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<DiskAllocError> for io::Error {
+    fn from(err: DiskAllocError) -> Self {
+        match err {
+            DiskAllocError::Io(io) => io,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+// Errors are passed around as io::Error inside the crate,
+// so the original DiskAllocError is unpacked again.
+impl From<io::Error> for DiskAllocError {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<Self>()) {
+            return *err.into_inner().unwrap().downcast::<Self>().unwrap();
+        }
+        Self::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn io_error_round_trip() {
+        let io_err = io::Error::from(DiskAllocError::QuotaExceeded);
+        assert_eq!(io_err.kind(), io::ErrorKind::QuotaExceeded);
+        assert!(matches!(
+            DiskAllocError::from(io_err),
+            DiskAllocError::QuotaExceeded
+        ));
+        let io_err = io::Error::from(DiskAllocError::Io(io::ErrorKind::NotFound.into()));
+        assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(
+            DiskAllocError::from(io_err),
+            DiskAllocError::Io(_)
+        ));
+    }
+}
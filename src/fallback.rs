@@ -0,0 +1,176 @@
+use crate::{DiskAlloc, ShardedDiskAlloc};
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    ptr::NonNull,
+};
+
+/// Allocators which can tell whether they allocated a pointer,
+/// needed by the fallback of a [`FallbackAlloc`].
+pub trait Owns {
+    /// Whether `ptr` points into memory of this allocator.
+    fn owns(&self, ptr: NonNull<u8>) -> bool;
+}
+
+impl Owns for DiskAlloc {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        DiskAlloc::owns(self, ptr)
+    }
+}
+
+impl Owns for ShardedDiskAlloc {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.shards().iter().any(|shard| shard.owns(ptr))
+    }
+}
+
+impl<A: Owns, B: Owns> Owns for FallbackAlloc<A, B> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.fallback.owns(ptr) || self.primary.owns(ptr)
+    }
+}
+
+impl<T: Owns + ?Sized> Owns for &T {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        (**self).owns(ptr)
+    }
+}
+
+/// Allocates from `primary`, and from `fallback`
+/// whenever that fails.
+///
+/// Memory growing beyond what the primary can give is moved
+/// to the fallback. Unlike [`crate::SpillAlloc`], which spills
+/// beyond a fixed threshold, the primary decides by failing,
+/// e.g. a heap limited by the OS or a [`DiskAlloc`] with a
+/// [`crate::DiskAllocBuilder::max_file_size`].
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use diskallocator::{DiskAlloc, DiskAllocBuilder, FallbackAlloc};
+///
+/// // A small file first, a big one once it is full
+/// let small = DiskAllocBuilder::new().max_file_size(64 * 1024).build().unwrap();
+/// let alloc = FallbackAlloc::new(small, DiskAlloc::new().unwrap());
+/// let mut v: Vec<u64, _> = Vec::new_in(&alloc);
+/// v.extend(0..100_000);
+/// assert!(alloc.fallback().stats().used_size > 0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct FallbackAlloc<A, B> {
+    primary: A,
+    fallback: B,
+}
+
+impl<A, B: Owns> FallbackAlloc<A, B> {
+    /// Try `primary` first, then `fallback`.
+    pub fn new(primary: A, fallback: B) -> Self {
+        Self { primary, fallback }
+    }
+
+    /// The allocator tried first.
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    /// The allocator used when the primary fails.
+    pub fn fallback(&self) -> &B {
+        &self.fallback
+    }
+
+    /// Both allocators.
+    pub fn into_parts(self) -> (A, B) {
+        (self.primary, self.fallback)
+    }
+}
+
+impl<A: Allocator, B: Allocator + Owns> FallbackAlloc<A, B> {
+    unsafe fn grow_any(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.fallback.owns(ptr) {
+            return if zeroed {
+                self.fallback.grow_zeroed(ptr, old_layout, new_layout)
+            } else {
+                self.fallback.grow(ptr, old_layout, new_layout)
+            };
+        }
+        let grown = if zeroed {
+            self.primary.grow_zeroed(ptr, old_layout, new_layout)
+        } else {
+            self.primary.grow(ptr, old_layout, new_layout)
+        };
+        if grown.is_ok() {
+            return grown;
+        }
+        // Move to the fallback
+        let new_ptr = if zeroed {
+            self.fallback.allocate_zeroed(new_layout)?
+        } else {
+            self.fallback.allocate(new_layout)?
+        };
+        std::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size(),
+        );
+        self.primary.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+unsafe impl<A: Allocator, B: Allocator + Owns> Allocator for FallbackAlloc<A, B> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.primary
+            .allocate(layout)
+            .or_else(|_| self.fallback.allocate(layout))
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.primary
+            .allocate_zeroed(layout)
+            .or_else(|_| self.fallback.allocate_zeroed(layout))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.fallback.owns(ptr) {
+            self.fallback.deallocate(ptr, layout)
+        } else {
+            self.primary.deallocate(ptr, layout)
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_any(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_any(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.fallback.owns(ptr) {
+            self.fallback.shrink(ptr, old_layout, new_layout)
+        } else {
+            self.primary.shrink(ptr, old_layout, new_layout)
+        }
+    }
+}
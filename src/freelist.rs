@@ -0,0 +1,154 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+// Freed intervals of the backing file, keyed by start offset.
+// Adjacent intervals are always merged on insert,
+// so no two intervals touch each other.
+//
+// The starts are binned by size class as well, so taking a small
+// interval does not scan past all the (many) ones too small for it.
+#[derive(Default, Debug)]
+pub(crate) struct FreeList {
+    intervals: BTreeMap<u64, u64>,
+    // Starts of the intervals by size class of their length
+    bins: Vec<BTreeSet<u64>>,
+}
+
+// Size classes like jemalloc: steps of 16 bytes up to 128,
+// then four classes per power of two.
+// Longer intervals never have a smaller class.
+fn size_class(len: u64) -> usize {
+    if len < 128 {
+        return (len / 16) as usize;
+    }
+    let log = len.ilog2();
+    let quarter = (len >> (log - 2)) & 3;
+    (8 + (log - 7) * 4 + quarter as u32) as usize
+}
+
+impl FreeList {
+    fn add(&mut self, start: u64, len: u64) {
+        let class = size_class(len);
+        if self.bins.len() <= class {
+            self.bins.resize_with(class + 1, BTreeSet::new);
+        }
+        self.bins[class].insert(start);
+        self.intervals.insert(start, len);
+    }
+
+    fn remove(&mut self, start: u64) -> Option<u64> {
+        let len = self.intervals.remove(&start)?;
+        self.bins[size_class(len)].remove(&start);
+        Some(len)
+    }
+
+    // Returns the interval after merging with its neighbours.
+    pub fn insert(&mut self, mut start: u64, mut len: u64) -> (u64, u64) {
+        if len == 0 {
+            return (start, len);
+        }
+        if let Some((&prev_start, &prev_len)) = self.intervals.range(..start).next_back() {
+            if prev_start + prev_len == start {
+                self.remove(prev_start);
+                start = prev_start;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.remove(start + len) {
+            len += next_len;
+        }
+        self.add(start, len);
+        (start, len)
+    }
+
+    // First fit in the smallest size class with a fitting interval.
+    // Returns the aligned start offset of the taken interval,
+    // the unused rest is kept in the list.
+    pub fn take(&mut self, len: u64, align: u64) -> Option<u64> {
+        let intervals = &self.intervals;
+        let (start, free_len, aligned) = self
+            .bins
+            .iter()
+            .skip(size_class(len))
+            .flatten()
+            .find_map(|&start| {
+                let free_len = intervals[&start];
+                let aligned = start.next_multiple_of(align);
+                (aligned + len <= start + free_len).then_some((start, free_len, aligned))
+            })?;
+        self.remove(start);
+        self.insert(start, aligned - start);
+        self.insert(aligned + len, start + free_len - aligned - len);
+        Some(aligned)
+    }
+
+    // Take `len` bytes from the interval starting exactly at `start`,
+    // used to grow an allocation in place.
+    pub fn take_at(&mut self, start: u64, len: u64) -> bool {
+        match self.intervals.get(&start) {
+            Some(&free_len) if free_len >= len => {
+                self.remove(start);
+                self.insert(start + len, free_len - len);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Remove the interval ending at `end` (the end of the used file),
+    // returning its start.
+    pub fn pop_tail(&mut self, end: u64) -> Option<u64> {
+        let (&start, &len) = self.intervals.iter().next_back()?;
+        if start + len != end {
+            return None;
+        }
+        self.remove(start);
+        Some(start)
+    }
+
+    // Start and length of every interval.
+    pub fn intervals(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.intervals.iter().map(|(&start, &len)| (start, len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge_and_take() {
+        let mut list = FreeList::default();
+        list.insert(0, 16);
+        list.insert(32, 16);
+        assert_eq!(list.insert(16, 16), (0, 48));
+        assert_eq!(list.intervals.len(), 1);
+
+        assert_eq!(list.take(8, 16), Some(0));
+        assert_eq!(list.take(16, 16), Some(16));
+        assert_eq!(list.take(32, 1), None);
+        assert!(list.take_at(8, 8));
+        assert_eq!(list.pop_tail(48), Some(32));
+        assert!(list.intervals.is_empty());
+    }
+
+    #[test]
+    fn take_by_size_class() {
+        assert_eq!(size_class(0), 0);
+        assert_eq!(size_class(127), 7);
+        assert_eq!(size_class(128), 8);
+        assert_eq!(size_class(160), 9);
+        assert_eq!(size_class(256), 12);
+        let mut list = FreeList::default();
+        list.insert(0, 4096);
+        for start in (8192..100_000).step_by(64) {
+            list.insert(start, 32);
+        }
+        // The small intervals are found before the big one
+        assert_eq!(list.take(24, 8), Some(8192));
+        assert_eq!(list.take(1000, 8), Some(0));
+        // The rest of the big one moved to a smaller class
+        assert_eq!(list.take(3000, 8), Some(1000));
+        assert_eq!(list.take(100, 1), None);
+        assert!(list.bins[size_class(96)].contains(&4000));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::{DiskAlloc, DiskAllocBuilder, Stats};
+use std::{
+    alloc::{Allocator, GlobalAlloc, Layout, System},
+    cell::Cell,
+    ops::Range,
+    ptr::NonNull,
+    sync::OnceLock,
+};
+
+/// Global allocator putting all heap memory of a binary on disk,
+/// available with the `global-alloc` feature.
+///
+/// ```rust,ignore
+/// use diskallocator::DiskGlobalAlloc;
+///
+/// #[global_allocator]
+/// static GLOBAL: DiskGlobalAlloc = DiskGlobalAlloc::new();
+/// ```
+///
+/// The [`DiskAlloc`] is created with the first allocation.
+/// Memory it needs for itself (and everything allocated
+/// if it can not be created) comes from the [`System`] allocator.
+/// The allocator uses a single file and does not chain segments,
+/// allocations beyond the reservation fail.
+pub struct DiskGlobalAlloc {
+    reservation: u64,
+    // None if the allocator could not be created
+    disk: OnceLock<Option<(DiskAlloc, Range<usize>)>>,
+}
+
+thread_local! {
+    // Set while the disk allocator runs on this thread
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Default for DiskGlobalAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskGlobalAlloc {
+    /// Reserve the default 512GiB of address space
+    /// (1GiB on 32-bit targets),
+    /// see [`DiskAlloc::new`].
+    pub const fn new() -> Self {
+        Self::with_reservation(crate::diskalloc::STORAGE)
+    }
+
+    /// Reserve `bytes` of address space,
+    /// see [`DiskAlloc::with_reservation`].
+    pub const fn with_reservation(bytes: u64) -> Self {
+        Self {
+            reservation: bytes,
+            disk: OnceLock::new(),
+        }
+    }
+
+    /// Usage statistics of the disk allocator,
+    /// `None` if it was not created (yet).
+    pub fn stats(&self) -> Option<Stats> {
+        self.disk
+            .get()?
+            .as_ref()
+            .map(|(disk, _)| run_busy(|| disk.stats()))
+    }
+
+    // The disk allocator, unless called from within it.
+    fn disk(&self) -> Option<&DiskAlloc> {
+        // Also busy while thread locals are destroyed
+        if BUSY.try_with(|busy| busy.get()).unwrap_or(true) {
+            return None;
+        }
+        let disk = self.disk.get_or_init(|| {
+            run_busy(|| {
+                let disk = DiskAllocBuilder::new()
+                    .reservation(self.reservation)
+                    .chain_segments(false)
+                    .build()
+                    .ok()?;
+                let range = disk.address_range();
+                Some((disk, range))
+            })
+        });
+        disk.as_ref().map(|(disk, _)| disk)
+    }
+
+    // The disk allocator, if it allocated `ptr`.
+    fn owner(&self, ptr: *mut u8) -> Option<&DiskAlloc> {
+        let (disk, range) = self.disk.get()?.as_ref()?;
+        range.contains(&ptr.addr()).then_some(disk)
+    }
+}
+
+fn run_busy<T>(f: impl FnOnce() -> T) -> T {
+    let was_busy = BUSY.try_with(|busy| busy.replace(true));
+    let result = f();
+    if let Ok(was_busy) = was_busy {
+        BUSY.with(|busy| busy.set(was_busy));
+    }
+    result
+}
+
+fn thin(ptr: Result<NonNull<[u8]>, std::alloc::AllocError>) -> *mut u8 {
+    ptr.map_or(std::ptr::null_mut(), |ptr| ptr.cast::<u8>().as_ptr())
+}
+
+unsafe impl GlobalAlloc for DiskGlobalAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.disk() {
+            Some(disk) => thin(run_busy(|| disk.allocate(layout))),
+            None => System.alloc(layout),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match self.disk() {
+            Some(disk) => thin(run_busy(|| disk.allocate_zeroed(layout))),
+            None => System.alloc_zeroed(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Memory of the disk allocator itself is never in its range
+        match self.owner(ptr) {
+            Some(disk) => run_busy(|| disk.deallocate(NonNull::new_unchecked(ptr), layout)),
+            None => System.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Some(disk) = self.owner(ptr) else {
+            return System.realloc(ptr, layout, new_size);
+        };
+        let ptr = NonNull::new_unchecked(ptr);
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        thin(run_busy(|| {
+            if new_size >= layout.size() {
+                disk.grow(ptr, layout, new_layout)
+            } else {
+                disk.shrink(ptr, layout, new_layout)
+            }
+        }))
+    }
+}
@@ -0,0 +1,363 @@
+// On-disk header of persistent allocators.
+// It occupies the first page of the file, allocations start after it.
+// The used size is written atomically, so another process
+// mapping the file can follow a single writer.
+// A DiskVec also records its element type and length,
+// the length is only updated when it is dropped or flushed.
+//
+// The layout is described in the README. Fields are only ever
+// added to the end, zero in files of older versions.
+// Readers reject newer versions and unknown flags, as flags
+// mark data older readers would misinterpret.
+// The version tells which fields exist, a zero field is no
+// substitute: version 2 added the vector fields, version 3
+// the journal and the slot table, which are ignored in older
+// files. Those are upgraded when opened for writing,
+// clearing the fields they did not have yet.
+//
+// The used size in the header is written whenever it changes,
+// so after a crash it may point past the end of the file,
+// if the OS wrote the header back but not the new file length.
+// Every flush therefore records the state it wrote to disk
+// in one of two checkpoints, taking turns, so a torn write
+// only destroys one of them. Opening such a file rolls back
+// to the newest checkpoint which fits into the file.
+
+use std::{
+    io::{Error, ErrorKind},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub(crate) const MAGIC: [u8; 8] = *b"DISKALOC";
+pub(crate) const VERSION: u32 = 3;
+// First version with the journal and the slot table
+pub(crate) const JOURNAL_VERSION: u32 = 3;
+pub(crate) const HEADER_LEN: u64 = 4096;
+// Vectors in the slot table
+pub(crate) const VEC_SLOTS: usize = 16;
+
+// The vector metadata is valid
+pub(crate) const FLAG_VEC: u32 = 1;
+// Freed space is never reused, see DiskAllocBuilder::append_only
+pub(crate) const FLAG_APPEND_ONLY: u32 = 2;
+const KNOWN_FLAGS: u32 = FLAG_VEC | FLAG_APPEND_ONLY;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Header {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub flags: u32,
+    // End of the last allocation, including the header itself
+    pub used: u64,
+    // Written by DiskVec, all zero for other files
+    pub vec: VecMeta,
+    // Last two states written to disk completely
+    pub journal: [Checkpoint; 2],
+    // Further vectors sharing the file, see DiskVec::create_in
+    pub slots: [Region; VEC_SLOTS],
+}
+
+// Where a vector in the slot table is, from the end of the header.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Region {
+    pub offset: u64,
+    // In elements
+    pub capacity: u64,
+    // Zero alignment for empty slots
+    pub vec: VecMeta,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    // Zero for unused slots
+    pub seq: u64,
+    pub used: u64,
+    pub vec_len: u64,
+    pub checksum: u64,
+}
+
+impl Checkpoint {
+    fn new(seq: u64, used: u64, vec_len: u64) -> Self {
+        Self {
+            seq,
+            used,
+            vec_len,
+            checksum: Self::checksum(seq, used, vec_len),
+        }
+    }
+
+    fn checksum(seq: u64, used: u64, vec_len: u64) -> u64 {
+        fnv1a([seq, used, vec_len].iter().flat_map(|n| n.to_ne_bytes()))
+    }
+
+    fn is_valid(&self) -> bool {
+        self.seq != 0 && self.checksum == Self::checksum(self.seq, self.used, self.vec_len)
+    }
+}
+
+// What a DiskVec stored in the file, checked when it is opened again.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct VecMeta {
+    pub elem_size: u64,
+    pub elem_align: u64,
+    pub len: u64,
+    pub type_hash: u64,
+}
+
+impl VecMeta {
+    pub fn new<T>(len: usize) -> Self {
+        Self {
+            elem_size: std::mem::size_of::<T>() as u64,
+            elem_align: std::mem::align_of::<T>() as u64,
+            len: len as u64,
+            type_hash: type_hash::<T>(),
+        }
+    }
+
+    // Whether the same type was stored, ignoring the length.
+    pub fn same_type(&self, other: &Self) -> bool {
+        (self.elem_size, self.elem_align, self.type_hash)
+            == (other.elem_size, other.elem_align, other.type_hash)
+    }
+}
+
+// Hash of the type name, which (unlike TypeId)
+// stays the same between builds.
+fn type_hash<T>() -> u64 {
+    fnv1a(std::any::type_name::<T>().bytes())
+}
+
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Header {
+    pub fn new(used: u64) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            flags: 0,
+            used,
+            vec: VecMeta::default(),
+            journal: Default::default(),
+            slots: Default::default(),
+        }
+    }
+
+    // Record that everything up to `used` is on disk,
+    // in the slot of the older checkpoint.
+    pub unsafe fn commit(header: *mut Header, used: u64) {
+        let current = header.read();
+        let seq = current.journal.iter().map(|entry| entry.seq).max().unwrap() + 1;
+        let entry = Checkpoint::new(seq, used, current.vec.len);
+        std::ptr::addr_of_mut!((*header).journal[(seq % 2) as usize]).write(entry);
+    }
+
+    // Roll back to the newest checkpoint if the used size
+    // does not fit into the file. Returns whether it did.
+    pub fn recover(&mut self, file_len: u64) -> bool {
+        if self.version < JOURNAL_VERSION || (HEADER_LEN..=file_len).contains(&self.used) {
+            return false;
+        }
+        let newest = self
+            .journal
+            .iter()
+            .filter(|entry| entry.is_valid() && (HEADER_LEN..=file_len).contains(&entry.used))
+            .max_by_key(|entry| entry.seq);
+        let Some(entry) = newest.copied() else {
+            return false;
+        };
+        self.used = entry.used;
+        self.vec.len = entry.vec_len;
+        true
+    }
+
+    // Bring a header of an older version to the current one.
+    pub fn upgrade(&mut self) {
+        if self.version < JOURNAL_VERSION {
+            self.journal = Default::default();
+            self.slots = Default::default();
+        }
+        self.version = VERSION;
+    }
+
+    // Update the used size in a mapped header.
+    pub unsafe fn store_used(header: *mut Header, used: u64) {
+        let used_ptr = std::ptr::addr_of_mut!((*header).used);
+        AtomicU64::from_ptr(used_ptr).store(used, Ordering::Release);
+    }
+
+    // Read the used size from a mapped header,
+    // while another process might update it.
+    pub unsafe fn load_used(header: *mut Header) -> u64 {
+        let used_ptr = std::ptr::addr_of_mut!((*header).used);
+        AtomicU64::from_ptr(used_ptr).load(Ordering::Acquire)
+    }
+
+    // Read a mapped header another process might be writing to,
+    // again until two reads agree, to not get half-written fields.
+    pub unsafe fn read_shared(header: *mut Header) -> Self {
+        loop {
+            let first = header.read_volatile();
+            let second = header.read_volatile();
+            // Whatever was written before the fields is visible
+            std::sync::atomic::fence(Ordering::Acquire);
+            if first.as_bytes() == second.as_bytes() {
+                return second;
+            }
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        // All fields are 8 byte aligned, there is no padding
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+
+    pub fn validate(&self, file_len: u64) -> Result<(), Error> {
+        self.validate_format()?;
+        if self.used < HEADER_LEN || self.used > file_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "diskallocator header points past the end of the file",
+            ));
+        }
+        Ok(())
+    }
+
+    // Magic, version and flags, everything needed to trust the journal.
+    pub fn validate_format(&self) -> Result<(), Error> {
+        if self.magic != MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "not a diskallocator file (bad magic)",
+            ));
+        }
+        if self.version > VERSION && (1..=VERSION).contains(&self.version.swap_bytes()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "diskallocator file written on a machine of another byte order",
+            ));
+        }
+        if self.version > VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "diskallocator file version {} is newer than the supported version {VERSION}",
+                    self.version
+                ),
+            ));
+        }
+        if self.version == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "invalid diskallocator file version 0",
+            ));
+        }
+        if self.flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "diskallocator file uses unknown flags {:#x}",
+                    self.flags & !KNOWN_FLAGS
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_version_and_flags() {
+        let file_len = 2 * HEADER_LEN;
+        assert!(Header::new(HEADER_LEN).validate(file_len).is_ok());
+        let old = Header {
+            version: 1,
+            ..Header::new(HEADER_LEN)
+        };
+        assert!(old.validate(file_len).is_ok());
+        let newer = Header {
+            version: VERSION + 1,
+            ..Header::new(HEADER_LEN)
+        };
+        assert!(newer
+            .validate(file_len)
+            .unwrap_err()
+            .to_string()
+            .contains("newer"));
+        let swapped = Header {
+            version: VERSION.swap_bytes(),
+            ..Header::new(HEADER_LEN)
+        };
+        assert!(swapped
+            .validate(file_len)
+            .unwrap_err()
+            .to_string()
+            .contains("byte order"));
+        let flagged = Header {
+            flags: FLAG_VEC | 1 << 31,
+            ..Header::new(HEADER_LEN)
+        };
+        assert!(flagged
+            .validate(file_len)
+            .unwrap_err()
+            .to_string()
+            .contains("0x80000000"));
+    }
+
+    #[test]
+    fn recover_from_checkpoint() {
+        let mut header = Header::new(HEADER_LEN);
+        let ptr = &mut header as *mut Header;
+        unsafe {
+            Header::commit(ptr, 5000);
+            Header::commit(ptr, 6000);
+            Header::commit(ptr, 9000);
+        }
+        assert_eq!(header.journal[0].used, 6000);
+        assert_eq!(header.journal[1].used, 9000);
+        header.used = 20_000;
+        let mut fits = header;
+        assert!(!fits.recover(20_000));
+        let mut newest = header;
+        assert!(newest.recover(10_000));
+        assert_eq!(newest.used, 9000);
+        let mut older = header;
+        assert!(older.recover(8192));
+        assert_eq!(older.used, 6000);
+        // A torn write is ignored
+        header.journal[1].used = 7000;
+        assert!(header.recover(10_000));
+        assert_eq!(header.used, 6000);
+        assert!(!Header::new(20_000).recover(8192));
+    }
+
+    #[test]
+    fn journal_of_older_versions_ignored() {
+        let mut header = Header::new(HEADER_LEN);
+        unsafe { Header::commit(&mut header, 6000) };
+        header.slots[3].offset = 100;
+        header.used = 20_000;
+        header.version = 2;
+        assert!(!header.recover(10_000));
+        header.upgrade();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.journal, [Checkpoint::default(); 2]);
+        assert_eq!(header.slots[3], Region::default());
+        assert_eq!(header.used, 20_000);
+    }
+}
@@ -0,0 +1,318 @@
+// Memory filled by a callback on first touch, with userfaultfd(2).
+// A thread reads the page faults of the region and answers each
+// with UFFDIO_COPY of a page the provider filled.
+//
+// Missing pages can only be reported for anonymous and shared
+// memory, not for mappings of regular files, so the region is
+// a shared mapping of a memory file.
+
+use crate::sys;
+use std::{
+    fs::File,
+    io::Error,
+    ops::{Deref, DerefMut},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    thread::JoinHandle,
+};
+
+// From linux/userfaultfd.h, requests use the generic ioctl
+// encoding of _IOWR(0xAA, nr, size)
+const UFFD_API: u64 = 0xAA;
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFDIO_API: u64 = iowr(0x3F, std::mem::size_of::<UffdioApi>());
+const UFFDIO_REGISTER: u64 = iowr(0x00, std::mem::size_of::<UffdioRegister>());
+const UFFDIO_COPY: u64 = iowr(0x03, std::mem::size_of::<UffdioCopy>());
+
+const fn iowr(nr: u64, size: usize) -> u64 {
+    (3 << 30) | ((size as u64) << 16) | (UFFD_API << 8) | nr
+}
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    start: u64,
+    len: u64,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+// uffd_msg, with the page fault member of its union
+#[repr(C)]
+struct UffdMsg {
+    event: u8,
+    _reserved: [u8; 7],
+    flags: u64,
+    address: u64,
+    _feat: u64,
+}
+
+/// Memory of which every page is filled by a callback the first
+/// time it is touched, e.g. to decompress or download data only
+/// where it is actually used (Linux only, `userfaultfd` feature).
+///
+/// The provider gets the offset of a page and the zeroed page to
+/// fill. Pages are filled once and then stay, in a memory file
+/// which can be swapped out, as the kernel can only report missing
+/// pages of memory, not of files on disk. Reads and writes of any
+/// thread wait while their page is being filled.
+///
+/// For data of object stores, with changes written back,
+/// see `RemotePages` (`object-store` feature).
+///
+/// Needs `userfaultfd(2)`: either the `vm.unprivileged_userfaultfd`
+/// sysctl, `CAP_SYS_PTRACE`, or Linux 5.11 or newer, where only
+/// faults of user code are caught. There, system calls touching
+/// missing pages (e.g. `write` from the region) fail with `EFAULT`.
+///
+/// ```rust
+/// use diskallocator::LazyPages;
+///
+/// // Pages of a terabyte, only those touched are filled
+/// let Ok(pages) = LazyPages::new(1 << 40, |offset, page| {
+///     page.fill(if offset == 0 { 1 } else { 2 });
+/// }) else {
+///     return; // userfaultfd is not allowed here
+/// };
+/// assert_eq!((pages[0], pages[1 << 30]), (1, 2));
+/// ```
+pub struct LazyPages {
+    ptr: *mut u8,
+    len: usize,
+    // Signalled on drop, stopping the handler thread
+    stop: OwnedFd,
+    handler: Option<JoinHandle<()>>,
+    memory: File,
+}
+
+// SAFETY: the mapping is owned by this struct, the handler
+// thread only fills pages nobody can access yet.
+unsafe impl Send for LazyPages {}
+unsafe impl Sync for LazyPages {}
+
+impl LazyPages {
+    /// Reserve `len` bytes (rounded up to pages) to be filled
+    /// by `provider`, which is called on a thread of its own.
+    ///
+    /// A panicking provider aborts the process,
+    /// the thread waiting for the page could not continue otherwise.
+    pub fn new<F>(len: usize, provider: F) -> Result<Self, Error>
+    where
+        F: FnMut(u64, &mut [u8]) + Send + 'static,
+    {
+        let page = sys::page_size() as usize;
+        let len = len.max(1).next_multiple_of(page);
+        let memory = sys::memory_file()?.ok_or_else(|| {
+            Error::new(
+                std::io::ErrorKind::Unsupported,
+                "memfd_create is not available",
+            )
+        })?;
+        memory.set_len(len as u64)?;
+        let uffd = open_userfaultfd()?;
+        let stop = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if stop < 0 {
+            return Err(Error::last_os_error());
+        }
+        let stop = unsafe { OwnedFd::from_raw_fd(stop) };
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                memory.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        let mut pages = Self {
+            ptr: ptr.cast(),
+            len,
+            stop,
+            handler: None,
+            memory,
+        };
+        let mut register = UffdioRegister {
+            start: ptr.addr() as u64,
+            len: len as u64,
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        if unsafe { libc::ioctl(uffd.as_raw_fd(), UFFDIO_REGISTER as _, &mut register) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let stop = pages.stop.try_clone()?;
+        let base = ptr.addr();
+        pages.handler = Some(
+            std::thread::Builder::new()
+                .name("diskalloc-lazy".into())
+                .spawn(move || handle_faults(uffd, stop, base, page, provider))?,
+        );
+        Ok(pages)
+    }
+
+    /// Length in bytes, a multiple of pages.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Always false, there is at least one page.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    // Drop the pages from `offset` to `offset + len`, rounded out to
+    // whole pages. The provider fills them again on the next touch.
+    #[cfg(feature = "object-store")]
+    pub(crate) fn discard(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let (offset, len) = sys::page_range(offset, len);
+        discard_pages(&self.memory, offset, len.min(self.len as u64 - offset))
+    }
+
+    // The memory file, to discard pages from the provider.
+    #[cfg(feature = "object-store")]
+    pub(crate) fn memory(&self) -> &File {
+        &self.memory
+    }
+}
+
+// Punch whole pages out of the memory file of LazyPages.
+#[cfg(feature = "object-store")]
+pub(crate) fn discard_pages(memory: &File, offset: u64, len: u64) -> Result<(), Error> {
+    let ret = unsafe {
+        libc::fallocate(
+            memory.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn open_userfaultfd() -> Result<OwnedFd, Error> {
+    let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+    let mut fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+    if fd < 0 {
+        // Allowed to unprivileged users where the sysctl is off
+        fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags | UFFD_USER_MODE_ONLY) };
+    }
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let uffd = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+    let mut api = UffdioApi {
+        api: UFFD_API,
+        features: 0,
+        ioctls: 0,
+    };
+    if unsafe { libc::ioctl(uffd.as_raw_fd(), UFFDIO_API as _, &mut api) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(uffd)
+}
+
+fn handle_faults<F>(uffd: OwnedFd, stop: OwnedFd, base: usize, page: usize, mut provider: F)
+where
+    F: FnMut(u64, &mut [u8]),
+{
+    let mut buf = vec![0; page];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: uffd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            if Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            // Faulting threads would wait forever
+            std::process::abort();
+        }
+        if fds[1].revents != 0 {
+            return;
+        }
+        let mut msg = std::mem::MaybeUninit::<UffdMsg>::uninit();
+        let size = std::mem::size_of::<UffdMsg>();
+        let read = unsafe { libc::read(uffd.as_raw_fd(), msg.as_mut_ptr().cast(), size) };
+        // EAGAIN: another wakeup took the message already
+        if read != size as isize {
+            continue;
+        }
+        let msg = unsafe { msg.assume_init() };
+        if msg.event != UFFD_EVENT_PAGEFAULT {
+            continue;
+        }
+        let addr = msg.address as usize / page * page;
+        buf.fill(0);
+        let filled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            provider((addr - base) as u64, &mut buf)
+        }));
+        if filled.is_err() {
+            std::process::abort();
+        }
+        let mut copy = UffdioCopy {
+            dst: addr as u64,
+            src: buf.as_ptr().addr() as u64,
+            len: page as u64,
+            mode: 0,
+            copy: 0,
+        };
+        // EEXIST if the page was filled meanwhile, nothing to do then
+        unsafe { libc::ioctl(uffd.as_raw_fd(), UFFDIO_COPY as _, &mut copy) };
+    }
+}
+
+impl Deref for LazyPages {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for LazyPages {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for LazyPages {
+    fn drop(&mut self) {
+        unsafe { libc::eventfd_write(self.stop.as_raw_fd(), 1) };
+        if let Some(handler) = self.handler.take() {
+            let _ = handler.join();
+        }
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
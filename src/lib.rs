@@ -1,6 +1,55 @@
 #![warn(missing_docs)]
 #![doc = include_str!("../README.md")]
+#![feature(strict_provenance_lints)]
+#![warn(fuzzy_provenance_casts, lossy_provenance_casts)]
 #![feature(allocator_api)]
+#![feature(cfg_sanitize)]
+#![feature(thread_id_value)]
+mod arena;
+mod builder;
 mod diskalloc;
+mod diskbox;
+mod diskbuf;
+mod diskcursor;
+mod diskvec;
+#[cfg(feature = "bytemuck")]
+mod diskview;
+mod error;
+mod fallback;
+mod freelist;
+#[cfg(feature = "global-alloc")]
+mod global;
+mod header;
+#[cfg(all(feature = "userfaultfd", target_os = "linux"))]
+mod lazy;
+#[cfg(all(feature = "object-store", target_os = "linux"))]
+mod remote;
+mod sharded;
+mod slab;
+mod spill;
+mod stats;
+mod sys;
 
-pub use diskalloc::DiskAlloc;
+pub use arena::DiskArena;
+pub use builder::{DiskAllocBuilder, DropBehavior, ShrinkPolicy, Strategy};
+pub use diskalloc::{
+    registry, AccessPattern, AllocatorInfo, DiskAlloc, DiskBudget, MemoryPressure,
+};
+pub use diskbox::DiskBox;
+pub use diskbuf::DiskBuf;
+pub use diskcursor::DiskCursor;
+pub use diskvec::{ChunksStreamed, DiskVec};
+#[cfg(feature = "bytemuck")]
+pub use diskview::DiskView;
+pub use error::DiskAllocError;
+pub use fallback::{FallbackAlloc, Owns};
+#[cfg(feature = "global-alloc")]
+pub use global::DiskGlobalAlloc;
+#[cfg(all(feature = "userfaultfd", target_os = "linux"))]
+pub use lazy::LazyPages;
+#[cfg(all(feature = "object-store", target_os = "linux"))]
+pub use remote::{ObjectStore, RemotePages};
+pub use sharded::ShardedDiskAlloc;
+pub use slab::DiskSlab;
+pub use spill::SpillAlloc;
+pub use stats::{SizeHistogram, Stats};
@@ -0,0 +1,474 @@
+// Chunks of an object store, paged in with LazyPages.
+// Page faults are answered from a local cache file holding a fixed
+// number of chunks, fetching missing ones from the store in place
+// of the oldest. The application marks what it writes, like with
+// DiskAlloc::mark_dirty, and those chunks are put back on flush,
+// and written to the cache too, so it never holds stale chunks.
+//
+// The handler thread counts the pages it fills, and once there are
+// more than the budget, punches the chunks faulted in first out of
+// the memory file, skipping those marked or being flushed.
+//
+// The handler thread locks the cache, the resident pages and the
+// marked chunks (in that order) while it fills a page, so nothing
+// may touch the pages while holding one of those locks.
+
+use crate::{diskalloc::dirty::DirtyPages, lazy, sys, LazyPages};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs::File,
+    io::{Error, ErrorKind},
+    ops::{Deref, DerefMut},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+// A page fault can not fail, so fetching its chunk is retried
+const FETCH_ATTEMPTS: u32 = 3;
+
+/// Where [`RemotePages`] gets its chunks from and puts them back,
+/// e.g. an S3 or GCS bucket with one object per chunk
+/// (Linux only, `object-store` feature).
+///
+/// Called on threads of the pages, blocking clients are fine.
+pub trait ObjectStore: Send + Sync + 'static {
+    /// Fill the zeroed `buf` with the chunk at `index`.
+    /// Chunks which were never put can be left as zeros.
+    fn get_chunk(&self, index: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Store `data` as the chunk at `index`, replacing it.
+    fn put_chunk(&self, index: u64, data: &[u8]) -> Result<(), Error>;
+}
+
+impl<S: ObjectStore + ?Sized> ObjectStore for Arc<S> {
+    fn get_chunk(&self, index: u64, buf: &mut [u8]) -> Result<(), Error> {
+        (**self).get_chunk(index, buf)
+    }
+
+    fn put_chunk(&self, index: u64, data: &[u8]) -> Result<(), Error> {
+        (**self).put_chunk(index, data)
+    }
+}
+
+/// Memory paged in from an [`ObjectStore`] in chunks, keeping
+/// the recently fetched ones in a local cache file of fixed size,
+/// so the data can be bigger than the local disk
+/// (Linux only, `object-store` feature).
+///
+/// This is a byte array, not an allocator: it is not connected to
+/// [`crate::DiskAlloc`], data is placed in it by offset.
+///
+/// Pages are filled like those of [`LazyPages`]. Once more than
+/// [`RemotePages::set_max_resident`] bytes are in memory, the chunks
+/// paged in first are dropped again, and paged in from the cache
+/// or the store when touched, like with [`RemotePages::evict`].
+/// Writes have to be marked with [`RemotePages::mark_dirty`]
+/// *before* they are made, which keeps the chunks in memory until
+/// [`RemotePages::flush`] puts them back. Changes not marked are
+/// lost whenever their pages are dropped.
+///
+/// A page fault can not report errors, so fetching its chunk is
+/// retried, and the process aborts if the store keeps failing.
+/// [`RemotePages::prefetch`] fetches chunks ahead, returning errors.
+///
+/// ```rust
+/// use diskallocator::{ObjectStore, RemotePages};
+/// use std::{collections::HashMap, io::Error, sync::Mutex};
+///
+/// // Stand-in for a bucket, with one object per chunk
+/// #[derive(Default)]
+/// struct Bucket(Mutex<HashMap<u64, Vec<u8>>>);
+///
+/// impl ObjectStore for Bucket {
+///     fn get_chunk(&self, index: u64, buf: &mut [u8]) -> Result<(), Error> {
+///         if let Some(object) = self.0.lock().unwrap().get(&index) {
+///             buf.copy_from_slice(object);
+///         }
+///         Ok(())
+///     }
+///
+///     fn put_chunk(&self, index: u64, data: &[u8]) -> Result<(), Error> {
+///         self.0.lock().unwrap().insert(index, data.to_vec());
+///         Ok(())
+///     }
+/// }
+///
+/// // A terabyte in chunks of 4MiB, caching 64MiB of them
+/// let cache_dir = std::env::temp_dir();
+/// let Ok(mut pages) =
+///     RemotePages::new(Bucket::default(), 1 << 40, 4 << 20, &cache_dir, 64 << 20)
+/// else {
+///     return; // userfaultfd is not allowed here
+/// };
+/// pages.mark_dirty(5 << 30, 1).unwrap();
+/// pages[5 << 30] = 1;
+/// pages.flush().unwrap();
+/// ```
+pub struct RemotePages {
+    // Dropped first, stopping the handler thread
+    pages: LazyPages,
+    store: Arc<dyn ObjectStore>,
+    cache: Arc<ChunkCache>,
+    resident: Arc<Mutex<Resident>>,
+    written: Arc<Mutex<Written>>,
+    // One flush at a time, see Written::flushing
+    flush: Mutex<()>,
+}
+
+impl RemotePages {
+    /// `len` bytes (rounded up to chunks) of the chunks of `store`,
+    /// each `chunk_size` bytes, a multiple of pages. At most
+    /// `cache_size` bytes of chunks (at least one) are cached
+    /// in an unnamed temporary file in `cache_dir`, and
+    /// kept in memory, see [`RemotePages::set_max_resident`].
+    pub fn new<S: ObjectStore>(
+        store: S,
+        len: usize,
+        chunk_size: usize,
+        cache_dir: &Path,
+        cache_size: u64,
+    ) -> Result<Self, Error> {
+        let page = sys::page_size() as usize;
+        if chunk_size == 0 || !chunk_size.is_multiple_of(page) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("chunk size {chunk_size} is not a multiple of {page} byte pages"),
+            ));
+        }
+        let store: Arc<dyn ObjectStore> = Arc::new(store);
+        let cache = Arc::new(ChunkCache {
+            file: sys::tempfile_in(cache_dir)?,
+            chunk: chunk_size as u64,
+            store: store.clone(),
+            slots: Mutex::new(Slots {
+                capacity: (cache_size / chunk_size as u64).max(1),
+                ..Default::default()
+            }),
+        });
+        let resident = Arc::new(Mutex::new(Resident {
+            max_pages: 0,
+            page: page as u64,
+            chunk: chunk_size as u64,
+            pages: BTreeSet::new(),
+            order: VecDeque::new(),
+        }));
+        let written = Arc::new(Mutex::new(Written::default()));
+        // Set before anyone can touch the pages
+        let memory = Arc::new(OnceLock::<File>::new());
+        let (fill, filled, marked, punch) = (
+            cache.clone(),
+            resident.clone(),
+            written.clone(),
+            memory.clone(),
+        );
+        let provider = move |offset: u64, page: &mut [u8]| {
+            let mut attempt = 1;
+            while let Err(err) = fill.read(offset, page) {
+                assert!(
+                    attempt < FETCH_ATTEMPTS,
+                    "RemotePages: fetching the chunk of offset {offset} failed: {err}"
+                );
+                std::thread::sleep(Duration::from_millis(100) * attempt);
+                attempt += 1;
+            }
+            let mut resident = filled.lock().unwrap();
+            resident.insert(offset);
+            // Nothing to report to, the pages just stay then
+            let _ = resident.trim(offset, &marked.lock().unwrap(), punch.get().unwrap());
+        };
+        let len = len.max(1).next_multiple_of(chunk_size);
+        let pages = LazyPages::new(len, provider)?;
+        let _ = memory.set(pages.memory().try_clone()?);
+        let pages = Self {
+            pages,
+            store,
+            cache,
+            resident,
+            written,
+            flush: Mutex::new(()),
+        };
+        pages.set_max_resident(cache_size);
+        Ok(pages)
+    }
+
+    /// Length in bytes, a multiple of chunks.
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Always false, there is at least one chunk.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Bytes of every chunk.
+    pub fn chunk_size(&self) -> usize {
+        self.cache.chunk as usize
+    }
+
+    /// Drop the chunks paged in first from memory once more than
+    /// `bytes` (rounded down to pages, at least one chunk) are in
+    /// memory. Defaults to the cache size of [`RemotePages::new`].
+    ///
+    /// Chunks with marked writes stay until flushed,
+    /// and may take more memory.
+    pub fn set_max_resident(&self, bytes: u64) {
+        let mut resident = self.resident.lock().unwrap();
+        resident.max_pages = (bytes / resident.page).max(resident.chunk / resident.page);
+    }
+
+    /// Bytes of pages in memory.
+    pub fn resident(&self) -> u64 {
+        let resident = self.resident.lock().unwrap();
+        resident.pages.len() as u64 * resident.page
+    }
+
+    /// Mark `len` bytes at `offset` as about to be written, so their
+    /// chunks stay in memory and [`RemotePages::flush`] puts them back.
+    pub fn mark_dirty(&self, offset: usize, len: usize) -> Result<(), Error> {
+        self.check_range(offset, len)?;
+        let mut written = self.written.lock().unwrap();
+        written.dirty.mark(offset as u64, len as u64);
+        Ok(())
+    }
+
+    /// Put every chunk with marked bytes back into the store.
+    /// If that fails, they stay marked.
+    pub fn flush(&self) -> Result<(), Error> {
+        let _flushing = self.flush.lock().unwrap();
+        let chunk = self.cache.chunk;
+        let mut written = self.written.lock().unwrap();
+        let dirty = written.dirty.take();
+        let chunks: BTreeSet<u64> = dirty
+            .iter()
+            .flat_map(|(start, end)| start / chunk..end.div_ceil(chunk))
+            .collect();
+        // Kept in memory until they are in the cache
+        written.flushing.clone_from(&chunks);
+        drop(written);
+        let result = chunks.into_iter().try_for_each(|index| {
+            let start = (index * chunk) as usize;
+            // Copied before locking the cache, reading may fault
+            let data = self.pages[start..start + chunk as usize].to_vec();
+            self.store.put_chunk(index, &data)?;
+            self.cache.write(index, &data)
+        });
+        let mut written = self.written.lock().unwrap();
+        written.flushing.clear();
+        if result.is_err() {
+            written.dirty.merge(dirty);
+        }
+        result
+    }
+
+    /// Flush, then drop the pages of `len` bytes at `offset`
+    /// (rounded out to whole pages) from memory. They are
+    /// paged in again from the cache or the store when touched.
+    pub fn evict(&mut self, offset: usize, len: usize) -> Result<(), Error> {
+        self.check_range(offset, len)?;
+        self.flush()?;
+        let mut resident = self.resident.lock().unwrap();
+        self.pages.discard(offset as u64, len as u64)?;
+        resident.remove(offset as u64, (offset + len) as u64);
+        Ok(())
+    }
+
+    /// Fetch the chunks of `len` bytes at `offset` into the cache,
+    /// so their pages are filled without waiting for the store.
+    /// Only as many as fit into the cache stay there.
+    pub fn prefetch(&self, offset: usize, len: usize) -> Result<(), Error> {
+        self.check_range(offset, len)?;
+        let chunk = self.cache.chunk as usize;
+        (offset / chunk..(offset + len).div_ceil(chunk))
+            .try_for_each(|index| self.cache.fetch(index as u64).map(drop))
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+        if offset.checked_add(len).is_none_or(|end| end > self.len()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "range exceeds the pages",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Deref for RemotePages {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.pages
+    }
+}
+
+impl DerefMut for RemotePages {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.pages
+    }
+}
+
+// Pages in memory, filled by the handler thread.
+struct Resident {
+    max_pages: u64,
+    page: u64,
+    chunk: u64,
+    // Offsets of the resident pages
+    pages: BTreeSet<u64>,
+    // Chunks with resident pages, the first paged in first
+    order: VecDeque<u64>,
+}
+
+impl Resident {
+    fn insert(&mut self, offset: u64) {
+        let index = offset / self.chunk;
+        if !self.has_chunk(index) {
+            self.order.push_back(index);
+        }
+        self.pages.insert(offset / self.page * self.page);
+    }
+
+    fn has_chunk(&self, index: u64) -> bool {
+        let start = index * self.chunk;
+        self.pages.range(start..start + self.chunk).next().is_some()
+    }
+
+    // Forget the pages from `start` to `end`, rounded out to whole ones.
+    fn remove(&mut self, start: u64, end: u64) {
+        let start = start / self.page * self.page;
+        let removed: Vec<u64> = self.pages.range(start..end).copied().collect();
+        for page in removed {
+            self.pages.remove(&page);
+        }
+        let order = std::mem::take(&mut self.order);
+        self.order = order
+            .into_iter()
+            .filter(|&index| self.has_chunk(index))
+            .collect();
+    }
+
+    // Discard the chunks paged in first while over the budget, not
+    // the one of the page at `offset` being filled, those marked
+    // as written or being flushed.
+    fn trim(&mut self, offset: u64, written: &Written, memory: &File) -> Result<(), Error> {
+        let current = offset / self.chunk;
+        let mut kept = Vec::new();
+        let mut result = Ok(());
+        while self.pages.len() as u64 > self.max_pages {
+            let Some(index) = self.order.pop_front() else {
+                break;
+            };
+            let start = index * self.chunk;
+            let end = start + self.chunk;
+            if index == current
+                || written.flushing.contains(&index)
+                || written.dirty.overlaps(start, end)
+            {
+                kept.push(index);
+                continue;
+            }
+            if let Err(err) = lazy::discard_pages(memory, start, self.chunk) {
+                kept.push(index);
+                result = Err(err);
+                break;
+            }
+            let removed: Vec<u64> = self.pages.range(start..end).copied().collect();
+            for page in removed {
+                self.pages.remove(&page);
+            }
+        }
+        // Still resident, in their old order
+        for index in kept.into_iter().rev() {
+            self.order.push_front(index);
+        }
+        result
+    }
+}
+
+// What the application marked as written, and what is being put back.
+#[derive(Default)]
+struct Written {
+    dirty: DirtyPages,
+    // Chunks taken out of `dirty` by a flush which is not done yet
+    flushing: BTreeSet<u64>,
+}
+
+struct ChunkCache {
+    file: File,
+    chunk: u64,
+    store: Arc<dyn ObjectStore>,
+    slots: Mutex<Slots>,
+}
+
+#[derive(Default)]
+struct Slots {
+    // Slot of the file by chunk index
+    by_chunk: HashMap<u64, u64>,
+    // Cached chunks, the first fetched first
+    order: VecDeque<u64>,
+    // Slots given up after a failed write
+    free: Vec<u64>,
+    // Slots used so far, and at most
+    used: u64,
+    capacity: u64,
+}
+
+impl ChunkCache {
+    // Read the bytes at `offset` of the data, fetching their chunk if missing.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let slot = self.fetch(offset / self.chunk)?;
+        self.file
+            .read_exact_at(buf, slot * self.chunk + offset % self.chunk)
+    }
+
+    // Slot of the chunk at `index`, fetched from the store if missing.
+    fn fetch(&self, index: u64) -> Result<u64, Error> {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(&slot) = slots.by_chunk.get(&index) {
+            return Ok(slot);
+        }
+        let mut data = vec![0; self.chunk as usize];
+        self.store.get_chunk(index, &mut data)?;
+        self.insert(&mut slots, index, &data)
+    }
+
+    // Cache `data` as the chunk at `index`, after putting it into the store.
+    fn write(&self, index: u64, data: &[u8]) -> Result<(), Error> {
+        let mut slots = self.slots.lock().unwrap();
+        self.insert(&mut slots, index, data).map(drop)
+    }
+
+    fn insert(&self, slots: &mut Slots, index: u64, data: &[u8]) -> Result<u64, Error> {
+        let cached = slots.by_chunk.get(&index).copied();
+        let slot = match cached {
+            Some(slot) => slot,
+            None => match slots.free.pop() {
+                Some(slot) => slot,
+                None if slots.used < slots.capacity => {
+                    slots.used += 1;
+                    slots.used - 1
+                }
+                None => {
+                    let oldest = slots.order.pop_front().unwrap();
+                    slots.by_chunk.remove(&oldest).unwrap()
+                }
+            },
+        };
+        if let Err(err) = self.file.write_all_at(data, slot * self.chunk) {
+            // Half written, the store has the chunk anyway
+            if cached.is_some() {
+                slots.by_chunk.remove(&index);
+                slots.order.retain(|&chunk| chunk != index);
+            }
+            slots.free.push(slot);
+            return Err(err);
+        }
+        if cached.is_none() {
+            slots.by_chunk.insert(index, slot);
+            slots.order.push_back(index);
+        }
+        Ok(slot)
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{DiskAlloc, DiskAllocBuilder, DiskAllocError, Stats};
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    hash::{BuildHasher, RandomState},
+    ptr::NonNull,
+    sync::Arc,
+};
+
+/// Several [`DiskAlloc`]s, each with its own file and lock,
+/// picked by the id of the allocating thread.
+///
+/// Threads building their own vectors at the same time
+/// do not wait for each other's allocations and file resizes.
+/// Memory can still be freed and grown on any thread.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use diskallocator::ShardedDiskAlloc;
+///
+/// let alloc = ShardedDiskAlloc::new(4).unwrap();
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let alloc = alloc.clone();
+///         std::thread::spawn(move || {
+///             let mut v: Vec<u64, _> = Vec::new_in(alloc);
+///             v.extend(0..1000);
+///             v.iter().sum::<u64>()
+///         })
+///     })
+///     .collect();
+/// for thread in threads {
+///     assert_eq!(thread.join().unwrap(), 499500);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ShardedDiskAlloc {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    shards: Vec<DiskAlloc>,
+    hasher: RandomState,
+}
+
+impl ShardedDiskAlloc {
+    /// Create `shards` allocators on temporary files,
+    /// see [`DiskAlloc::new`].
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    pub fn new(shards: usize) -> Result<Self, DiskAllocError> {
+        Self::with_builder(shards, DiskAllocBuilder::new())
+    }
+
+    /// Like [`ShardedDiskAlloc::new`], but create each
+    /// shard with `builder`. Limits like
+    /// [`DiskAllocBuilder::max_file_size`] apply per shard.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    pub fn with_builder(shards: usize, builder: DiskAllocBuilder) -> Result<Self, DiskAllocError> {
+        assert!(shards > 0, "at least one shard is needed");
+        Ok(Self {
+            inner: Arc::new(Inner {
+                shards: (0..shards)
+                    .map(|_| builder.build())
+                    .collect::<Result<_, _>>()?,
+                hasher: RandomState::new(),
+            }),
+        })
+    }
+
+    /// The allocators memory is taken from.
+    pub fn shards(&self) -> &[DiskAlloc] {
+        &self.inner.shards
+    }
+
+    /// Usage statistics of all shards together.
+    pub fn stats(&self) -> Stats {
+        self.shards()
+            .iter()
+            .fold(Stats::default(), |sum, shard| sum.combine(&shard.stats()))
+    }
+
+    // The shard for allocations of this thread.
+    fn local(&self) -> &DiskAlloc {
+        let shards = self.shards();
+        let hash = self.inner.hasher.hash_one(std::thread::current().id());
+        &shards[hash as usize % shards.len()]
+    }
+
+    fn owner(&self, ptr: NonNull<u8>) -> &DiskAlloc {
+        // Mostly freed by the thread which allocated it
+        let local = self.local();
+        if local.owns(ptr) {
+            return local;
+        }
+        self.shards()
+            .iter()
+            .find(|shard| shard.owns(ptr))
+            .expect("pointer not allocated by this allocator")
+    }
+}
+
+unsafe impl Allocator for ShardedDiskAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.local().allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.local().allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.owner(ptr).deallocate(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.owner(ptr).grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.owner(ptr).grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.owner(ptr).shrink(ptr, old_layout, new_layout)
+    }
+}
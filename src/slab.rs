@@ -0,0 +1,192 @@
+use crate::{DiskAlloc, DiskAllocBuilder, Stats};
+use std::{
+    alloc::AllocError,
+    fmt,
+    mem::MaybeUninit,
+    ops::{Index, IndexMut},
+};
+
+/// Values of one type on disk in fixed-size slots,
+/// addressed by the key returned when inserting them.
+///
+/// Inserting and removing take constant time, removed slots
+/// are reused by the next insert, so the file never fragments.
+/// Meant for object pools and graph nodes referring to each
+/// other by key. Which slots are in use is kept in a bitmap
+/// in the same file.
+///
+/// ```rust
+/// use diskallocator::DiskSlab;
+///
+/// let mut nodes = DiskSlab::new().unwrap();
+/// let a = nodes.insert([1_u64; 64]);
+/// let b = nodes.insert([2_u64; 64]);
+/// assert_eq!(nodes.remove(a), Some([1; 64]));
+/// // The slot of `a` is reused
+/// assert_eq!(nodes.insert([3; 64]), a);
+/// assert_eq!(nodes[b][0], 2);
+/// ```
+pub struct DiskSlab<T> {
+    slots: Vec<MaybeUninit<T>, DiskAlloc>,
+    // One bit per slot, set if it holds a value
+    occupied: Vec<u64, DiskAlloc>,
+    // Keys of removed values, with space for every slot
+    free: Vec<usize, DiskAlloc>,
+    len: usize,
+}
+
+impl<T> DiskSlab<T> {
+    /// Empty slab on a new temporary file, see [`DiskAlloc::new`].
+    pub fn new() -> Result<Self, std::io::Error> {
+        Self::with_builder(&DiskAllocBuilder::new())
+    }
+
+    /// Empty slab on a new temporary file
+    /// configured by `builder`.
+    pub fn with_builder(builder: &DiskAllocBuilder) -> Result<Self, std::io::Error> {
+        let alloc = builder.build()?;
+        Ok(Self {
+            slots: Vec::new_in(alloc.clone()),
+            occupied: Vec::new_in(alloc.clone()),
+            free: Vec::new_in(alloc),
+            len: 0,
+        })
+    }
+
+    /// Move `value` into a free slot, returning its key.
+    ///
+    /// # Panics
+    /// Panics if the file can not grow,
+    /// see [`DiskSlab::try_insert`].
+    pub fn insert(&mut self, value: T) -> usize {
+        self.try_insert(value)
+            .expect("failed to allocate in the disk slab")
+    }
+
+    /// Like [`DiskSlab::insert`], but fails if the file can not grow.
+    /// The value is dropped then.
+    pub fn try_insert(&mut self, value: T) -> Result<usize, AllocError> {
+        let key = match self.free.pop() {
+            Some(key) => key,
+            None => {
+                let key = self.slots.len();
+                // Removing never allocates
+                self.free.try_reserve(key + 1).map_err(|_| AllocError)?;
+                if key.is_multiple_of(64) {
+                    self.occupied.try_reserve(1).map_err(|_| AllocError)?;
+                    self.occupied.push(0);
+                }
+                self.slots.try_reserve(1).map_err(|_| AllocError)?;
+                self.slots.push(MaybeUninit::uninit());
+                key
+            }
+        };
+        self.slots[key].write(value);
+        self.occupied[key / 64] |= 1 << (key % 64);
+        self.len += 1;
+        Ok(key)
+    }
+
+    /// Move the value at `key` out, freeing its slot.
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        if !self.contains(key) {
+            return None;
+        }
+        self.occupied[key / 64] &= !(1 << (key % 64));
+        self.len -= 1;
+        self.free.push(key);
+        // The slot is free now, the value is not read again
+        Some(unsafe { self.slots[key].assume_init_read() })
+    }
+
+    /// Whether a value is stored at `key`.
+    pub fn contains(&self, key: usize) -> bool {
+        key < self.slots.len() && self.occupied[key / 64] & (1 << (key % 64)) != 0
+    }
+
+    /// The value at `key`, if any.
+    pub fn get(&self, key: usize) -> Option<&T> {
+        self.contains(key)
+            .then(|| unsafe { self.slots[key].assume_init_ref() })
+    }
+
+    /// The value at `key`, if any.
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        self.contains(key)
+            .then(|| unsafe { self.slots[key].assume_init_mut() })
+    }
+
+    /// Number of values.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no values.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots, used or free.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// All values with their keys, in order of the keys.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        (0..self.slots.len()).filter_map(|key| Some((key, self.get(key)?)))
+    }
+
+    /// Drop all values, keeping the file.
+    pub fn clear(&mut self) {
+        self.drop_values();
+        self.slots.clear();
+        self.occupied.clear();
+        self.free.clear();
+        self.len = 0;
+    }
+
+    /// Current usage statistics, see [`DiskAlloc::stats`].
+    pub fn stats(&self) -> Stats {
+        self.slots.allocator().stats()
+    }
+
+    fn drop_values(&mut self) {
+        if !std::mem::needs_drop::<T>() {
+            return;
+        }
+        for key in 0..self.slots.len() {
+            if self.contains(key) {
+                unsafe { self.slots[key].assume_init_drop() };
+            }
+        }
+    }
+}
+
+impl<T> Drop for DiskSlab<T> {
+    fn drop(&mut self) {
+        self.drop_values();
+    }
+}
+
+impl<T> Index<usize> for DiskSlab<T> {
+    type Output = T;
+
+    fn index(&self, key: usize) -> &T {
+        self.get(key).expect("no value at this key")
+    }
+}
+
+impl<T> IndexMut<usize> for DiskSlab<T> {
+    fn index_mut(&mut self, key: usize) -> &mut T {
+        self.get_mut(key).expect("no value at this key")
+    }
+}
+
+impl<T> fmt::Debug for DiskSlab<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskSlab")
+            .field("len", &self.len)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
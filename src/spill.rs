@@ -0,0 +1,212 @@
+use crate::{DiskAlloc, DiskAllocBuilder};
+use std::{
+    alloc::{AllocError, Allocator, Global, Layout},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+};
+
+/// Allocates from the heap up to a threshold,
+/// and from disk beyond it.
+///
+/// Small workloads run at RAM speed and never touch the disk,
+/// big ones still fit. The [`DiskAlloc`] is only created
+/// with the first allocation not fitting on the heap.
+/// A vector growing beyond the threshold is moved to disk.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use diskallocator::SpillAlloc;
+///
+/// // Up to 8GiB in RAM
+/// let alloc = SpillAlloc::new(8 * 1024 * 1024 * 1024);
+/// let mut v: Vec<u64, _> = Vec::new_in(alloc);
+/// v.push(1);
+/// ```
+#[derive(Clone)]
+pub struct SpillAlloc {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    threshold: usize,
+    // Bytes currently allocated on the heap
+    heap_used: AtomicUsize,
+    builder: DiskAllocBuilder,
+    disk: OnceLock<DiskAlloc>,
+}
+
+impl SpillAlloc {
+    /// Keep up to `threshold` bytes on the heap,
+    /// spill to a [`DiskAlloc::new`] allocator beyond.
+    pub fn new(threshold: usize) -> Self {
+        Self::with_builder(threshold, DiskAllocBuilder::new())
+    }
+
+    /// Like [`SpillAlloc::new`], but create the
+    /// disk allocator with `builder`.
+    pub fn with_builder(threshold: usize, builder: DiskAllocBuilder) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                threshold,
+                heap_used: 0.into(),
+                builder,
+                disk: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Bytes currently allocated on the heap.
+    pub fn heap_used(&self) -> usize {
+        self.inner.heap_used.load(Ordering::SeqCst)
+    }
+
+    /// The disk allocator, if anything got spilled yet.
+    pub fn disk(&self) -> Option<&DiskAlloc> {
+        self.inner.disk.get()
+    }
+
+    // Count `bytes` more on the heap, if they fit.
+    fn reserve_heap(&self, bytes: usize) -> bool {
+        let threshold = self.inner.threshold;
+        self.inner
+            .heap_used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&sum| sum <= threshold)
+            })
+            .is_ok()
+    }
+
+    fn release_heap(&self, bytes: usize) {
+        self.inner.heap_used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    fn get_or_create_disk(&self) -> Result<&DiskAlloc, AllocError> {
+        if let Some(disk) = self.inner.disk.get() {
+            return Ok(disk);
+        }
+        // Racing threads might create one too many, it is just dropped
+        let disk = self.inner.builder.build().map_err(|_| AllocError)?;
+        Ok(self.inner.disk.get_or_init(|| disk))
+    }
+
+    fn on_disk(&self, ptr: NonNull<u8>) -> Option<&DiskAlloc> {
+        self.inner.disk.get().filter(|disk| disk.owns(ptr))
+    }
+
+    fn allocate_any(&self, layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+        if self.reserve_heap(layout.size()) {
+            let result = if zeroed {
+                Global.allocate_zeroed(layout)
+            } else {
+                Global.allocate(layout)
+            };
+            if result.is_ok() {
+                return result;
+            }
+            self.release_heap(layout.size());
+        }
+        let disk = self.get_or_create_disk()?;
+        if zeroed {
+            disk.allocate_zeroed(layout)
+        } else {
+            disk.allocate(layout)
+        }
+    }
+
+    unsafe fn grow_any(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(disk) = self.on_disk(ptr) {
+            return if zeroed {
+                disk.grow_zeroed(ptr, old_layout, new_layout)
+            } else {
+                disk.grow(ptr, old_layout, new_layout)
+            };
+        }
+        let growth = new_layout.size() - old_layout.size();
+        if self.reserve_heap(growth) {
+            let result = if zeroed {
+                Global.grow_zeroed(ptr, old_layout, new_layout)
+            } else {
+                Global.grow(ptr, old_layout, new_layout)
+            };
+            if result.is_ok() {
+                return result;
+            }
+            self.release_heap(growth);
+        }
+        // Move to disk
+        let disk = self.get_or_create_disk()?;
+        let new_ptr = if zeroed {
+            disk.allocate_zeroed(new_layout)?
+        } else {
+            disk.allocate(new_layout)?
+        };
+        std::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size(),
+        );
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+unsafe impl Allocator for SpillAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_any(layout, false)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_any(layout, true)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self.on_disk(ptr) {
+            Some(disk) => disk.deallocate(ptr, layout),
+            None => {
+                Global.deallocate(ptr, layout);
+                self.release_heap(layout.size());
+            }
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_any(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_any(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(disk) = self.on_disk(ptr) {
+            return disk.shrink(ptr, old_layout, new_layout);
+        }
+        let new_ptr = Global.shrink(ptr, old_layout, new_layout)?;
+        self.release_heap(old_layout.size() - new_layout.size());
+        Ok(new_ptr)
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Size 0, then one class per power of two
+const CLASSES: usize = usize::BITS as usize + 1;
+
+/// Usage statistics of a [`crate::DiskAlloc`],
+/// see [`crate::DiskAlloc::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Current size of the backing file in bytes.
+    pub file_size: u64,
+    /// Bytes up to the end of the last allocation.
+    /// Can be less than `file_size` with chunked growth.
+    pub used_size: u64,
+    /// Biggest file size so far.
+    pub peak_file_size: u64,
+    /// Number of allocations not freed yet.
+    /// Allocations restored by [`crate::DiskAlloc::open`] are not counted.
+    pub live_allocations: u64,
+    /// Bytes skipped in total to align allocations.
+    /// They are free space, reused by later allocations fitting into them.
+    pub wasted_alignment_bytes: u64,
+    /// Number of times the file was resized.
+    pub resize_syscalls: u64,
+    /// Sizes requested so far, by power of two.
+    pub size_histogram: SizeHistogram,
+}
+
+/// Number of allocations by requested size, see [`Stats::size_histogram`].
+///
+/// Every allocation and every grow or shrink counts once, for its
+/// new size, whether it is still live or not. Sizes are binned by
+/// power of two, so class `n` holds `2^(n-1)..=2^n - 1` bytes.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// let alloc = diskallocator::DiskAlloc::new().unwrap();
+/// let mut v: Vec<u8, _> = Vec::with_capacity_in(100, alloc.clone());
+/// v.resize(1000, 0);
+/// let histogram = alloc.stats().size_histogram;
+/// assert_eq!(histogram.count(100), 1);
+/// assert_eq!(histogram.total(), 2);
+/// for (sizes, count) in histogram.classes() {
+///     println!("{sizes:?} bytes: {count}");
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SizeHistogram {
+    counts: [u64; CLASSES],
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; CLASSES],
+        }
+    }
+}
+
+fn class_of(size: usize) -> usize {
+    size.checked_ilog2().map_or(0, |log| log as usize + 1)
+}
+
+impl SizeHistogram {
+    /// Allocations in the class `size` belongs to.
+    pub fn count(&self, size: usize) -> u64 {
+        self.counts[class_of(size)]
+    }
+
+    /// Allocations of all sizes.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The sizes of each class with its count, smallest first,
+    /// skipping empty classes.
+    pub fn classes(&self) -> impl Iterator<Item = (RangeInclusive<usize>, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count != 0)
+            .map(|(class, &count)| {
+                let sizes = match class {
+                    0 => 0..=0,
+                    _ => 1 << (class - 1)..=usize::MAX >> (CLASSES - 1 - class),
+                };
+                (sizes, count)
+            })
+    }
+
+    fn combine(&self, other: &SizeHistogram) -> SizeHistogram {
+        SizeHistogram {
+            counts: std::array::from_fn(|class| self.counts[class] + other.counts[class]),
+        }
+    }
+}
+
+// Counts behind a SizeHistogram, shared by clones of an allocator
+// and updated without its lock.
+#[derive(Debug)]
+pub(crate) struct SizeCounters {
+    counts: [AtomicU64; CLASSES],
+}
+
+impl Default for SizeCounters {
+    fn default() -> Self {
+        Self {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl SizeCounters {
+    pub fn record(&self, size: usize) {
+        self.counts[class_of(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn histogram(&self) -> SizeHistogram {
+        SizeHistogram {
+            counts: std::array::from_fn(|class| self.counts[class].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Stats {
+    // Statistics of two allocators together.
+    pub(crate) fn combine(&self, other: &Stats) -> Stats {
+        Stats {
+            file_size: self.file_size + other.file_size,
+            used_size: self.used_size + other.used_size,
+            peak_file_size: self.peak_file_size + other.peak_file_size,
+            live_allocations: self.live_allocations + other.live_allocations,
+            wasted_alignment_bytes: self.wasted_alignment_bytes + other.wasted_alignment_bytes,
+            resize_syscalls: self.resize_syscalls + other.resize_syscalls,
+            size_histogram: self.size_histogram.combine(&other.size_histogram),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_classes() {
+        let counters = SizeCounters::default();
+        for size in [0, 1, 2, 3, 4, 4096, 8191, usize::MAX] {
+            counters.record(size);
+        }
+        let histogram = counters.histogram();
+        assert_eq!(histogram.count(0), 1);
+        assert_eq!(histogram.count(3), 2);
+        assert_eq!(histogram.count(5000), 2);
+        assert_eq!(histogram.total(), 8);
+        let classes: Vec<_> = histogram.classes().collect();
+        assert_eq!(classes[0], (0..=0, 1));
+        assert_eq!(classes[1], (1..=1, 1));
+        assert_eq!(classes[2], (2..=3, 2));
+        assert_eq!(classes[4], (4096..=8191, 2));
+        assert_eq!(classes[5], (1 << (usize::BITS - 1)..=usize::MAX, 1));
+    }
+}
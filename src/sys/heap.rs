@@ -0,0 +1,139 @@
+// Miri can not map memory, so there the whole reservation is allocated
+// from the heap up front, and files can not be mapped at all.
+// Miri keeps every byte in memory, so bigger reservations fail
+// with OutOfMemory, shrinking the default one down to `MAX_LEN`.
+
+use super::{Advice, MapOptions};
+use crate::AccessPattern;
+use std::{alloc::Layout, fs::File, io::Error};
+
+const MAX_LEN: u64 = 64 * 1024 * 1024;
+
+pub(crate) struct Mapping {
+    ptr: *mut u8,
+    len: u64,
+}
+
+impl Mapping {
+    pub fn new(file: Option<&File>, len: u64, _options: &MapOptions) -> Result<Self, Error> {
+        if file.is_some() {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "files can not be mapped under Miri",
+            ));
+        }
+        if len > MAX_LEN {
+            return Err(Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                format!("reservations under Miri are limited to {MAX_LEN} bytes"),
+            ));
+        }
+        let ptr = unsafe { std::alloc::alloc_zeroed(Self::layout(len)) };
+        if ptr.is_null() {
+            return Err(Error::from(std::io::ErrorKind::OutOfMemory));
+        }
+        Ok(Self { ptr, len })
+    }
+
+    fn layout(len: u64) -> Layout {
+        Layout::from_size_align(len.max(1) as usize, super::page_size() as usize).unwrap()
+    }
+
+    pub fn ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn resize_granularity(&self) -> u64 {
+        1
+    }
+
+    pub fn file_len(&self, _file: Option<&File>) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    pub fn grow_reservation(&self, _file: Option<&File>, _len: u64) -> Result<(), Error> {
+        Err(Error::new(
+            std::io::ErrorKind::Unsupported,
+            "heap reservations can not be moved",
+        ))
+    }
+
+    pub fn follow_file_len(&self, _file: Option<&File>, _file_len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn set_file_len(
+        &self,
+        _file: Option<&File>,
+        old_len: u64,
+        new_len: u64,
+    ) -> Result<(), Error> {
+        if new_len < old_len {
+            // Later allocations expect fresh space to be zeroed
+            unsafe {
+                std::ptr::write_bytes(
+                    self.ptr.add(new_len as usize),
+                    0,
+                    (old_len - new_len) as usize,
+                )
+            };
+        }
+        Ok(())
+    }
+
+    pub fn punch_hole(&self, _file: Option<&File>, _offset: u64, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn protect(&self, _offset: u64, _len: u64, _accessible: bool) -> Result<(), Error> {
+        Err(Error::new(
+            std::io::ErrorKind::Unsupported,
+            "heap memory can not be protected",
+        ))
+    }
+
+    // Only hints, the memory stays as it is.
+    pub fn advise(
+        &self,
+        _file: Option<&File>,
+        _offset: u64,
+        _len: u64,
+        advice: Advice,
+    ) -> Result<(), Error> {
+        if advice == Advice::DontNeed {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "dropping pages of heap memory would lose them",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_access_pattern(
+        &self,
+        _file: Option<&File>,
+        _pattern: AccessPattern,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn flush(&self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn flush_async(&self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn close(&mut self, _file: Option<&File>, _file_len: u64) {
+        if self.ptr.is_null() {
+            return;
+        }
+        unsafe { std::alloc::dealloc(self.ptr, Self::layout(self.len)) };
+        self.ptr = std::ptr::null_mut();
+    }
+}
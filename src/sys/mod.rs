@@ -0,0 +1,161 @@
+// Platform specific memory mapping.
+//
+// Every backend reserves `len` bytes of address space at once
+// and lets the file grow and shrink below it, on unix by
+// mapping the file over an inaccessible reservation.
+// Pointers into the mapping must stay valid while the file
+// is resized, which mapping crates like memmap2 can not
+// express (they map the file length, and remapping may move).
+// So each backend talks to the OS directly, behind the same
+// interface:
+//
+// - `Mapping::new` reserves the address space, `close` unmaps
+//   and leaves the file at the given length, `grow_reservation`
+//   moves it to a bigger reservation where supported
+// - without a file (`None`), the mapping is anonymous memory,
+//   for in-memory allocators where `memory_file` has none
+// - offsets and lengths are relative to `MapOptions::offset`,
+//   the part of the file before it is never touched
+// - `set_file_len` resizes the file below the mapping, `file_len`
+//   is its current length,
+//   `resize_granularity` is the step it can be resized in,
+//   `follow_file_len` maps what another process appended
+// - `punch_hole`, `protect`, `advise`, `flush` and `flush_async`
+//   work on ranges of the mapping, all errors are returned
+// - lengths are checked with `check_map_len` before mapping
+// - `page_size`, `tempfile_in`, `link_file`, `file_path`,
+//   `memory_file`, `set_permissions`, `free_space`, `disk_usage`
+//   and `clone_range` cover the rest of the platform differences,
+//   `shm_open` and `shm_unlink` exist on Linux and FreeBSD only
+
+#[cfg(unix)]
+#[cfg_attr(miri, allow(dead_code))]
+mod unix;
+#[cfg(all(unix, not(miri)))]
+pub(crate) use unix::Mapping;
+#[cfg(unix)]
+pub(crate) use unix::{
+    clone_range, disk_usage, file_path, free_space, link_file, memory_file, page_size,
+    set_permissions, tempfile_in,
+};
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub(crate) use unix::{shm_open, shm_unlink};
+
+// Miri can not map memory, the reservation comes from the heap
+#[cfg(miri)]
+mod heap;
+#[cfg(miri)]
+pub(crate) use heap::Mapping;
+
+#[cfg(windows)]
+#[cfg_attr(miri, allow(dead_code))]
+mod windows;
+#[cfg(all(windows, not(miri)))]
+pub(crate) use windows::Mapping;
+#[cfg(windows)]
+pub(crate) use windows::{
+    clone_range, disk_usage, file_path, free_space, link_file, memory_file, page_size,
+    set_permissions, tempfile_in,
+};
+
+// How to create a mapping.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MapOptions {
+    // Try huge pages, silently falling back to normal ones,
+    // opt out of transparent ones, or leave it to the system
+    pub huge_pages: Option<bool>,
+    // Only map for reading, the file never changes
+    pub read_only: bool,
+    // Fault in the part of the file existing when mapping it
+    pub populate: bool,
+    // Copy-on-write, changes never reach the file (unix only)
+    pub private: bool,
+    // Allocate disk blocks when the file grows (Linux and FreeBSD)
+    pub preallocate: bool,
+    // Keep the mapping from forked children (Linux only)
+    pub dont_fork: bool,
+    // Where the mapping starts in the file, a multiple of pages
+    // (of the allocation granularity on Windows)
+    pub offset: u64,
+    // Other mappings use the rest of the file, so it is never
+    // resized, released space is zeroed instead (partitions)
+    pub shared_file: bool,
+}
+
+// Size of huge pages created with MAP_HUGETLB.
+#[cfg(target_os = "linux")]
+pub(crate) const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+// Hints about how a range of the mapping is going to be used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Advice {
+    WillNeed,
+    // Drop clean pages from memory, they are read from disk again on access
+    DontNeed,
+    // The data is dead, drop the pages without writing them back.
+    // They read as zeros or old data afterwards.
+    Free,
+    // Reclaim the pages before others, keeping their data
+    Cold,
+}
+
+// Fault in `len` bytes at `ptr` by reading a byte of every page,
+// where mappings can not be populated by the OS.
+#[cfg(not(target_os = "linux"))]
+pub(crate) unsafe fn touch_pages(ptr: *const u8, len: u64) {
+    for offset in (0..len).step_by(page_size() as usize) {
+        std::ptr::read_volatile(ptr.add(offset as usize));
+    }
+}
+
+// The file of a mapping, for operations anonymous memory does not have.
+pub(crate) fn backing(file: Option<&std::fs::File>) -> Result<&std::fs::File, std::io::Error> {
+    file.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "anonymous memory has no backing file",
+        )
+    })
+}
+
+// Lengths are passed to the OS as `usize`, which can not hold
+// big reservations on 32-bit targets.
+pub(crate) fn check_map_len(len: u64) -> Result<(), std::io::Error> {
+    if len > isize::MAX as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("mapping {len} bytes does not fit into the address space of this target"),
+        ));
+    }
+    Ok(())
+}
+
+// Expand a range to whole pages, as needed by most memory syscalls.
+pub(crate) fn page_range(offset: u64, len: u64) -> (u64, u64) {
+    let page = page_size();
+    let start = offset / page * page;
+    let end = (offset + len).next_multiple_of(page);
+    (start, end - start)
+}
+
+// Directory for temporary files if none is configured.
+#[cfg(unix)]
+pub(crate) fn default_dir() -> std::path::PathBuf {
+    std::path::PathBuf::from("/var/tmp/")
+}
+
+#[cfg(not(unix))]
+pub(crate) fn default_dir() -> std::path::PathBuf {
+    std::env::temp_dir()
+}
+
+// Where to try creating temporary files, in order. `/var/tmp`
+// is missing or read-only in some containers and sandboxes.
+pub(crate) fn default_dirs() -> Vec<std::path::PathBuf> {
+    let mut dirs = vec![default_dir()];
+    let temp_dir = std::env::temp_dir();
+    if !dirs.contains(&temp_dir) {
+        dirs.push(temp_dir);
+    }
+    dirs
+}
@@ -0,0 +1,939 @@
+// Address space is reserved with an inaccessible anonymous mapping,
+// and the file is mapped over it (MAP_FIXED) as it grows.
+// Without a file, the reservation is anonymous memory itself,
+// like a copy-on-write mapping of an empty file.
+// Pages past the end of the file are never mapped to it,
+// so access beyond the used part faults with SIGSEGV right away,
+// instead of relying on the OS to map a file beyond its length.
+
+use super::{Advice, MapOptions};
+use crate::AccessPattern;
+use std::{
+    fs::File,
+    io::Error,
+    os::fd::AsRawFd,
+    sync::atomic::{AtomicI32, AtomicPtr, AtomicU64, Ordering::SeqCst},
+};
+
+pub(crate) fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+#[cfg(target_os = "linux")]
+const RESERVE_FLAGS: libc::c_int = libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_NORESERVE;
+#[cfg(not(target_os = "linux"))]
+const RESERVE_FLAGS: libc::c_int = libc::MAP_PRIVATE | libc::MAP_ANON;
+
+pub(crate) struct Mapping {
+    // Change when the mapping moves to a bigger reservation
+    ptr: AtomicPtr<u8>,
+    len: AtomicU64,
+    // The file has to be resized in multiples of this
+    granularity: u64,
+    // Copy-on-write, the file is not resized
+    private: bool,
+    #[cfg_attr(not(any(target_os = "linux", target_os = "freebsd")), allow(dead_code))]
+    preallocate: bool,
+    // Protection and flags of the file mapped over the reservation
+    prot: libc::c_int,
+    flags: libc::c_int,
+    // MADV_HUGEPAGE or MADV_NOHUGEPAGE
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    transparent_huge_pages: Option<bool>,
+    // madvise advice, given again for parts mapped later
+    access_pattern: AtomicI32,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    dont_fork: bool,
+    // Bytes from the start mapped to the file, a multiple of pages
+    mapped: AtomicU64,
+    // Start of the mapping in the file
+    offset: u64,
+    // See MapOptions::shared_file
+    shared_file: bool,
+}
+
+impl Mapping {
+    pub fn new(file: Option<&File>, len: u64, options: &MapOptions) -> Result<Self, Error> {
+        let Some(file) = file else {
+            return Self::map_private(None, len, options);
+        };
+        if options.private {
+            check_offset(options.offset, page_size())?;
+            return Self::map_private(Some(file), len, options);
+        }
+        #[cfg(target_os = "linux")]
+        let flags = libc::MAP_SHARED_VALIDATE;
+        #[cfg(not(target_os = "linux"))]
+        let flags = libc::MAP_SHARED;
+
+        let prot = if options.read_only {
+            libc::PROT_READ
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+        let mut mapping = Self {
+            ptr: AtomicPtr::new(std::ptr::null_mut()),
+            len: len.into(),
+            granularity: 1,
+            private: false,
+            preallocate: options.preallocate,
+            prot,
+            flags,
+            transparent_huge_pages: None,
+            access_pattern: libc::MADV_NORMAL.into(),
+            dont_fork: options.dont_fork,
+            mapped: 0.into(),
+            offset: options.offset,
+            shared_file: options.shared_file,
+        };
+        #[cfg(not(target_os = "linux"))]
+        if options.dont_fork {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "keeping mappings from forked children is Linux only",
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        if options.huge_pages == Some(true) && on_hugetlbfs(file)? {
+            mapping.granularity = super::HUGE_PAGE_SIZE;
+            mapping.flags |= libc::MAP_HUGETLB;
+        } else {
+            // Not supported by every file system
+            mapping.transparent_huge_pages = options.huge_pages;
+        }
+        check_offset(options.offset, mapping.granularity.max(page_size()))?;
+        let file_len = mapping.file_len(Some(file))?;
+        *mapping.ptr.get_mut() = reserve(len, mapping.granularity.max(page_size()))?;
+        mapping.keep_from_children(mapping.ptr(), len);
+        // Only the existing data, later growth is faulted in on use
+        #[cfg(target_os = "linux")]
+        let flags = mapping.flags;
+        #[cfg(target_os = "linux")]
+        if options.populate {
+            mapping.flags |= libc::MAP_POPULATE;
+        }
+        if let Err(err) = mapping.map_up_to(file, file_len) {
+            mapping.close(Some(file), file_len);
+            return Err(err);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            mapping.flags = flags;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if options.populate {
+            unsafe { super::touch_pages(mapping.ptr(), file_len.min(len)) };
+        }
+        Ok(mapping)
+    }
+
+    // The file itself can not grow, so the mapping of the file
+    // is continued by anonymous memory.
+    fn map_private(file: Option<&File>, len: u64, options: &MapOptions) -> Result<Self, Error> {
+        super::check_map_len(len)?;
+        let offset = options.offset;
+        #[cfg(target_os = "linux")]
+        let populate = if options.populate {
+            libc::MAP_POPULATE
+        } else {
+            0
+        };
+        #[cfg(not(target_os = "linux"))]
+        let populate = 0;
+        let file_len = match file {
+            Some(file) => file
+                .metadata()?
+                .len()
+                .saturating_sub(offset)
+                .next_multiple_of(page_size())
+                .min(len),
+            None => 0,
+        };
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as libc::size_t,
+                libc::PROT_READ | libc::PROT_WRITE,
+                RESERVE_FLAGS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        if let Some(file) = file.filter(|_| file_len > 0) {
+            let file_addr = unsafe {
+                libc::mmap(
+                    addr,
+                    file_len as libc::size_t,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_FIXED | populate,
+                    file.as_raw_fd(),
+                    offset as libc::off_t,
+                )
+            };
+            if file_addr == libc::MAP_FAILED {
+                let err = Error::last_os_error();
+                unsafe { libc::munmap(addr, len as libc::size_t) };
+                return Err(err);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        if options.populate {
+            unsafe { super::touch_pages(addr.cast::<u8>(), file_len) };
+        }
+        Ok(Self {
+            ptr: AtomicPtr::new(addr.cast::<u8>()),
+            len: len.into(),
+            granularity: 1,
+            private: true,
+            preallocate: false,
+            prot: libc::PROT_READ | libc::PROT_WRITE,
+            flags: libc::MAP_PRIVATE,
+            transparent_huge_pages: None,
+            access_pattern: libc::MADV_NORMAL.into(),
+            // Children get a copy anyway
+            dont_fork: false,
+            mapped: file_len.into(),
+            offset,
+            shared_file: false,
+        })
+    }
+
+    pub fn resize_granularity(&self) -> u64 {
+        self.granularity
+    }
+
+    pub fn ptr(&self) -> *mut u8 {
+        self.ptr.load(SeqCst)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len.load(SeqCst)
+    }
+
+    // Length of the file after the offset.
+    pub fn file_len(&self, file: Option<&File>) -> Result<u64, Error> {
+        let Some(file) = file.filter(|_| !self.shared_file) else {
+            // Belongs to the other mappings, or there is none
+            return Ok(0);
+        };
+        Ok(file.metadata()?.len().saturating_sub(self.offset))
+    }
+
+    // Map the file over the reservation up to `file_len`.
+    fn map_up_to(&self, file: &File, file_len: u64) -> Result<(), Error> {
+        let start = self.mapped.load(SeqCst);
+        let end = file_len.next_multiple_of(page_size()).min(self.len());
+        if end <= start {
+            return Ok(());
+        }
+        let addr = unsafe {
+            libc::mmap(
+                self.ptr().add(start as usize).cast::<libc::c_void>(),
+                (end - start) as libc::size_t,
+                self.prot,
+                self.flags | libc::MAP_FIXED,
+                file.as_raw_fd(),
+                (self.offset + start) as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(huge_pages) = self.transparent_huge_pages {
+            let advice = if huge_pages {
+                libc::MADV_HUGEPAGE
+            } else {
+                libc::MADV_NOHUGEPAGE
+            };
+            unsafe { libc::madvise(addr, (end - start) as libc::size_t, advice) };
+        }
+        self.keep_from_children(addr.cast::<u8>(), end - start);
+        if self.access_pattern.load(SeqCst) != libc::MADV_NORMAL {
+            // Only a hint, a new mapping starts without it
+            unsafe {
+                libc::madvise(
+                    addr,
+                    (end - start) as libc::size_t,
+                    self.access_pattern.load(SeqCst),
+                )
+            };
+        }
+        self.mapped.store(end, SeqCst);
+        Ok(())
+    }
+
+    // Every new mapping has to be marked again. If that fails,
+    // children still get the mapping, just like without the option.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn keep_from_children(&self, addr: *mut u8, len: u64) {
+        #[cfg(target_os = "linux")]
+        if self.dont_fork {
+            unsafe {
+                libc::madvise(
+                    addr.cast::<libc::c_void>(),
+                    len as libc::size_t,
+                    libc::MADV_DONTFORK,
+                )
+            };
+        }
+    }
+
+    // Make the reservation inaccessible again after `file_len`.
+    fn unmap_from(&self, file_len: u64) -> Result<(), Error> {
+        let start = file_len.next_multiple_of(page_size()).min(self.len());
+        let end = self.mapped.load(SeqCst);
+        if start >= end {
+            return Ok(());
+        }
+        let addr = unsafe {
+            libc::mmap(
+                self.ptr().add(start as usize).cast::<libc::c_void>(),
+                (end - start) as libc::size_t,
+                libc::PROT_NONE,
+                RESERVE_FLAGS | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        self.keep_from_children(addr.cast::<u8>(), end - start);
+        self.mapped.store(start, SeqCst);
+        Ok(())
+    }
+
+    // Map the part of the file another process appended,
+    // for read-only mappings.
+    pub fn follow_file_len(&self, file: Option<&File>, file_len: u64) -> Result<(), Error> {
+        self.map_up_to(super::backing(file)?, file_len)
+    }
+
+    // Move the mapping to a new reservation of at least `len` bytes.
+    // Shared mappings show the same data wherever the file is mapped,
+    // moving with mremap would need the file mapped in one piece.
+    // Pointers into the old reservation become invalid.
+    pub fn grow_reservation(&self, file: Option<&File>, len: u64) -> Result<(), Error> {
+        if self.private {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "copy-on-write and anonymous mappings can not be moved",
+            ));
+        }
+        let file = super::backing(file)?;
+        let align = self.granularity.max(page_size());
+        let len = len.next_multiple_of(align);
+        let mut moved = Self {
+            ptr: AtomicPtr::new(reserve(len, align)?),
+            len: len.into(),
+            access_pattern: self.access_pattern.load(SeqCst).into(),
+            mapped: 0.into(),
+            ..*self
+        };
+        moved.keep_from_children(moved.ptr(), len);
+        if let Err(err) = moved.map_up_to(file, self.mapped.load(SeqCst)) {
+            moved.close(Some(file), 0);
+            return Err(err);
+        }
+        unsafe {
+            libc::munmap(
+                self.ptr().cast::<libc::c_void>(),
+                self.len() as libc::size_t,
+            )
+        };
+        self.ptr.store(moved.ptr(), SeqCst);
+        self.len.store(len, SeqCst);
+        Ok(())
+    }
+
+    // Pointers into the mapping stay valid, pages past
+    // the end of the file just can not be accessed.
+    pub fn set_file_len(
+        &self,
+        file: Option<&File>,
+        old_len: u64,
+        new_len: u64,
+    ) -> Result<(), Error> {
+        if self.private {
+            if new_len < old_len {
+                // Later allocations expect fresh space to be zeroed
+                unsafe {
+                    std::ptr::write_bytes(
+                        self.ptr().add(new_len as usize),
+                        0,
+                        (old_len - new_len) as usize,
+                    )
+                };
+            }
+            return Ok(());
+        }
+        let file = super::backing(file)?;
+        if self.shared_file {
+            return self.set_len_in_shared_file(file, old_len, new_len);
+        }
+        if new_len < old_len {
+            self.unmap_from(new_len)?;
+            return file.set_len(self.offset + new_len);
+        }
+        self.grow_file(file, old_len, new_len)?;
+        if let Err(err) = self.map_up_to(file, new_len) {
+            let _ = file.set_len(self.offset + old_len);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Like truncating, released space reads as zeros again,
+    // and its disk space is given back where holes can be punched.
+    fn set_len_in_shared_file(&self, file: &File, old_len: u64, new_len: u64) -> Result<(), Error> {
+        if new_len >= old_len {
+            return self.map_up_to(file, new_len);
+        }
+        // The rest of the last page stays mapped
+        let page_end = new_len.next_multiple_of(page_size()).min(old_len);
+        unsafe {
+            std::ptr::write_bytes(
+                self.ptr().add(new_len as usize),
+                0,
+                (page_end - new_len) as usize,
+            )
+        };
+        #[cfg(target_os = "linux")]
+        let punched = self
+            .punch_hole(Some(file), page_end, old_len - page_end)
+            .is_ok();
+        #[cfg(not(target_os = "linux"))]
+        let punched = false;
+        if !punched {
+            unsafe {
+                std::ptr::write_bytes(
+                    self.ptr().add(page_end as usize),
+                    0,
+                    (old_len - page_end) as usize,
+                )
+            };
+        }
+        self.unmap_from(new_len)
+    }
+
+    fn grow_file(&self, file: &File, old_len: u64, new_len: u64) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        if self.preallocate {
+            // Fails right away if the disk is full, instead of
+            // with a SIGBUS on the first write to a sparse page
+            let ret = unsafe {
+                libc::fallocate(
+                    file.as_raw_fd(),
+                    0,
+                    (self.offset + old_len) as libc::off_t,
+                    (new_len - old_len) as libc::off_t,
+                )
+            };
+            if ret == 0 {
+                return Ok(());
+            }
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err);
+            }
+            // Not supported by the file system, grow sparse
+        }
+        #[cfg(target_os = "freebsd")]
+        if self.preallocate {
+            let ret = unsafe {
+                libc::posix_fallocate(
+                    file.as_raw_fd(),
+                    (self.offset + old_len) as libc::off_t,
+                    (new_len - old_len) as libc::off_t,
+                )
+            };
+            match ret {
+                0 => return Ok(()),
+                // Not supported by the file system (e.g. ZFS), grow sparse
+                libc::EINVAL | libc::EOPNOTSUPP => {}
+                _ => return Err(Error::from_raw_os_error(ret)),
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+        let _ = old_len;
+        file.set_len(self.offset + new_len)
+    }
+
+    // Give the disk space of a range back to the file system,
+    // reading it afterwards returns zeroes.
+    #[cfg(target_os = "linux")]
+    pub fn punch_hole(&self, file: Option<&File>, offset: u64, len: u64) -> Result<(), Error> {
+        if self.private {
+            return Ok(());
+        }
+        let ret = unsafe {
+            libc::fallocate(
+                super::backing(file)?.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (self.offset + offset) as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn punch_hole(&self, _file: Option<&File>, _offset: u64, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Make pages inaccessible, or readable and writable again.
+    pub fn protect(&self, offset: u64, len: u64, accessible: bool) -> Result<(), Error> {
+        let (offset, len) = super::page_range(offset, len);
+        let prot = if accessible {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_NONE
+        };
+        let ret = unsafe {
+            libc::mprotect(
+                self.ptr().add(offset as usize).cast::<libc::c_void>(),
+                len as libc::size_t,
+                prot,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Linux only supports MADV_FREE for anonymous memory, there
+    // file pages are removed instead, like punching a hole, and
+    // changes of a copy-on-write mapping are dropped.
+    fn free(&self, offset: u64, len: u64) -> Result<(), Error> {
+        let addr = unsafe { self.ptr().add(offset as usize).cast::<libc::c_void>() };
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_vendor = "apple",
+            target_os = "freebsd",
+            target_os = "dragonfly",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        ))]
+        if unsafe { libc::madvise(addr, len as libc::size_t, libc::MADV_FREE) } == 0 {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let advice = if self.private {
+                libc::MADV_DONTNEED
+            } else {
+                libc::MADV_REMOVE
+            };
+            if unsafe { libc::madvise(addr, len as libc::size_t, advice) } == 0 {
+                return Ok(());
+            }
+            Err(Error::last_os_error())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (addr, len);
+            Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "freeing pages of a file mapping is not supported",
+            ))
+        }
+    }
+
+    // Only a hint, skipped where the system does not support it.
+    fn cold(&self, offset: u64, len: u64) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        {
+            let addr = unsafe { self.ptr().add(offset as usize).cast::<libc::c_void>() };
+            if unsafe { libc::madvise(addr, len as libc::size_t, libc::MADV_COLD) } != 0 {
+                let err = Error::last_os_error();
+                // Before Linux 5.4, and for huge pages
+                if err.raw_os_error() != Some(libc::EINVAL) {
+                    return Err(err);
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (offset, len);
+        Ok(())
+    }
+
+    pub fn advise(
+        &self,
+        file: Option<&File>,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> Result<(), Error> {
+        if self.private && advice == Advice::DontNeed {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "dropping pages of a copy-on-write mapping would lose changes",
+            ));
+        }
+        let (offset, len) = super::page_range(offset, len);
+        let advice = match advice {
+            Advice::WillNeed => libc::MADV_WILLNEED,
+            Advice::DontNeed => libc::MADV_DONTNEED,
+            Advice::Free => return self.free(offset, len),
+            Advice::Cold => return self.cold(offset, len),
+        };
+        let ret = unsafe {
+            libc::madvise(
+                self.ptr().add(offset as usize).cast::<libc::c_void>(),
+                len as libc::size_t,
+                advice,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        // Unmapped pages still stay in the page cache
+        #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+        if let Some(file) = file.filter(|_| advice == libc::MADV_DONTNEED) {
+            let ret = unsafe {
+                libc::posix_fadvise(
+                    file.as_raw_fd(),
+                    (self.offset + offset) as libc::off_t,
+                    len as libc::off_t,
+                    libc::POSIX_FADV_DONTNEED,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::from_raw_os_error(ret));
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly")))]
+        let _ = file;
+        Ok(())
+    }
+
+    // For the whole reservation and file.
+    pub fn set_access_pattern(
+        &self,
+        file: Option<&File>,
+        pattern: AccessPattern,
+    ) -> Result<(), Error> {
+        let advice = match pattern {
+            AccessPattern::Normal => libc::MADV_NORMAL,
+            AccessPattern::Sequential => libc::MADV_SEQUENTIAL,
+            AccessPattern::Random => libc::MADV_RANDOM,
+        };
+        let ret = unsafe {
+            libc::madvise(
+                self.ptr().cast::<libc::c_void>(),
+                self.len() as libc::size_t,
+                advice,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        self.access_pattern.store(advice, SeqCst);
+        // Readahead of reads from the file, e.g. by DiskAlloc::snapshot
+        #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+        if let Some(file) = file {
+            let advice = match pattern {
+                AccessPattern::Normal => libc::POSIX_FADV_NORMAL,
+                AccessPattern::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+                AccessPattern::Random => libc::POSIX_FADV_RANDOM,
+            };
+            let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+            if ret != 0 {
+                return Err(Error::from_raw_os_error(ret));
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly")))]
+        let _ = file;
+        Ok(())
+    }
+
+    pub fn flush(&self, offset: u64, len: u64) -> Result<(), Error> {
+        self.msync(offset, len, libc::MS_SYNC)
+    }
+
+    // Only start writing back, do not wait for it.
+    pub fn flush_async(&self, offset: u64, len: u64) -> Result<(), Error> {
+        self.msync(offset, len, libc::MS_ASYNC)
+    }
+
+    fn msync(&self, offset: u64, len: u64, flags: libc::c_int) -> Result<(), Error> {
+        let (offset, len) = super::page_range(offset, len);
+        let ret = unsafe {
+            libc::msync(
+                self.ptr().add(offset as usize).cast::<libc::c_void>(),
+                len as libc::size_t,
+                flags,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self, _file: Option<&File>, _file_len: u64) {
+        if self.ptr().is_null() {
+            return;
+        }
+        unsafe {
+            libc::munmap(
+                self.ptr().cast::<libc::c_void>(),
+                self.len() as libc::size_t,
+            );
+        }
+        self.ptr.store(std::ptr::null_mut(), SeqCst);
+    }
+}
+
+// Unnamed file in `dir`, which can still get a name with `link_file`.
+#[cfg(target_os = "linux")]
+pub fn tempfile_in(dir: &std::path::Path) -> Result<File, Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_TMPFILE)
+        .open(dir)
+        .or_else(|err| match err.raw_os_error() {
+            // Not supported by the file system or kernel
+            Some(libc::EOPNOTSUPP | libc::EISDIR | libc::ENOENT) => tempfile::tempfile_in(dir),
+            _ => Err(err),
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tempfile_in(dir: &std::path::Path) -> Result<File, Error> {
+    tempfile::tempfile_in(dir)
+}
+
+// Give a file created by `tempfile_in` a name.
+#[cfg(target_os = "linux")]
+pub fn link_file(file: &File, path: &std::path::Path) -> Result<(), Error> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let fd = format!("/proc/self/fd/{}", file.as_raw_fd());
+    let from = CString::new(fd).unwrap();
+    let to = CString::new(path.as_os_str().as_bytes()).map_err(Error::other)?;
+    // Linking the fd itself needs CAP_DAC_READ_SEARCH, following /proc does not
+    let result = unsafe {
+        libc::linkat(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::AT_SYMLINK_FOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn link_file(_file: &File, _path: &std::path::Path) -> Result<(), Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "unnamed files can only be linked on Linux",
+    ))
+}
+
+// Where an open file is, if it has a name at all.
+#[cfg(target_os = "linux")]
+pub fn file_path(file: &File) -> Result<std::path::PathBuf, Error> {
+    use std::os::unix::fs::MetadataExt;
+    if file.metadata()?.nlink() == 0 {
+        return Err(Error::new(std::io::ErrorKind::NotFound, "file was deleted"));
+    }
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn file_path(_file: &File) -> Result<std::path::PathBuf, Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "paths of open files are only known on Linux",
+    ))
+}
+
+// File only living in memory, for allocators not touching the disk.
+// `None` where there are none, anonymous memory is mapped instead.
+#[cfg(all(any(target_os = "linux", target_os = "freebsd"), not(miri)))]
+pub fn memory_file() -> Result<Option<File>, Error> {
+    use std::os::fd::FromRawFd;
+    let fd = unsafe { libc::memfd_create(c"diskallocator".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        let err = Error::last_os_error();
+        // Before Linux 3.17, or filtered by seccomp
+        if err.raw_os_error() == Some(libc::ENOSYS) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(unsafe { File::from_raw_fd(fd) }))
+}
+
+#[cfg(any(not(any(target_os = "linux", target_os = "freebsd")), miri))]
+pub fn memory_file() -> Result<Option<File>, Error> {
+    Ok(None)
+}
+
+// POSIX shared memory object called `name`, without the leading slash.
+// Created empty (or truncated) for writing, or opened read-only.
+// Elsewhere, shared memory objects can not be resized once mapped.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn shm_open(name: &str, create: bool) -> Result<File, Error> {
+    use std::os::fd::FromRawFd;
+    let name = shm_name(name)?;
+    let (flags, mode) = match create {
+        true => (libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC, 0o600),
+        false => (libc::O_RDONLY, 0),
+    };
+    let fd = unsafe { libc::shm_open(name.as_ptr(), flags | libc::O_CLOEXEC, mode) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn shm_unlink(name: &str) -> Result<(), Error> {
+    let name = shm_name(name)?;
+    if unsafe { libc::shm_unlink(name.as_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn shm_name(name: &str) -> Result<std::ffi::CString, Error> {
+    if name.is_empty() || name.contains('/') {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "shared memory names are not empty and have no slash",
+        ));
+    }
+    std::ffi::CString::new(format!("/{name}"))
+        .map_err(|err| Error::new(std::io::ErrorKind::InvalidInput, err))
+}
+
+// Bytes the file system of `file` still has for unprivileged users.
+pub fn free_space(file: &File) -> Result<u64, Error> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Set the mode bits of a created file, and its owner if given.
+// Unlike the mode passed when creating, the umask does not apply.
+pub fn set_permissions(
+    file: &File,
+    mode: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::fchown(file, uid, gid)?;
+    }
+    // After changing the owner, which may clear the setuid and setgid bits
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+}
+
+// Bytes of disk space the file takes, less than its length if sparse.
+pub fn disk_usage(file: &File) -> Result<u64, Error> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(file.metadata()?.blocks() * 512)
+}
+
+// Make `dest` share the blocks of `src` from `offset` to its end
+// (a reflink, e.g. on Btrfs and XFS), without copying any data.
+// Fails if the file system can not, or the files are on different ones.
+#[cfg(target_os = "linux")]
+pub fn clone_range(src: &File, offset: u64, dest: &File) -> Result<(), Error> {
+    let range = libc::file_clone_range {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset: offset,
+        // Up to the end of the file
+        src_length: 0,
+        dest_offset: 0,
+    };
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONERANGE, &range) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clone_range(_src: &File, _offset: u64, _dest: &File) -> Result<(), Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "ranges of files can only be cloned on Linux",
+    ))
+}
+
+// Files can only be mapped from multiples of `granularity`.
+fn check_offset(offset: u64, granularity: u64) -> Result<(), Error> {
+    if !offset.is_multiple_of(granularity) {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("file offset {offset} is not a multiple of {granularity} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+// Inaccessible address space of `len` bytes, starting at a multiple of `align`.
+fn reserve(len: u64, align: u64) -> Result<*mut u8, Error> {
+    let extra = align.saturating_sub(page_size());
+    super::check_map_len(len + extra)?;
+    let total = (len + extra) as libc::size_t;
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            total,
+            libc::PROT_NONE,
+            RESERVE_FLAGS,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(Error::last_os_error());
+    }
+    let head = addr.addr().next_multiple_of(align as usize) - addr.addr();
+    let tail = total - head - len as usize;
+    let start = unsafe { addr.cast::<u8>().add(head) };
+    unsafe {
+        if head > 0 {
+            libc::munmap(addr, head);
+        }
+        if tail > 0 {
+            libc::munmap(start.add(len as usize).cast::<libc::c_void>(), tail);
+        }
+    }
+    Ok(start)
+}
+
+// Both types differ between architectures
+#[cfg(target_os = "linux")]
+#[allow(clippy::unnecessary_cast)]
+fn on_hugetlbfs(file: &File) -> Result<bool, Error> {
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat.f_type as i64 == libc::HUGETLBFS_MAGIC as i64)
+}
@@ -0,0 +1,490 @@
+// Windows can not map more than the size of the file,
+// and files can not be truncated while mapped.
+// So the file is made sparse and extended to the whole
+// reservation up front, which costs no disk space.
+// Released regions are zeroed by hand instead of truncated,
+// and the file gets its real size back on close.
+// Without a file, the view is of a section backed by the page file,
+// only reserved up front and committed as it grows.
+
+use super::{Advice, MapOptions};
+use crate::AccessPattern;
+use std::{fs::File, io::Error, os::windows::io::AsRawHandle};
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        GetCompressedFileSizeW, GetDiskFreeSpaceExW, GetFinalPathNameByHandleW,
+        FILE_NAME_NORMALIZED, INVALID_FILE_SIZE,
+    },
+    System::{
+        Ioctl::FSCTL_SET_SPARSE,
+        Memory::{
+            CreateFileMappingW, FlushViewOfFile, MapViewOfFile, PrefetchVirtualMemory,
+            UnmapViewOfFile, VirtualAlloc, VirtualProtect, VirtualUnlock, FILE_MAP_ALL_ACCESS,
+            FILE_MAP_READ, MEMORY_MAPPED_VIEW_ADDRESS, MEM_COMMIT, PAGE_NOACCESS, PAGE_READONLY,
+            PAGE_READWRITE, SEC_RESERVE, WIN32_MEMORY_RANGE_ENTRY,
+        },
+        SystemInformation::{GetSystemInfo, SYSTEM_INFO},
+        Threading::GetCurrentProcess,
+        IO::DeviceIoControl,
+    },
+};
+
+pub(crate) fn page_size() -> u64 {
+    let mut info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+    unsafe { GetSystemInfo(&mut info) };
+    info.dwPageSize as u64
+}
+
+pub(crate) struct Mapping {
+    ptr: *mut u8,
+    handle: HANDLE,
+    // Bytes covered by the view
+    len: u64,
+    read_only: bool,
+    // Backed by the page file, pages are committed as it grows
+    anonymous: bool,
+    // Start of the view in the file
+    offset: u64,
+    // See MapOptions::shared_file
+    shared_file: bool,
+}
+
+impl Mapping {
+    // Large pages only work for anonymous mappings on Windows,
+    // so `options.huge_pages` is ignored.
+    pub fn new(file: Option<&File>, len: u64, options: &MapOptions) -> Result<Self, Error> {
+        let mut info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+        unsafe { GetSystemInfo(&mut info) };
+        let granularity = info.dwAllocationGranularity as u64;
+        let offset = options.offset;
+        super::check_map_len(len)?;
+        if !offset.is_multiple_of(granularity) {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("file offset {offset} is not a multiple of {granularity} bytes"),
+            ));
+        }
+        let Some(file) = file else {
+            return Self::map_anonymous(len);
+        };
+        if options.read_only {
+            return Self::map_read_only(file, offset, options.populate);
+        }
+        if options.private {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "copy-on-write mappings are not supported on Windows",
+            ));
+        }
+        let file_handle = file.as_raw_handle().addr() as HANDLE;
+        let mut returned = 0;
+        let sparse = unsafe {
+            DeviceIoControl(
+                file_handle,
+                FSCTL_SET_SPARSE,
+                std::ptr::null(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if sparse == 0 {
+            return Err(Error::last_os_error());
+        }
+        // The file is extended to the whole view below
+        let file_len = file.metadata()?.len().saturating_sub(offset).min(len);
+        let mapped_len = offset + len;
+        let handle = unsafe {
+            CreateFileMappingW(
+                file_handle,
+                std::ptr::null(),
+                PAGE_READWRITE,
+                (mapped_len >> 32) as u32,
+                mapped_len as u32,
+                std::ptr::null(),
+            )
+        };
+        if handle == 0 {
+            return Err(Error::last_os_error());
+        }
+        let view = unsafe {
+            MapViewOfFile(
+                handle,
+                FILE_MAP_ALL_ACCESS,
+                (offset >> 32) as u32,
+                offset as u32,
+                len as usize,
+            )
+        };
+        if view.Value.is_null() {
+            let err = Error::last_os_error();
+            unsafe { CloseHandle(handle) };
+            return Err(err);
+        }
+        if options.populate {
+            unsafe { super::touch_pages(view.Value.cast::<u8>(), file_len) };
+        }
+        Ok(Self {
+            ptr: view.Value.cast::<u8>(),
+            handle,
+            len,
+            read_only: false,
+            anonymous: false,
+            offset,
+            shared_file: options.shared_file,
+        })
+    }
+
+    // Reserving does not count against the commit limit,
+    // so the section can be as big as the reservation.
+    fn map_anonymous(len: u64) -> Result<Self, Error> {
+        let handle = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                std::ptr::null(),
+                PAGE_READWRITE | SEC_RESERVE,
+                (len >> 32) as u32,
+                len as u32,
+                std::ptr::null(),
+            )
+        };
+        if handle == 0 {
+            return Err(Error::last_os_error());
+        }
+        let view = unsafe { MapViewOfFile(handle, FILE_MAP_ALL_ACCESS, 0, 0, len as usize) };
+        if view.Value.is_null() {
+            let err = Error::last_os_error();
+            unsafe { CloseHandle(handle) };
+            return Err(err);
+        }
+        Ok(Self {
+            ptr: view.Value.cast::<u8>(),
+            handle,
+            len,
+            read_only: false,
+            anonymous: true,
+            offset: 0,
+            shared_file: false,
+        })
+    }
+
+    // Read-only files can not be extended, so only the file itself is mapped.
+    fn map_read_only(file: &File, offset: u64, populate: bool) -> Result<Self, Error> {
+        let len = file.metadata()?.len().saturating_sub(offset);
+        super::check_map_len(len)?;
+        let file_handle = file.as_raw_handle().addr() as HANDLE;
+        let handle = unsafe {
+            CreateFileMappingW(
+                file_handle,
+                std::ptr::null(),
+                PAGE_READONLY,
+                0,
+                0,
+                std::ptr::null(),
+            )
+        };
+        if handle == 0 {
+            return Err(Error::last_os_error());
+        }
+        let view = unsafe {
+            MapViewOfFile(
+                handle,
+                FILE_MAP_READ,
+                (offset >> 32) as u32,
+                offset as u32,
+                0,
+            )
+        };
+        if view.Value.is_null() {
+            let err = Error::last_os_error();
+            unsafe { CloseHandle(handle) };
+            return Err(err);
+        }
+        if populate {
+            unsafe { super::touch_pages(view.Value.cast::<u8>(), len) };
+        }
+        Ok(Self {
+            ptr: view.Value.cast::<u8>(),
+            handle,
+            len,
+            read_only: true,
+            anonymous: false,
+            offset,
+            shared_file: false,
+        })
+    }
+
+    pub fn ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn resize_granularity(&self) -> u64 {
+        1
+    }
+
+    // Length of the file after the offset.
+    pub fn file_len(&self, file: Option<&File>) -> Result<u64, Error> {
+        let Some(file) = file.filter(|_| !self.shared_file) else {
+            // Belongs to the other mappings, or there is none
+            return Ok(0);
+        };
+        Ok(file.metadata()?.len().saturating_sub(self.offset))
+    }
+
+    // The view can only grow by mapping the file somewhere else,
+    // which does not work while it is extended to the reservation.
+    pub fn grow_reservation(&self, _file: Option<&File>, _len: u64) -> Result<(), Error> {
+        Err(Error::new(
+            std::io::ErrorKind::Unsupported,
+            "mappings can not be moved on Windows",
+        ))
+    }
+
+    // The view of a read-only file can not grow.
+    pub fn follow_file_len(&self, _file: Option<&File>, file_len: u64) -> Result<(), Error> {
+        if file_len > self.len {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "read-only mappings can not grow on Windows",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_file_len(
+        &self,
+        _file: Option<&File>,
+        old_len: u64,
+        new_len: u64,
+    ) -> Result<(), Error> {
+        if self.anonymous && new_len > old_len {
+            let start = unsafe { self.ptr.add(old_len as usize) };
+            let committed = unsafe {
+                VirtualAlloc(
+                    start.cast(),
+                    (new_len - old_len) as usize,
+                    MEM_COMMIT,
+                    PAGE_READWRITE,
+                )
+            };
+            if committed.is_null() {
+                return Err(Error::last_os_error());
+            }
+        }
+        if new_len < old_len {
+            // Later allocations expect fresh space to be zeroed
+            unsafe {
+                std::ptr::write_bytes(
+                    self.ptr.add(new_len as usize),
+                    0,
+                    (old_len - new_len) as usize,
+                )
+            };
+        }
+        Ok(())
+    }
+
+    // Not supported yet, the space stays allocated.
+    pub fn punch_hole(&self, _file: Option<&File>, _offset: u64, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn protect(&self, offset: u64, len: u64, accessible: bool) -> Result<(), Error> {
+        let (offset, len) = super::page_range(offset, len);
+        let prot = if accessible {
+            PAGE_READWRITE
+        } else {
+            PAGE_NOACCESS
+        };
+        let mut old = 0;
+        let ret = unsafe {
+            VirtualProtect(
+                self.ptr.add(offset as usize).cast(),
+                len as usize,
+                prot,
+                &mut old,
+            )
+        };
+        if ret == 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn advise(
+        &self,
+        _file: Option<&File>,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> Result<(), Error> {
+        let (offset, len) = super::page_range(offset, len);
+        match advice {
+            Advice::WillNeed => {
+                let range = WIN32_MEMORY_RANGE_ENTRY {
+                    VirtualAddress: unsafe { self.ptr.add(offset as usize).cast() },
+                    NumberOfBytes: len as usize,
+                };
+                let ret = unsafe { PrefetchVirtualMemory(GetCurrentProcess(), 1, &range, 0) };
+                if ret == 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            // Views of files can not be discarded, only left.
+            // Left pages stay cached until the memory is needed.
+            Advice::DontNeed | Advice::Free | Advice::Cold => {
+                // Unlocking pages which are not locked removes them
+                // from the working set, the error is expected.
+                unsafe { VirtualUnlock(self.ptr.add(offset as usize).cast(), len as usize) };
+            }
+        }
+        Ok(())
+    }
+
+    // Views have no access hints, FILE_FLAG_SEQUENTIAL_SCAN
+    // only applies to reads and has to be given on open.
+    pub fn set_access_pattern(
+        &self,
+        _file: Option<&File>,
+        _pattern: AccessPattern,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // FlushViewOfFile does not wait for the disk,
+    // callers sync the file afterwards.
+    pub fn flush(&self, offset: u64, len: u64) -> Result<(), Error> {
+        self.flush_async(offset, len)
+    }
+
+    pub fn flush_async(&self, offset: u64, len: u64) -> Result<(), Error> {
+        let start = unsafe { self.ptr.add(offset as usize) };
+        if unsafe { FlushViewOfFile(start.cast(), len as usize) } == 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self, file: Option<&File>, file_len: u64) {
+        if self.ptr.is_null() {
+            return;
+        }
+        unsafe {
+            UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS {
+                Value: self.ptr.cast(),
+            });
+            CloseHandle(self.handle);
+        }
+        self.ptr = std::ptr::null_mut();
+        if let Some(file) = file.filter(|_| !self.read_only && !self.shared_file) {
+            // Nobody to report to, the file is just bigger than needed
+            let _ = file.set_len(self.offset + file_len);
+        }
+    }
+}
+
+pub fn tempfile_in(dir: &std::path::Path) -> Result<File, Error> {
+    tempfile::tempfile_in(dir)
+}
+
+pub fn link_file(_file: &File, _path: &std::path::Path) -> Result<(), Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "unnamed files can only be linked on Linux",
+    ))
+}
+
+// Where an open file is.
+pub fn file_path(file: &File) -> Result<std::path::PathBuf, Error> {
+    use std::os::windows::ffi::OsStringExt;
+    let path = final_path(file)?;
+    Ok(std::ffi::OsString::from_wide(&path).into())
+}
+
+pub fn clone_range(_src: &File, _offset: u64, _dest: &File) -> Result<(), Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "ranges of files can only be cloned on Linux",
+    ))
+}
+
+// There are no files only living in memory, anonymous memory is mapped instead.
+pub fn memory_file() -> Result<Option<File>, Error> {
+    Ok(None)
+}
+
+// Bytes the volume of `file` still has for the current user.
+pub fn free_space(file: &File) -> Result<u64, Error> {
+    let mut path = final_path(file)?;
+    // Only directories can be asked, keep the trailing backslash
+    let dir_len = path
+        .iter()
+        .rposition(|&c| c == u16::from(b'\\'))
+        .map_or(path.len(), |i| i + 1);
+    path.truncate(dir_len);
+    path.push(0);
+    let mut free = 0;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut free,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(free)
+}
+
+// Files get the permissions of the directory, there are no mode bits.
+pub fn set_permissions(
+    _file: &File,
+    _mode: u32,
+    _uid: Option<u32>,
+    _gid: Option<u32>,
+) -> Result<(), Error> {
+    Ok(())
+}
+
+// Bytes of disk space the file takes, less than its length if sparse.
+pub fn disk_usage(file: &File) -> Result<u64, Error> {
+    let mut path = final_path(file)?;
+    path.push(0);
+    let mut high = 0;
+    let low = unsafe { GetCompressedFileSizeW(path.as_ptr(), &mut high) };
+    if low == INVALID_FILE_SIZE {
+        // Also a valid low half of the size
+        let err = Error::last_os_error();
+        if err.raw_os_error() != Some(0) {
+            return Err(err);
+        }
+    }
+    Ok((high as u64) << 32 | low as u64)
+}
+
+// Path of an open file, without the terminating zero.
+fn final_path(file: &File) -> Result<Vec<u16>, Error> {
+    let mut path = vec![0u16; 32 * 1024];
+    let len = unsafe {
+        GetFinalPathNameByHandleW(
+            file.as_raw_handle().addr() as HANDLE,
+            path.as_mut_ptr(),
+            path.len() as u32,
+            FILE_NAME_NORMALIZED,
+        )
+    } as usize;
+    if len == 0 || len >= path.len() {
+        return Err(Error::last_os_error());
+    }
+    path.truncate(len);
+    Ok(path)
+}
@@ -0,0 +1,26 @@
+// Needs its own binary, as it replaces the allocator of all tests.
+#![cfg(feature = "global-alloc")]
+
+use diskallocator::DiskGlobalAlloc;
+use std::collections::HashMap;
+
+#[global_allocator]
+static GLOBAL: DiskGlobalAlloc = DiskGlobalAlloc::with_reservation(16 * 1024 * 1024 * 1024);
+
+#[test]
+fn everything_on_disk() {
+    let mut map = HashMap::new();
+    for i in 0..10_000u64 {
+        map.insert(i.to_string(), vec![i; 16]);
+    }
+    for i in (0..10_000u64).step_by(2) {
+        map.remove(&i.to_string());
+    }
+    assert_eq!(map.len(), 5_000);
+    assert!(map
+        .iter()
+        .all(|(k, v)| v.len() == 16 && *k == v[0].to_string()));
+    let stats = GLOBAL.stats().unwrap();
+    assert!(stats.live_allocations >= 10_000);
+    assert!(stats.used_size > 5_000 * 16 * 8);
+}
@@ -1,7 +1,13 @@
 #![feature(allocator_api)]
+#![feature(cfg_sanitize)]
 
-use diskallocator::{self, DiskAlloc};
+use diskallocator::{
+    self, AccessPattern, DiskAlloc, DiskAllocBuilder, DiskAllocError, DiskArena, DiskBox,
+    DiskBudget, DiskBuf, DiskSlab, DiskVec, DropBehavior, FallbackAlloc, MemoryPressure, Owns,
+    ShardedDiskAlloc, SpillAlloc, Strategy,
+};
 use rand::Rng;
+use std::collections::VecDeque;
 
 #[test]
 fn fill_slowly() {
@@ -105,3 +111,2215 @@ fn large_memory_vec() {
         assert_eq!(v[byte as usize], byte);
     }
 }
+
+// Guard pages change the file layout
+#[cfg(not(feature = "debug-guards"))]
+#[test]
+fn small_reservation() {
+    let file = tempfile::tempfile().unwrap();
+    let alloc = DiskAlloc::on_file_with_reservation(file, 1024 * 1024).unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc);
+    v.resize(1024 * 1024, 7);
+    assert!(v.try_reserve(1).is_err());
+}
+
+#[test]
+fn chunked_growth_and_trim() {
+    let alloc = DiskAllocBuilder::new()
+        .growth_chunk(1024 * 1024)
+        .build()
+        .unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.resize(1536 * 1024, 1);
+    assert_eq!(alloc.stats().file_size, 2 * 1024 * 1024);
+    v.truncate(1024);
+    v.shrink_to_fit();
+    assert_eq!(alloc.stats().file_size, 2 * 1024 * 1024);
+    alloc.trim().unwrap();
+    let stats = alloc.stats();
+    assert_eq!(stats.file_size, stats.used_size);
+    assert!(v.iter().all(|&x| x == 1));
+}
+
+// Guard pages and canaries change the file layout
+#[cfg(not(any(feature = "debug-guards", feature = "debug-canaries")))]
+#[test]
+fn shrink_policies() {
+    use diskallocator::ShrinkPolicy;
+
+    let file_size_after_pop = |policy| {
+        let alloc = DiskAllocBuilder::new()
+            .shrink_policy(policy)
+            .build()
+            .unwrap();
+        let mut v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(100_000, alloc.clone());
+        v.resize(100_000, 1);
+        v.truncate(90_000);
+        v.shrink_to_fit();
+        let shrunk = alloc.stats().file_size;
+        v.truncate(50_000);
+        v.shrink_to_fit();
+        (shrunk, alloc.stats().file_size)
+    };
+    assert_eq!(file_size_after_pop(ShrinkPolicy::Never), (100_000, 100_000));
+    assert_eq!(
+        file_size_after_pop(ShrinkPolicy::Immediate),
+        (90_000, 50_000)
+    );
+    let hysteresis = ShrinkPolicy::Hysteresis { slack: 20_000 };
+    assert_eq!(file_size_after_pop(hysteresis), (100_000, 50_000));
+}
+
+#[test]
+fn concurrent_vecs() {
+    let alloc = DiskAllocBuilder::new()
+        .growth_chunk(64 * 1024 * 1024)
+        .build()
+        .unwrap();
+    let threads: Vec<_> = (0..4u64)
+        .map(|t| {
+            let alloc = alloc.clone();
+            std::thread::spawn(move || {
+                let mut v: Vec<u64, DiskAlloc> = Vec::new_in(alloc);
+                for i in 0..100_000 {
+                    v.push(t * i);
+                }
+                v
+            })
+        })
+        .collect();
+    for (t, thread) in threads.into_iter().enumerate() {
+        let v = thread.join().unwrap();
+        assert!(v.iter().enumerate().all(|(i, &x)| x == t as u64 * i as u64));
+    }
+}
+
+#[test]
+fn sharded_threads() {
+    let alloc = ShardedDiskAlloc::new(4).unwrap();
+    assert_eq!(alloc.shards().len(), 4);
+    let threads: Vec<_> = (0..8u64)
+        .map(|t| {
+            let alloc = alloc.clone();
+            std::thread::spawn(move || {
+                let mut v: Vec<u64, ShardedDiskAlloc> = Vec::new_in(alloc);
+                for i in 0..100_000 {
+                    v.push(t * i);
+                }
+                v
+            })
+        })
+        .collect();
+    let vecs: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(alloc.stats().live_allocations, 8);
+    for (t, v) in vecs.iter().enumerate() {
+        assert!(v.iter().enumerate().all(|(i, &x)| x == t as u64 * i as u64));
+    }
+    // Freed on another thread than allocated
+    drop(vecs);
+    assert_eq!(alloc.stats().live_allocations, 0);
+}
+
+#[test]
+fn hooks() {
+    use std::sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    };
+    let (grown, allocs, errors) = (
+        Arc::new(AtomicU64::new(0)),
+        Arc::new(AtomicUsize::new(0)),
+        Arc::new(AtomicUsize::new(0)),
+    );
+    let alloc = DiskAllocBuilder::new()
+        .reservation(1024 * 1024)
+        .chain_segments(false)
+        .on_grow({
+            let grown = grown.clone();
+            move |file_size| {
+                grown.fetch_max(file_size, Ordering::SeqCst);
+            }
+        })
+        .on_alloc({
+            let allocs = allocs.clone();
+            move |_| {
+                allocs.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .on_error({
+            let errors = errors.clone();
+            move |err| {
+                assert!(matches!(err, DiskAllocError::ReservationExhausted));
+                errors.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .build()
+        .unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1000, alloc);
+    v.reserve_exact(5000);
+    assert_eq!(allocs.load(Ordering::SeqCst), 2);
+    assert!(grown.load(Ordering::SeqCst) >= 5000);
+    assert!(v.try_reserve_exact(2 * 1024 * 1024).is_err());
+    assert_eq!(errors.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn remaining_reservation() {
+    let alloc = DiskAllocBuilder::new()
+        .reservation(1024 * 1024)
+        .max_file_size(512 * 1024)
+        .build()
+        .unwrap();
+    assert_eq!(alloc.remaining_reservation(), 1024 * 1024);
+    assert!(alloc.headroom_on_disk().unwrap() <= 512 * 1024);
+    let v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(100 * 1024, alloc.clone());
+    let used = alloc.stats().used_size;
+    assert!(used >= 100 * 1024);
+    assert_eq!(alloc.remaining_reservation(), 1024 * 1024 - used);
+    assert!(alloc.headroom_on_disk().unwrap() <= 512 * 1024 - 100 * 1024);
+    drop(v);
+}
+
+#[test]
+fn disk_usage() {
+    let alloc = DiskAlloc::new().unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.resize(1 << 20, 1);
+    v.allocator().flush().unwrap();
+    assert!(alloc.disk_usage().unwrap() >= 1 << 20);
+}
+
+#[test]
+fn debug_shows_diagnostics() {
+    let alloc = DiskAllocBuilder::new()
+        .name("debug test")
+        .reservation(1 << 30)
+        .build()
+        .unwrap();
+    let v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(100, alloc.clone());
+    let debug = format!("{alloc:?}");
+    assert!(debug.starts_with("DiskAlloc { name: \"debug test\", backing_path: "));
+    assert!(debug.contains("reservation: 1073741824,"), "{debug}");
+    assert!(debug.contains(&format!("file_size: {},", alloc.stats().file_size)));
+    assert!(debug.ends_with("live_allocations: 1 }"), "{debug}");
+    drop(v);
+}
+
+#[test]
+fn registry_lists_live_allocators() {
+    let find = |name: &str| {
+        diskallocator::registry()
+            .into_iter()
+            .filter(|info| info.name.as_deref() == Some(name))
+            .collect::<Vec<_>>()
+    };
+    let alloc = DiskAllocBuilder::new()
+        .name("registry test")
+        .prefix("registry")
+        .build()
+        .unwrap();
+    assert_eq!(alloc.name(), Some("registry test"));
+    let v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(100_000, alloc.clone());
+    let found = find("registry test");
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].stats.file_size, alloc.stats().file_size);
+    assert_eq!(found[0].stats.live_allocations, 1);
+    assert_eq!(found[0].backing_path, alloc.backing_path());
+    drop(v);
+    drop(alloc);
+    assert!(find("registry test").is_empty());
+}
+
+#[test]
+fn shared_disk_budget() {
+    use std::sync::{Arc, Mutex};
+
+    let budget = DiskBudget::new(3 * 1024 * 1024);
+    let builder = DiskAllocBuilder::new().budget(&budget);
+    let a = builder.build().unwrap();
+    let b = builder.build().unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(a);
+    v.resize(2 * 1024 * 1024, 1);
+    let mut w: Vec<u8, DiskAlloc> = Vec::new_in(b.clone());
+    assert!(w.try_reserve_exact(2 * 1024 * 1024).is_err());
+    assert!(matches!(
+        b.take_error(),
+        Some(DiskAllocError::QuotaExceeded)
+    ));
+    assert_eq!(b.headroom_on_disk().unwrap(), budget.remaining());
+
+    // Making space from the hook lets the allocation go ahead
+    let old = Arc::new(Mutex::new(Some(v)));
+    let missing = Arc::new(Mutex::new(0));
+    let budget = budget.on_exceeded({
+        let (old, missing) = (old.clone(), missing.clone());
+        move |bytes| {
+            *missing.lock().unwrap() = bytes;
+            old.lock().unwrap().take();
+        }
+    });
+    w.try_reserve_exact(2 * 1024 * 1024).unwrap();
+    assert!(old.lock().unwrap().is_none());
+    assert!(*missing.lock().unwrap() > 0);
+    drop(w);
+    drop(b);
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+fn max_file_size() {
+    let alloc = DiskAllocBuilder::new()
+        .reservation(1024 * 1024)
+        .max_file_size(3 * 1024 * 1024)
+        .build()
+        .unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.resize(2 * 1024 * 1024, 1);
+    assert!(v.try_reserve_exact(2 * 1024 * 1024).is_err());
+    assert!(matches!(
+        alloc.take_error(),
+        Some(DiskAllocError::QuotaExceeded)
+    ));
+    assert!(alloc.stats().file_size <= 3 * 1024 * 1024);
+    drop(v);
+    // Freed space counts again
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc);
+    v.resize(2 * 1024 * 1024, 1);
+}
+
+#[test]
+fn allocation_errors() {
+    let alloc = DiskAllocBuilder::new()
+        .reservation(1024 * 1024)
+        .chain_segments(false)
+        .build()
+        .unwrap();
+    assert!(alloc.take_error().is_none());
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    assert!(v.try_reserve_exact(2 * 1024 * 1024).is_err());
+    assert!(matches!(
+        alloc.take_error(),
+        Some(DiskAllocError::ReservationExhausted)
+    ));
+    assert!(alloc.take_error().is_none());
+    let layout = std::alloc::Layout::from_size_align(8, 1 << 40).unwrap();
+    assert!(std::alloc::Allocator::allocate(&alloc, layout).is_err());
+    assert!(matches!(
+        alloc.take_error(),
+        Some(DiskAllocError::UnsupportedLayout)
+    ));
+}
+
+#[test]
+fn new_in_memory() {
+    let alloc = DiskAlloc::new_in_memory().unwrap();
+    let mut v: Vec<u64, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.extend(0..100_000);
+    assert!(v.iter().copied().eq(0..100_000));
+    assert!(alloc.stats().file_size >= 800_000);
+    alloc.flush().unwrap();
+}
+
+#[test]
+fn background_flush() {
+    let alloc = DiskAllocBuilder::new()
+        .background_flush(std::time::Duration::from_millis(1), 64 * 1024)
+        .build()
+        .unwrap();
+    let mut v: Vec<u64, DiskAlloc> = Vec::new_in(alloc);
+    for i in 0..1_000_000 {
+        v.push(i);
+        if i % 100_000 == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+    }
+    assert!(v.iter().copied().eq(0..1_000_000));
+}
+
+#[test]
+fn with_capacity() {
+    let alloc = DiskAlloc::with_capacity(8 * 1024 * 1024).unwrap();
+    let stats = alloc.stats();
+    assert_eq!(stats.file_size, 8 * 1024 * 1024);
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    for i in 0..1024 * 1024 {
+        v.push(i as u8);
+    }
+    assert_eq!(alloc.stats().resize_syscalls, stats.resize_syscalls);
+    drop(v);
+    alloc.trim().unwrap();
+    assert_eq!(alloc.stats().file_size, 8 * 1024 * 1024);
+}
+
+// Guarded blocks never grow in place
+#[cfg(not(feature = "debug-guards"))]
+#[test]
+fn grow_reservation() {
+    let alloc = DiskAllocBuilder::new()
+        .reservation(1024 * 1024)
+        .chain_segments(false)
+        .grow_reservation(true)
+        .build()
+        .unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    // Another allocation keeps the mapping in place
+    let other: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1, alloc.clone());
+    assert!(v.try_reserve_exact(2 * 1024 * 1024).is_err());
+    drop(other);
+    for i in 0..4 * 1024 * 1024 {
+        v.push(i as u8);
+    }
+    assert!(v.iter().enumerate().all(|(i, &x)| x == i as u8));
+    assert_eq!(alloc.segment_count(), 1);
+    let reservation = alloc.remaining_reservation() + alloc.stats().used_size;
+    assert!(reservation >= 4 * 1024 * 1024);
+}
+
+#[test]
+fn chain_segments() {
+    let alloc = DiskAlloc::with_reservation(1024 * 1024).unwrap();
+    let mut small: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    small.resize(512 * 1024, 1);
+    let mut big: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    for i in 0..4 * 1024 * 1024 {
+        big.push(i as u8);
+    }
+    assert!(alloc.segment_count() > 1);
+    assert!(alloc.stats().used_size >= 4 * 1024 * 1024 + 512 * 1024);
+    assert!(small.iter().all(|&x| x == 1));
+    assert!(big.iter().enumerate().all(|(i, &x)| x == i as u8));
+    drop(big);
+    assert_eq!(alloc.segment_count(), 1);
+}
+
+// Guarded zero-size blocks take pages, keeping the segment
+#[cfg(not(feature = "debug-guards"))]
+#[test]
+fn zero_size_outlive_segment() {
+    use std::alloc::{Allocator, Layout};
+    let alloc = DiskAlloc::with_reservation(1024 * 1024).unwrap();
+    let mut small: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    small.resize(512 * 1024, 1);
+    let big = Layout::from_size_align(2 * 1024 * 1024, 1).unwrap();
+    let empty = Layout::from_size_align(0, 1).unwrap();
+    // Shrunk to nothing at the start of a chained segment
+    let shrunk: Vec<_> = (0..2)
+        .map(|_| unsafe {
+            let ptr = alloc.allocate(big).unwrap().cast::<u8>();
+            alloc.shrink(ptr, big, empty).unwrap().cast::<u8>()
+        })
+        .collect();
+    assert_eq!(alloc.segment_count(), 2);
+    unsafe {
+        let last = alloc.allocate(big).unwrap().cast::<u8>();
+        alloc.deallocate(last, big);
+        assert_eq!(alloc.segment_count(), 1);
+        alloc.deallocate(shrunk[0], empty);
+        let word = Layout::new::<u64>();
+        let grown = alloc
+            .grow_zeroed(shrunk[1], empty, word)
+            .unwrap()
+            .cast::<u64>();
+        assert_eq!(*grown.as_ptr(), 0);
+        alloc.deallocate(grown.cast(), word);
+    }
+    assert!(small.iter().all(|&x| x == 1));
+}
+
+#[test]
+fn stripe_dirs() {
+    let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let alloc = DiskAllocBuilder::new()
+        .stripe_dirs(dirs.iter().map(|dir| dir.path()))
+        .prefix("diskalloc-stripe")
+        .build()
+        .unwrap();
+    assert_eq!(alloc.segment_count(), 2);
+    let vecs: Vec<Vec<u64, DiskAlloc>> = (0..4)
+        .map(|_| {
+            let mut v = Vec::with_capacity_in(1000, alloc.clone());
+            v.extend(0..1000u64);
+            v
+        })
+        .collect();
+    for dir in &dirs {
+        let file = std::fs::read_dir(dir.path()).unwrap().next().unwrap();
+        assert!(file.unwrap().metadata().unwrap().len() >= 2 * 8000);
+    }
+    assert!(vecs.iter().all(|v| v.iter().copied().eq(0..1000)));
+}
+
+#[test]
+fn page_align_above() {
+    let alloc = DiskAllocBuilder::new()
+        .page_align_above(4096)
+        .build()
+        .unwrap();
+    let mut small: Vec<u8, DiskAlloc> = Vec::with_capacity_in(10, alloc.clone());
+    small.push(1);
+    let mut big: Vec<u8, DiskAlloc> = Vec::with_capacity_in(10_000, alloc.clone());
+    big.push(1);
+    assert_eq!(big.as_ptr() as usize % 4096, 0);
+    assert_eq!(alloc.stats().used_size % 4096, 0);
+    // Growing past the threshold moves to a page boundary
+    small.resize(5000, 2);
+    assert_eq!(small.as_ptr() as usize % 4096, 0);
+    assert_eq!((small[0], small[4999]), (1, 2));
+}
+
+// Guard pages change the file layout
+#[cfg(not(feature = "debug-guards"))]
+#[test]
+fn builder_prefix_and_chunk() {
+    let dir = tempfile::tempdir().unwrap();
+    let alloc = DiskAllocBuilder::new()
+        .dir(dir.path())
+        .prefix("diskalloc-test")
+        .growth_chunk(1024 * 1024)
+        .flush_on_drop(true)
+        .build()
+        .unwrap();
+    let mut v: Vec<u32, DiskAlloc> = Vec::new_in(alloc);
+    v.push(1);
+
+    let files: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap())
+        .collect();
+    assert_eq!(files.len(), 1);
+    let name = files[0].file_name().into_string().unwrap();
+    assert!(name.starts_with("diskalloc-test"));
+    assert_eq!(files[0].metadata().unwrap().len(), 1024 * 1024);
+
+    drop(v);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn file_mode() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::tempdir().unwrap();
+    let mode_of =
+        |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    let alloc = DiskAllocBuilder::new()
+        .dir(dir.path())
+        .prefix("diskalloc-test")
+        .build()
+        .unwrap();
+    let temp = std::fs::read_dir(dir.path()).unwrap().next().unwrap();
+    assert_eq!(mode_of(&temp.unwrap().path()), 0o600);
+    drop(alloc);
+
+    // Not limited by the umask
+    let path = dir.path().join("persistent");
+    let gid = unsafe { libc::getgid() };
+    let alloc = DiskAllocBuilder::new()
+        .mode(0o664)
+        .owner(None, Some(gid))
+        .create(&path)
+        .unwrap();
+    assert_eq!(mode_of(&path), 0o664);
+    drop(alloc);
+}
+
+// Guard pages and canaries change the file layout
+#[cfg(not(any(feature = "debug-guards", feature = "debug-canaries")))]
+#[test]
+fn reuse_freed_holes() {
+    let file = tempfile::tempfile().unwrap();
+    let alloc = DiskAlloc::on_file(file.try_clone().unwrap()).unwrap();
+
+    let mut boxes: Vec<Option<Box<[u8; 4096], DiskAlloc>>> = (0..100)
+        .map(|_| Some(Box::new_in([1; 4096], alloc.clone())))
+        .collect();
+    let full_size = file.metadata().unwrap().len();
+    assert_eq!(full_size, 100 * 4096);
+
+    // Punch holes into the middle, then fill them again
+    for b in boxes.iter_mut().step_by(2) {
+        *b = None;
+    }
+    for b in boxes.iter_mut().step_by(2) {
+        *b = Some(Box::new_in([2; 4096], alloc.clone()));
+    }
+    assert_eq!(file.metadata().unwrap().len(), full_size);
+
+    // Dropping from the front leaves one big hole
+    // which gets truncated with the last box
+    drop(boxes);
+    assert_eq!(file.metadata().unwrap().len(), 0);
+}
+
+// Canaries change the file layout
+#[cfg(not(feature = "debug-canaries"))]
+#[test]
+fn persist_and_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+
+    let alloc = DiskAlloc::create(&path).unwrap();
+    let mut v: Vec<u64, DiskAlloc> = Vec::new_in(alloc);
+    v.extend(0..10_000);
+    let (_, len, capacity, alloc) = v.into_raw_parts_with_alloc();
+    drop(alloc);
+
+    let alloc = DiskAlloc::open(&path).unwrap();
+    let data = alloc.data();
+    assert_eq!(data.len(), capacity * 8);
+    let v = unsafe { Vec::from_raw_parts_in(data.as_ptr().cast::<u64>(), len, capacity, alloc) };
+    assert!(v.iter().copied().eq(0..10_000));
+
+    assert!(DiskAlloc::open(dir.path().join("missing")).is_err());
+    std::fs::write(dir.path().join("garbage"), [1; 8192]).unwrap();
+    assert!(DiskAlloc::open(dir.path().join("garbage")).is_err());
+    // Rejected files are left as they were
+    assert_eq!(
+        std::fs::read(dir.path().join("garbage")).unwrap(),
+        [1; 8192]
+    );
+
+    // Version 2 had no journal and slot table, whatever is there
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[8..12].copy_from_slice(&2_u32.to_ne_bytes());
+    bytes[56..120 + 16 * 48].fill(0xFF);
+    std::fs::write(&path, &bytes).unwrap();
+    let alloc = DiskAlloc::open_readonly(&path).unwrap();
+    assert_eq!(alloc.data().len(), capacity * 8);
+    drop(alloc);
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    let alloc = DiskAlloc::open(&path).unwrap();
+    let upgraded = std::fs::read(&path).unwrap();
+    assert_eq!(upgraded[8..12], 3_u32.to_ne_bytes());
+    assert!(upgraded[56..120 + 16 * 48].iter().all(|&b| b == 0));
+    assert_eq!(alloc.data().len(), capacity * 8);
+    drop(alloc);
+
+    // Written by a future version
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[8..12].copy_from_slice(&99_u32.to_ne_bytes());
+    std::fs::write(&path, &bytes).unwrap();
+    let err = DiskAlloc::open(&path).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+}
+
+// Canaries change the file layout
+#[cfg(not(feature = "debug-canaries"))]
+#[test]
+fn open_readonly() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..10_000_u64);
+    drop(v);
+    let before = std::fs::read(&path).unwrap();
+
+    let alloc = DiskAlloc::open_readonly(&path).unwrap();
+    let data = unsafe { alloc.as_slice::<u64>() };
+    assert!(data.iter().copied().eq(0..10_000));
+    let mut other: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    assert!(other.try_reserve(1).is_err());
+    drop(alloc);
+    assert_eq!(std::fs::read(&path).unwrap(), before);
+}
+
+#[test]
+fn populate_on_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..100_000_u64);
+    drop(v);
+
+    let builder = DiskAllocBuilder::new().populate(true);
+    let mut v = unsafe { DiskVec::<u64>::open_with(&path, &builder) }.unwrap();
+    assert!(v.iter().copied().eq(0..100_000));
+    v.extend(100_000..200_000);
+    drop(v);
+    let reader = builder.open_readonly(&path).unwrap();
+    assert_eq!(unsafe { reader.as_slice::<u64>() }[199_999], 199_999);
+    let private = builder.open_private(&path).unwrap();
+    assert_eq!(unsafe { private.as_slice::<u64>() }[0], 0);
+}
+
+#[test]
+fn refresh_shared() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..1000_u64);
+    assert!(v.allocator().refresh().is_err());
+    // The writer might shrink the file
+    let reader = DiskAlloc::open_readonly(&path).unwrap();
+    assert!(reader.refresh().is_err());
+    drop((reader, v));
+
+    let builder = DiskAllocBuilder::new().append_only(true);
+    let mut v = DiskVec::create_with(&path, &builder).unwrap();
+    v.extend(0..1000_u64);
+    let reader = DiskAlloc::open_readonly(&path).unwrap();
+    assert!(unsafe { reader.as_slice::<u64>() }.len() >= 1000);
+    v.extend(1000..100_000_u64);
+    reader.refresh().unwrap();
+    let data = unsafe { reader.as_slice::<u64>() };
+    assert!(data[..100_000].iter().copied().eq(0..100_000));
+
+    // Nothing is given back under the reader
+    let used = v.allocator().stats().used_size;
+    v.truncate(10);
+    v.shrink_to_fit();
+    assert!(v.compact().is_err());
+    assert_eq!(v.allocator().stats().used_size, used);
+    reader.refresh().unwrap();
+    // With debug-canaries, one follows the shrunk block
+    assert!(data[..10].iter().copied().eq(0..10));
+    assert!(data[100..100_000].iter().copied().eq(100..100_000));
+    assert!(builder
+        .clone()
+        .strategy(Strategy::Buddy)
+        .create(dir.path().join("buddy.bin"))
+        .is_err());
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "freebsd"), not(miri)))]
+#[test]
+fn follow_live_vec_across_fork() {
+    const LEN: usize = 200_000;
+    let name = format!("diskallocator-test-{}", std::process::id());
+    let alloc = DiskAlloc::create_shared_memory(&name).unwrap();
+    let mut v = DiskVec::create_in(alloc, 0).unwrap();
+    v.extend(0..1000_u64);
+    v.flush().unwrap();
+    let child = unsafe { libc::fork() };
+    if child == 0 {
+        let followed = std::panic::catch_unwind(|| {
+            let alloc = DiskAlloc::open_shared_memory(&name).unwrap();
+            let mut v = unsafe { DiskVec::<u64>::open_in(alloc, 0) }.unwrap();
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(60);
+            while v.len() < LEN {
+                assert!(std::time::Instant::now() < deadline);
+                v.refresh().unwrap();
+                let len = v.len() as u64;
+                assert_eq!(v.last(), Some(&(len - 1)));
+                assert_eq!(v[v.len() / 2], len / 2);
+            }
+            assert!(v.iter().copied().eq(0..LEN as u64));
+        });
+        unsafe { libc::_exit(followed.is_err() as i32) };
+    }
+    // Moved to bigger blocks as it grows, the old ones stay
+    for i in (1000..LEN as u64).step_by(1000) {
+        v.extend(i..i + 1000);
+        v.flush().unwrap();
+    }
+    drop(v);
+    let mut status = 0;
+    unsafe { libc::waitpid(child, &mut status, 0) };
+    DiskAlloc::remove_shared_memory(&name).unwrap();
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn open_private() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..10_000_u64);
+    drop(v);
+    let before = std::fs::read(&path).unwrap();
+
+    let alloc = DiskAlloc::open_private(&path).unwrap();
+    let data = alloc.data();
+    let capacity = data.len() / 8;
+    let mut v =
+        unsafe { Vec::from_raw_parts_in(data.as_ptr().cast::<u64>(), 10_000, capacity, alloc) };
+    v.iter_mut().for_each(|x| *x *= 2);
+    v.extend(0..1_000_000);
+    assert!(v[..10_000].iter().copied().eq((0..10_000).map(|x| x * 2)));
+    assert!(v[10_000..].iter().copied().eq(0..1_000_000));
+    assert!(v.allocator().evict(4096, 8).is_err());
+    drop(v);
+    assert_eq!(std::fs::read(&path).unwrap(), before);
+}
+
+// Guard pages can not be compacted, canaries change the file layout
+#[cfg(not(any(feature = "debug-guards", feature = "debug-canaries")))]
+#[test]
+fn compact() {
+    let alloc = DiskAlloc::new().unwrap();
+    let mut dead: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1024 * 1024, alloc.clone());
+    dead.push(1);
+    let mut v = DiskVec::new_in(alloc.clone());
+    v.extend(0..10_000_u64);
+    assert!(v.compact().is_err());
+    drop(dead);
+    drop(alloc);
+    assert_eq!(v.allocator().stats().file_size, 1024 * 1024 + 80_000);
+
+    assert_eq!(v.compact().unwrap(), 1024 * 1024);
+    assert_eq!(v.allocator().stats().file_size, 80_000);
+    assert!(v.iter().copied().eq(0..10_000));
+    v.push(10_000);
+    assert_eq!(v[10_000], 10_000);
+}
+
+#[cfg(feature = "track-allocations")]
+#[test]
+fn live_allocations() {
+    let alloc = DiskAlloc::new().unwrap();
+    let a: Vec<u8, DiskAlloc> = Vec::with_capacity_in(10, alloc.clone());
+    let mut b: Vec<u64, DiskAlloc> = Vec::with_capacity_in(10, alloc.clone());
+    b.extend(0..100);
+    let live = alloc.live_allocations();
+    assert_eq!(live.len(), 2);
+    assert_eq!(live[0].0.as_ptr(), a.as_ptr() as *mut u8);
+    assert_eq!(live[1].1.size(), b.capacity() * 8);
+    drop(a);
+    drop(b);
+    assert!(alloc.live_allocations().is_empty());
+}
+
+#[cfg(all(feature = "track-allocations", debug_assertions))]
+#[test]
+#[should_panic(expected = "1 live allocations")]
+fn leak_report() {
+    let alloc = DiskAlloc::new().unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc);
+    v.push(1);
+    let (_, _, _, alloc) = v.into_raw_parts_with_alloc();
+    drop(alloc);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "not live (double free?)")]
+fn double_free_panics() {
+    let alloc = DiskAlloc::new().unwrap();
+    let layout = std::alloc::Layout::new::<u64>();
+    let ptr = std::alloc::Allocator::allocate(&alloc, layout)
+        .unwrap()
+        .cast::<u8>();
+    unsafe {
+        std::alloc::Allocator::deallocate(&alloc, ptr, layout);
+        std::alloc::Allocator::deallocate(&alloc, ptr, layout);
+    }
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "with 16 bytes aligned to 8, allocated with 8 bytes aligned to 8")]
+fn layout_mismatch_panics() {
+    let alloc = DiskAlloc::new().unwrap();
+    let layout = std::alloc::Layout::new::<u64>();
+    let ptr = std::alloc::Allocator::allocate(&alloc, layout)
+        .unwrap()
+        .cast::<u8>();
+    let _ = unsafe {
+        std::alloc::Allocator::shrink(&alloc, ptr, std::alloc::Layout::new::<[u64; 2]>(), layout)
+    };
+}
+
+#[test]
+fn zero_size_allocations_interleaved() {
+    use std::alloc::{Allocator, Layout};
+    let alloc = DiskAlloc::new().unwrap();
+    let empty = Layout::from_size_align(0, 1).unwrap();
+    let word = Layout::new::<u64>();
+    let mut blocks = Vec::new();
+    for _ in 0..4 {
+        blocks.push((alloc.allocate(empty).unwrap().cast::<u8>(), empty));
+        blocks.push((alloc.allocate(word).unwrap().cast::<u8>(), word));
+    }
+    unsafe {
+        // Grow one zero-size block, and shrink another block to zero
+        let (ptr, _) = blocks[2];
+        blocks[2] = (alloc.grow(ptr, empty, word).unwrap().cast(), word);
+        let (ptr, _) = blocks[3];
+        blocks[3] = (alloc.shrink(ptr, word, empty).unwrap().cast(), empty);
+        for (ptr, layout) in blocks {
+            alloc.deallocate(ptr, layout);
+        }
+    }
+}
+
+// Freed guarded blocks span whole pages, which are punched out
+#[cfg(all(feature = "debug-fill", not(feature = "debug-guards")))]
+#[test]
+fn debug_fill() {
+    let alloc = DiskAlloc::new().unwrap();
+    let mut a: Vec<u8, DiskAlloc> = Vec::with_capacity_in(100, alloc.clone());
+    let b: Vec<u8, DiskAlloc> = Vec::with_capacity_in(100, alloc.clone());
+    let spare = a.spare_capacity_mut();
+    assert!(spare
+        .iter()
+        .all(|byte| unsafe { byte.assume_init() } == 0xAA));
+    a.push(1);
+    let freed = a.as_ptr();
+    drop(a);
+    // Still mapped, `b` comes after it
+    let freed = unsafe { std::slice::from_raw_parts(freed, 100) };
+    assert!(freed.iter().all(|&byte| byte == 0xDD));
+    drop(b);
+}
+
+// Only in builds with -Zsanitizer=address
+#[cfg(all(feature = "sanitizers", sanitize = "address"))]
+#[test]
+fn freed_memory_poisoned() {
+    extern "C" {
+        fn __asan_address_is_poisoned(addr: *const u8) -> i32;
+    }
+    let is_poisoned = |ptr: *const u8| unsafe { __asan_address_is_poisoned(ptr) } != 0;
+    let alloc = DiskAlloc::new().unwrap();
+    let layout = std::alloc::Layout::new::<[u8; 4096]>();
+    let a = std::alloc::Allocator::allocate(&alloc, layout)
+        .unwrap()
+        .cast::<u8>();
+    let _b = std::alloc::Allocator::allocate(&alloc, layout).unwrap();
+    assert!(!is_poisoned(a.as_ptr()));
+    unsafe { std::alloc::Allocator::deallocate(&alloc, a, layout) };
+    assert!(is_poisoned(a.as_ptr()));
+    // Reused for the next allocation
+    let c = std::alloc::Allocator::allocate(&alloc, layout)
+        .unwrap()
+        .cast::<u8>();
+    assert_eq!(c, a);
+    assert!(!is_poisoned(c.as_ptr()));
+}
+
+#[cfg(feature = "debug-canaries")]
+#[test]
+#[should_panic(expected = "were overwritten (buffer overflow?)")]
+fn canary_overwritten() {
+    let alloc = DiskAlloc::new().unwrap();
+    let layout = std::alloc::Layout::new::<[u8; 100]>();
+    let ptr = std::alloc::Allocator::allocate(&alloc, layout).unwrap();
+    assert_eq!(ptr.len(), 100);
+    alloc.check_integrity().unwrap();
+    let ptr = ptr.cast::<u8>();
+    // One byte too many
+    unsafe { ptr.add(100).write(0) };
+    let err = alloc.check_integrity().err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    unsafe { std::alloc::Allocator::deallocate(&alloc, ptr, layout) };
+}
+
+#[test]
+fn allocation_trace() {
+    let alloc = DiskAllocBuilder::new().record_trace(true).build().unwrap();
+    let mut v: Vec<u64, DiskAlloc> = Vec::new_in(alloc.clone());
+    for i in 0..10_000 {
+        v.push(i);
+    }
+    v.truncate(10);
+    v.shrink_to_fit();
+    drop(v);
+    let mut json = Vec::new();
+    alloc.write_trace(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    for name in [
+        "allocate",
+        "grow",
+        "shrink",
+        "deallocate",
+        "file size",
+        "live bytes",
+    ] {
+        assert!(json.contains(&format!("\"name\":\"{name}\"")), "{name}");
+    }
+    // Nothing is live at the end
+    assert!(json.ends_with("\"args\":{\"bytes\":0}}],\"displayTimeUnit\":\"ms\"}\n"));
+
+    let err = DiskAlloc::new()
+        .unwrap()
+        .write_trace(Vec::new())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let snapshot = dir.path().join("snapshot.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..10_000_u64);
+    v.allocator().snapshot(&snapshot).unwrap();
+    v.extend(0..10_000_u64);
+    drop(v);
+
+    let alloc = DiskAlloc::open(&snapshot).unwrap();
+    let data = unsafe { alloc.as_slice::<u64>() };
+    assert!(data[..10_000].iter().copied().eq(0..10_000));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+// Canaries change the file layout
+#[cfg(not(feature = "debug-canaries"))]
+#[test]
+fn save_as() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let archive = dir.path().join("archive.bin");
+    let builder = DiskAllocBuilder::new().growth_chunk(1024 * 1024);
+    let mut v = DiskVec::create_with(&path, &builder).unwrap();
+    v.extend(0..10_000_u64);
+    let len = v.save_as(&archive).unwrap();
+    assert_eq!(std::fs::metadata(&archive).unwrap().len(), len);
+    assert!(len < std::fs::metadata(&path).unwrap().len());
+    drop(v);
+
+    let v = unsafe { DiskVec::<u64>::open(&archive) }.unwrap();
+    assert!(v.iter().copied().eq(0..10_000));
+    assert!(DiskVec::<u64>::new().unwrap().save_as(&archive).is_err());
+}
+
+// Canaries change the file layout
+#[cfg(all(feature = "bytemuck", not(feature = "debug-canaries")))]
+#[test]
+fn open_view() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..10_000_u32);
+    drop(v);
+
+    let view = DiskVec::<u32>::open_view(&path).unwrap();
+    assert!(view.iter().copied().eq(0..10_000));
+    // Same size and alignment, another type
+    let floats = DiskVec::<f32>::open_view(&path).unwrap();
+    assert_eq!(floats[1], f32::from_bits(1));
+    let err = DiskVec::<u64>::open_view(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn rayon() {
+    use rayon::prelude::*;
+    let mut v: Vec<u64, DiskAlloc> = Vec::new_in(DiskAlloc::new().unwrap());
+    v.extend(0..100_000);
+    v.par_iter_mut().for_each(|x| *x *= 2);
+    assert_eq!(v.par_iter().sum::<u64>(), 99_999 * 100_000);
+
+    let mut v = DiskVec::new().unwrap();
+    v.push(u64::MAX);
+    v.par_extend_from_iter((0..100_000_u64).into_par_iter().filter(|i| i % 3 == 0))
+        .unwrap();
+    assert_eq!(v[0], u64::MAX);
+    assert!(v[1..].iter().copied().eq((0..100_000).step_by(3)));
+}
+
+// Guard pages and canaries change the file layout
+#[cfg(not(any(feature = "debug-guards", feature = "debug-canaries")))]
+#[test]
+fn keep_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let alloc = DiskAllocBuilder::new()
+        .dir(dir.path())
+        .prefix("diskalloc-keep")
+        .growth_chunk(1024 * 1024)
+        .build()
+        .unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.extend(0..100);
+    assert!(alloc.into_file().is_err());
+    let (_, _, _, alloc) = v.into_raw_parts_with_alloc();
+
+    let path = dir.path().join("kept.bin");
+    alloc.keep(&path).unwrap();
+    let data = std::fs::read(&path).unwrap();
+    assert!(data.into_iter().eq(0..100));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    let alloc = DiskAlloc::new().unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc);
+    v.extend(0..100);
+    let (_, _, _, alloc) = v.into_raw_parts_with_alloc();
+    assert_eq!(alloc.into_file().unwrap().metadata().unwrap().len(), 100);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn link_into() {
+    let dir = tempfile::tempdir().unwrap();
+    let alloc = DiskAllocBuilder::new().dir(dir.path()).build().unwrap();
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.extend(0..100);
+    let path = dir.path().join("linked.bin");
+    alloc.link_into(&path).unwrap();
+    alloc.flush().unwrap();
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(&data[..4], &[0, 1, 2, 3]);
+    drop(v);
+    drop(alloc);
+    assert!(path.exists());
+}
+
+#[test]
+fn disk_box() {
+    let mut boxed = DiskBox::new([7u32; 1024]).unwrap();
+    boxed[3] = 3;
+    assert_eq!(boxed.iter().sum::<u32>(), 1023 * 7 + 3);
+    assert_eq!(boxed.allocator().stats().live_allocations, 1);
+    assert_eq!(boxed.into_inner()[3], 3);
+
+    let pinned = DiskBox::pin(String::from("pinned")).unwrap();
+    assert_eq!(pinned.as_str(), "pinned");
+
+    let zeroed = unsafe { DiskBox::<[u8; 1 << 20]>::new_zeroed() }.unwrap();
+    assert!(zeroed.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn slab_reuses_slots() {
+    let counter = std::rc::Rc::new(());
+    let mut slab = DiskSlab::new().unwrap();
+    let keys: Vec<usize> = (0..1000).map(|_| slab.insert(counter.clone())).collect();
+    assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+    assert_eq!(std::rc::Rc::strong_count(&counter), 1001);
+    for key in (0..1000).step_by(3) {
+        assert!(slab.remove(key).is_some());
+    }
+    assert!(slab.remove(0).is_none());
+    assert!(slab.get(3).is_none());
+    assert!(slab.get(1000).is_none());
+    assert_eq!(slab.len(), 666);
+    assert_eq!(std::rc::Rc::strong_count(&counter), 667);
+
+    // Freed slots first, then new ones
+    let reused: Vec<usize> = (0..335).map(|_| slab.insert(counter.clone())).collect();
+    assert!(reused[..334].iter().all(|key| key % 3 == 0));
+    assert_eq!(reused[334], 1000);
+    assert_eq!(slab.capacity(), 1001);
+    assert_eq!(slab.iter().count(), 1001);
+    drop(slab);
+    assert_eq!(std::rc::Rc::strong_count(&counter), 1);
+}
+
+#[test]
+fn arena_reset() {
+    let mut arena = DiskArena::new().unwrap();
+    for round in 0..3 {
+        let mut values: Vec<&mut [u64; 512]> = (0..100).map(|i| arena.alloc([i; 512])).collect();
+        values[99][0] += round;
+        assert_eq!(values[99][0], 99 + round);
+        assert_eq!(arena.stats().live_allocations, 100);
+        assert!(arena.stats().used_size >= 100 * 512 * 8);
+        arena.reset();
+        let stats = arena.stats();
+        assert_eq!(stats.used_size, 0);
+        assert_eq!(stats.file_size, 0);
+        assert_eq!(stats.live_allocations, 0);
+    }
+}
+
+#[test]
+fn disk_vec_persistence() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+
+    let mut v = DiskVec::create(&path).unwrap();
+    assert!(v.is_persistent());
+    v.extend((0..100_000_u32).map(|i| i * 2));
+    assert_eq!(v.pop(), Some(199_998));
+    drop(v);
+
+    let mut v = unsafe { DiskVec::<u32>::open(&path).unwrap() };
+    assert_eq!(v.len(), 99_999);
+    assert!(v.iter().copied().eq((0..99_999).map(|i| i * 2)));
+    v.push(7);
+    assert_eq!(v[99_999], 7);
+    drop(v);
+    assert_eq!(
+        unsafe { DiskVec::<u32>::open(&path) }.unwrap().len(),
+        100_000
+    );
+
+    let wrong_type = unsafe { DiskVec::<i32>::open(&path) }.unwrap_err();
+    assert_eq!(wrong_type.kind(), std::io::ErrorKind::InvalidData);
+    assert!(matches!(wrong_type, DiskAllocError::Io(_)));
+    assert!(unsafe { DiskVec::<u64>::open(&path) }.is_err());
+    let other = dir.path().join("alloc.bin");
+    drop(DiskAlloc::create(&other).unwrap());
+    assert!(unsafe { DiskVec::<u32>::open(&other) }.is_err());
+}
+
+// Guard pages and canaries change the file layout
+#[cfg(all(
+    target_os = "linux",
+    not(any(feature = "debug-guards", feature = "debug-canaries"))
+))]
+#[test]
+fn punch_freed_holes() {
+    use std::os::unix::fs::MetadataExt;
+
+    let file = tempfile::tempfile().unwrap();
+    let alloc = DiskAlloc::on_file(file.try_clone().unwrap()).unwrap();
+    let mut first: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1 << 20, alloc.clone());
+    first.resize(1 << 20, 1);
+    let _second: Box<[u8; 4096], DiskAlloc> = Box::new_in([2; 4096], alloc.clone());
+    let used_blocks = file.metadata().unwrap().blocks();
+
+    drop(first);
+    let blocks = file.metadata().unwrap().blocks();
+    assert_eq!(file.metadata().unwrap().len(), (1 << 20) + 4096);
+    assert!(blocks < used_blocks, "{blocks} >= {used_blocks}");
+    assert_eq!(alloc.disk_usage().unwrap(), blocks * 512);
+}
+
+// Filling the memory allocates disk blocks
+#[cfg(all(target_os = "linux", not(feature = "debug-fill")))]
+#[test]
+fn preallocate_growth() {
+    use std::os::unix::fs::MetadataExt;
+
+    for preallocate in [true, false] {
+        let file = tempfile::tempfile().unwrap();
+        let alloc = DiskAllocBuilder::new()
+            .preallocate(preallocate)
+            .build_on_file(file.try_clone().unwrap())
+            .unwrap();
+        let v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1 << 20, alloc);
+        let allocated = file.metadata().unwrap().blocks() * 512;
+        assert_eq!(allocated >= 1 << 20, preallocate, "{allocated}");
+        drop(v);
+    }
+}
+
+#[test]
+fn prefetch() {
+    let mut v = DiskVec::new().unwrap();
+    v.extend(0..1_000_000_u64);
+    v.prefetch_range(0..1_000_000).unwrap();
+    v.prefetch_range(1234..5678).unwrap();
+    v.prefetch_range(10..10).unwrap();
+
+    let alloc = v.allocator();
+    alloc.prefetch(0, 4096).unwrap();
+    assert!(alloc.prefetch(0, u64::MAX).is_err());
+}
+
+#[test]
+fn access_pattern() {
+    let mut v = DiskVec::new().unwrap();
+    v.allocator().advise(AccessPattern::Sequential).unwrap();
+    // Mapped after the advice
+    v.extend(0..1_000_000_u64);
+    assert_eq!(v.iter().sum::<u64>(), 499_999_500_000);
+    v.allocator().advise(AccessPattern::Random).unwrap();
+    assert_eq!(v[123_456], 123_456);
+    v.allocator().advise(AccessPattern::Normal).unwrap();
+}
+
+#[test]
+fn evict() {
+    let mut v = DiskVec::new().unwrap();
+    v.extend(0..1_000_000_u64);
+    v.evict_range(0..500_000).unwrap();
+    v.evict_range(123..4567).unwrap();
+    assert!(v.iter().copied().eq(0..1_000_000));
+    assert!(v.allocator().evict(0, u64::MAX).is_err());
+}
+
+#[test]
+fn chunks_streamed() {
+    let mut v = DiskVec::new().unwrap();
+    v.extend(0..1_000_000_u64);
+    let chunks = v.chunks_streamed(300_000);
+    assert_eq!(chunks.len(), 4);
+    let lens: Vec<_> = chunks.map(<[u64]>::len).collect();
+    assert_eq!(lens, [300_000, 300_000, 300_000, 100_000]);
+    assert!(v.chunks_streamed(4096).flatten().copied().eq(0..1_000_000));
+    assert_eq!(DiskVec::<u64>::new().unwrap().chunks_streamed(1).count(), 0);
+}
+
+#[test]
+fn disk_buf() {
+    use std::io::Write;
+    let mut buf = DiskBuf::new().unwrap();
+    let data: Vec<u8> = (0..1_000_000_u32).map(|i| i as u8).collect();
+    std::io::copy(&mut data.as_slice(), &mut buf).unwrap();
+    buf.flush().unwrap();
+    assert_eq!(&buf[..], &data[..]);
+
+    let mut v = buf.into_vec();
+    v.truncate(10);
+    let mut buf = DiskBuf::from(v);
+    buf.write_all(b"end").unwrap();
+    assert_eq!(&buf[..], b"\0\x01\x02\x03\x04\x05\x06\x07\x08\x09end");
+
+    let limited = DiskAllocBuilder::new()
+        .max_file_size(64 * 1024)
+        .build()
+        .unwrap();
+    let mut buf = DiskBuf::new_in(limited);
+    assert!(buf.write_all(&data).is_err());
+}
+
+// Canaries change the file layout
+#[cfg(not(feature = "debug-canaries"))]
+#[test]
+fn disk_cursor() {
+    use diskallocator::DiskCursor;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    let mut buf = DiskBuf::new().unwrap();
+    buf.write_all(b"0123456789").unwrap();
+    let mut cursor = DiskCursor::from(buf);
+    let mut bytes = [0; 4];
+    cursor.read_exact(&mut bytes).unwrap();
+    assert_eq!(&bytes, b"0123");
+    assert_eq!(cursor.seek(SeekFrom::End(-2)).unwrap(), 8);
+    let mut rest = Vec::new();
+    cursor.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"89");
+    assert!(cursor.seek(SeekFrom::Current(-11)).is_err());
+    cursor.set_position(100);
+    assert_eq!(cursor.read(&mut bytes).unwrap(), 0);
+    assert!(cursor.read_exact(&mut bytes).is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..=255_u8);
+    drop(v);
+    let alloc = DiskAlloc::open_readonly(&path).unwrap();
+    let data = alloc.data();
+    let mut cursor = unsafe { DiskCursor::from_raw_parts(alloc, data) };
+    cursor.seek(SeekFrom::Start(250)).unwrap();
+    let mut rest = Vec::new();
+    cursor.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, [250, 251, 252, 253, 254, 255]);
+    assert!(cursor.into_vec().is_none());
+}
+
+#[test]
+fn mark_cold() {
+    let mut v = DiskVec::new().unwrap();
+    v.extend(0..1_000_000_u64);
+    v.mark_cold_range(0..500_000).unwrap();
+    v.mark_cold_range(10..10).unwrap();
+    assert!(v.iter().copied().eq(0..1_000_000));
+    v.allocator().mark_cold(0, 4096).unwrap();
+    assert!(v.allocator().mark_cold(0, u64::MAX).is_err());
+}
+
+// Canaries change the file layout
+#[cfg(not(feature = "debug-canaries"))]
+#[test]
+fn flush_incremental() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..1_000_000_u64);
+    v.flush().unwrap();
+    v[123_456] = 0;
+    v.mark_dirty_range(123_456..123_457).unwrap();
+    // Appended, written back without marking
+    v.extend(0..1000);
+    v.flush_incremental().unwrap();
+    assert!(v.allocator().mark_dirty(0, u64::MAX).is_err());
+    drop(v);
+
+    let v = unsafe { DiskVec::<u64>::open(&path) }.unwrap();
+    assert_eq!(v.len(), 1_001_000);
+    assert_eq!((v[123_455], v[123_456]), (123_455, 0));
+    assert!(v[1_000_000..].iter().copied().eq(0..1000));
+    v.flush_incremental().unwrap();
+}
+
+#[cfg(all(feature = "userfaultfd", target_os = "linux"))]
+#[test]
+fn lazy_pages() {
+    use diskallocator::LazyPages;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    let provider = move |offset: u64, page: &mut [u8]| {
+        counted.fetch_add(1, Ordering::SeqCst);
+        for chunk in page.chunks_exact_mut(8) {
+            chunk.copy_from_slice(&offset.to_ne_bytes());
+        }
+    };
+    let mut pages = match LazyPages::new(1 << 30, provider) {
+        Ok(pages) => pages,
+        // Not allowed in this environment
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => return,
+        Err(err) => panic!("{err}"),
+    };
+    assert_eq!(pages.len(), 1 << 30);
+    let at = |pages: &LazyPages, i: usize| u64::from_ne_bytes(pages[i..i + 8].try_into().unwrap());
+    let i = 123 * 65536 + 4096;
+    let start = at(&pages, i);
+    assert!(start <= i as u64 && i as u64 - start < 65536);
+    assert_eq!(at(&pages, i + 8), start);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Written pages are filled first too
+    pages[1 << 29] = 0xFF;
+    assert_eq!(pages[(1 << 29) + 8], 0);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    std::thread::scope(|scope| {
+        for t in 0..4 {
+            let pages = &pages;
+            scope.spawn(move || assert_eq!(at(pages, t * 65536), (t * 65536) as u64));
+        }
+    });
+    assert_eq!(calls.load(Ordering::SeqCst), 6);
+}
+
+#[cfg(all(feature = "object-store", target_os = "linux"))]
+#[test]
+fn remote_pages() {
+    use diskallocator::{ObjectStore, RemotePages};
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+    #[derive(Default)]
+    struct Bucket {
+        objects: Mutex<HashMap<u64, Vec<u8>>>,
+        gets: AtomicUsize,
+    }
+    impl ObjectStore for Bucket {
+        fn get_chunk(&self, index: u64, buf: &mut [u8]) -> Result<(), std::io::Error> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            if let Some(object) = self.objects.lock().unwrap().get(&index) {
+                buf.copy_from_slice(object);
+            }
+            Ok(())
+        }
+
+        fn put_chunk(&self, index: u64, data: &[u8]) -> Result<(), std::io::Error> {
+            self.objects.lock().unwrap().insert(index, data.to_vec());
+            Ok(())
+        }
+    }
+    let chunk = 65536;
+    let bucket = Arc::new(Bucket::default());
+    bucket.objects.lock().unwrap().insert(3, vec![7; chunk]);
+    let dir = tempfile::tempdir().unwrap();
+    // Two chunks fit into the cache
+    let cache_size = 2 * chunk as u64 + 1;
+    let mut pages = match RemotePages::new(bucket.clone(), 1 << 30, chunk, dir.path(), cache_size) {
+        Ok(pages) => pages,
+        // Not allowed in this environment
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => return,
+        Err(err) => panic!("{err}"),
+    };
+    assert_eq!(pages.len(), 1 << 30);
+    assert!(RemotePages::new(Bucket::default(), 1, 1000, dir.path(), 0).is_err());
+    let gets = || bucket.gets.load(Ordering::SeqCst);
+
+    // One get per chunk, not per page
+    assert_eq!(pages[3 * chunk + 100], 7);
+    assert_eq!(pages[4 * chunk - 1], 7);
+    assert_eq!(pages[0], 0);
+    assert_eq!(gets(), 2);
+
+    // Only marked writes are put back
+    pages.mark_dirty(10 * chunk + 8192, 1).unwrap();
+    pages[10 * chunk + 8192] = 1;
+    pages[11 * chunk] = 2;
+    assert!(pages.mark_dirty(1 << 30, 1).is_err());
+    pages.flush().unwrap();
+    assert_eq!(gets(), 4);
+    let objects = bucket.objects.lock().unwrap().clone();
+    assert_eq!(objects.len(), 2);
+    assert_eq!(objects[&10].len(), chunk);
+    assert_eq!(objects[&10][8192], 1);
+    pages.flush().unwrap();
+
+    // Evicted pages come back from the cache, holding chunks 11 and 10
+    // by now, or from the store
+    pages.evict(0, pages.len()).unwrap();
+    assert_eq!(pages[10 * chunk + 8192], 1);
+    assert_eq!(pages[11 * chunk], 0);
+    assert_eq!(gets(), 4);
+    assert_eq!(pages[3 * chunk], 7);
+    assert_eq!(gets(), 5);
+    pages.prefetch(20 * chunk, 2 * chunk).unwrap();
+    assert_eq!(gets(), 7);
+    assert_eq!(pages[21 * chunk], 0);
+    assert_eq!(gets(), 7);
+
+    // No more than the cache holds stays in memory
+    for offset in (30 * chunk..40 * chunk).step_by(4096) {
+        assert_eq!(pages[offset], 0);
+    }
+    assert!(pages.resident() > 0 && pages.resident() <= cache_size);
+    // Marked chunks stay until flushed
+    pages.set_max_resident(0);
+    pages.mark_dirty(50 * chunk, 1).unwrap();
+    pages[50 * chunk] = 5;
+    assert_eq!(pages[51 * chunk] + pages[52 * chunk], 0);
+    assert_eq!(pages[50 * chunk], 5);
+    pages.flush().unwrap();
+    assert_eq!(bucket.objects.lock().unwrap()[&50][0], 5);
+}
+
+#[test]
+fn mixed_containers_free_everything() {
+    let alloc = DiskAllocBuilder::new().growth_chunk(4096).build().unwrap();
+    let mut rng = rand::thread_rng();
+    let mut deques: Vec<VecDeque<u16, DiskAlloc>> = Vec::new();
+    let mut boxes = Vec::new();
+    for _ in 0..500 {
+        let mut deque = VecDeque::new_in(alloc.clone());
+        for i in 0..rng.gen_range(0..300) {
+            deque.push_front(i);
+            deque.push_back(i);
+        }
+        deque.drain(..deque.len() / 3);
+        deques.push(deque);
+        // Different alignments, like the tables of hash maps
+        boxes.push(Box::new_in([rng.gen::<u128>(); 3], alloc.clone()));
+        if rng.gen_bool(0.3) {
+            let index = rng.gen_range(0..deques.len());
+            deques.swap_remove(index);
+        }
+    }
+    for deque in &deques {
+        let front: Vec<u16> = deque.iter().copied().take_while(|&x| x != 0).collect();
+        assert!(front.iter().rev().copied().eq(1..=front.len() as u16));
+    }
+    drop(deques);
+    drop(boxes);
+    assert_eq!(alloc.stats().live_allocations, 0);
+    assert_eq!(alloc.stats().used_size, 0);
+}
+
+#[test]
+fn vec_deque_wraparound() {
+    let alloc = DiskAlloc::new().unwrap();
+    let mut deque = VecDeque::with_capacity_in(1000, alloc.clone());
+    let mut expected = VecDeque::new();
+    let mut boxes = Vec::new();
+    for round in 0..60_u64 {
+        // The elements wrap around the end of the buffer
+        for i in 0..round * 40 {
+            deque.push_back(i);
+            expected.push_back(i);
+        }
+        for _ in 0..round * 30 {
+            deque.pop_front();
+            expected.pop_front();
+        }
+        deque.push_front(round);
+        expected.push_front(round);
+        // Behind the buffer, so it grows by moving
+        boxes.push(Box::new_in(round as u8, alloc.clone()));
+        if round % 10 == 9 {
+            deque.shrink_to_fit();
+        }
+    }
+    assert!(deque.iter().eq(expected.iter()));
+    drop(deque);
+    drop(boxes);
+    let stats = alloc.stats();
+    assert_eq!(stats.live_allocations, 0);
+    assert_eq!(stats.used_size, 0);
+}
+
+#[test]
+fn hash_map_rehash_and_shrink() {
+    let alloc = DiskAlloc::new().unwrap();
+    let mut small = hashbrown::HashMap::new_in(alloc.clone());
+    let mut map = hashbrown::HashMap::new_in(alloc.clone());
+    for i in 0..100_000_u64 {
+        map.insert(i, i * 3);
+        // Tables of both maps take turns at the end of the file
+        if i % 1000 == 0 {
+            small.insert(i as u16, i as u8);
+        }
+    }
+    map.retain(|k, _| k % 10 == 0);
+    map.shrink_to_fit();
+    for i in 0..10_000 {
+        map.insert(i * 10 + 1, 1);
+    }
+    assert_eq!(map.len(), 20_000);
+    assert!((0..10_000).all(|i| map[&(i * 10)] == i * 30 && map[&(i * 10 + 1)] == 1));
+    assert_eq!(small.len(), 100);
+    let used = alloc.stats().used_size;
+    map.clear();
+    map.shrink_to_fit();
+    small.shrink_to(10);
+    assert!(alloc.stats().used_size < used);
+    drop(small);
+    let stats = alloc.stats();
+    assert_eq!(stats.live_allocations, 0);
+    assert_eq!(stats.used_size, 0);
+}
+
+#[test]
+fn buddy_strategy() {
+    let alloc = DiskAllocBuilder::new()
+        .strategy(Strategy::Buddy)
+        .build()
+        .unwrap();
+    let mut rng = rand::thread_rng();
+    let mut vecs: Vec<Vec<u32, DiskAlloc>> = Vec::new();
+    for round in 0..2000 {
+        if rng.gen_bool(0.4) && !vecs.is_empty() {
+            vecs.swap_remove(rng.gen_range(0..vecs.len()));
+        }
+        let mut v = Vec::new_in(alloc.clone());
+        v.extend(0..rng.gen_range(1..2000));
+        if round % 3 == 0 {
+            v.truncate(v.len() / 2);
+            v.shrink_to_fit();
+        }
+        vecs.push(v);
+    }
+    for v in &vecs {
+        assert!(v.iter().copied().eq(0..v.len() as u32));
+        // Empty ones point nowhere
+        assert!(v.capacity() == 0 || (v.as_ptr() as usize).is_multiple_of(16));
+    }
+    // Freed blocks are reused, the file stays within the live blocks
+    let live: usize = vecs
+        .iter()
+        .map(|v| (v.capacity() * 4).next_power_of_two().max(16))
+        .sum();
+    assert!(alloc.stats().used_size < 4 * live as u64);
+    drop(vecs);
+    // Merged back and cut off
+    assert_eq!(alloc.stats().used_size, 0);
+    assert_eq!(alloc.stats().live_allocations, 0);
+}
+
+#[test]
+fn disk_vec_slots() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("table.bin");
+    let alloc = DiskAlloc::create(&path).unwrap();
+    let mut keys = DiskVec::create_in(alloc.clone(), 0).unwrap();
+    let mut values = DiskVec::create_in(alloc.clone(), 3).unwrap();
+    let empty = DiskVec::<u8>::create_in(alloc.clone(), 5).unwrap();
+    for i in 0..100_000_u32 {
+        keys.push(i);
+        values.push(i as u64 * 3);
+    }
+    assert!(DiskVec::<u8>::create_in(alloc.clone(), 16).is_err());
+    drop((alloc, keys, values, empty));
+    assert!(DiskVec::<u8>::create_in(DiskAlloc::new().unwrap(), 0).is_err());
+
+    let alloc = DiskAlloc::open(&path).unwrap();
+    let keys = unsafe { DiskVec::<u32>::open_in(alloc.clone(), 0) }.unwrap();
+    let mut values = unsafe { DiskVec::<u64>::open_in(alloc.clone(), 3) }.unwrap();
+    assert!(keys.iter().copied().eq(0..100_000));
+    assert!(values.iter().copied().eq((0..100_000).map(|i| i * 3)));
+    values.push(7);
+    assert!(unsafe { DiskVec::<u8>::open_in(alloc.clone(), 5) }
+        .unwrap()
+        .is_empty());
+    assert!(unsafe { DiskVec::<u32>::open_in(alloc.clone(), 1) }.is_err());
+    assert!(unsafe { DiskVec::<u32>::open_in(alloc.clone(), 3) }.is_err());
+    // Not the vector of the whole file
+    assert!(unsafe { DiskVec::<u32>::open(&path) }.is_err());
+}
+
+// Canaries change the file layout
+#[cfg(not(feature = "debug-canaries"))]
+#[test]
+fn recover_after_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let mut v = DiskVec::create(&path).unwrap();
+    v.extend(0..10_000_u64);
+    v.flush().unwrap();
+    let flushed_capacity = v.capacity();
+    v.extend(10_000..1_000_000);
+    drop(v);
+
+    // The new file length never reached the disk
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(4096 + flushed_capacity as u64 * 8).unwrap();
+    drop(file);
+    let v = unsafe { DiskVec::<u64>::open(&path) }.unwrap();
+    assert!(v.iter().copied().eq(0..10_000));
+}
+
+#[test]
+fn checksums() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let builder = DiskAllocBuilder::new().checksums(true);
+    let mut v = DiskVec::create_with(&path, &builder).unwrap();
+    v.extend(0..100_000_u64);
+    v.flush().unwrap();
+    v.extend(100_000..200_000);
+    drop(v);
+    assert!(dir.path().join("vec.bin.crc").exists());
+    let v = unsafe { DiskVec::<u64>::open_with(&path, &builder) }.unwrap();
+    assert!(v.iter().copied().eq(0..200_000));
+    drop(v);
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[4096 + 3 * 4096 + 100] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    assert!(matches!(
+        builder.open_readonly(&path),
+        Err(DiskAllocError::ChecksumMismatch { offset: 16384 })
+    ));
+    // Not checked without the option
+    assert!(DiskAlloc::open_readonly(&path).is_ok());
+}
+
+#[test]
+fn disk_vec_flush_on_drop() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vec.bin");
+    let builder = DiskAllocBuilder::new().flush_on_drop(true);
+    let mut v = DiskVec::create_with(&path, &builder).unwrap();
+    v.extend(0..100_000_u64);
+    drop(v);
+
+    let v = unsafe { DiskVec::<u64>::open_with(&path, &builder) }.unwrap();
+    assert!(v.iter().copied().eq(0..100_000));
+}
+
+#[test]
+fn flush() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut v = DiskVec::create(dir.path().join("vec.bin")).unwrap();
+    v.extend(0..100_000_u64);
+    let alloc = v.allocator();
+    alloc.flush().unwrap();
+    alloc.flush_async().unwrap();
+    alloc.flush_range(4096, 8 * 1000).unwrap();
+    assert!(alloc.flush_range(0, u64::MAX).is_err());
+}
+
+#[test]
+fn huge_pages_fallback() {
+    let alloc = DiskAllocBuilder::new().huge_pages(true).build().unwrap();
+    let mut v = DiskVec::new_in(alloc);
+    v.extend(0..1_000_000_u64);
+    assert!(v.iter().copied().eq(0..1_000_000));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn transparent_huge_page_advice() {
+    // The VmFlags of the mapping holding `addr`
+    fn vm_flags(addr: usize) -> String {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut inside = false;
+        for line in smaps.lines() {
+            if let Some((range, _)) = line.split_once(' ').filter(|(r, _)| r.contains('-')) {
+                let (start, end) = range.split_once('-').unwrap();
+                let start = usize::from_str_radix(start, 16).unwrap();
+                let end = usize::from_str_radix(end, 16).unwrap();
+                inside = (start..end).contains(&addr);
+            } else if let Some(flags) = line.strip_prefix("VmFlags:").filter(|_| inside) {
+                return flags.to_string();
+            }
+        }
+        panic!("no mapping at {addr:#x}");
+    }
+    if !std::path::Path::new("/sys/kernel/mm/transparent_hugepage").exists() {
+        return;
+    }
+    for (huge_pages, flag) in [(true, " hg"), (false, " nh")] {
+        let alloc = DiskAllocBuilder::new()
+            .huge_pages(huge_pages)
+            .build()
+            .unwrap();
+        let mut v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(4096, alloc);
+        v.push(1);
+        assert!(vm_flags(v.as_ptr() as usize).contains(flag));
+    }
+}
+
+// The first bytes past the end are the canary
+#[cfg(all(unix, feature = "debug-guards", not(feature = "debug-canaries")))]
+#[test]
+fn guard_page_catches_overrun() {
+    // Run the overrun in a child process, which has to crash
+    if std::env::var_os("DISKALLOC_OVERRUN").is_some() {
+        let mut v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(4096, DiskAlloc::new().unwrap());
+        v.resize(4096, 1);
+        let past_end = unsafe { v.as_mut_ptr().add(4096) };
+        unsafe { past_end.write_volatile(1) };
+        return;
+    }
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["guard_page_catches_overrun", "--exact", "--nocapture"])
+        .env("DISKALLOC_OVERRUN", "1")
+        .status()
+        .unwrap();
+    use std::os::unix::process::ExitStatusExt;
+    assert!(status.signal().is_some(), "{status:?}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reservation_fits_address_space() {
+    // Limit the address space of a child process only
+    if std::env::var_os("DISKALLOC_LIMIT_AS").is_some() {
+        let limit = libc::rlimit {
+            rlim_cur: 64 << 30,
+            rlim_max: 64 << 30,
+        };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) }, 0);
+        let alloc = DiskAlloc::new().unwrap();
+        assert!(alloc.remaining_reservation() < 64 << 30);
+        let mut v: Vec<u64, DiskAlloc> = Vec::new_in(alloc);
+        v.extend(0..1000);
+        assert!(DiskAlloc::with_reservation(512 << 30).is_err());
+        return;
+    }
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["reservation_fits_address_space", "--exact", "--nocapture"])
+        .env("DISKALLOC_LIMIT_AS", "1")
+        .status()
+        .unwrap();
+    assert!(status.success(), "{status:?}");
+}
+
+#[cfg(not(feature = "debug-guards"))]
+#[test]
+fn stats() {
+    let alloc = DiskAlloc::new().unwrap();
+    assert_eq!(alloc.stats(), diskallocator::Stats::default());
+
+    let a = Box::new_in(1_u8, alloc.clone());
+    let b = Box::new_in(2_u64, alloc.clone());
+    let stats = alloc.stats();
+    assert_eq!(stats.live_allocations, 2);
+    assert_eq!(stats.file_size, 16);
+    assert_eq!(stats.used_size, 16);
+    assert_eq!(stats.wasted_alignment_bytes, 7);
+    assert_eq!(stats.resize_syscalls, 2);
+    assert_eq!(stats.size_histogram.count(1), 1);
+    assert_eq!(stats.size_histogram.count(8), 1);
+    assert_eq!(stats.size_histogram.total(), 2);
+
+    drop(b);
+    drop(a);
+    let stats = alloc.stats();
+    assert_eq!(stats.live_allocations, 0);
+    // The alignment padding before `b` is freed with it
+    assert_eq!(stats.file_size, 0);
+    assert_eq!(stats.peak_file_size, 16);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let mut v = DiskVec::new().unwrap();
+    v.extend(0..10_000_u32);
+    let json = serde_json::to_string(&v).unwrap();
+    assert!(json.starts_with("[0,1,2,"));
+    let back: DiskVec<u32> = serde_json::from_str(&json).unwrap();
+    assert!(back.iter().copied().eq(0..10_000));
+    assert!(serde_json::from_str::<DiskVec<u32>>("[1,-1]").is_err());
+}
+
+#[cfg(not(any(feature = "debug-guards", feature = "debug-canaries")))]
+#[test]
+fn alignment_padding_reused() {
+    let alloc = DiskAlloc::new().unwrap();
+    let a = Box::new_in(1_u8, alloc.clone());
+    let b = Box::new_in(2_u64, alloc.clone());
+    // Fill the 7 bytes skipped before `b`
+    let small: Vec<_> = (0..7_u8).map(|i| Box::new_in(i, alloc.clone())).collect();
+    let halves = Box::new_in(3_u16, alloc.clone());
+    let stats = alloc.stats();
+    assert_eq!(stats.used_size, 18);
+    assert_eq!(stats.wasted_alignment_bytes, 7);
+    assert!(small.iter().enumerate().all(|(i, x)| **x == i as u8));
+    assert_eq!((*a, *b, *halves), (1, 2, 3));
+}
+
+#[test]
+fn erase_on_drop() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("erased");
+    let alloc = DiskAllocBuilder::new()
+        .dir(dir.path())
+        .prefix("secret")
+        // Freed memory stays in the file until the drop
+        .growth_chunk(1024 * 1024)
+        .erase_on_drop(true)
+        .build()
+        .unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::new_in(alloc);
+    v.extend(std::iter::repeat_n(0x5e, 100_000));
+    v.allocator().link_into(&path).unwrap();
+    v.truncate(10_000);
+    v.shrink_to_fit();
+    drop(v);
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(data.len(), 1024 * 1024);
+    assert!(data.iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn spill_to_disk() {
+    let alloc = SpillAlloc::new(1024 * 1024);
+    let mut small: Vec<u8, SpillAlloc> = Vec::new_in(alloc.clone());
+    small.resize(1024, 1);
+    assert!(alloc.disk().is_none());
+    assert_eq!(alloc.heap_used(), 1024);
+
+    let mut big: Vec<u8, SpillAlloc> = Vec::new_in(alloc.clone());
+    for i in 0..4 * 1024 * 1024 {
+        big.push(i as u8);
+    }
+    assert!(big.iter().enumerate().all(|(i, &x)| x == i as u8));
+    assert!(alloc.disk().unwrap().stats().used_size >= 4 * 1024 * 1024);
+    assert_eq!(alloc.heap_used(), 1024);
+    drop(small);
+    assert_eq!(alloc.heap_used(), 0);
+}
+
+#[test]
+fn memory_pressure_from_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let psi = dir.path().join("memory.pressure");
+    let interval = std::time::Duration::from_millis(1);
+    assert!(MemoryPressure::watch_file(&psi, 10.0, interval).is_err());
+    std::fs::write(
+        &psi,
+        "some avg10=50.00 avg60=20.00 avg300=5.00 total=1000\n\
+         full avg10=10.00 avg60=5.00 avg300=1.00 total=100\n",
+    )
+    .unwrap();
+    let pressure = MemoryPressure::watch_file(&psi, 10.0, interval).unwrap();
+    let alloc = DiskAlloc::new().unwrap();
+    pressure.register(&alloc);
+    let mut v: Vec<u64, _> = Vec::new_in(alloc);
+    for i in 0..100_000 {
+        v.push(i);
+    }
+    // Relieved over and over while the data is used
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert!(v.iter().copied().eq(0..100_000));
+    drop(pressure);
+}
+
+#[test]
+fn drop_behavior() {
+    let dir = tempfile::tempdir().unwrap();
+    let open = |name: &str| {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join(name))
+            .unwrap()
+    };
+    // Grows the file to 1MiB, then frees everything
+    let use_and_free = |alloc: DiskAlloc| {
+        let mut v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1000, alloc);
+        v.extend(std::iter::repeat_n(1, 1000));
+    };
+    let len_of = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap().len();
+    let builder = DiskAllocBuilder::new().growth_chunk(1024 * 1024);
+
+    use_and_free(builder.build_on_file(open("kept")).unwrap());
+    assert_eq!(len_of("kept"), 1024 * 1024);
+
+    let truncating = builder.clone().on_drop(DropBehavior::TruncateToUsed);
+    use_and_free(truncating.build_on_file(open("truncated")).unwrap());
+    assert_eq!(len_of("truncated"), 0);
+
+    let deleting = builder.clone().on_drop(DropBehavior::Delete);
+    use_and_free(deleting.create(dir.path().join("created")).unwrap());
+    assert!(!dir.path().join("created").exists());
+    #[cfg(any(target_os = "linux", windows))]
+    {
+        use_and_free(deleting.build_on_file(open("on_file")).unwrap());
+        assert!(!dir.path().join("on_file").exists());
+    }
+
+    let named = builder
+        .dir(dir.path())
+        .prefix("diskalloc-kept")
+        .on_drop(DropBehavior::Keep);
+    use_and_free(named.build().unwrap());
+    let kept_temp = std::fs::read_dir(dir.path()).unwrap().any(|entry| {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        name.starts_with("diskalloc-kept")
+    });
+    assert!(kept_temp);
+}
+
+#[test]
+fn backing_file_and_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("persistent");
+    let alloc = DiskAlloc::create(&path).unwrap();
+    assert_eq!(alloc.backing_path().as_deref(), Some(path.as_path()));
+    let mut v: Vec<u64, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.extend(0..1000);
+    let used = alloc.stats().used_size;
+    assert!(alloc.file().unwrap().metadata().unwrap().len() >= used);
+    drop(v);
+
+    let alloc = DiskAllocBuilder::new()
+        .dir(dir.path())
+        .prefix("diskalloc-test")
+        .build()
+        .unwrap();
+    let temp = alloc.backing_path().unwrap();
+    assert!(temp.starts_with(dir.path()) && temp.exists());
+    #[cfg(unix)]
+    assert_eq!(DiskAlloc::new().unwrap().backing_path(), None);
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(DiskAlloc::new_in_memory().unwrap().backing_path(), None);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(dir.path().join("on_file"))
+            .unwrap();
+        let alloc = DiskAlloc::on_file(file).unwrap();
+        let on_file = alloc.backing_path().unwrap();
+        assert_eq!(on_file.file_name().unwrap(), "on_file");
+    }
+}
+
+// Guard pages and canaries change the file layout
+#[cfg(not(any(feature = "debug-guards", feature = "debug-canaries")))]
+#[test]
+fn on_file_at_offset() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    let offset = 1024 * 1024;
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&[7; 100]).unwrap();
+    let alloc = DiskAllocBuilder::new()
+        .on_drop(DropBehavior::TruncateToUsed)
+        .build_on_file_at(file.try_clone().unwrap(), offset)
+        .unwrap();
+    let mut v: Vec<u32, DiskAlloc> = Vec::new_in(alloc.clone());
+    v.extend(0..10_000);
+    assert_eq!(file.metadata().unwrap().len(), offset + 40_000);
+    v.truncate(100);
+    v.shrink_to_fit();
+    assert_eq!(file.metadata().unwrap().len(), offset + 400);
+    alloc.evict(0, 400).unwrap();
+    assert_eq!(v.iter().copied().sum::<u32>(), 4950);
+
+    let mut data = [0; 4];
+    file.seek(SeekFrom::Start(offset + 4 * 99)).unwrap();
+    file.read_exact(&mut data).unwrap();
+    assert_eq!(u32::from_ne_bytes(data), 99);
+    drop((v, alloc));
+    // Everything before the offset is kept
+    let mut header = Vec::new();
+    file.rewind().unwrap();
+    file.read_to_end(&mut header).unwrap();
+    assert_eq!(header.len(), offset as usize);
+    assert!(header[..100].iter().all(|&b| b == 7));
+    assert!(header[100..].iter().all(|&b| b == 0));
+
+    let misaligned = DiskAlloc::on_file_at(tempfile::tempfile().unwrap(), 100);
+    assert!(misaligned.is_err());
+}
+
+// Guard pages and canaries change the file layout
+#[cfg(not(any(feature = "debug-guards", feature = "debug-canaries")))]
+#[test]
+fn partitions_share_one_file() {
+    use std::io::{Read, Seek, SeekFrom};
+    let alloc = DiskAlloc::with_reservation(3 << 30).unwrap();
+    let _in_use: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1, alloc.clone());
+    assert!(alloc.partition(3).is_err());
+
+    let partitions = DiskAlloc::with_reservation(3 << 30)
+        .unwrap()
+        .partition(3)
+        .unwrap();
+    let mut vecs: Vec<Vec<u64, DiskAlloc>> = partitions
+        .iter()
+        .map(|partition| Vec::new_in(partition.clone()))
+        .collect();
+    for i in 0..100_000 {
+        for (n, v) in vecs.iter_mut().enumerate() {
+            v.push(i * 3 + n as u64);
+        }
+    }
+    for (n, v) in vecs.iter().enumerate() {
+        assert!(v.iter().copied().eq((0..100_000).map(|i| i * 3 + n as u64)));
+    }
+    let mut file = partitions[0].file().unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 3 << 30);
+
+    // Given back at the end of the slice, and zeroed
+    for v in &mut vecs {
+        v.truncate(10);
+        v.shrink_to_fit();
+    }
+    assert_eq!(partitions[1].stats().file_size, 80);
+    let mut second = Vec::new();
+    file.seek(SeekFrom::Start(1 << 30)).unwrap();
+    (&mut file).take(800_000).read_to_end(&mut second).unwrap();
+    let kept = second[..80]
+        .chunks(8)
+        .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()));
+    assert!(kept.eq(vecs[1].iter().copied()));
+    assert!(second[80..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn fallback_when_primary_fails() {
+    let small = DiskAllocBuilder::new()
+        .max_file_size(64 * 1024)
+        .build()
+        .unwrap();
+    let alloc = FallbackAlloc::new(small, DiskAlloc::new().unwrap());
+    let mut fits: Vec<u64, _> = Vec::new_in(&alloc);
+    fits.extend(0..1000);
+    assert_eq!(alloc.fallback().stats().used_size, 0);
+    // Moved over when growing beyond the limit
+    let mut moved: Vec<u64, _> = Vec::new_in(&alloc);
+    moved.extend(0..100_000);
+    let ptr = std::ptr::NonNull::new(moved.as_mut_ptr().cast::<u8>()).unwrap();
+    assert!(alloc.fallback().owns(ptr));
+    assert!(moved.iter().copied().eq(0..100_000));
+    moved.truncate(10);
+    moved.shrink_to_fit();
+    drop((fits, moved));
+    assert_eq!(alloc.primary().stats().live_allocations, 0);
+    assert_eq!(alloc.fallback().stats().live_allocations, 0);
+
+    let heap_first = FallbackAlloc::new(std::alloc::System, DiskAlloc::new().unwrap());
+    let mut v: Vec<u64, _> = Vec::new_in(heap_first);
+    v.extend(0..1000);
+    assert_eq!(v.allocator().fallback().stats().used_size, 0);
+}
+
+#[test]
+fn dir_fallback_chain() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    let alloc = DiskAllocBuilder::new()
+        .dirs([missing.as_path(), dir.path()])
+        .build()
+        .unwrap();
+    assert_eq!(alloc.dir().as_deref(), Some(dir.path()));
+    assert!(DiskAlloc::new().unwrap().dir().is_some());
+
+    let full = DiskAllocBuilder::new()
+        .dirs([dir.path()])
+        .min_free_space(u64::MAX)
+        .build();
+    assert_eq!(full.err().unwrap().kind(), std::io::ErrorKind::StorageFull);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+// Guard pages and canaries change the file layout
+#[cfg(all(
+    target_os = "linux",
+    not(any(feature = "debug-guards", feature = "debug-canaries"))
+))]
+#[test]
+fn lazy_free_tail() {
+    use diskallocator::ShrinkPolicy;
+    use std::os::unix::fs::MetadataExt;
+
+    let alloc = DiskAllocBuilder::new()
+        .shrink_policy(ShrinkPolicy::Never)
+        .lazy_free(true)
+        .build()
+        .unwrap();
+    let file = alloc.file().unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(1 << 20, alloc.clone());
+    v.resize(1 << 20, 1);
+    let used_blocks = file.metadata().unwrap().blocks();
+
+    v.truncate(4096);
+    v.shrink_to_fit();
+    assert_eq!(file.metadata().unwrap().len(), 1 << 20);
+    let blocks = file.metadata().unwrap().blocks();
+    assert!(blocks < used_blocks, "{blocks} >= {used_blocks}");
+    assert!(v.iter().all(|&b| b == 1));
+    v.resize(1 << 20, 2);
+    assert!(v[4096..].iter().all(|&b| b == 2));
+}
+
+// Guard pages allocate one by one
+#[cfg(not(feature = "debug-guards"))]
+#[test]
+fn allocate_many_resizes_once() {
+    use std::alloc::{Allocator, Layout};
+
+    let alloc = DiskAlloc::new().unwrap();
+    let _first: Box<u8, DiskAlloc> = Box::new_in(1, alloc.clone());
+    let layouts: Vec<Layout> = (0..100)
+        .map(|i| Layout::from_size_align(1000 + i, 1 << (i % 8)).unwrap())
+        .collect();
+    let syscalls = alloc.stats().resize_syscalls;
+    let blocks = alloc.allocate_many(&layouts).unwrap();
+    assert_eq!(alloc.stats().resize_syscalls, syscalls + 1);
+    assert_eq!(alloc.stats().live_allocations, 101);
+    let mut end = 0;
+    for (block, layout) in blocks.iter().zip(&layouts) {
+        let start = block.cast::<u8>().as_ptr() as usize;
+        assert_eq!(start % layout.align(), 0);
+        assert_eq!(block.len(), layout.size());
+        assert!(start >= end);
+        end = start + block.len();
+    }
+    for (block, layout) in blocks.into_iter().zip(&layouts).rev() {
+        unsafe { alloc.deallocate(block.cast(), *layout) };
+    }
+    assert_eq!(alloc.stats().live_allocations, 1);
+
+    // Placed one by one, but all the same
+    let buddy = DiskAllocBuilder::new()
+        .strategy(Strategy::Buddy)
+        .build()
+        .unwrap();
+    let blocks = buddy.allocate_many(&layouts).unwrap();
+    assert_eq!(blocks.len(), 100);
+    for (block, layout) in blocks.into_iter().zip(&layouts) {
+        unsafe { buddy.deallocate(block.cast(), *layout) };
+    }
+}
+
+#[test]
+fn background_extend_ahead() {
+    let alloc = DiskAllocBuilder::new()
+        .background_extend(std::time::Duration::from_millis(5), 1 << 20)
+        .build()
+        .unwrap();
+    let file = alloc.file().unwrap();
+    let mut v: Vec<u8, DiskAlloc> = Vec::with_capacity_in(4096, alloc.clone());
+    v.push(1);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while file.metadata().unwrap().len() < 1 << 20 {
+        assert!(std::time::Instant::now() < deadline, "file not extended");
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    // Growing into the extension needs no resize, shrinking neither
+    let syscalls = alloc.stats().resize_syscalls;
+    let mut w: Vec<u8, DiskAlloc> = Vec::with_capacity_in(100 << 10, alloc.clone());
+    w.push(2);
+    drop(w);
+    assert_eq!(alloc.stats().resize_syscalls, syscalls);
+    assert_eq!(v[0], 1);
+}
+
+#[test]
+fn reservation_beyond_address_space() {
+    let err = DiskAlloc::with_reservation(1 << 63).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("address space"), "{err}");
+}